@@ -0,0 +1,100 @@
+//! Data directory namespacing for running several profiles side by side.
+//!
+//! Setting `AGENT_CHATGROUP_PROFILE=work` moves every per-user location
+//! (database, config, chat history, temp workspaces) from `agents-chatgroup`
+//! to `agents-chatgroup-work`. Without the variable the original paths are used.
+
+/// Environment variable selecting the active profile.
+pub const PROFILE_ENV: &str = "AGENT_CHATGROUP_PROFILE";
+
+/// Base application name used for data directories.
+pub const APP_NAME: &str = "agents-chatgroup";
+
+/// Normalize a profile name into something safe to embed in a directory name.
+/// Keeps ASCII alphanumerics, `-` and `_`, lowercased. Returns `None` for
+/// empty names and for `default`, which maps to the un-suffixed namespace.
+pub fn sanitize_profile(raw: &str) -> Option<String> {
+    let name: String = raw
+        .trim()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-' || *c == '_')
+        .map(|c| c.to_ascii_lowercase())
+        .collect();
+    let name = name.trim_matches('-').to_string();
+
+    if name.is_empty() || name == "default" {
+        None
+    } else {
+        Some(name)
+    }
+}
+
+/// The active profile from `AGENT_CHATGROUP_PROFILE`, if any.
+pub fn active_profile() -> Option<String> {
+    std::env::var(PROFILE_ENV)
+        .ok()
+        .and_then(|raw| sanitize_profile(&raw))
+}
+
+/// Application directory name for the given profile.
+pub fn app_name_for(profile: Option<&str>) -> String {
+    match profile {
+        Some(profile) => format!("{APP_NAME}-{profile}"),
+        None => APP_NAME.to_string(),
+    }
+}
+
+/// Application directory name for the active profile.
+pub fn app_name() -> String {
+    app_name_for(active_profile().as_deref())
+}
+
+/// Temp workspace directory for the given profile. Debug builds add a `-dev`
+/// suffix so they never share workspaces with an installed app.
+pub fn temp_dir_for(profile: Option<&str>) -> std::path::PathBuf {
+    let app_name = app_name_for(profile);
+    let dir_name = if cfg!(debug_assertions) {
+        format!("{app_name}-dev")
+    } else {
+        app_name
+    };
+
+    if cfg!(target_os = "macos") {
+        // macOS already uses /var/folders/... which is persistent storage
+        std::env::temp_dir().join(dir_name)
+    } else if cfg!(target_os = "linux") {
+        // Linux: use /var/tmp instead of /tmp to avoid RAM usage
+        std::path::PathBuf::from("/var/tmp").join(dir_name)
+    } else {
+        // Windows and other platforms: use temp dir with agents-chatgroup subdirectory
+        std::env::temp_dir().join(dir_name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sanitize_profile() {
+        assert_eq!(sanitize_profile(" Work "), Some("work".to_string()));
+        assert_eq!(sanitize_profile("../evil"), Some("evil".to_string()));
+        assert_eq!(sanitize_profile("team_a-1"), Some("team_a-1".to_string()));
+        assert_eq!(sanitize_profile(""), None);
+        assert_eq!(sanitize_profile("default"), None);
+        assert_eq!(sanitize_profile("///"), None);
+    }
+
+    #[test]
+    fn test_profiles_resolve_to_distinct_directories() {
+        let work = app_name_for(sanitize_profile("work").as_deref());
+        let personal = app_name_for(sanitize_profile("personal").as_deref());
+        let default = app_name_for(None);
+
+        assert_eq!(default, "agents-chatgroup");
+        assert_eq!(work, "agents-chatgroup-work");
+        assert_eq!(personal, "agents-chatgroup-personal");
+        assert_ne!(work, personal);
+        assert_ne!(work, default);
+    }
+}
@@ -0,0 +1 @@
+ALTER TABLE chat_sessions ADD COLUMN meta TEXT NOT NULL DEFAULT '{}';
@@ -1,6 +1,6 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, SqlitePool, Type};
+use sqlx::{Executor, FromRow, Sqlite, SqlitePool, Type};
 use ts_rs::TS;
 use uuid::Uuid;
 
@@ -109,6 +109,18 @@ impl ChatMessage {
         data: &CreateChatMessage,
         id: Uuid,
     ) -> Result<Self, sqlx::Error> {
+        Self::create_tx(pool, data, id).await
+    }
+
+    /// Transaction-compatible version of create
+    pub async fn create_tx<'e, E>(
+        executor: E,
+        data: &CreateChatMessage,
+        id: Uuid,
+    ) -> Result<Self, sqlx::Error>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
         let mentions_json = sqlx::types::Json(data.mentions.clone());
         let meta_json = sqlx::types::Json(data.meta.clone());
 
@@ -132,7 +144,7 @@ impl ChatMessage {
             mentions_json,
             meta_json
         )
-        .fetch_one(pool)
+        .fetch_one(executor)
         .await
     }
 
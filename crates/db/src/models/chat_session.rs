@@ -1,6 +1,6 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use sqlx::{FromRow, SqlitePool, Type};
+use sqlx::{Executor, FromRow, Sqlite, SqlitePool, Type};
 use ts_rs::TS;
 use uuid::Uuid;
 
@@ -176,11 +176,19 @@ impl ChatSession {
     }
 
     pub async fn touch(pool: &SqlitePool, id: Uuid) -> Result<(), sqlx::Error> {
+        Self::touch_tx(pool, id).await
+    }
+
+    /// Transaction-compatible version of touch
+    pub async fn touch_tx<'e, E>(executor: E, id: Uuid) -> Result<(), sqlx::Error>
+    where
+        E: Executor<'e, Database = Sqlite>,
+    {
         sqlx::query!(
             "UPDATE chat_sessions SET updated_at = datetime('now', 'subsec') WHERE id = $1",
             id
         )
-        .execute(pool)
+        .execute(executor)
         .await?;
         Ok(())
     }
@@ -9,6 +9,10 @@ use services::services::{
     analytics::{AnalyticsConfig, AnalyticsContext, AnalyticsService, generate_user_id},
     approvals::Approvals,
     auth::AuthContext,
+    chat::{
+        MentionNotifierRegistration, QuietHoursNotifier, apply_message_settings,
+        register_mention_notifier,
+    },
     chat_runner::ChatRunner,
     config::{Config, load_config_from_file, save_config_to_file},
     container::ContainerService,
@@ -16,6 +20,7 @@ use services::services::{
     file_search::FileSearchCache,
     filesystem::FilesystemService,
     image::ImageService,
+    notification::NotificationService,
     oauth_credentials::OAuthCredentials,
     pr_monitor::PrMonitorService,
     project::ProjectService,
@@ -59,6 +64,9 @@ pub struct LocalDeployment {
     auth_context: AuthContext,
     oauth_handoffs: Arc<RwLock<HashMap<Uuid, PendingHandoff>>>,
     pty: PtyService,
+    mention_notifier: Arc<QuietHoursNotifier>,
+    /// Keeps chat mention notifications registered while the deployment lives.
+    _mention_notifications: Arc<MentionNotifierRegistration>,
 }
 
 #[derive(Debug, Clone)]
@@ -99,6 +107,7 @@ impl Deployment for LocalDeployment {
             WorktreeManager::set_workspace_dir_override(path);
         }
 
+        apply_message_settings(&raw_config);
         let config = Arc::new(RwLock::new(raw_config));
         let user_id = generate_user_id();
         let analytics = AnalyticsConfig::new().map(AnalyticsService::new);
@@ -135,7 +144,7 @@ impl Deployment for LocalDeployment {
 
         let approvals = Approvals::new(msg_stores.clone());
         let queued_message_service = QueuedMessageService::new();
-        let chat_runner = ChatRunner::new(db.clone());
+        let chat_runner = ChatRunner::new(db.clone(), config.clone());
 
         let oauth_credentials = Arc::new(OAuthCredentials::new(credentials_path()));
         if let Err(e) = oauth_credentials.load().await {
@@ -192,6 +201,11 @@ impl Deployment for LocalDeployment {
         let file_search_cache = Arc::new(FileSearchCache::new());
 
         let pty = PtyService::new();
+        let mention_notifier = Arc::new(QuietHoursNotifier::new(
+            Arc::new(NotificationService::new(config.clone())),
+            config.read().await.chat_notification_schedule.clone(),
+        ));
+        let mention_notifications = Arc::new(register_mention_notifier(mention_notifier.clone()));
         {
             let db = db.clone();
             let analytics = analytics.as_ref().map(|s| AnalyticsContext {
@@ -223,6 +237,8 @@ impl Deployment for LocalDeployment {
             auth_context,
             oauth_handoffs,
             pty,
+            mention_notifier,
+            _mention_notifications: mention_notifications,
         };
 
         Ok(deployment)
@@ -354,4 +370,9 @@ impl LocalDeployment {
     pub fn pty(&self) -> &PtyService {
         &self.pty
     }
+
+    /// Mention notifications, held back during the configured quiet hours.
+    pub fn mention_notifier(&self) -> &Arc<QuietHoursNotifier> {
+        &self.mention_notifier
+    }
 }
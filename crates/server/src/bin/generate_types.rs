@@ -193,6 +193,8 @@ fn generate_types_content() -> String {
         services::services::config::ShowcaseState::decl(),
         services::services::config::SendMessageShortcut::decl(),
         services::services::config::ChatCompressionConfig::decl(),
+        services::services::config::NotificationSchedule::decl(),
+        services::services::config::QuietHours::decl(),
         services::services::config::ChatPresetsConfig::decl(),
         services::services::config::ChatMemberPreset::decl(),
         services::services::config::ChatTeamPreset::decl(),
@@ -3,6 +3,7 @@ use server::mcp::task_server::TaskServer;
 use tracing_subscriber::{EnvFilter, prelude::*};
 use utils::{
     port_file::read_port_file,
+    profile,
     sentry::{self as sentry_utils, SentrySource, sentry_layer},
 };
 
@@ -51,7 +52,7 @@ fn main() -> anyhow::Result<()> {
                         })?
                     }
                     Err(_) => {
-                        let port = read_port_file("agents-chatgroup").await?;
+                        let port = read_port_file(&profile::app_name()).await?;
                         tracing::info!("[MCP] Using port from port file: {}", port);
                         port
                     }
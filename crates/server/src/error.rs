@@ -392,6 +392,9 @@ impl IntoResponse for ApiError {
             ApiError::Chat(ChatServiceError::SessionNotFound) => {
                 ErrorInfo::not_found("ChatServiceError", "Chat session not found.")
             }
+            ApiError::Chat(ChatServiceError::AgentNotFound) => {
+                ErrorInfo::not_found("ChatServiceError", "Chat agent not found.")
+            }
             ApiError::Chat(ChatServiceError::SessionArchived) => {
                 ErrorInfo::conflict("ChatServiceError", "Chat session is archived.")
             }
@@ -7,3 +7,9 @@ pub mod routes;
 // type DeploymentImpl = agent_chatgroup_cloud::deployment::CloudDeployment;
 // #[cfg(not(feature = "cloud"))]
 pub type DeploymentImpl = local_deployment::LocalDeployment;
+
+/// Whether the backend runs as the desktop shell's sidecar, which sets
+/// `AGENT_CHATGROUP_DESKTOP`.
+pub fn is_desktop_mode() -> bool {
+    std::env::var_os("AGENT_CHATGROUP_DESKTOP").is_some()
+}
@@ -6,8 +6,8 @@ use executors::{
     env::{ExecutionEnv, RepoContext},
     model_sync,
 };
-use server::{DeploymentImpl, routes};
-use services::services::container::ContainerService;
+use server::{DeploymentImpl, is_desktop_mode, routes};
+use services::services::{chat_history_file, container::ContainerService};
 use sqlx::Error as SqlxError;
 use strip_ansi_escapes::strip;
 use thiserror::Error;
@@ -31,10 +31,6 @@ pub enum AgentChatgroupError {
     Other(#[from] AnyhowError),
 }
 
-fn is_desktop_mode() -> bool {
-    std::env::var_os("AGENT_CHATGROUP_DESKTOP").is_some()
-}
-
 #[tokio::main]
 async fn main() -> Result<(), AgentChatgroupError> {
     // Install rustls crypto provider before any TLS operations
@@ -60,6 +56,10 @@ async fn main() -> Result<(), AgentChatgroupError> {
         std::fs::create_dir_all(asset_dir())?;
     }
 
+    // Load the tokenizer once up front; a failure is logged here a single time and
+    // every later token estimate goes straight to the character-based fallback.
+    chat_history_file::verify_tokenizer();
+
     let deployment = DeploymentImpl::new().await?;
     deployment.update_sentry_scope().await?;
     deployment
@@ -20,6 +20,7 @@ pub fn router(deployment: &DeploymentImpl) -> Router<DeploymentImpl> {
         )
         .route("/archive", axum::routing::post(sessions::archive_session))
         .route("/restore", axum::routing::post(sessions::restore_session))
+        .route("/export", axum::routing::post(sessions::export_session))
         .route("/stream", get(sessions::stream_session_ws))
         .route(
             "/agents",
@@ -353,10 +353,10 @@ pub async fn archive_session(
         return Ok(ResponseJson(ApiResponse::success(session)));
     }
 
-    let archive_dir = asset_dir()
-        .join("chat")
-        .join(format!("session_{}", session.id))
-        .join("archive");
+    let archive_dir = services::services::chat::session_archive_dir(
+        &services::services::chat::default_chat_archive_root(),
+        session.id,
+    );
     let archive_ref = services::services::chat::export_session_archive(
         &deployment.db().pool,
         &session,
@@ -379,6 +379,36 @@ pub async fn archive_session(
     Ok(ResponseJson(ApiResponse::success(updated)))
 }
 
+/// Export the session's archive into a staging directory under the asset dir
+/// and return it. Only the desktop shell calls this; it moves the files to the
+/// folder the user picked, so clients never choose where the backend writes.
+pub async fn export_session(
+    Extension(session): Extension<ChatSession>,
+    State(deployment): State<DeploymentImpl>,
+) -> Result<ResponseJson<ApiResponse<String>>, ApiError> {
+    if !crate::is_desktop_mode() {
+        return Err(ApiError::Forbidden(
+            "Session export is only available in the desktop app.".to_string(),
+        ));
+    }
+
+    let staging_dir = asset_dir()
+        .join("exports")
+        .join(format!("session_{}", session.id));
+    match tokio::fs::remove_dir_all(&staging_dir).await {
+        Ok(()) => {}
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+        Err(err) => return Err(ApiError::Io(err)),
+    }
+    let archive_ref = services::services::chat::export_session_archive(
+        &deployment.db().pool,
+        &session,
+        &staging_dir,
+    )
+    .await?;
+    Ok(ResponseJson(ApiResponse::success(archive_ref)))
+}
+
 pub async fn restore_session(
     Extension(session): Extension<ChatSession>,
     State(deployment): State<DeploymentImpl>,
@@ -22,6 +22,7 @@ use executors::{
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use services::services::{
+    chat,
     config::{
         Config, ConfigError, SoundFile,
         editor::{EditorConfig, EditorType},
@@ -39,7 +40,7 @@ use crate::{DeploymentImpl, error::ApiError};
 pub fn router() -> Router<DeploymentImpl> {
     Router::new()
         .route("/info", get(get_user_system_info))
-        .route("/config", put(update_config))
+        .route("/config", put(update_config).patch(patch_config))
         .route("/sounds/{sound}", get(get_sound))
         .route("/mcp-config", get(get_mcp_servers).post(update_mcp_servers))
         .route("/profiles", get(get_profiles).put(update_profiles))
@@ -157,6 +158,34 @@ async fn update_config(
     }
 }
 
+/// Apply only the fields in the request body, under the config write lock, so
+/// concurrent updates to other fields aren't overwritten.
+async fn patch_config(
+    State(deployment): State<DeploymentImpl>,
+    Json(patch): Json<Value>,
+) -> ResponseJson<ApiResponse<Config>> {
+    let mut config = deployment.config().write().await;
+    let old_config = config.clone();
+    let mut new_config = old_config.clone();
+
+    if let Err(e) = new_config.apply_patch(patch) {
+        return ResponseJson(ApiResponse::error(&e.to_string()));
+    }
+    if !git::is_valid_branch_prefix(&new_config.git_branch_prefix) {
+        return ResponseJson(ApiResponse::error(
+            "Invalid git branch prefix. Must be a valid git branch name component without slashes.",
+        ));
+    }
+    if let Err(e) = save_config_to_file(&new_config, &config_path()).await {
+        return ResponseJson(ApiResponse::error(&format!("Failed to save config: {}", e)));
+    }
+    *config = new_config.clone();
+    drop(config);
+
+    handle_config_events(&deployment, &old_config, &new_config).await;
+    ResponseJson(ApiResponse::success(new_config))
+}
+
 /// Track config events when fields transition from false → true
 async fn track_config_events(deployment: &DeploymentImpl, old: &Config, new: &Config) {
     let events = [
@@ -191,6 +220,13 @@ async fn track_config_events(deployment: &DeploymentImpl, old: &Config, new: &Co
 
 async fn handle_config_events(deployment: &DeploymentImpl, old: &Config, new: &Config) {
     track_config_events(deployment, old, new).await;
+    chat::apply_message_settings(new);
+
+    if old.chat_notification_schedule != new.chat_notification_schedule {
+        deployment
+            .mention_notifier()
+            .set_schedule(new.chat_notification_schedule.clone());
+    }
 
     if !old.disclaimer_acknowledged && new.disclaimer_acknowledged {
         // Spawn auto project setup as background task to avoid blocking config response
@@ -1,19 +1,24 @@
 use std::{
-    collections::{HashMap, HashSet, hash_map::DefaultHasher},
+    collections::{HashMap, HashSet, VecDeque, hash_map::DefaultHasher},
     hash::Hasher,
-    path::Path,
+    path::{Component, Path, PathBuf},
     str::FromStr,
-    sync::Arc,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicU64, Ordering},
+    },
     time::Duration,
 };
 
+use async_trait::async_trait;
+use backon::{ExponentialBuilder, Retryable};
 use chrono::Utc;
 use dashmap::DashMap;
 use db::models::{
     chat_agent::ChatAgent,
     chat_message::{ChatMessage, ChatSenderType, CreateChatMessage},
-    chat_session::{ChatSession, ChatSessionStatus},
-    chat_session_agent::{ChatSessionAgent, ChatSessionAgentState},
+    chat_session::{ChatSession, ChatSessionStatus, UpdateChatSession},
+    chat_session_agent::{ChatSessionAgent, ChatSessionAgentState, CreateChatSessionAgent},
 };
 use executors::{
     approvals::NoopExecutorApprovalService,
@@ -29,14 +34,26 @@ use futures::StreamExt;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::{Row, SqlitePool};
+use sqlx::{FromRow, Row, SqlitePool};
 use thiserror::Error;
 use tokio::{fs, io::AsyncWriteExt};
 use tokio_util::io::ReaderStream;
 use ts_rs::TS;
-use utils::{assets::config_path, log_msg::LogMsg, msg_store::MsgStore};
+use unicode_segmentation::UnicodeSegmentation;
+use utils::{assets::asset_dir, log_msg::LogMsg, msg_store::MsgStore};
 use uuid::Uuid;
 
+use super::{
+    chat_archive_checksum::write_archive_manifest,
+    chat_dead_letter::record_failed_message,
+    chat_html_export::{HtmlMessage, attachment_href, render_transcript_html},
+    chat_indexer::spawn_index_message,
+    chat_redaction::redact_secrets,
+    chat_session_meta::SessionMeta,
+    config::NotificationSchedule,
+    notification::{quiet_hours_remaining, should_notify_now},
+};
+
 #[derive(Debug, Error)]
 pub enum ChatServiceError {
     #[error(transparent)]
@@ -45,12 +62,64 @@ pub enum ChatServiceError {
     Io(#[from] std::io::Error),
     #[error("Chat session not found")]
     SessionNotFound,
+    #[error("Chat agent not found")]
+    AgentNotFound,
     #[error("Chat session is archived")]
     SessionArchived,
     #[error("Validation error: {0}")]
     Validation(String),
 }
 
+impl ChatServiceError {
+    /// SQLite busy/locked errors, which usually clear once a competing write
+    /// finishes.
+    pub fn is_transient(&self) -> bool {
+        let Self::Database(sqlx::Error::Database(err)) = self else {
+            return false;
+        };
+        // Extended result codes keep the primary code in the low byte.
+        err.code()
+            .and_then(|code| code.parse::<i32>().ok())
+            .is_some_and(|code| matches!(code & 0xff, SQLITE_BUSY | SQLITE_LOCKED))
+    }
+}
+
+const SQLITE_BUSY: i32 = 5;
+const SQLITE_LOCKED: i32 = 6;
+
+/// Retries [`create_message`] gives a busy or locked database.
+pub const DEFAULT_DB_RETRY_ATTEMPTS: usize = 3;
+
+/// Run `operation`, retrying with exponential backoff up to `max_retries`
+/// times while it fails with a [transient](ChatServiceError::is_transient)
+/// database error. Other errors are returned immediately.
+pub async fn retry_transient<T, F, Fut>(
+    max_retries: usize,
+    operation: F,
+) -> Result<T, ChatServiceError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, ChatServiceError>>,
+{
+    operation
+        .retry(
+            ExponentialBuilder::default()
+                .with_min_delay(Duration::from_millis(20))
+                .with_max_delay(Duration::from_millis(500))
+                .with_max_times(max_retries)
+                .with_jitter(),
+        )
+        .when(ChatServiceError::is_transient)
+        .notify(|err, delay| {
+            tracing::warn!(
+                error = %err,
+                "Chat database busy, retrying after {:.2}s",
+                delay.as_secs_f64()
+            )
+        })
+        .await
+}
+
 /// Default token threshold for compression (50,000 tokens)
 pub const DEFAULT_TOKEN_THRESHOLD: u32 = 50000;
 /// Default percentage of messages to compress (25%)
@@ -77,6 +146,230 @@ static COMPRESSION_RESULT_CACHE: Lazy<DashMap<Uuid, CompressionCacheEntry>> =
     Lazy::new(DashMap::new);
 const COMPRESSION_STATE_TABLE: &str = "chat_session_compression_states";
 
+/// Emitted after a message that @mentions one or more handles is stored.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MentionEvent {
+    pub session_id: Uuid,
+    pub message_id: Uuid,
+    pub sender_type: ChatSenderType,
+    /// Mentioned handles, in the order they appear in the message.
+    pub handles: Vec<String>,
+    /// Whether one of `handles` addresses the user rather than an agent:
+    /// [`USER_MENTION_HANDLE`] or a handle the user has sent messages as in
+    /// this session.
+    pub mentions_user: bool,
+}
+
+/// Handle agents use to address the user when a message carries no
+/// `sender_handle`.
+pub const USER_MENTION_HANDLE: &str = "you";
+
+/// Receives mention events from the chat service (e.g. to show desktop notifications).
+pub trait MentionNotifier: Send + Sync {
+    fn notify(&self, event: &MentionEvent);
+
+    /// Deliver events that were held back (e.g. during quiet hours), oldest
+    /// first. Notifiers that interrupt the user should coalesce them into one
+    /// summary; by default each event is delivered on its own.
+    fn notify_held(&self, events: &[MentionEvent]) {
+        for event in events {
+            self.notify(event);
+        }
+    }
+}
+
+/// Lets subscribers consume mention events from a broadcast channel.
+impl MentionNotifier for tokio::sync::broadcast::Sender<MentionEvent> {
+    fn notify(&self, event: &MentionEvent) {
+        // No receivers is not an error; nobody is listening right now.
+        let _ = self.send(event.clone());
+    }
+}
+
+/// Most mention events [`QuietHoursNotifier`] holds back; the oldest are
+/// dropped once a night's queue grows past it.
+const MAX_QUEUED_MENTIONS: usize = 200;
+
+/// Wraps a notifier so mention events arriving during quiet hours (see
+/// [`should_notify_now`]) are queued instead of delivered. When quiet hours
+/// end, or ahead of the next event outside them, or when
+/// [`flush`](Self::flush) is called, the queue is handed to
+/// [`MentionNotifier::notify_held`] in one batch.
+pub struct QuietHoursNotifier {
+    state: Arc<QuietHoursState>,
+}
+
+struct QuietHoursState {
+    inner: Arc<dyn MentionNotifier>,
+    schedule: std::sync::RwLock<NotificationSchedule>,
+    queued: std::sync::Mutex<VecDeque<MentionEvent>>,
+    /// Set while a task waits for quiet hours to end to flush the queue.
+    flush_scheduled: AtomicBool,
+}
+
+impl QuietHoursNotifier {
+    pub fn new(inner: Arc<dyn MentionNotifier>, schedule: NotificationSchedule) -> Self {
+        Self {
+            state: Arc::new(QuietHoursState {
+                inner,
+                schedule: std::sync::RwLock::new(schedule),
+                queued: std::sync::Mutex::new(VecDeque::new()),
+                flush_scheduled: AtomicBool::new(false),
+            }),
+        }
+    }
+
+    /// Replace the schedule, e.g. after the config was saved. Events queued
+    /// under the old schedule are delivered right away if the new one allows.
+    pub fn set_schedule(&self, schedule: NotificationSchedule) {
+        let allowed = should_notify_now(&schedule, chrono::Local::now().time());
+        *self
+            .state
+            .schedule
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = schedule;
+        if allowed {
+            self.flush();
+        }
+    }
+
+    pub fn queued_len(&self) -> usize {
+        self.state.queued().len()
+    }
+
+    /// Deliver every queued event, regardless of the schedule.
+    pub fn flush(&self) {
+        self.state.flush();
+    }
+
+    fn notify_at(&self, event: &MentionEvent, now: chrono::NaiveTime) {
+        if should_notify_now(&self.state.schedule(), now) {
+            self.flush();
+            self.state.inner.notify(event);
+            return;
+        }
+
+        {
+            let mut queued = self.state.queued();
+            if queued.len() >= MAX_QUEUED_MENTIONS {
+                queued.pop_front();
+            }
+            queued.push_back(event.clone());
+        }
+        self.schedule_flush();
+    }
+
+    /// Flush the queue once quiet hours are over, unless a flush is already
+    /// scheduled. Without a Tokio runtime the queue waits for the next event
+    /// outside quiet hours instead.
+    fn schedule_flush(&self) {
+        if self.state.flush_scheduled.swap(true, Ordering::AcqRel) {
+            return;
+        }
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            self.state.flush_scheduled.store(false, Ordering::Release);
+            return;
+        };
+        // Weak, so dropping the notifier also ends the wait.
+        let state = Arc::downgrade(&self.state);
+        runtime.spawn(async move {
+            loop {
+                let remaining = {
+                    let Some(state) = state.upgrade() else {
+                        return;
+                    };
+                    quiet_hours_remaining(&state.schedule(), chrono::Local::now().time())
+                };
+                match remaining {
+                    // Re-checked after the wait, in case the schedule changed.
+                    Some(remaining) => tokio::time::sleep(remaining).await,
+                    None => break,
+                }
+            }
+            if let Some(state) = state.upgrade() {
+                state.flush_scheduled.store(false, Ordering::Release);
+                state.flush();
+            }
+        });
+    }
+}
+
+impl QuietHoursState {
+    fn schedule(&self) -> std::sync::RwLockReadGuard<'_, NotificationSchedule> {
+        self.schedule
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn queued(&self) -> std::sync::MutexGuard<'_, VecDeque<MentionEvent>> {
+        self.queued
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn flush(&self) {
+        let queued: Vec<MentionEvent> = std::mem::take(&mut *self.queued()).into();
+        if !queued.is_empty() {
+            self.inner.notify_held(&queued);
+        }
+    }
+}
+
+impl MentionNotifier for QuietHoursNotifier {
+    fn notify(&self, event: &MentionEvent) {
+        self.notify_at(event, chrono::Local::now().time());
+    }
+}
+
+type MentionNotifierList = Vec<(u64, Arc<dyn MentionNotifier>)>;
+
+static MENTION_NOTIFIERS: Lazy<std::sync::RwLock<MentionNotifierList>> =
+    Lazy::new(|| std::sync::RwLock::new(Vec::new()));
+static NEXT_MENTION_NOTIFIER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps a notifier added with [`register_mention_notifier`] registered.
+/// Dropping it unregisters the notifier.
+#[derive(Debug)]
+#[must_use = "the notifier is unregistered when this is dropped"]
+pub struct MentionNotifierRegistration {
+    id: u64,
+}
+
+impl Drop for MentionNotifierRegistration {
+    fn drop(&mut self) {
+        MENTION_NOTIFIERS
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// Register a notifier that is called for every stored message with mentions,
+/// for as long as the returned registration is kept.
+pub fn register_mention_notifier(
+    notifier: Arc<dyn MentionNotifier>,
+) -> MentionNotifierRegistration {
+    let id = NEXT_MENTION_NOTIFIER_ID.fetch_add(1, Ordering::Relaxed);
+    MENTION_NOTIFIERS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push((id, notifier));
+    MentionNotifierRegistration { id }
+}
+
+fn notify_mention(event: &MentionEvent) {
+    // Cloned so a notifier can register or unregister without deadlocking.
+    let notifiers: Vec<Arc<dyn MentionNotifier>> = MENTION_NOTIFIERS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .iter()
+        .map(|(_, notifier)| notifier.clone())
+        .collect();
+    for notifier in notifiers {
+        notifier.notify(event);
+    }
+}
+
 /// Result of the message compression process
 #[derive(Debug, Clone)]
 pub struct CompressionResult {
@@ -109,6 +402,17 @@ pub struct CompressionWarning {
     pub message: String,
     /// Path to the split file containing archived messages
     pub split_file_path: String,
+    /// Where in the file's `messages` array this truncation's messages went.
+    /// Absent on warnings recorded before this was tracked.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub archived_range: Option<ArchivedMessageRange>,
+}
+
+/// A run of messages in a split or cutoff file's `messages` array.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, TS)]
+pub struct ArchivedMessageRange {
+    pub start: usize,
+    pub count: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -143,34 +447,191 @@ pub fn extract_reference_message_id(meta: &Value) -> Option<Uuid> {
     id.and_then(|value| Uuid::parse_str(value).ok())
 }
 
+/// Check a reply reference in `meta` and store it as `reference.message_id`.
+/// The referenced message must exist and belong to `session_id`.
+async fn normalize_message_reference(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    meta: &mut Value,
+) -> Result<(), ChatServiceError> {
+    let has_reference = meta.get("reference").is_some_and(|value| !value.is_null())
+        || meta
+            .get("reference_message_id")
+            .is_some_and(|value| !value.is_null());
+    if !has_reference {
+        return Ok(());
+    }
+
+    let reference_id = extract_reference_message_id(meta).ok_or_else(|| {
+        ChatServiceError::Validation("reference message_id must be a valid UUID".to_string())
+    })?;
+    let referenced = ChatMessage::find_by_id(pool, reference_id)
+        .await?
+        .ok_or_else(|| {
+            ChatServiceError::Validation(format!(
+                "referenced message {reference_id} does not exist"
+            ))
+        })?;
+    if referenced.session_id != session_id {
+        return Err(ChatServiceError::Validation(format!(
+            "referenced message {reference_id} belongs to another session"
+        )));
+    }
+
+    if let Some(map) = meta.as_object_mut() {
+        map.remove("reference_message_id");
+    }
+    meta["reference"] = serde_json::json!({ "message_id": reference_id });
+    Ok(())
+}
+
+/// Device a message was sent from: `meta.origin.device_id`, or a top-level
+/// `meta.device_id` supplied by the client.
+pub fn extract_device_id(meta: &Value) -> Option<String> {
+    meta.get("origin")
+        .and_then(|value| value.get("device_id"))
+        .and_then(|value| value.as_str())
+        .or_else(|| meta.get("device_id").and_then(|value| value.as_str()))
+        .filter(|value| !value.trim().is_empty())
+        .map(|value| value.to_string())
+}
+
+/// Named system actor (e.g. `scheduler`) from `meta.system_actor`, used in place
+/// of the generic `system` label for system messages.
+pub fn extract_system_actor(meta: &Value) -> Option<String> {
+    meta.get("system_actor")
+        .and_then(|value| value.as_str())
+        .map(str::trim)
+        .filter(|value| !value.is_empty())
+        .map(|value| value.to_string())
+}
+
+/// Characters allowed in an agent or user handle: Unicode letters and digits
+/// (so handles like `架构师` work), plus `_` and `-`.
+pub fn is_handle_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_' || c == '-'
+}
+
+/// Whether an `@` preceded by `prev` can start a mention. Handle characters and
+/// `.` before the `@` mean it is part of a word or an email address.
+fn can_start_mention(prev: Option<char>) -> bool {
+    prev.is_none_or(|c| !is_handle_char(c) && c != '.')
+}
+
+/// Whether a line opens or closes a fenced code block.
+fn is_code_fence(line: &[char]) -> bool {
+    line.iter()
+        .skip_while(|c| **c == ' ' || **c == '\t')
+        .take(3)
+        .filter(|c| **c == '`')
+        .count()
+        == 3
+}
+
+/// Mark characters inside inline code spans. A run of backticks opens a span
+/// that closes at the next run of the same length; unmatched runs are literal.
+fn mark_inline_code(chars: &[char], mask: &mut [bool]) {
+    let backtick_run = |from: usize| chars[from..].iter().take_while(|c| **c == '`').count();
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] != '`' {
+            i += 1;
+            continue;
+        }
+        let run = backtick_run(i);
+        let mut j = i + run;
+        let mut close = None;
+        while j < chars.len() {
+            if chars[j] != '`' {
+                j += 1;
+                continue;
+            }
+            let len = backtick_run(j);
+            if len == run {
+                close = Some(j + len);
+                break;
+            }
+            j += len;
+        }
+        match close {
+            Some(end) => {
+                mask[i..end].fill(true);
+                i = end;
+            }
+            None => i += run,
+        }
+    }
+}
+
+/// For each character, whether it sits inside a fenced code block or an
+/// inline code span. An unterminated fence runs to the end of the content.
+fn code_mask(chars: &[char]) -> Vec<bool> {
+    let mut mask = vec![false; chars.len()];
+    let mut in_fence = false;
+    let mut prose_start = 0;
+    let mut line_start = 0;
+
+    while line_start < chars.len() {
+        let line_end = chars[line_start..]
+            .iter()
+            .position(|c| *c == '\n')
+            .map_or(chars.len(), |offset| line_start + offset + 1);
+        let fence = is_code_fence(&chars[line_start..line_end]);
+        if in_fence {
+            mask[line_start..line_end].fill(true);
+            if fence {
+                in_fence = false;
+                prose_start = line_end;
+            }
+        } else if fence {
+            mark_inline_code(
+                &chars[prose_start..line_start],
+                &mut mask[prose_start..line_start],
+            );
+            mask[line_start..line_end].fill(true);
+            in_fence = true;
+        }
+        line_start = line_end;
+    }
+    if !in_fence {
+        mark_inline_code(&chars[prose_start..], &mut mask[prose_start..]);
+    }
+
+    mask
+}
+
+/// Collect `@handle` mentions, skipping anything inside code blocks or inline
+/// code so pasted decorators and emails don't ping agents.
 pub fn parse_mentions(content: &str) -> Vec<String> {
+    parse_tokens(content, '@')
+}
+
+/// Collect `#topic` tags, with the same rules as [`parse_mentions`].
+pub fn parse_topics(content: &str) -> Vec<String> {
+    parse_tokens(content, '#')
+}
+
+/// Collect the distinct names following `prefix` outside code. A prefix only
+/// counts at the start of a word, so `test@example.com` and `C#` don't match.
+pub fn parse_tokens(content: &str, prefix: char) -> Vec<String> {
     let chars: Vec<char> = content.chars().collect();
+    let in_code = code_mask(&chars);
     let mut mentions = Vec::new();
     let mut seen = HashSet::new();
 
     for i in 0..chars.len() {
-        if chars[i] != '@' {
+        if chars[i] != prefix || in_code[i] {
             continue;
         }
 
-        if i > 0 {
-            let prev = chars[i - 1];
-            if prev.is_alphanumeric() || prev == '_' || prev == '-' || prev == '.' {
-                continue;
-            }
+        if !can_start_mention(i.checked_sub(1).map(|prev| chars[prev])) {
+            continue;
         }
 
-        let mut name = String::new();
-        let mut j = i + 1;
-        while j < chars.len() {
-            let c = chars[j];
-            if c.is_alphanumeric() || c == '_' || c == '-' {
-                name.push(c);
-                j += 1;
-            } else {
-                break;
-            }
-        }
+        let name: String = chars[i + 1..]
+            .iter()
+            .take_while(|c| is_handle_char(**c))
+            .collect();
 
         if !name.is_empty() && seen.insert(name.clone()) {
             mentions.push(name);
@@ -212,12 +673,7 @@ pub fn parse_send_message_directives(content: &str) -> Vec<String> {
 
         let name = content[name_start..name_end].trim();
 
-        if !name.is_empty()
-            && name
-                .chars()
-                .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
-            && seen.insert(name.to_string())
-        {
+        if !name.is_empty() && name.chars().all(is_handle_char) && seen.insert(name.to_string()) {
             mentions.push(name.to_string());
         }
 
@@ -227,6 +683,262 @@ pub fn parse_send_message_directives(content: &str) -> Vec<String> {
     mentions
 }
 
+/// Order in which agents mentioned by the same message are dispatched.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TurnOrder {
+    /// The order the handles appear in the message.
+    #[default]
+    MentionOrder,
+    /// By the matching member preset's `sort_order`, then its position in the
+    /// preset list. Handles without a preset go last, in mention order.
+    PresetOrder,
+    /// Mention order rotated by `turn`, so consecutive multi-mention messages
+    /// don't always start with the same agent.
+    RoundRobin { turn: usize },
+}
+
+/// Agent handles to dispatch for `mentions`, ordered by `order`. Duplicate
+/// handles (compared case-insensitively) are dispatched once.
+pub fn next_responders(
+    config: &super::config::ChatPresetsConfig,
+    mentions: &[String],
+    order: TurnOrder,
+) -> Vec<String> {
+    let mut seen = HashSet::new();
+    let mut responders: Vec<String> = mentions
+        .iter()
+        .filter(|handle| !handle.is_empty() && seen.insert(handle.to_lowercase()))
+        .cloned()
+        .collect();
+
+    match order {
+        TurnOrder::MentionOrder => {}
+        TurnOrder::PresetOrder => {
+            let preset_rank = |handle: &String| {
+                let handle = handle.to_lowercase();
+                config
+                    .members
+                    .iter()
+                    .enumerate()
+                    .find(|(_, preset)| preset.name.to_lowercase() == handle)
+                    .map(|(index, preset)| {
+                        (
+                            preset.sort_order.is_none(),
+                            preset.sort_order.unwrap_or_default(),
+                            index,
+                        )
+                    })
+            };
+            // Stable sort keeps mention order among equal ranks; `None` sorts
+            // before `Some`, so unmatched handles are moved to the end.
+            responders.sort_by_key(|handle| {
+                let rank = preset_rank(handle);
+                (rank.is_none(), rank)
+            });
+        }
+        TurnOrder::RoundRobin { turn } => {
+            if !responders.is_empty() {
+                let len = responders.len();
+                responders.rotate_left(turn % len);
+            }
+        }
+    }
+
+    responders
+}
+
+/// Replace `@old` mentions (and `[sendMessageTo@@{old}]` directives) with the new
+/// handle, using the same boundaries as [`parse_mentions`]. Handles compare
+/// case-insensitively and anything inside code is left alone. Returns None
+/// when nothing changed.
+fn rewrite_handle_in_content(content: &str, old: &str, new: &str) -> Option<String> {
+    let chars: Vec<char> = content.chars().collect();
+    let in_code = code_mask(&chars);
+    let directive: Vec<char> = "[sendMessageTo@@{".chars().collect();
+    let old_lower = old.to_lowercase();
+    let mut result = String::with_capacity(content.len());
+    let mut changed = false;
+    let mut i = 0;
+
+    while i < chars.len() {
+        if !in_code[i] && chars[i..].starts_with(&directive) {
+            let name_start = i + directive.len();
+            if let Some(len) = chars[name_start..]
+                .windows(2)
+                .position(|pair| pair == ['}', ']'])
+            {
+                let end = name_start + len + 2;
+                let name: String = chars[name_start..name_start + len].iter().collect();
+                if name.trim().to_lowercase() == old_lower {
+                    result.push_str(&format!("[sendMessageTo@@{{{new}}}]"));
+                    changed = true;
+                } else {
+                    result.extend(&chars[i..end]);
+                }
+                i = end;
+                continue;
+            }
+        }
+
+        let c = chars[i];
+        result.push(c);
+        i += 1;
+        if c != '@' || in_code[i - 1] {
+            continue;
+        }
+        if !can_start_mention(i.checked_sub(2).map(|prev| chars[prev])) {
+            continue;
+        }
+
+        let start = i;
+        while i < chars.len() && is_handle_char(chars[i]) {
+            i += 1;
+        }
+        let name: String = chars[start..i].iter().collect();
+        if !name.is_empty() && name.to_lowercase() == old_lower {
+            result.push_str(new);
+            changed = true;
+        } else {
+            result.push_str(&name);
+        }
+    }
+
+    changed.then_some(result)
+}
+
+/// Which parts of stored history [`rename_agent`] rewrites.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RenameAgentOptions {
+    /// Replace the old handle in stored `mentions` arrays.
+    pub rewrite_mentions: bool,
+    /// Replace `@old` in message content. Off by default since it edits history.
+    pub rewrite_content: bool,
+}
+
+/// Rename an agent and, if requested, rewrite references to its old handle in
+/// the sessions it belongs to. The rename and every rewrite commit together.
+pub async fn rename_agent(
+    pool: &SqlitePool,
+    agent_id: Uuid,
+    new_name: &str,
+    options: RenameAgentOptions,
+) -> Result<ChatAgent, ChatServiceError> {
+    let new_name = new_name.trim();
+    if new_name.is_empty() || !new_name.chars().all(is_handle_char) {
+        return Err(ChatServiceError::Validation(
+            "agent name must be a non-empty handle of letters, digits, '_' or '-'".to_string(),
+        ));
+    }
+
+    let mut tx = pool.begin().await?;
+    let old_name: String = sqlx::query_scalar("SELECT name FROM chat_agents WHERE id = ?1")
+        .bind(agent_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .ok_or(ChatServiceError::AgentNotFound)?;
+    // Mentions resolve to members by name, so two members of one session
+    // can't share it (the server checks the same when adding a member).
+    let clash: Option<Uuid> = sqlx::query_scalar(
+        r#"SELECT mine.session_id
+           FROM chat_session_agents mine
+           JOIN chat_session_agents others
+             ON others.session_id = mine.session_id AND others.agent_id != mine.agent_id
+           JOIN chat_agents agents ON agents.id = others.agent_id
+           WHERE mine.agent_id = ?1
+             AND lower(trim(agents.name)) = lower(trim(?2))
+           LIMIT 1"#,
+    )
+    .bind(agent_id)
+    .bind(new_name)
+    .fetch_optional(&mut *tx)
+    .await?;
+    if let Some(session_id) = clash {
+        return Err(ChatServiceError::Validation(format!(
+            "another member of session {session_id} is already named {new_name}"
+        )));
+    }
+    let renamed = sqlx::query_as::<_, ChatAgent>(
+        "UPDATE chat_agents
+         SET name = ?2, updated_at = datetime('now', 'subsec')
+         WHERE id = ?1
+         RETURNING id, name, runner_type, system_prompt, tools_enabled, created_at, updated_at",
+    )
+    .bind(agent_id)
+    .bind(new_name)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    if !options.rewrite_mentions && !options.rewrite_content {
+        tx.commit().await?;
+        return Ok(renamed);
+    }
+
+    let messages = sqlx::query(
+        r#"SELECT m.id, m.content, m.mentions, m.meta
+           FROM chat_messages m
+           JOIN chat_session_agents sa ON sa.session_id = m.session_id
+           WHERE sa.agent_id = ?1"#,
+    )
+    .bind(agent_id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    let old_lower = old_name.to_lowercase();
+    let mut rewritten = 0usize;
+    for row in messages {
+        let id: Uuid = row.try_get("id")?;
+        let mut content: String = row.try_get("content")?;
+        let sqlx::types::Json(mut mentions): sqlx::types::Json<Vec<String>> =
+            row.try_get("mentions")?;
+        let sqlx::types::Json(mut meta): sqlx::types::Json<Value> = row.try_get("meta")?;
+        let mut changed = false;
+
+        if options.rewrite_mentions {
+            for mention in mentions.iter_mut() {
+                if mention.to_lowercase() == old_lower {
+                    *mention = new_name.to_string();
+                    changed = true;
+                }
+            }
+            if changed && meta.get("structured").is_some_and(Value::is_object) {
+                meta["structured"]["mentions"] = serde_json::json!(mentions);
+            }
+        }
+        if options.rewrite_content
+            && let Some(updated) = rewrite_handle_in_content(&content, &old_name, new_name)
+        {
+            content = updated;
+            if meta.get("structured").is_some_and(Value::is_object) {
+                meta["structured"]["content"] = serde_json::json!(content);
+            }
+            changed = true;
+        }
+
+        if changed {
+            sqlx::query(
+                "UPDATE chat_messages SET content = ?2, mentions = ?3, meta = ?4 WHERE id = ?1",
+            )
+            .bind(id)
+            .bind(&content)
+            .bind(sqlx::types::Json(&mentions))
+            .bind(sqlx::types::Json(&meta))
+            .execute(&mut *tx)
+            .await?;
+            rewritten += 1;
+        }
+    }
+    tx.commit().await?;
+
+    tracing::info!(
+        agent_id = %agent_id,
+        old_name = %old_name,
+        new_name = %new_name,
+        rewritten_messages = rewritten,
+        "Renamed chat agent"
+    );
+    Ok(renamed)
+}
+
 pub async fn create_message(
     pool: &SqlitePool,
     session_id: Uuid,
@@ -247,42 +959,184 @@ pub async fn create_message(
     .await
 }
 
-pub async fn create_message_with_id(
-    pool: &SqlitePool,
-    session_id: Uuid,
-    sender_type: ChatSenderType,
-    sender_id: Option<Uuid>,
-    content: String,
-    meta: Option<Value>,
-    message_id: Uuid,
-) -> Result<ChatMessage, ChatServiceError> {
-    if matches!(sender_type, ChatSenderType::Agent) && sender_id.is_none() {
-        return Err(ChatServiceError::Validation(
-            "sender_id is required for agent messages".to_string(),
-        ));
-    }
+/// Longest title [`derive_session_title`] produces, in grapheme clusters.
+const DERIVED_TITLE_MAX_CHARS: usize = 60;
 
-    let session = ChatSession::find_by_id(pool, session_id)
-        .await?
-        .ok_or(ChatServiceError::SessionNotFound)?;
+fn is_untitled(session: &ChatSession) -> bool {
+    session
+        .title
+        .as_deref()
+        .is_none_or(|title| title.trim().is_empty())
+}
 
-    if session.status != ChatSessionStatus::Active {
-        return Err(ChatServiceError::SessionArchived);
+/// Message text with `@mentions` removed and whitespace collapsed. Mentions
+/// inside code are kept, matching [`parse_mentions`].
+fn strip_mentions(content: &str) -> String {
+    let chars: Vec<char> = content.chars().collect();
+    let in_code = code_mask(&chars);
+    let mut stripped = String::with_capacity(content.len());
+    let mut i = 0;
+    while i < chars.len() {
+        if chars[i] == '@'
+            && !in_code[i]
+            && can_start_mention(i.checked_sub(1).map(|prev| chars[prev]))
+        {
+            let handle_len = chars[i + 1..]
+                .iter()
+                .take_while(|c| is_handle_char(**c))
+                .count();
+            if handle_len > 0 {
+                i += 1 + handle_len;
+                continue;
+            }
+        }
+        stripped.push(chars[i]);
+        i += 1;
     }
+    stripped.split_whitespace().collect::<Vec<_>>().join(" ")
+}
 
-    let mentions = match sender_type {
-        ChatSenderType::Agent => parse_send_message_directives(&content),
-        _ => parse_mentions(&content),
+/// Cut `text` to [`DERIVED_TITLE_MAX_CHARS`] grapheme clusters, backing up to
+/// the last word boundary when there is one. Cutting by grapheme keeps emoji
+/// sequences and combining marks whole.
+fn truncate_title(text: &str) -> String {
+    let Some((cut_end, next)) = text.grapheme_indices(true).nth(DERIVED_TITLE_MAX_CHARS) else {
+        return text.to_string();
     };
-    let mut meta = meta.unwrap_or_else(|| serde_json::json!({}));
-    if !meta.is_object() {
-        meta = serde_json::json!({ "raw_meta": meta });
-    }
+    let cut = &text[..cut_end];
+    let at_boundary = next.starts_with(char::is_whitespace);
+    let cut = match cut.rfind(' ') {
+        Some(index) if !at_boundary && index > 0 => &cut[..index],
+        _ => cut,
+    };
+    let cut = cut.trim_end_matches(|c: char| c.is_whitespace() || ",;:-".contains(c));
+    format!("{cut}…")
+}
+
+/// Derive a title from the session's first non-empty user message (mentions
+/// stripped, cut to about [`DERIVED_TITLE_MAX_CHARS`] characters at a word
+/// boundary) and store it if the session has no title yet. Returns the
+/// derived title either way.
+pub async fn derive_session_title(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<String, ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+    let title = ChatMessage::find_by_session_id(pool, session_id, None)
+        .await?
+        .iter()
+        .filter(|message| matches!(message.sender_type, ChatSenderType::User))
+        .map(|message| strip_mentions(&message.content))
+        .find(|text| !text.is_empty())
+        .map(|text| truncate_title(&text))
+        .ok_or_else(|| {
+            ChatServiceError::Validation(
+                "session has no user message to derive a title from".to_string(),
+            )
+        })?;
+
+    // Only fill an empty title; a concurrent rename wins.
+    sqlx::query(
+        "UPDATE chat_sessions SET title = ?2 WHERE id = ?1 AND (title IS NULL OR trim(title) = '')",
+    )
+    .bind(session_id)
+    .bind(&title)
+    .execute(pool)
+    .await?;
+
+    Ok(title)
+}
+
+/// Message fields for [`create_messages_batch`].
+#[derive(Debug, Clone)]
+pub struct NewMessage {
+    pub sender_type: ChatSenderType,
+    pub sender_id: Option<Uuid>,
+    pub content: String,
+    pub meta: Option<Value>,
+}
+
+fn validate_sender(
+    sender_type: &ChatSenderType,
+    sender_id: Option<Uuid>,
+) -> Result<(), ChatServiceError> {
+    if matches!(sender_type, ChatSenderType::Agent) && sender_id.is_none() {
+        return Err(ChatServiceError::Validation(
+            "sender_id is required for agent messages".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+async fn ensure_session_active(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<ChatSession, ChatServiceError> {
+    let session = ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+
+    if session.status != ChatSessionStatus::Active {
+        return Err(ChatServiceError::SessionArchived);
+    }
+    if SessionMeta::load(pool, session_id).await?.is_template() {
+        return Err(ChatServiceError::Validation(format!(
+            "chat session {session_id} is a template and doesn't accept messages"
+        )));
+    }
+    Ok(session)
+}
+
+/// Redact, parse mentions and build the stored meta for a new message.
+async fn prepare_message(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    sender_type: ChatSenderType,
+    sender_id: Option<Uuid>,
+    content: String,
+    meta: Option<Value>,
+    settings: &MessageSettings,
+) -> Result<CreateChatMessage, ChatServiceError> {
+    let content = if settings.redact_secrets {
+        let (redacted, kinds) = redact_secrets(&content);
+        if !kinds.is_empty() {
+            tracing::info!(
+                session_id = %session_id,
+                kinds = ?kinds,
+                "Redacted secrets from chat message before storing"
+            );
+        }
+        redacted
+    } else {
+        content
+    };
+    let content = if settings.normalize_whitespace {
+        normalize_content(&content)
+    } else {
+        content
+    };
+
+    let mentions = match sender_type {
+        ChatSenderType::Agent => parse_send_message_directives(&content),
+        _ => parse_mentions(&content),
+    };
+    check_mention_limit(&mentions, settings.max_mentions_per_message)?;
+    let topics = parse_topics(&content);
+    let mut meta = meta.unwrap_or_else(|| serde_json::json!({}));
+    if !meta.is_object() {
+        meta = serde_json::json!({ "raw_meta": meta });
+    }
+    if !topics.is_empty() {
+        meta["topics"] = serde_json::json!(topics);
+    }
     if content.trim().is_empty() && !has_attachments(&meta) {
         return Err(ChatServiceError::Validation(
             "content cannot be empty".to_string(),
         ));
     }
+    normalize_message_reference(pool, session_id, &mut meta).await?;
 
     let sender_handle = meta
         .get("sender_handle")
@@ -306,9 +1160,19 @@ pub async fn create_message_with_id(
             .clone()
             .or_else(|| sender_id.map(|id| id.to_string()))
             .unwrap_or_else(|| "agent".to_string()),
-        ChatSenderType::System => "system".to_string(),
+        ChatSenderType::System => {
+            extract_system_actor(&meta).unwrap_or_else(|| "system".to_string())
+        }
     };
 
+    let device_id = extract_device_id(&meta);
+    if let Some(device_id) = device_id.as_ref() {
+        if !meta.get("origin").is_some_and(Value::is_object) {
+            meta["origin"] = serde_json::json!({});
+        }
+        meta["origin"]["device_id"] = serde_json::json!(device_id);
+    }
+
     if meta.get("sender").is_none() {
         meta["sender"] = serde_json::json!({
             "type": sender_type,
@@ -326,1989 +1190,9356 @@ pub async fn create_message_with_id(
         "sender_label": sender_label,
         "content": content.clone(),
         "mentions": mentions.clone(),
+        "topics": topics,
+        "device_id": device_id,
         "created_at": Utc::now().to_rfc3339(),
     });
 
-    let message = ChatMessage::create(
+    Ok(CreateChatMessage {
+        session_id,
+        sender_type,
+        sender_id,
+        content,
+        mentions,
+        meta,
+    })
+}
+
+async fn notify_message_mentions(pool: &SqlitePool, message: &ChatMessage) {
+    if message.mentions.0.is_empty() {
+        return;
+    }
+    let mentions_user = mentions_user(pool, message)
+        .await
+        .inspect_err(|err| {
+            tracing::warn!(
+                session_id = %message.session_id,
+                message_id = %message.id,
+                error = %err,
+                "Failed to look up the user's handles for a mention event"
+            );
+        })
+        .unwrap_or(false);
+    notify_mention(&MentionEvent {
+        session_id: message.session_id,
+        message_id: message.id,
+        sender_type: message.sender_type.clone(),
+        handles: message.mentions.0.clone(),
+        mentions_user,
+    });
+}
+
+/// Whether `message` mentions [`USER_MENTION_HANDLE`] or a `sender_handle`
+/// of the user's messages in its session.
+async fn mentions_user(pool: &SqlitePool, message: &ChatMessage) -> Result<bool, sqlx::Error> {
+    let mentions = &message.mentions.0;
+    if mentions
+        .iter()
+        .any(|mention| mention.eq_ignore_ascii_case(USER_MENTION_HANDLE))
+    {
+        return Ok(true);
+    }
+    let user_handles: Vec<String> = sqlx::query_scalar(
+        r#"SELECT DISTINCT json_extract(meta, '$.sender_handle')
+           FROM chat_messages
+           WHERE session_id = ?1 AND sender_type = 'user'
+             AND json_extract(meta, '$.sender_handle') IS NOT NULL"#,
+    )
+    .bind(message.session_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(mentions.iter().any(|mention| {
+        user_handles
+            .iter()
+            .any(|handle| handle.eq_ignore_ascii_case(mention))
+    }))
+}
+
+/// Insert a message and touch its session in one transaction. `before_commit`
+/// runs after both writes; an error from it rolls the transaction back.
+async fn insert_message_and_touch<F>(
+    pool: &SqlitePool,
+    data: &CreateChatMessage,
+    message_id: Uuid,
+    before_commit: F,
+) -> Result<ChatMessage, ChatServiceError>
+where
+    F: FnOnce(&ChatMessage) -> Result<(), ChatServiceError>,
+{
+    let mut tx = pool.begin().await?;
+    let message = ChatMessage::create_tx(&mut *tx, data, message_id).await?;
+    ChatSession::touch_tx(&mut *tx, data.session_id).await?;
+    before_commit(&message)?;
+    tx.commit().await?;
+    Ok(message)
+}
+
+#[tracing::instrument(
+    name = "create_message",
+    level = "debug",
+    skip_all,
+    fields(session_id = %session_id, message_id = %message_id)
+)]
+pub async fn create_message_with_id(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    sender_type: ChatSenderType,
+    sender_id: Option<Uuid>,
+    content: String,
+    meta: Option<Value>,
+    message_id: Uuid,
+) -> Result<ChatMessage, ChatServiceError> {
+    create_message_with_settings(
         pool,
-        &CreateChatMessage {
-            session_id,
+        session_id,
+        NewMessage {
             sender_type,
             sender_id,
             content,
-            mentions,
             meta,
         },
         message_id,
+        &MessageSettings::current(),
+        &FsHistoryStore::default(),
+    )
+    .await
+}
+
+async fn create_message_with_settings(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    new_message: NewMessage,
+    message_id: Uuid,
+    settings: &MessageSettings,
+    history_store: &dyn HistoryStore,
+) -> Result<ChatMessage, ChatServiceError> {
+    validate_sender(&new_message.sender_type, new_message.sender_id)?;
+    let session = retry_transient(DEFAULT_DB_RETRY_ATTEMPTS, || {
+        ensure_session_active(pool, session_id)
+    })
+    .await?;
+    let data = prepare_message(
+        pool,
+        session_id,
+        new_message.sender_type,
+        new_message.sender_id,
+        new_message.content,
+        new_message.meta,
+        settings,
     )
     .await?;
 
-    ChatSession::touch(pool, session_id).await?;
+    let inserted = retry_transient(DEFAULT_DB_RETRY_ATTEMPTS, || {
+        insert_message_and_touch(pool, &data, message_id, |_| Ok(()))
+    })
+    .await;
+    let message = match inserted {
+        Ok(message) => message,
+        Err(err) => {
+            record_failed_message(message_id, &data, &err).await;
+            return Err(err);
+        }
+    };
+    tracing::debug!(
+        mentions = message.mentions.0.len(),
+        content_chars = message.content.chars().count(),
+        "Stored chat message"
+    );
+
+    notify_message_mentions(pool, &message).await;
+    spawn_index_message(&message);
+    enforce_session_size(pool, session_id, settings, history_store).await;
+
+    if matches!(message.sender_type, ChatSenderType::User)
+        && is_untitled(&session)
+        && let Err(err) = derive_session_title(pool, session_id).await
+    {
+        tracing::debug!(
+            session_id = %session_id,
+            error = %err,
+            "Could not derive chat session title"
+        );
+    }
 
     Ok(message)
 }
 
-pub async fn build_structured_messages(
-    pool: &SqlitePool,
-    session_id: Uuid,
-) -> Result<Vec<Value>, ChatServiceError> {
-    let messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
-    let agents = ChatAgent::find_all(pool).await?;
-    let agent_map: HashMap<Uuid, String> = agents
-        .into_iter()
-        .map(|agent| (agent.id, agent.name))
-        .collect();
+/// Meta flag set on an agent reply placeholder until it is finalized.
+pub const PENDING_REPLY_META: &str = "pending";
 
-    let mut result = Vec::with_capacity(messages.len());
+pub fn is_pending_reply(meta: &Value) -> bool {
+    meta.get(PENDING_REPLY_META)
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
 
-    for message in messages {
-        let sender_handle = message
-            .meta
-            .0
-            .get("sender_handle")
-            .and_then(|value| value.as_str())
-            .map(|value| value.to_string());
-        let sender_name = message.sender_id.and_then(|id| agent_map.get(&id).cloned());
-        let sender_label = match message.sender_type {
-            ChatSenderType::User => sender_handle.clone().unwrap_or_else(|| "user".to_string()),
-            ChatSenderType::Agent => sender_name
-                .clone()
-                .or_else(|| message.sender_id.map(|id| id.to_string()))
-                .unwrap_or_else(|| "agent".to_string()),
-            ChatSenderType::System => "system".to_string(),
-        };
+/// Meta flag marking an agent reply placeholder that was cancelled. Such
+/// placeholders are kept as a record but left out of structured messages and
+/// contexts.
+pub const CANCELLED_REPLY_META: &str = "cancelled";
 
-        let sender = serde_json::json!({
-            "type": message.sender_type,
-            "id": message.sender_id,
-            "handle": sender_handle,
-            "name": sender_name,
-            "label": sender_label,
-        });
+pub fn is_cancelled_reply(meta: &Value) -> bool {
+    meta.get(CANCELLED_REPLY_META)
+        .and_then(Value::as_bool)
+        .unwrap_or(false)
+}
 
-        result.push(serde_json::json!({
-            "id": message.id,
-            "session_id": message.session_id,
-            "created_at": message.created_at,
-            "sender": sender,
-            "content": message.content,
-            "mentions": message.mentions.0,
-            "meta": message.meta.0,
-        }));
+/// Insert an empty placeholder for a reply `agent_id` is generating, flagged
+/// with [`PENDING_REPLY_META`] so the UI can show it as in progress. Fill it
+/// in with [`finalize_agent_reply`]. Returns the placeholder's message ID.
+pub async fn begin_agent_reply(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    agent_id: Uuid,
+) -> Result<Uuid, ChatServiceError> {
+    ensure_session_active(pool, session_id).await?;
+    if ChatAgent::find_by_id(pool, agent_id).await?.is_none() {
+        return Err(ChatServiceError::Validation(format!(
+            "chat agent {agent_id} not found"
+        )));
     }
 
-    Ok(result)
+    let data = CreateChatMessage {
+        session_id,
+        sender_type: ChatSenderType::Agent,
+        sender_id: Some(agent_id),
+        content: String::new(),
+        mentions: Vec::new(),
+        meta: serde_json::json!({ PENDING_REPLY_META: true }),
+    };
+    let message = retry_transient(DEFAULT_DB_RETRY_ATTEMPTS, || {
+        insert_message_and_touch(pool, &data, Uuid::new_v4(), |_| Ok(()))
+    })
+    .await?;
+    Ok(message.id)
 }
 
-/// Context with LLM-compressed summary message included
-pub struct CompactedContext {
-    /// The compacted messages (summary + recent messages)
-    pub messages: Vec<Value>,
-    /// Raw JSONL string for prompt injection
-    pub jsonl: String,
-    /// Whether context compression has been applied
-    pub context_compacted: bool,
-    /// Warning if compression fell back to truncation
-    pub compression_warning: Option<CompressionWarning>,
+/// Replace a placeholder from [`begin_agent_reply`] with the finished reply.
+/// The content goes through the same processing as a new message (redaction,
+/// mention parsing, meta) and the pending flag is cleared. Fails if the
+/// message isn't a pending placeholder, e.g. when it was already finalized.
+pub async fn finalize_agent_reply(
+    pool: &SqlitePool,
+    message_id: Uuid,
+    content: String,
+) -> Result<ChatMessage, ChatServiceError> {
+    let placeholder = ChatMessage::find_by_id(pool, message_id)
+        .await?
+        .filter(|message| is_pending_reply(&message.meta.0))
+        .ok_or_else(|| {
+            ChatServiceError::Validation(format!(
+                "message {message_id} is not a pending agent reply"
+            ))
+        })?;
+    ensure_session_active(pool, placeholder.session_id).await?;
+    let mut meta = placeholder.meta.0;
+    if let Some(meta) = meta.as_object_mut() {
+        meta.remove(PENDING_REPLY_META);
+    }
+    let data = prepare_message(
+        pool,
+        placeholder.session_id,
+        placeholder.sender_type,
+        placeholder.sender_id,
+        content,
+        Some(meta),
+        &MessageSettings::current(),
+    )
+    .await?;
+
+    // Conditional on the flag, so two finalizers can't both win.
+    let updated = sqlx::query(
+        "UPDATE chat_messages SET content = ?2, mentions = ?3, meta = ?4
+         WHERE id = ?1 AND json_extract(meta, '$.pending') = 1",
+    )
+    .bind(message_id)
+    .bind(&data.content)
+    .bind(sqlx::types::Json(&data.mentions))
+    .bind(sqlx::types::Json(&data.meta))
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if updated == 0 {
+        return Err(ChatServiceError::Validation(format!(
+            "message {message_id} is not a pending agent reply"
+        )));
+    }
+    ChatSession::touch(pool, data.session_id).await?;
+
+    let message = ChatMessage::find_by_id(pool, message_id)
+        .await?
+        .ok_or_else(|| ChatServiceError::Validation(format!("message {message_id} not found")))?;
+    notify_message_mentions(pool, &message).await;
+    spawn_index_message(&message);
+    Ok(message)
 }
 
-async fn load_chat_compression_settings() -> (u32, u8) {
-    let config = super::config::load_config_from_file(&config_path()).await;
-    let threshold = config.chat_compression.token_threshold.max(1);
-    let percentage = config.chat_compression.compression_percentage.clamp(1, 100);
-    (threshold, percentage)
+/// Cancel a reply started with [`begin_agent_reply`]: the placeholder loses
+/// its pending flag and is marked [`CANCELLED_REPLY_META`] (with a
+/// `cancelled_at` timestamp), which hides it. Does nothing if the reply was
+/// already finalized or cancelled.
+pub async fn cancel_agent_reply(
+    pool: &SqlitePool,
+    message_id: Uuid,
+) -> Result<(), ChatServiceError> {
+    ChatMessage::find_by_id(pool, message_id)
+        .await?
+        .ok_or_else(|| ChatServiceError::Validation(format!("message {message_id} not found")))?;
+
+    // Conditional on the flag, so a reply finalized meanwhile is left alone.
+    let cancelled = sqlx::query(
+        "UPDATE chat_messages
+         SET meta = json_set(json_remove(meta, '$.pending'), '$.cancelled', json('true'), '$.cancelled_at', ?2)
+         WHERE id = ?1 AND json_extract(meta, '$.pending') = 1",
+    )
+    .bind(message_id)
+    .bind(Utc::now().to_rfc3339())
+    .execute(pool)
+    .await?
+    .rows_affected();
+    if cancelled > 0 {
+        tracing::info!(message_id = %message_id, "Cancelled pending agent reply");
+    }
+    Ok(())
 }
 
-fn simplified_to_context_value(message: &SimplifiedMessage) -> Value {
-    let time = chrono::DateTime::parse_from_rfc3339(&message.timestamp)
-        .map(|dt| {
-            dt.with_timezone(&chrono::Local)
-                .format("%Y-%m-%d %H:%M:%S")
-                .to_string()
-        })
-        .unwrap_or_else(|_| message.timestamp.clone());
+/// Meta key holding a client-supplied idempotency key.
+pub const IDEMPOTENCY_KEY_META: &str = "idempotency_key";
 
-    serde_json::json!({
-        "sender": message.sender,
-        "content": message.content,
-        "time": time,
-    })
+/// Async mutexes keyed by session ID. An entry only lives while some task
+/// holds or waits on its lock, so the map doesn't grow with every session.
+#[derive(Default)]
+struct SessionLocks {
+    locks: DashMap<Uuid, Arc<tokio::sync::Mutex<()>>>,
 }
 
-fn simplified_messages_to_jsonl(messages: &[SimplifiedMessage]) -> (Vec<Value>, String) {
-    let context_messages: Vec<Value> = messages.iter().map(simplified_to_context_value).collect();
-    let jsonl = context_messages
-        .iter()
-        .filter_map(|msg| serde_json::to_string(msg).ok())
-        .collect::<Vec<_>>()
-        .join("\n")
-        + "\n";
-    (context_messages, jsonl)
+impl SessionLocks {
+    /// Run `task` while holding the lock for `session_id`.
+    async fn run<T>(&self, session_id: Uuid, task: impl Future<Output = T>) -> T {
+        let lock = self.locks.entry(session_id).or_default().clone();
+        let output = {
+            let _guard = lock.lock().await;
+            task.await
+        };
+        // Only the map and `lock` own it when nobody else is waiting.
+        self.locks
+            .remove_if(&session_id, |_, held| Arc::strong_count(held) == 2);
+        output
+    }
 }
 
-/// Build full (uncompressed) context.
-///
-/// This is used by the non-blocking main execution path so agent runs are never
-/// delayed by summarization/compression.
-pub async fn build_full_context(
+/// Serializes idempotent creates per session so the lookup and the insert
+/// can't race with a concurrent retry of the same request.
+static IDEMPOTENT_CREATE_LOCKS: Lazy<SessionLocks> = Lazy::new(SessionLocks::default);
+
+async fn find_message_by_idempotency_key(
     pool: &SqlitePool,
     session_id: Uuid,
-) -> Result<CompactedContext, ChatServiceError> {
-    let all_messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
-    let agents = ChatAgent::find_all(pool).await?;
-    let agent_map: HashMap<Uuid, String> = agents
-        .into_iter()
-        .map(|agent| (agent.id, agent.name))
-        .collect();
-
-    let simplified_messages: Vec<SimplifiedMessage> = all_messages
-        .iter()
-        .map(|message| to_simplified_message(message, &agent_map))
-        .collect();
-
-    let (messages, jsonl) = simplified_messages_to_jsonl(&simplified_messages);
-    Ok(CompactedContext {
-        messages,
-        jsonl,
-        context_compacted: false,
-        compression_warning: None,
-    })
+    key: &str,
+) -> Result<Option<ChatMessage>, ChatServiceError> {
+    let existing: Option<Uuid> = sqlx::query_scalar(
+        "SELECT id FROM chat_messages
+         WHERE session_id = ?1 AND json_extract(meta, '$.idempotency_key') = ?2
+         ORDER BY created_at ASC
+         LIMIT 1",
+    )
+    .bind(session_id)
+    .bind(key)
+    .fetch_optional(pool)
+    .await?;
+    match existing {
+        Some(id) => Ok(ChatMessage::find_by_id(pool, id).await?),
+        None => Ok(None),
+    }
 }
 
-/// Build compacted context with token-threshold based compression only.
-///
-/// # Arguments
-/// * `pool` - Database connection pool
-/// * `session_id` - Chat session ID
-/// * `runner_type` - Runner type string for the agent (e.g., "CLAUDE_CODE", "CODEX")
-/// * `workspace_path` - Path to workspace for running LLM
-/// * `context_dir` - Path to context directory for storing cutoff files
-///
-/// # Returns
-/// CompactedContext with messages and JSONL string
-pub async fn build_compacted_context(
+/// Like [`create_message`], but when `meta` carries an `idempotency_key` and a
+/// message with that key already exists in the session, that message is
+/// returned instead of storing a duplicate (e.g. when the UI double-submits).
+/// Without a key this behaves exactly like [`create_message`].
+pub async fn create_message_idempotent(
     pool: &SqlitePool,
     session_id: Uuid,
-    _runner_type: Option<&str>,
-    workspace_path: Option<&std::path::Path>,
-    context_dir: Option<&std::path::Path>,
-) -> Result<CompactedContext, ChatServiceError> {
-    // Fetch all messages for the session
-    let all_messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
-    let agents = ChatAgent::find_all(pool).await?;
-    let agent_map: HashMap<Uuid, String> = agents
-        .into_iter()
-        .map(|agent| (agent.id, agent.name))
-        .collect();
+    sender_type: ChatSenderType,
+    sender_id: Option<Uuid>,
+    content: String,
+    meta: Option<Value>,
+) -> Result<ChatMessage, ChatServiceError> {
+    let key = meta
+        .as_ref()
+        .and_then(|meta| meta.get(IDEMPOTENCY_KEY_META))
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .map(str::to_string);
+    let Some(key) = key else {
+        return create_message(pool, session_id, sender_type, sender_id, content, meta).await;
+    };
 
-    let simplified_messages: Vec<SimplifiedMessage> = all_messages
-        .iter()
-        .map(|message| to_simplified_message(message, &agent_map))
-        .collect();
-    let session_agents = ChatSessionAgent::find_all_for_session(pool, session_id).await?;
-    let (token_threshold, compression_percentage) = load_chat_compression_settings().await;
-    let workspace_path = workspace_path.unwrap_or(std::path::Path::new("."));
+    IDEMPOTENT_CREATE_LOCKS
+        .run(session_id, async {
+            if let Some(existing) = find_message_by_idempotency_key(pool, session_id, &key).await? {
+                tracing::debug!(
+                    session_id = %session_id,
+                    message_id = %existing.id,
+                    "Reusing chat message for repeated idempotency key"
+                );
+                return Ok(existing);
+            }
+            create_message(pool, session_id, sender_type, sender_id, content, meta).await
+        })
+        .await
+}
 
-    let compression_result = compress_messages_if_needed(
-        pool,
-        session_id,
-        simplified_messages,
-        token_threshold,
-        compression_percentage,
-        &session_agents,
-        workspace_path,
-        context_dir,
-    )
-    .await?;
+/// Create several messages in one transaction, e.g. when importing a
+/// conversation. The session is checked once and touched once; every message
+/// is validated before anything is written.
+async fn insert_message_batch(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    prepared: &[CreateChatMessage],
+    message_ids: &[Uuid],
+) -> Result<Vec<ChatMessage>, ChatServiceError> {
+    let mut tx = pool.begin().await?;
+    let mut created = Vec::with_capacity(prepared.len());
+    for (data, message_id) in prepared.iter().zip(message_ids) {
+        created.push(ChatMessage::create_tx(&mut *tx, data, *message_id).await?);
+    }
+    ChatSession::touch_tx(&mut *tx, session_id).await?;
+    tx.commit().await?;
+    Ok(created)
+}
 
-    let (messages, jsonl) = simplified_messages_to_jsonl(&compression_result.messages);
+#[tracing::instrument(level = "debug", skip_all, fields(session_id = %session_id))]
+pub async fn create_messages_batch(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    messages: Vec<NewMessage>,
+) -> Result<Vec<ChatMessage>, ChatServiceError> {
+    if messages.is_empty() {
+        return Ok(Vec::new());
+    }
+    for message in &messages {
+        validate_sender(&message.sender_type, message.sender_id)?;
+    }
+    ensure_session_active(pool, session_id).await?;
 
-    Ok(CompactedContext {
-        messages,
-        jsonl,
-        context_compacted: compression_result.compression_type != CompressionType::None,
-        compression_warning: compression_result.warning,
-    })
+    let settings = MessageSettings::current();
+    let mut prepared = Vec::with_capacity(messages.len());
+    for message in messages {
+        prepared.push(
+            prepare_message(
+                pool,
+                session_id,
+                message.sender_type,
+                message.sender_id,
+                message.content,
+                message.meta,
+                &settings,
+            )
+            .await?,
+        );
+    }
+
+    let message_ids: Vec<Uuid> = prepared.iter().map(|_| Uuid::new_v4()).collect();
+    let created = match insert_message_batch(pool, session_id, &prepared, &message_ids).await {
+        Ok(created) => created,
+        Err(err) => {
+            for (data, message_id) in prepared.iter().zip(&message_ids) {
+                record_failed_message(*message_id, data, &err).await;
+            }
+            return Err(err);
+        }
+    };
+    tracing::debug!(messages = created.len(), "Stored chat message batch");
+
+    for message in &created {
+        notify_message_mentions(pool, message).await;
+        spawn_index_message(message);
+    }
+    enforce_session_size(pool, session_id, &settings, &FsHistoryStore::default()).await;
+
+    Ok(created)
 }
 
-pub async fn export_session_archive(
+/// Keep the session within `chat_max_messages_per_session`, if configured.
+/// The message is already stored, so a failure here is only logged.
+async fn enforce_session_size(
     pool: &SqlitePool,
-    session: &ChatSession,
-    archive_dir: &Path,
-) -> Result<String, ChatServiceError> {
-    fs::create_dir_all(archive_dir).await?;
+    session_id: Uuid,
+    settings: &MessageSettings,
+    history_store: &dyn HistoryStore,
+) {
+    let Some(max_messages) = settings.max_messages_per_session else {
+        return;
+    };
+    if let Err(err) = TRIM_LOCKS
+        .run(
+            session_id,
+            trim_session_messages(pool, session_id, max_messages, history_store),
+        )
+        .await
+    {
+        tracing::warn!(
+            session_id = %session_id,
+            error = %err,
+            "Failed to move old chat messages to the split history file"
+        );
+    }
+}
 
-    let messages = build_structured_messages(pool, session.id).await?;
-    let export_path = archive_dir.join("messages_export.jsonl");
-    let mut file = fs::File::create(&export_path).await?;
-    for message in messages {
-        let line = serde_json::to_string(&message).unwrap_or_default();
-        file.write_all(line.as_bytes()).await?;
-        file.write_all(b"\n").await?;
+/// Serializes [`trim_session_messages`] per session, so concurrent creates
+/// can't move the same overflow twice.
+static TRIM_LOCKS: Lazy<SessionLocks> = Lazy::new(SessionLocks::default);
+
+/// Move the oldest messages beyond `max_messages` to the session's split
+/// history file and delete their rows. Each split entry keeps the full row in
+/// [`SimplifiedMessage::record`], and rows are only deleted once the split
+/// file has them, so no history is lost. Callers hold the session's
+/// [`TRIM_LOCKS`] entry. Returns how many were moved.
+async fn trim_session_messages(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    max_messages: usize,
+    history_store: &dyn HistoryStore,
+) -> Result<usize, ChatServiceError> {
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM chat_messages WHERE session_id = ?1")
+        .bind(session_id)
+        .fetch_one(pool)
+        .await?;
+    let overflow = (count as usize).saturating_sub(max_messages);
+    if overflow == 0 {
+        return Ok(0);
     }
 
-    let summary_path = archive_dir.join("session_summary.md");
-    let summary = session
-        .summary_text
-        .clone()
-        .unwrap_or_else(|| "No summary available.".to_string());
-    fs::write(&summary_path, summary).await?;
+    let oldest = sqlx::query_as::<_, ChatMessage>(
+        "SELECT id, session_id, sender_type, sender_id, content, mentions, meta, created_at
+         FROM chat_messages
+         WHERE session_id = ?1
+         ORDER BY created_at ASC, rowid ASC
+         LIMIT ?2",
+    )
+    .bind(session_id)
+    .bind(overflow as i64)
+    .fetch_all(pool)
+    .await?;
+    let agent_map = build_agent_map(pool).await?;
+    let simplified: Vec<SimplifiedMessage> = oldest
+        .iter()
+        .map(|message| SimplifiedMessage {
+            record: Some(message.clone()),
+            ..to_simplified_message(message, &agent_map)
+        })
+        .collect();
+    history_store
+        .append(session_id, HistoryFileKind::Split, &simplified)
+        .await
+        .map_err(|e| {
+            ChatServiceError::Io(std::io::Error::other(format!(
+                "Failed to append to split file: {}",
+                e
+            )))
+        })?;
 
-    Ok(archive_dir.to_string_lossy().to_string())
+    let mut tx = pool.begin().await?;
+    for message in &oldest {
+        sqlx::query("DELETE FROM chat_messages WHERE id = ?1")
+            .bind(message.id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    COMPRESSION_RESULT_CACHE.remove(&session_id);
+
+    tracing::info!(
+        session_id = %session_id,
+        moved = oldest.len(),
+        max_messages,
+        "Moved oldest chat messages to the split history file"
+    );
+    Ok(oldest.len())
 }
 
-// ==========================================
-// New Token-Based Compression System
-// ==========================================
+/// Display label for a message's sender: the user handle, agent name or
+/// system actor, with generic fallbacks.
+fn message_sender_label(message: &ChatMessage, agent_map: &HashMap<Uuid, String>) -> String {
+    match message.sender_type {
+        ChatSenderType::User => message
+            .meta
+            .0
+            .get("sender_handle")
+            .and_then(|value| value.as_str())
+            .unwrap_or("user")
+            .to_string(),
+        ChatSenderType::Agent => message
+            .sender_id
+            .and_then(|id| agent_map.get(&id).cloned())
+            .or_else(|| message.sender_id.map(|id| id.to_string()))
+            .unwrap_or_else(|| "agent".to_string()),
+        ChatSenderType::System => {
+            extract_system_actor(&message.meta.0).unwrap_or_else(|| "system".to_string())
+        }
+    }
+}
+
+/// Version of the structured message shape produced by
+/// [`build_structured_messages`], [`build_structured_messages_filtered`] and
+/// archive exports. Bump it whenever a field is added, removed, renamed or
+/// changes meaning, so frontend and MCP consumers can detect the change.
+///
+/// Version 2 added `"pending"`, version 3 `"sender.color"`. Current shape:
+///
+/// ```text
+/// {
+///   "schema_version": 3,
+///   "id": uuid,
+///   "session_id": uuid,
+///   "created_at": RFC 3339 timestamp,
+///   "sender": {
+///     "type": "user" | "agent" | "system",
+///     "id": uuid | null,
+///     "handle": string | null,   // meta.sender_handle
+///     "name": string | null,     // agent name for agent senders
+///     "label": string,           // display label, e.g. "coder" or "user"
+///     "color": string | null     // "#rrggbb" for agent senders, see agent_color
+///   },
+///   "content": string,
+///   "mentions": [string],
+///   "device_id": string | null,
+///   "pending": bool,           // agent reply still being generated
+///   "meta": object             // stored message meta, unchanged
+/// }
+/// ```
+pub const STRUCTURED_MESSAGE_SCHEMA_VERSION: u32 = 3;
+
+/// Display color for an agent as `#rrggbb`, derived from its ID alone so it
+/// stays the same across sessions and restarts. The hue comes from an FNV-1a
+/// hash of the UUID bytes; saturation and lightness are fixed so every agent
+/// color reads well on the same background.
+pub fn agent_color(agent_id: Uuid) -> String {
+    let hash = agent_id
+        .as_bytes()
+        .iter()
+        .fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ u32::from(*byte)).wrapping_mul(0x0100_0193)
+        });
+    let (r, g, b) = hsl_to_rgb(f64::from(hash % 360), 0.65, 0.5);
+    format!("#{r:02x}{g:02x}{b:02x}")
+}
 
-use super::chat_history_file::{SimplifiedMessage, append_to_split_file, estimate_token_count};
+fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> (u8, u8, u8) {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let sector = hue / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |value: f64| ((value + m) * 255.0).round() as u8;
+    (channel(r), channel(g), channel(b))
+}
 
-/// Convert ChatMessage to SimplifiedMessage format (sender + content only)
-pub fn to_simplified_message(
-    message: &ChatMessage,
-    agent_map: &HashMap<Uuid, String>,
-) -> SimplifiedMessage {
+fn structured_message_value(message: ChatMessage, agent_map: &HashMap<Uuid, String>) -> Value {
     let sender_handle = message
         .meta
         .0
         .get("sender_handle")
-        .and_then(|value| value.as_str());
+        .and_then(|value| value.as_str())
+        .map(|value| value.to_string());
     let sender_name = message.sender_id.and_then(|id| agent_map.get(&id).cloned());
-
-    let sender = match message.sender_type {
-        ChatSenderType::User => format!("user:{}", sender_handle.unwrap_or("user")),
-        ChatSenderType::Agent => format!(
-            "agent:{}",
-            sender_name.unwrap_or_else(|| "agent".to_string())
-        ),
-        ChatSenderType::System => "system".to_string(),
+    let sender_label = message_sender_label(&message, agent_map);
+    let sender_color = match message.sender_type {
+        ChatSenderType::Agent => message.sender_id.map(agent_color),
+        _ => None,
     };
 
-    SimplifiedMessage {
-        sender,
-        content: message.content.clone(),
-        timestamp: message.created_at.to_rfc3339(),
-    }
+    let sender = serde_json::json!({
+        "type": message.sender_type,
+        "id": message.sender_id,
+        "handle": sender_handle,
+        "name": sender_name,
+        "label": sender_label,
+        "color": sender_color,
+    });
+
+    serde_json::json!({
+        "schema_version": STRUCTURED_MESSAGE_SCHEMA_VERSION,
+        "id": message.id,
+        "session_id": message.session_id,
+        "created_at": message.created_at,
+        "sender": sender,
+        "content": message.content,
+        "mentions": message.mentions.0,
+        "device_id": extract_device_id(&message.meta.0),
+        "pending": is_pending_reply(&message.meta.0),
+        "meta": message.meta.0,
+    })
 }
 
-/// Convert all messages in a session to SimplifiedMessage format
-pub async fn build_simplified_messages(
-    pool: &SqlitePool,
-    session_id: Uuid,
-) -> Result<Vec<SimplifiedMessage>, ChatServiceError> {
-    let messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
-    let agents = ChatAgent::find_all(pool).await?;
-    let agent_map: HashMap<Uuid, String> = agents
+/// Agent names by ID, as the message builders use to label agent senders.
+/// Build it once and pass it to the `*_with_agent_map` builders when a caller
+/// needs several of them.
+pub async fn build_agent_map(pool: &SqlitePool) -> Result<HashMap<Uuid, String>, ChatServiceError> {
+    Ok(ChatAgent::find_all(pool)
+        .await?
         .into_iter()
         .map(|agent| (agent.id, agent.name))
-        .collect();
-
-    Ok(messages
-        .iter()
-        .map(|msg| to_simplified_message(msg, &agent_map))
         .collect())
 }
 
-/// Build the prompt for AI summarization
-fn build_summarization_prompt(messages_to_compress: &[SimplifiedMessage]) -> String {
-    let mut prompt = String::from(
-        "Summarize the following chat history while preserving key tasks, decisions, \
-constraints, and references. Keep the summary concise (under 500 words).\n\
-Return only the summary body. Do not ask follow-up questions. Do not run any tools or shell commands.\n\nMessages:\n",
-    );
-
-    for msg in messages_to_compress {
-        prompt.push_str(&format!("{}: {}\n", msg.sender, msg.content));
-    }
+/// A participant in a [`MentionGraph`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MentionGraphNode {
+    pub label: String,
+    /// False for handles that match no agent and no sender in the session.
+    pub resolved: bool,
+}
 
-    prompt
+/// `from` mentioned `to` in `count` messages.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MentionGraphEdge {
+    pub from: String,
+    pub to: String,
+    pub count: u32,
 }
 
-fn limit_summary_input_messages(
-    messages_to_compress: &[SimplifiedMessage],
-    token_limit: u32,
-) -> (Vec<SimplifiedMessage>, u32, u32) {
-    let total_tokens = estimate_token_count(messages_to_compress);
-    if messages_to_compress.is_empty() || total_tokens <= token_limit {
-        return (messages_to_compress.to_vec(), total_tokens, total_tokens);
-    }
+/// Who mentioned whom in a session. Nodes and edges are sorted by label.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MentionGraph {
+    pub nodes: Vec<MentionGraphNode>,
+    pub edges: Vec<MentionGraphEdge>,
+}
 
-    // Keep the most recent part of the compressed segment under token budget.
-    let mut selected_rev = Vec::new();
-    let mut selected_tokens = 0u32;
-    for message in messages_to_compress.iter().rev() {
-        let message_tokens = estimate_token_count(std::slice::from_ref(message)).max(1);
-        if !selected_rev.is_empty() && selected_tokens.saturating_add(message_tokens) > token_limit
-        {
-            break;
-        }
-        selected_rev.push(message.clone());
-        selected_tokens = selected_tokens.saturating_add(message_tokens);
-        if selected_tokens >= token_limit {
-            break;
+/// Build the directed mention graph for a session from each message's sender
+/// and stored mentions. Handles that don't resolve to anyone are kept as
+/// target nodes.
+pub async fn session_mention_graph(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<MentionGraph, ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+    let messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    let agent_map = build_agent_map(pool).await?;
+
+    let mut known: HashSet<String> = agent_map.values().map(|name| name.to_lowercase()).collect();
+    let mut labels = std::collections::BTreeSet::new();
+    let mut counts: std::collections::BTreeMap<(String, String), u32> =
+        std::collections::BTreeMap::new();
+    for message in &messages {
+        let from = message_sender_label(message, &agent_map);
+        known.insert(from.to_lowercase());
+        labels.insert(from.clone());
+        for handle in &message.mentions.0 {
+            labels.insert(handle.clone());
+            *counts.entry((from.clone(), handle.clone())).or_default() += 1;
         }
     }
 
-    if selected_rev.is_empty() {
-        selected_rev.push(
-            messages_to_compress
-                .last()
-                .expect("messages_to_compress must be non-empty")
-                .clone(),
-        );
-        selected_tokens = estimate_token_count(&selected_rev);
-    }
-
-    selected_rev.reverse();
-    (selected_rev, total_tokens, selected_tokens)
+    Ok(MentionGraph {
+        nodes: labels
+            .into_iter()
+            .map(|label| MentionGraphNode {
+                resolved: known.contains(&label.to_lowercase()),
+                label,
+            })
+            .collect(),
+        edges: counts
+            .into_iter()
+            .map(|((from, to), count)| MentionGraphEdge { from, to, count })
+            .collect(),
+    })
 }
 
-fn all_agents_running(session_agents: &[ChatSessionAgent]) -> bool {
-    !session_agents.is_empty()
-        && session_agents
-            .iter()
-            .all(|agent| agent.state == ChatSessionAgentState::Running)
+/// Width of the buckets [`session_activity_timeseries`] groups messages into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ActivityBucketSize {
+    Hour,
+    Day,
 }
 
-fn summary_agent_priority(state: ChatSessionAgentState) -> u8 {
-    match state {
-        ChatSessionAgentState::Idle => 0,
-        ChatSessionAgentState::WaitingApproval => 1,
-        ChatSessionAgentState::Dead => 2,
-        ChatSessionAgentState::Running => 3,
+impl ActivityBucketSize {
+    fn bucket_start(self, at: chrono::DateTime<Utc>) -> chrono::DateTime<Utc> {
+        use chrono::{DurationRound, TimeDelta};
+
+        let width = match self {
+            Self::Hour => TimeDelta::hours(1),
+            Self::Day => TimeDelta::days(1),
+        };
+        at.duration_trunc(width).unwrap_or(at)
     }
 }
 
-fn prioritize_summary_agents(session_agents: &[ChatSessionAgent]) -> Vec<ChatSessionAgent> {
-    let mut agents = session_agents.to_vec();
-    agents.sort_by_key(|agent| summary_agent_priority(agent.state.clone()));
-    agents
+/// Activity in one time bucket of a session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActivityBucket {
+    /// Start of the bucket (UTC).
+    pub start: chrono::DateTime<Utc>,
+    pub messages: u32,
+    /// Distinct sender labels that posted in the bucket.
+    pub distinct_senders: u32,
+    pub estimated_tokens: u32,
 }
 
-async fn wait_for_idle_agent_if_needed(
+/// Per-hour or per-day activity of a session, bucketed by `created_at` in
+/// UTC. Only buckets with messages are returned, oldest first.
+pub async fn session_activity_timeseries(
     pool: &SqlitePool,
     session_id: Uuid,
-    session_agents: &[ChatSessionAgent],
-) -> Result<Vec<ChatSessionAgent>, ChatServiceError> {
-    if !all_agents_running(session_agents) {
-        return Ok(session_agents.to_vec());
+    bucket: ActivityBucketSize,
+) -> Result<Vec<ActivityBucket>, ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+    let messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    let agent_map = build_agent_map(pool).await?;
+
+    let mut buckets: std::collections::BTreeMap<
+        chrono::DateTime<Utc>,
+        (ActivityBucket, HashSet<String>),
+    > = std::collections::BTreeMap::new();
+    for message in &messages {
+        let start = bucket.bucket_start(message.created_at);
+        let (entry, senders) = buckets.entry(start).or_insert_with(|| {
+            (
+                ActivityBucket {
+                    start,
+                    messages: 0,
+                    distinct_senders: 0,
+                    estimated_tokens: 0,
+                },
+                HashSet::new(),
+            )
+        });
+        entry.messages += 1;
+        entry.estimated_tokens += estimate_string_tokens(&message.content);
+        senders.insert(message_sender_label(message, &agent_map));
     }
 
-    // Avoid waiting forever in single-agent sessions where the current run just marked it running.
-    if session_agents.len() == 1 {
-        tracing::debug!(
-            session_id = %session_id,
-            session_agent_id = %session_agents[0].id,
-            "Skipping idle-agent wait for summarization in single-agent running session"
-        );
-        return Ok(session_agents.to_vec());
-    }
-
-    // Do not block the active mention execution path.
-    // When all agents are currently running, summarization should quickly fall back
-    // so normal group chat delivery is not stalled.
-    tracing::info!(
-        session_id = %session_id,
-        "All session agents are running; skipping idle wait to avoid blocking chat flow"
-    );
-    ChatSessionAgent::find_all_for_session(pool, session_id)
-        .await
-        .map_err(ChatServiceError::from)
+    Ok(buckets
+        .into_values()
+        .map(|(mut entry, senders)| {
+            entry.distinct_senders = senders.len() as u32;
+            entry
+        })
+        .collect())
 }
 
-/// Try to summarize messages using available AI agents
-/// Returns Some(summary) if any agent succeeds, None if all fail
-async fn try_summarize_with_agents(
+/// Messages whose stored mentions include `handle` (case-insensitive, with or
+/// without a leading `@`), newest first across all sessions.
+pub async fn messages_mentioning(
     pool: &SqlitePool,
-    session_id: Uuid,
-    session_agents: &[ChatSessionAgent],
-    messages_to_compress: &[SimplifiedMessage],
-    workspace_path: &Path,
-) -> Option<String> {
-    let (summary_input_messages, input_tokens_before_limit, input_tokens_after_limit) =
-        limit_summary_input_messages(messages_to_compress, SUMMARY_INPUT_TOKEN_LIMIT);
-    if summary_input_messages.len() < messages_to_compress.len() {
-        tracing::warn!(
-            session_id = %session_id,
-            original_messages = messages_to_compress.len(),
-            included_messages = summary_input_messages.len(),
-            original_tokens = input_tokens_before_limit,
-            included_tokens = input_tokens_after_limit,
-            token_limit = SUMMARY_INPUT_TOKEN_LIMIT,
-            "Summarization input exceeded token limit; truncating to most recent messages"
-        );
-    }
-    let summarize_prompt = build_summarization_prompt(&summary_input_messages);
-    let candidate_agents =
-        match wait_for_idle_agent_if_needed(pool, session_id, session_agents).await {
-            Ok(agents) => agents,
-            Err(err) => {
-                tracing::warn!(
-                    session_id = %session_id,
-                    error = %err,
-                    "Failed to refresh session agents before summarization; using initial snapshot"
-                );
-                session_agents.to_vec()
-            }
-        };
-
-    if all_agents_running(&candidate_agents) {
-        tracing::warn!(
-            session_id = %session_id,
-            "Skipping AI summarization because all agents are still running"
-        );
-        return None;
+    handle: &str,
+    limit: i64,
+) -> Result<Vec<ChatMessage>, ChatServiceError> {
+    let handle = handle.trim().trim_start_matches('@');
+    if handle.is_empty() {
+        return Err(ChatServiceError::Validation(
+            "mention handle cannot be empty".to_string(),
+        ));
     }
+    let messages = sqlx::query_as::<_, ChatMessage>(
+        "SELECT id, session_id, sender_type, sender_id, content, mentions, meta, created_at
+         FROM chat_messages
+         WHERE EXISTS (
+             SELECT 1 FROM json_each(chat_messages.mentions)
+             WHERE lower(json_each.value) = lower(?1)
+         )
+         ORDER BY created_at DESC, rowid DESC
+         LIMIT ?2",
+    )
+    .bind(handle)
+    .bind(limit)
+    .fetch_all(pool)
+    .await?;
+    Ok(messages)
+}
 
-    for session_agent in prioritize_summary_agents(&candidate_agents) {
-        // Get the agent details
-        let agent = match ChatAgent::find_by_id(pool, session_agent.agent_id).await {
-            Ok(Some(agent)) => agent,
-            _ => continue,
-        };
+/// How often each handle is mentioned in a session, from the stored
+/// `mentions` arrays. Handles are compared case-insensitively and reported in
+/// lowercase, most mentioned first (ties by handle).
+pub async fn session_mention_frequencies(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Vec<(String, usize)>, ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+    let rows: Vec<(String, i64)> = sqlx::query_as(
+        "SELECT lower(json_each.value) AS handle, COUNT(*) AS mentions
+         FROM chat_messages, json_each(chat_messages.mentions)
+         WHERE chat_messages.session_id = ?1
+         GROUP BY handle
+         ORDER BY mentions DESC, handle ASC",
+    )
+    .bind(session_id)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(handle, count)| (handle, count as usize))
+        .collect())
+}
 
-        tracing::debug!(
-            "Attempting to summarize with agent: {} ({})",
-            agent.name,
-            agent.id
-        );
+pub async fn build_structured_messages(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Vec<Value>, ChatServiceError> {
+    build_structured_messages_filtered(pool, session_id, SystemMessageFilter::default()).await
+}
 
-        let workspace_override = session_agent.workspace_path.as_deref().map(Path::new);
-        let effective_workspace_path = workspace_override.unwrap_or(workspace_path);
+/// Which system messages (e.g. "session archived" notices) the context
+/// builders keep.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SystemMessageFilter {
+    #[default]
+    IncludeAll,
+    Exclude,
+    /// Keep only system messages whose meta has `"context_relevant": true`.
+    ContextRelevantOnly,
+}
 
-        // Try to call the agent for summarization
-        match call_agent_for_summary(&agent, &summarize_prompt, effective_workspace_path).await {
-            Ok(summary) => {
-                tracing::info!(
-                    session_id = %session_id,
-                    agent = %agent.name,
-                    "AI summarization successful"
-                );
-                return Some(summary);
-            }
-            Err(e) => {
-                tracing::warn!(
-                    session_id = %session_id,
-                    agent = %agent.name,
-                    error = %e,
-                    "Agent failed to summarize, trying next agent"
-                );
-                continue;
-            }
+impl SystemMessageFilter {
+    pub fn keeps(self, message: &ChatMessage) -> bool {
+        if !matches!(message.sender_type, ChatSenderType::System) {
+            return true;
+        }
+        match self {
+            Self::IncludeAll => true,
+            Self::Exclude => false,
+            Self::ContextRelevantOnly => message
+                .meta
+                .0
+                .get("context_relevant")
+                .and_then(Value::as_bool)
+                .unwrap_or(false),
         }
     }
+}
 
-    tracing::warn!(
-        session_id = %session_id,
-        "All agents failed to summarize messages"
-    );
-    None
+/// [`build_structured_messages`] with system messages filtered by `filter`.
+/// Attachment-only messages get placeholder content (see [`context_content`]);
+/// archive exports keep the stored content.
+#[tracing::instrument(level = "debug", skip_all, fields(session_id = %session_id, ?filter))]
+pub async fn build_structured_messages_filtered(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    filter: SystemMessageFilter,
+) -> Result<Vec<Value>, ChatServiceError> {
+    let agent_map = build_agent_map(pool).await?;
+    build_structured_messages_with_agent_map(pool, session_id, filter, &agent_map).await
 }
 
-/// Call an agent to generate a summary
-/// This spawns a temporary agent process to summarize messages
-async fn call_agent_for_summary(
-    agent: &ChatAgent,
-    prompt: &str,
-    workspace_path: &Path,
-) -> Result<String, ChatServiceError> {
-    let executor_profile_id = parse_executor_profile_id(agent)?;
-    let mut executor =
-        ExecutorConfigs::get_cached().get_coding_agent_or_default(&executor_profile_id);
-    executor.use_approvals(Arc::new(NoopExecutorApprovalService));
+/// Like [`build_structured_messages_filtered`], labelling agents from a map
+/// built with [`build_agent_map`].
+pub async fn build_structured_messages_with_agent_map(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    filter: SystemMessageFilter,
+    agent_map: &HashMap<Uuid, String>,
+) -> Result<Vec<Value>, ChatServiceError> {
+    let messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
 
-    let repo_context = RepoContext::new(workspace_path.to_path_buf(), Vec::new());
-    let env = ExecutionEnv::new(repo_context, false, String::new());
-    let mut spawned = executor
-        .spawn(workspace_path, prompt, &env)
-        .await
-        .map_err(map_executor_error)?;
+    let structured: Vec<Value> = messages
+        .into_iter()
+        .filter(|message| filter.keeps(message) && !is_cancelled_reply(&message.meta.0))
+        .map(|mut message| {
+            message.content = context_content(&message.content, &message.meta.0);
+            structured_message_value(message, agent_map)
+        })
+        .collect();
+    tracing::debug!(messages = structured.len(), "Built structured messages");
+    Ok(structured)
+}
 
-    let msg_store = Arc::new(MsgStore::new());
-    spawn_summary_log_forwarders(&mut spawned.child, msg_store.clone())?;
-    executor.normalize_logs(msg_store.clone(), workspace_path);
+/// Messages fetched per query when streaming a session export.
+const EXPORT_PAGE_SIZE: i64 = 500;
+
+/// Progress of a long-running export or import, counted in messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct ProgressUpdate {
+    /// Messages handled since the previous update.
+    pub batch: usize,
+    /// Messages handled so far.
+    pub processed: usize,
+    pub total: usize,
+}
 
-    let mut failed_by_signal = false;
-    let mut status = None;
-    if let Some(exit_signal) = spawned.exit_signal.take() {
-        // Prefer explicit completion signal from executor, then reap child process.
-        match tokio::time::timeout(SUMMARY_EXECUTION_TIMEOUT, exit_signal).await {
-            Ok(Ok(ExecutorExitResult::Success)) => {}
-            Ok(Ok(ExecutorExitResult::Failure)) => failed_by_signal = true,
-            Ok(Err(err)) => {
-                tracing::warn!(
-                    agent_name = %agent.name,
-                    error = %err,
-                    "Summarization exit signal dropped; falling back to process wait"
-                );
-                status = Some(wait_for_summary_process_exit(&mut spawned, &agent.name).await?);
-            }
-            Err(_) => {
-                terminate_summary_child(&mut spawned).await;
-                return Err(ChatServiceError::Validation(format!(
-                    "AI summarization timed out for agent {} after {} seconds",
-                    agent.name,
-                    SUMMARY_EXECUTION_TIMEOUT.as_secs()
-                )));
+/// Progress callback that ignores every update.
+fn no_progress(_: ProgressUpdate) {}
+
+/// Write a session's structured messages to `writer` as JSONL, one page of
+/// messages at a time, in the same order and format as
+/// [`build_structured_messages`]. Attachment `relative_path` values found in
+/// `attachment_paths` are replaced (e.g. with archive-relative paths).
+/// Only messages after the `start_after` keyset position are written, and
+/// `progress` is called after each page. Returns the last message written.
+async fn write_structured_messages_jsonl<W>(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    writer: &mut W,
+    page_size: i64,
+    attachment_paths: &HashMap<String, String>,
+    start_after: Option<(String, i64)>,
+    progress: &dyn Fn(ProgressUpdate),
+) -> Result<WrittenMessages, ChatServiceError>
+where
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    let agent_map = build_agent_map(pool).await?;
+    // Keyset cursor on the raw stored created_at and rowid, matching the
+    // created_at order of `find_by_session_id` with insertion order for ties.
+    let mut cursor: (String, i64) = start_after.unwrap_or_default();
+    let total: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM chat_messages WHERE session_id = ?1 AND (created_at, rowid) > (?2, ?3)",
+    )
+    .bind(session_id)
+    .bind(&cursor.0)
+    .bind(cursor.1)
+    .fetch_one(pool)
+    .await?;
+    let mut processed = 0;
+    let mut written = WrittenMessages::default();
+
+    loop {
+        let rows = sqlx::query(
+            r#"SELECT id, session_id, sender_type, sender_id, content, mentions, meta,
+                      created_at, created_at AS created_at_raw, rowid AS row_id
+               FROM chat_messages
+               WHERE session_id = ?1 AND (created_at, rowid) > (?2, ?3)
+               ORDER BY created_at ASC, rowid ASC
+               LIMIT ?4"#,
+        )
+        .bind(session_id)
+        .bind(&cursor.0)
+        .bind(cursor.1)
+        .bind(page_size)
+        .fetch_all(pool)
+        .await?;
+        let page_len = rows.len() as i64;
+
+        for row in rows {
+            cursor = (row.try_get("created_at_raw")?, row.try_get("row_id")?);
+            let message = ChatMessage::from_row(&row)?;
+            written.last_id = Some(message.id);
+            written.count += 1;
+            if is_pending_reply(&message.meta.0) {
+                written.pending_ids.push(message.id);
             }
+            let mut value = structured_message_value(message, &agent_map);
+            rewrite_attachment_paths(&mut value, attachment_paths);
+            let line = serde_json::to_string(&value).unwrap_or_default();
+            writer.write_all(line.as_bytes()).await?;
+            writer.write_all(b"\n").await?;
+        }
+        if page_len > 0 {
+            processed += page_len as usize;
+            progress(ProgressUpdate {
+                batch: page_len as usize,
+                processed,
+                total: total as usize,
+            });
         }
 
-        if status.is_none() {
-            match tokio::time::timeout(SUMMARY_REAP_TIMEOUT, spawned.child.wait()).await {
-                Ok(Ok(exit_status)) => status = Some(exit_status),
-                Ok(Err(err)) => return Err(ChatServiceError::Io(err)),
-                Err(_) => {
-                    tracing::debug!(
-                        agent_name = %agent.name,
-                        timeout_ms = SUMMARY_REAP_TIMEOUT.as_millis(),
-                        "Summarization process did not exit after completion signal; forcing shutdown"
-                    );
-                    terminate_summary_child(&mut spawned).await;
-                }
-            }
+        if page_len < page_size {
+            break;
         }
-    } else {
-        status = Some(wait_for_summary_process_exit(&mut spawned, &agent.name).await?);
     }
+    writer.flush().await?;
+    Ok(written)
+}
 
-    msg_store.push_finished();
-    tokio::time::sleep(SUMMARY_DRAIN_TIMEOUT).await;
+/// Messages written by [`write_structured_messages_jsonl`].
+#[derive(Debug, Default)]
+struct WrittenMessages {
+    last_id: Option<Uuid>,
+    count: usize,
+    /// Messages that were still pending agent replies when written.
+    pending_ids: Vec<Uuid>,
+}
 
-    if failed_by_signal {
-        return Err(ChatServiceError::Validation(format!(
-            "AI summarization process failed for agent {}",
-            agent.name
-        )));
+fn rewrite_attachment_paths(value: &mut Value, attachment_paths: &HashMap<String, String>) {
+    if attachment_paths.is_empty() {
+        return;
     }
-
-    if let Some(exit_status) = status
-        && !exit_status.success()
-    {
-        return Err(ChatServiceError::Validation(format!(
-            "AI summarization process failed for agent {}",
-            agent.name
-        )));
+    let Some(attachments) = value
+        .pointer_mut("/meta/attachments")
+        .and_then(|attachments| attachments.as_array_mut())
+    else {
+        return;
+    };
+    for attachment in attachments {
+        let archived = attachment
+            .get("relative_path")
+            .and_then(|path| path.as_str())
+            .and_then(|path| attachment_paths.get(path));
+        if let Some(archived) = archived.cloned() {
+            attachment["relative_path"] = Value::String(archived);
+        }
     }
+}
 
-    extract_latest_assistant_from_history(&msg_store.get_history()).ok_or_else(|| {
-        ChatServiceError::Validation(format!(
-            "No assistant summary output generated by agent {}",
-            agent.name
-        ))
-    })
+/// Textual stand-in for attachments when exporting to formats without them.
+fn attachment_note(meta: &Value) -> Option<String> {
+    let attachments = extract_attachments(meta);
+    if attachments.is_empty() {
+        return None;
+    }
+    let names: Vec<&str> = attachments
+        .iter()
+        .map(|attachment| attachment.name.as_str())
+        .collect();
+    Some(format!("[Attachments: {}]", names.join(", ")))
 }
 
-async fn wait_for_summary_process_exit(
-    spawned: &mut SpawnedChild,
-    agent_name: &str,
-) -> Result<std::process::ExitStatus, ChatServiceError> {
-    match tokio::time::timeout(SUMMARY_EXECUTION_TIMEOUT, spawned.child.wait()).await {
-        Ok(Ok(status)) => Ok(status),
-        Ok(Err(err)) => Err(ChatServiceError::Io(err)),
-        Err(_) => {
-            terminate_summary_child(spawned).await;
-            Err(ChatServiceError::Validation(format!(
-                "AI summarization timed out for agent {} after {} seconds",
-                agent_name,
-                SUMMARY_EXECUTION_TIMEOUT.as_secs()
-            )))
-        }
+/// Content handed to models. A message with attachments but no text becomes
+/// one `[attachment: name (kind)]` line per attachment, so the model knows
+/// something was shared.
+fn context_content(content: &str, meta: &Value) -> String {
+    if !content.trim().is_empty() {
+        return content.to_string();
+    }
+    let placeholders: Vec<String> = extract_attachments(meta)
+        .iter()
+        .map(|attachment| format!("[attachment: {} ({})]", attachment.name, attachment.kind))
+        .collect();
+    if placeholders.is_empty() {
+        content.to_string()
+    } else {
+        placeholders.join("\n")
     }
 }
 
-async fn terminate_summary_child(spawned: &mut SpawnedChild) {
-    if let Some(cancel) = spawned.cancel.take() {
-        cancel.cancel();
+fn content_with_attachment_note(content: &str, meta: &Value) -> String {
+    match attachment_note(meta) {
+        Some(note) if content.trim().is_empty() => note,
+        Some(note) => format!("{content}\n{note}"),
+        None => content.to_string(),
     }
-    let _ = spawned.child.kill().await;
-    let _ = tokio::time::timeout(SUMMARY_KILL_WAIT_TIMEOUT, spawned.child.wait()).await;
 }
 
-fn parse_runner_type(agent: &ChatAgent) -> Result<BaseCodingAgent, ChatServiceError> {
-    let raw = agent.runner_type.trim();
-    let normalized = raw.replace(['-', ' '], "_").to_ascii_uppercase();
-    BaseCodingAgent::from_str(&normalized)
-        .map_err(|_| ChatServiceError::Validation(format!("unknown runner type: {raw}")))
+/// Export a session as OpenAI chat-completion messages (`[{role, content}]`).
+///
+/// Agent messages become `assistant` turns prefixed with the agent name so
+/// multi-agent conversations stay readable. Attachments are listed by name.
+pub async fn to_openai_messages(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Vec<Value>, ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+    let messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    let agent_map = build_agent_map(pool).await?;
+
+    Ok(messages
+        .iter()
+        .map(|message| {
+            let content = content_with_attachment_note(&message.content, &message.meta.0);
+            let (role, content) = match message.sender_type {
+                ChatSenderType::User => ("user", content),
+                ChatSenderType::Agent => {
+                    let name = message
+                        .sender_id
+                        .and_then(|id| agent_map.get(&id).map(String::as_str))
+                        .unwrap_or("agent");
+                    ("assistant", format!("[{name}]: {content}"))
+                }
+                ChatSenderType::System => ("system", content),
+            };
+            serde_json::json!({ "role": role, "content": content })
+        })
+        .collect())
 }
 
-fn extract_executor_profile_variant(tools_enabled: &serde_json::Value) -> Option<String> {
-    let variant = tools_enabled
-        .as_object()
-        .and_then(|value| value.get(EXECUTOR_PROFILE_VARIANT_KEY))
-        .and_then(serde_json::Value::as_str)?
-        .trim();
-    if variant.is_empty() || variant.eq_ignore_ascii_case("DEFAULT") {
-        return None;
+/// Export a session in Anthropic Messages API form: `{system, messages}`.
+///
+/// System chat messages are folded into the system prompt, and consecutive
+/// turns with the same role are merged so user and assistant turns alternate.
+/// The API requires the first turn to come from the user, so agent messages
+/// that open the session are folded into the system prompt as well.
+pub async fn to_anthropic_messages(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    system_prompt: Option<&str>,
+) -> Result<Value, ChatServiceError> {
+    let openai_messages = to_openai_messages(pool, session_id).await?;
+
+    let mut system_parts: Vec<String> = system_prompt
+        .filter(|prompt| !prompt.trim().is_empty())
+        .map(|prompt| vec![prompt.to_string()])
+        .unwrap_or_default();
+    let mut turns: Vec<(String, String)> = Vec::new();
+    for message in openai_messages {
+        let role = message["role"].as_str().unwrap_or("user").to_string();
+        let content = message["content"].as_str().unwrap_or_default().to_string();
+        if role == "system" || (role == "assistant" && turns.is_empty()) {
+            system_parts.push(content);
+            continue;
+        }
+        match turns.last_mut() {
+            Some((last_role, last_content)) if *last_role == role => {
+                last_content.push_str("\n\n");
+                last_content.push_str(&content);
+            }
+            _ => turns.push((role, content)),
+        }
     }
-    Some(canonical_variant_key(variant))
+
+    let messages: Vec<Value> = turns
+        .into_iter()
+        .map(|(role, content)| serde_json::json!({ "role": role, "content": content }))
+        .collect();
+
+    Ok(serde_json::json!({
+        "system": system_parts.join("\n\n"),
+        "messages": messages,
+    }))
 }
 
-fn parse_executor_profile_id(agent: &ChatAgent) -> Result<ExecutorProfileId, ChatServiceError> {
-    let executor = parse_runner_type(agent)?;
-    let variant = extract_executor_profile_variant(&agent.tools_enabled.0);
-    Ok(match variant {
-        Some(variant) => ExecutorProfileId::with_variant(executor, variant),
-        None => ExecutorProfileId::new(executor),
-    })
+/// Distinct agents that have sent messages in a session, most recently active first.
+pub async fn session_participants(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Vec<ChatAgent>, ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+
+    let agents = sqlx::query_as::<_, ChatAgent>(
+        r#"SELECT a.id, a.name, a.runner_type, a.system_prompt, a.tools_enabled,
+                  a.created_at, a.updated_at
+           FROM chat_agents a
+           JOIN (
+               SELECT sender_id,
+                      MAX(created_at) AS last_active_at,
+                      MAX(rowid) AS last_rowid
+               FROM chat_messages
+               WHERE session_id = ?1 AND sender_type = 'agent' AND sender_id IS NOT NULL
+               GROUP BY sender_id
+           ) p ON p.sender_id = a.id
+           ORDER BY p.last_active_at DESC, p.last_rowid DESC"#,
+    )
+    .bind(session_id)
+    .fetch_all(pool)
+    .await?;
+
+    Ok(agents)
 }
 
-fn map_executor_error(err: ExecutorError) -> ChatServiceError {
-    ChatServiceError::Validation(format!("executor error: {err}"))
+/// Longest last-message snippet returned in a [`SessionPreview`], in characters.
+const SESSION_PREVIEW_SNIPPET_CHARS: usize = 120;
+
+/// A session-list entry with a preview of the latest message.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionPreview {
+    pub session_id: Uuid,
+    pub title: Option<String>,
+    pub summary_text: Option<String>,
+    /// Start of the latest message, cut at [`SESSION_PREVIEW_SNIPPET_CHARS`].
+    pub last_message_snippet: Option<String>,
+    pub last_sender_label: Option<String>,
+    pub updated_at: chrono::DateTime<Utc>,
 }
 
-fn spawn_summary_log_forwarders(
-    child: &mut command_group::AsyncGroupChild,
-    msg_store: Arc<MsgStore>,
-) -> Result<(), ChatServiceError> {
-    let stdout = child.inner().stdout.take().ok_or_else(|| {
-        ChatServiceError::Validation("summarization child missing stdout".to_string())
-    })?;
-    let stderr = child.inner().stderr.take().ok_or_else(|| {
-        ChatServiceError::Validation("summarization child missing stderr".to_string())
-    })?;
+fn preview_snippet(content: &str) -> String {
+    let content = content.trim();
+    let mut chars = content.chars();
+    let snippet: String = chars.by_ref().take(SESSION_PREVIEW_SNIPPET_CHARS).collect();
+    if chars.next().is_some() {
+        format!("{}…", snippet.trim_end())
+    } else {
+        snippet
+    }
+}
 
-    let stdout_store = msg_store.clone();
-    tokio::spawn(async move {
-        let mut stream = ReaderStream::new(stdout);
-        while let Some(chunk) = stream.next().await {
-            match chunk {
-                Ok(bytes) => {
-                    let text = String::from_utf8_lossy(&bytes).into_owned();
-                    stdout_store.push(LogMsg::Stdout(text));
-                }
-                Err(err) => {
-                    stdout_store.push(LogMsg::Stderr(format!("stdout error: {err}")));
-                }
-            }
-        }
-    });
+/// Sessions ordered by most recent activity, each with its latest message, in
+/// a single query.
+pub async fn list_sessions_with_preview(
+    pool: &SqlitePool,
+    limit: u32,
+    offset: u32,
+) -> Result<Vec<SessionPreview>, ChatServiceError> {
+    let rows = sqlx::query(
+        r#"SELECT s.id, s.title, s.summary_text, s.updated_at,
+                  m.content AS last_content,
+                  COALESCE(json_extract(m.meta, '$.sender.label'), a.name, m.sender_type)
+                      AS last_sender_label
+           FROM chat_sessions s
+           LEFT JOIN chat_messages m ON m.id = (
+               SELECT id FROM chat_messages
+               WHERE session_id = s.id
+               ORDER BY created_at DESC, rowid DESC
+               LIMIT 1
+           )
+           LEFT JOIN chat_agents a ON a.id = m.sender_id
+           ORDER BY s.updated_at DESC
+           LIMIT ?1 OFFSET ?2"#,
+    )
+    .bind(i64::from(limit))
+    .bind(i64::from(offset))
+    .fetch_all(pool)
+    .await?;
 
-    let stderr_store = msg_store;
-    tokio::spawn(async move {
-        let mut stream = ReaderStream::new(stderr);
-        while let Some(chunk) = stream.next().await {
-            match chunk {
-                Ok(bytes) => {
-                    let text = String::from_utf8_lossy(&bytes).into_owned();
-                    stderr_store.push(LogMsg::Stderr(text));
-                }
-                Err(err) => {
-                    stderr_store.push(LogMsg::Stderr(format!("stderr error: {err}")));
-                }
-            }
-        }
-    });
+    rows.into_iter()
+        .map(|row| {
+            let last_content: Option<String> = row.try_get("last_content")?;
+            Ok(SessionPreview {
+                session_id: row.try_get("id")?,
+                title: row.try_get("title")?,
+                summary_text: row.try_get("summary_text")?,
+                last_message_snippet: last_content.as_deref().map(preview_snippet),
+                last_sender_label: row.try_get("last_sender_label")?,
+                updated_at: row.try_get("updated_at")?,
+            })
+        })
+        .collect()
+}
+
+/// Move the session's read cursor to `up_to_message_id`, which must belong to
+/// the session.
+pub async fn mark_session_read(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    up_to_message_id: Uuid,
+) -> Result<(), ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+    let message = ChatMessage::find_by_id(pool, up_to_message_id).await?;
+    if message.is_none_or(|message| message.session_id != session_id) {
+        return Err(ChatServiceError::Validation(format!(
+            "message {up_to_message_id} is not part of session {session_id}"
+        )));
+    }
 
+    SessionMeta::modify(pool, session_id, |meta| {
+        meta.set_read_cursor(Some(up_to_message_id));
+    })
+    .await?;
     Ok(())
 }
 
-fn extract_latest_assistant_from_history(history: &[LogMsg]) -> Option<String> {
-    let mut assistant_entries: HashMap<usize, String> = HashMap::new();
-
-    for message in history {
-        let LogMsg::JsonPatch(patch) = message else {
-            continue;
-        };
+/// Move every session's read cursor to its latest message. Returns how many
+/// sessions changed; empty sessions and sessions already read are skipped.
+pub async fn mark_all_read(pool: &SqlitePool) -> Result<usize, ChatServiceError> {
+    let latest: Vec<(Uuid, Uuid)> = sqlx::query_as(
+        r#"SELECT s.id, (
+               SELECT m.id FROM chat_messages m
+               WHERE m.session_id = s.id
+               ORDER BY m.created_at DESC, m.rowid DESC
+               LIMIT 1
+           ) AS latest_id
+           FROM chat_sessions s
+           WHERE latest_id IS NOT NULL"#,
+    )
+    .fetch_all(pool)
+    .await?;
 
-        let Some((index, entry)) = extract_normalized_entry_from_patch(patch) else {
+    let mut updated = 0;
+    for (session_id, latest_id) in latest {
+        if SessionMeta::load(pool, session_id).await?.read_cursor() == Some(latest_id) {
             continue;
-        };
-
-        if matches!(entry.entry_type, NormalizedEntryType::AssistantMessage) {
-            assistant_entries.insert(index, entry.content);
         }
+        SessionMeta::modify(pool, session_id, |meta| {
+            meta.set_read_cursor(Some(latest_id));
+        })
+        .await?;
+        updated += 1;
     }
+    Ok(updated)
+}
 
-    assistant_entries
-        .into_iter()
-        .max_by_key(|(index, _)| *index)
-        .map(|(_, content)| content.trim().to_string())
-        .filter(|content| !content.is_empty())
+/// Agent and system messages after the session's read cursor. The user's own
+/// messages never count as unread. Without a cursor, every such message does.
+pub async fn unread_count(pool: &SqlitePool, session_id: Uuid) -> Result<i64, ChatServiceError> {
+    let meta = match SessionMeta::load(pool, session_id).await {
+        Ok(meta) => meta,
+        Err(sqlx::Error::RowNotFound) => return Err(ChatServiceError::SessionNotFound),
+        Err(err) => return Err(err.into()),
+    };
+
+    let count: i64 = sqlx::query_scalar(
+        r#"SELECT COUNT(*)
+           FROM chat_messages m
+           LEFT JOIN chat_messages c ON c.id = ?2 AND c.session_id = m.session_id
+           WHERE m.session_id = ?1
+             AND m.sender_type != 'user'
+             AND (c.id IS NULL OR (m.created_at, m.rowid) > (c.created_at, c.rowid))"#,
+    )
+    .bind(session_id)
+    .bind(meta.read_cursor())
+    .fetch_one(pool)
+    .await?;
+    Ok(count)
 }
 
-fn select_messages_to_compress_by_token(
-    messages: &[SimplifiedMessage],
-    total_tokens: u32,
-    compression_percentage: u8,
-) -> (usize, u32, u32) {
-    if messages.is_empty() {
-        return (0, 0, 0);
+/// Context with LLM-compressed summary message included
+pub struct CompactedContext {
+    /// The compacted messages (summary + recent messages)
+    pub messages: Vec<Value>,
+    /// Raw JSONL string for prompt injection
+    pub jsonl: String,
+    /// Whether context compression has been applied
+    pub context_compacted: bool,
+    /// Warning if compression fell back to truncation
+    pub compression_warning: Option<CompressionWarning>,
+}
+
+/// Config values consulted while storing messages, taken from the deployment
+/// config via [`apply_message_settings`].
+#[derive(Debug, Clone, Copy)]
+struct MessageSettings {
+    redact_secrets: bool,
+    normalize_whitespace: bool,
+    max_mentions_per_message: usize,
+    max_messages_per_session: Option<usize>,
+}
+
+impl MessageSettings {
+    fn from_config(config: &super::config::Config) -> Self {
+        Self {
+            redact_secrets: config.chat_redact_secrets,
+            normalize_whitespace: config.chat_normalize_whitespace,
+            max_mentions_per_message: config.chat_max_mentions_per_message.max(1) as usize,
+            max_messages_per_session: config
+                .chat_max_messages_per_session
+                .filter(|max| *max > 0)
+                .map(|max| max as usize),
+        }
     }
 
-    let target_tokens = ((total_tokens as u64) * (compression_percentage as u64)).div_ceil(100);
-    let target_tokens = (target_tokens as u32).max(1);
+    fn current() -> Self {
+        *MESSAGE_SETTINGS
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
 
-    let mut selected_tokens = 0u32;
-    let mut selected_count = 0usize;
+/// Settings used by every message write. Config defaults until the
+/// deployment applies its config.
+static MESSAGE_SETTINGS: Lazy<std::sync::RwLock<MessageSettings>> =
+    Lazy::new(|| std::sync::RwLock::new(MessageSettings::from_config(&Default::default())));
+
+/// Use `config`'s chat message settings (redaction, whitespace normalization,
+/// mention and history limits) for messages stored from now on. Called when
+/// the deployment loads its config and whenever it changes.
+pub fn apply_message_settings(config: &super::config::Config) {
+    *MESSAGE_SETTINGS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = MessageSettings::from_config(config);
+}
 
-    for message in messages {
-        // Use per-message token estimates so we can choose a prefix by token budget.
-        let message_tokens = estimate_token_count(std::slice::from_ref(message)).max(1);
-        selected_tokens = selected_tokens.saturating_add(message_tokens);
-        selected_count += 1;
-        if selected_tokens >= target_tokens {
-            break;
+/// Collapse runs of blank lines to a single blank line and trim trailing
+/// whitespace from each line. Fenced code blocks (including an unterminated
+/// one) are left exactly as written.
+pub fn normalize_content(content: &str) -> String {
+    let mut lines = Vec::new();
+    let mut in_fence = false;
+    let mut blank_run = 0;
+
+    for line in content.split('\n') {
+        let chars: Vec<char> = line.chars().collect();
+        let fence = is_code_fence(&chars);
+        if in_fence {
+            lines.push(line);
+            in_fence = !fence;
+            continue;
+        }
+        in_fence = fence;
+
+        let trimmed = line.trim_end();
+        if trimmed.is_empty() {
+            blank_run += 1;
+            if blank_run > 1 {
+                continue;
+            }
+        } else {
+            blank_run = 0;
         }
+        lines.push(trimmed);
     }
 
-    (
-        selected_count.max(1).min(messages.len()),
-        target_tokens,
-        selected_tokens,
-    )
+    lines.join("\n")
 }
 
-fn calculate_messages_fingerprint(messages: &[SimplifiedMessage]) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    for message in messages {
-        hasher.write(message.sender.as_bytes());
-        hasher.write_u8(0x1f);
-        hasher.write(message.content.as_bytes());
-        hasher.write_u8(0x1e);
-        hasher.write(message.timestamp.as_bytes());
-        hasher.write_u8(0x1d);
+/// Reject messages that mention more than `limit` distinct handles.
+fn check_mention_limit(mentions: &[String], limit: usize) -> Result<(), ChatServiceError> {
+    let distinct: HashSet<String> = mentions.iter().map(|m| m.to_lowercase()).collect();
+    if distinct.len() > limit {
+        return Err(ChatServiceError::Validation(format!(
+            "message mentions {} handles; at most {limit} are allowed",
+            distinct.len()
+        )));
     }
-    hasher.finish()
+    Ok(())
 }
 
-fn compression_type_to_db_value(value: &CompressionType) -> &'static str {
-    match value {
-        CompressionType::None => "none",
-        CompressionType::AiSummarized => "ai_summarized",
-        CompressionType::Truncated => "truncated",
+/// Range of `token_safety_margin` honoured, so a typo can't force compression
+/// on every turn or switch it off entirely.
+const TOKEN_SAFETY_MARGIN_RANGE: (f32, f32) = (0.25, 4.0);
+
+/// Threshold to compare raw token estimates against when every estimate is
+/// to be scaled by `margin` first: `estimate * margin <= threshold` is
+/// `estimate <= threshold / margin`. Reported token counts stay unscaled.
+fn threshold_with_safety_margin(threshold: u32, margin: f32) -> u32 {
+    if !margin.is_finite() || margin <= 0.0 {
+        return threshold;
     }
+    let (min, max) = TOKEN_SAFETY_MARGIN_RANGE;
+    let margin = margin.clamp(min, max);
+    // `as` saturates, so margins below 1.0 can't overflow.
+    ((f64::from(threshold) / f64::from(margin)).floor() as u32).max(1)
 }
 
-fn compression_type_from_db_value(value: &str) -> Option<CompressionType> {
-    match value {
-        "none" => Some(CompressionType::None),
-        "ai_summarized" => Some(CompressionType::AiSummarized),
-        "truncated" => Some(CompressionType::Truncated),
-        _ => None,
-    }
+/// Token threshold (scaled by the safety margin) and compression percentage.
+fn chat_compression_settings(config: &super::config::ChatCompressionConfig) -> (u32, u8) {
+    let threshold =
+        threshold_with_safety_margin(config.token_threshold.max(1), config.token_safety_margin);
+    let percentage = config.compression_percentage.clamp(1, 100);
+    (threshold, percentage)
 }
 
-fn is_missing_compression_state_table_error(err: &sqlx::Error) -> bool {
-    match err {
-        sqlx::Error::Database(db_err) => {
-            let message = db_err.message();
-            message.contains("no such table") && message.contains(COMPRESSION_STATE_TABLE)
-        }
-        _ => false,
-    }
+/// Sender used for the preamble message at the top of a built context.
+pub const CONTEXT_PREAMBLE_SENDER: &str = "system:preamble";
+
+/// The preamble as the system message that opens a context, unless blank.
+fn context_preamble_message(preamble: Option<&str>) -> Option<SimplifiedMessage> {
+    preamble
+        .map(str::trim)
+        .filter(|preamble| !preamble.is_empty())
+        .map(|preamble| SimplifiedMessage {
+            sender: CONTEXT_PREAMBLE_SENDER.to_string(),
+            content: preamble.to_string(),
+            timestamp: Utc::now().to_rfc3339(),
+            record: None,
+        })
 }
 
-fn parse_required_u32(row: &sqlx::sqlite::SqliteRow, field: &str) -> Result<u32, sqlx::Error> {
-    let value: i64 = row.try_get(field)?;
-    u32::try_from(value).map_err(|_| {
-        sqlx::Error::Decode(Box::new(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!("field {field} is out of range for u32: {value}"),
-        )))
+fn simplified_to_context_value(message: &SimplifiedMessage) -> Value {
+    let time = chrono::DateTime::parse_from_rfc3339(&message.timestamp)
+        .map(|dt| {
+            dt.with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_else(|_| message.timestamp.clone());
+
+    serde_json::json!({
+        "sender": message.sender,
+        "content": message.content,
+        "time": time,
     })
 }
 
-fn parse_required_usize(row: &sqlx::sqlite::SqliteRow, field: &str) -> Result<usize, sqlx::Error> {
-    let value: i64 = row.try_get(field)?;
-    usize::try_from(value).map_err(|_| {
-        sqlx::Error::Decode(Box::new(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!("field {field} is out of range for usize: {value}"),
-        )))
-    })
+/// Context values and their JSONL form. With `include_token_counts`, each
+/// value also gets a `"tokens"` field: the estimated tokens of its JSONL line.
+/// The JSONL itself never carries the counts.
+fn simplified_messages_to_jsonl(
+    messages: &[SimplifiedMessage],
+    include_token_counts: bool,
+) -> (Vec<Value>, String) {
+    context_values_to_jsonl(
+        messages.iter().map(simplified_to_context_value).collect(),
+        include_token_counts,
+    )
 }
 
-fn cache_compression_result_in_memory(
-    session_id: Uuid,
-    source_fingerprint: u64,
-    source_message_count: usize,
-    token_threshold: u32,
-    compression_percentage: u8,
-    source_token_count: u32,
-    result: &CompressionResult,
-) -> CompressionCacheEntry {
-    let effective_token_count = estimate_token_count(&result.messages);
-    let entry = CompressionCacheEntry {
-        source_fingerprint,
-        source_message_count,
-        token_threshold,
-        compression_percentage,
-        source_token_count,
-        effective_token_count,
-        result: result.clone(),
-    };
-    COMPRESSION_RESULT_CACHE.insert(session_id, entry.clone());
-    entry
+fn context_values_to_jsonl(
+    mut context_messages: Vec<Value>,
+    include_token_counts: bool,
+) -> (Vec<Value>, String) {
+    let lines: Vec<String> = context_messages
+        .iter()
+        .filter_map(|msg| serde_json::to_string(msg).ok())
+        .collect();
+    if include_token_counts {
+        for (message, line) in context_messages.iter_mut().zip(&lines) {
+            message["tokens"] = serde_json::json!(estimate_string_tokens(line));
+        }
+    }
+    let jsonl = lines.join("\n") + "\n";
+    (context_messages, jsonl)
 }
 
-async fn persist_compression_result(
+/// Build full (uncompressed) context.
+///
+/// This is used by the non-blocking main execution path so agent runs are never
+/// delayed by summarization/compression. Of `options`, only the preamble
+/// applies.
+#[tracing::instrument(level = "debug", skip_all, fields(session_id = %session_id))]
+pub async fn build_full_context(
     pool: &SqlitePool,
     session_id: Uuid,
-    entry: &CompressionCacheEntry,
-) -> Result<(), ChatServiceError> {
-    let warning_json = entry
-        .result
-        .warning
-        .as_ref()
-        .map(serde_json::to_string)
-        .transpose()
-        .map_err(|err| {
-            ChatServiceError::Validation(format!("failed to serialize compression warning: {err}"))
-        })?;
-    let result_messages_json = serde_json::to_string(&entry.result.messages).map_err(|err| {
-        ChatServiceError::Validation(format!(
-            "failed to serialize compression result messages: {err}"
-        ))
-    })?;
+    options: &ContextBuildOptions,
+) -> Result<CompactedContext, ChatServiceError> {
+    let all_messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    let agent_map = build_agent_map(pool).await?;
+
+    let simplified_messages: Vec<SimplifiedMessage> =
+        context_preamble_message(options.preamble.as_deref())
+            .into_iter()
+            .chain(
+                all_messages
+                    .iter()
+                    .map(|message| to_simplified_message(message, &agent_map)),
+            )
+            .collect();
 
-    let query = format!(
-        "INSERT INTO {COMPRESSION_STATE_TABLE} (
-            session_id,
-            source_fingerprint,
-            source_message_count,
-            token_threshold,
-            compression_percentage,
-            source_token_count,
-            effective_token_count,
-            compression_type,
-            warning_json,
-            result_messages_json,
-            updated_at
-        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, datetime('now', 'subsec'))
-        ON CONFLICT(session_id) DO UPDATE SET
-            source_fingerprint = excluded.source_fingerprint,
-            source_message_count = excluded.source_message_count,
-            token_threshold = excluded.token_threshold,
-            compression_percentage = excluded.compression_percentage,
-            source_token_count = excluded.source_token_count,
-            effective_token_count = excluded.effective_token_count,
-            compression_type = excluded.compression_type,
-            warning_json = excluded.warning_json,
-            result_messages_json = excluded.result_messages_json,
-            updated_at = datetime('now', 'subsec')"
-    );
+    log_context_totals(&simplified_messages, false);
+    let (messages, jsonl) = simplified_messages_to_jsonl(&simplified_messages, false);
+    Ok(CompactedContext {
+        messages,
+        jsonl,
+        context_compacted: false,
+        compression_warning: None,
+    })
+}
 
-    let execute_result = sqlx::query(&query)
-        .bind(session_id)
-        .bind(entry.source_fingerprint.to_string())
-        .bind(entry.source_message_count as i64)
-        .bind(entry.token_threshold as i64)
-        .bind(entry.compression_percentage as i64)
-        .bind(entry.source_token_count as i64)
-        .bind(entry.effective_token_count as i64)
-        .bind(compression_type_to_db_value(&entry.result.compression_type))
-        .bind(warning_json)
-        .bind(result_messages_json)
-        .execute(pool)
-        .await;
+/// Build compacted context with token-threshold based compression only.
+///
+/// # Arguments
+/// * `pool` - Database connection pool
+/// * `session_id` - Chat session ID
+/// * `runner_type` - Runner type string for the agent (e.g., "CLAUDE_CODE", "CODEX")
+/// * `workspace_path` - Path to workspace for running LLM
+/// * `context_dir` - Path to context directory for storing cutoff files
+/// * `config` - Settings the context is shaped by (see [`ContextBuildOptions::from_config`])
+///
+/// # Returns
+/// CompactedContext with messages and JSONL string
+pub async fn build_compacted_context(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    runner_type: Option<&str>,
+    workspace_path: Option<&std::path::Path>,
+    context_dir: Option<&std::path::Path>,
+    config: &super::config::Config,
+) -> Result<CompactedContext, ChatServiceError> {
+    let options = ContextBuildOptions::from_config(config);
+    build_compacted_context_with_options(
+        pool,
+        session_id,
+        runner_type,
+        workspace_path,
+        context_dir,
+        &options,
+    )
+    .await
+}
 
-    match execute_result {
-        Ok(_) => Ok(()),
-        Err(err) if is_missing_compression_state_table_error(&err) => {
-            tracing::debug!(
-                table = COMPRESSION_STATE_TABLE,
-                "Compression state table is missing; skip persisting compression cache"
-            );
-            Ok(())
+/// Extra shaping applied by [`build_compacted_context_with_options`].
+#[derive(Debug, Clone, Default)]
+pub struct ContextBuildOptions {
+    /// Prepended as a system message. Its tokens are taken out of the
+    /// compression threshold so the whole context stays within budget.
+    pub preamble: Option<String>,
+    /// When history gets compressed, and how much of it.
+    pub compression: super::config::ChatCompressionConfig,
+    /// Fold runs of messages from the same sender into one entry, so short
+    /// consecutive messages don't each repeat the sender label.
+    pub merge_consecutive_senders: bool,
+    /// Which system messages to keep in the context.
+    pub system_messages: SystemMessageFilter,
+    /// Add a `"tokens"` estimate to every returned message, for debugging
+    /// context size. Off by default since it tokenizes each message again.
+    pub include_token_counts: bool,
+    /// When history was truncated, list each archived message as a short stub
+    /// with an `"original_ref"` (see [`resolve_original_ref`]) after the
+    /// fallback summary, so the full text can be fetched on demand.
+    pub include_compressed_refs: bool,
+}
+
+impl ContextBuildOptions {
+    /// Options for an agent run: the configured preamble and compression
+    /// settings, and defaults otherwise.
+    pub fn from_config(config: &super::config::Config) -> Self {
+        Self {
+            preamble: config.chat_presets.context_preamble.clone(),
+            compression: config.chat_compression.clone(),
+            ..Default::default()
         }
-        Err(err) => Err(ChatServiceError::Database(err)),
     }
 }
 
-#[allow(clippy::too_many_arguments)]
-async fn cache_compression_result(
-    pool: &SqlitePool,
-    session_id: Uuid,
-    source_fingerprint: u64,
-    source_message_count: usize,
-    token_threshold: u32,
-    compression_percentage: u8,
-    source_token_count: u32,
-    result: &CompressionResult,
-) {
-    let entry = cache_compression_result_in_memory(
-        session_id,
-        source_fingerprint,
-        source_message_count,
-        token_threshold,
-        compression_percentage,
-        source_token_count,
-        result,
-    );
+/// Merge runs of consecutive messages from the same sender into the first
+/// message of the run: contents are joined with newlines, mentions are
+/// unioned in order, and the latest timestamp is kept.
+pub fn merge_consecutive_sender_messages(
+    messages: Vec<ChatMessage>,
+    agent_map: &HashMap<Uuid, String>,
+) -> Vec<ChatMessage> {
+    let mut merged: Vec<(String, ChatMessage)> = Vec::with_capacity(messages.len());
+    for message in messages {
+        let key = to_simplified_message(&message, agent_map).sender;
+        match merged.last_mut() {
+            Some((last_key, last)) if *last_key == key => {
+                last.content.push('\n');
+                last.content.push_str(&message.content);
+                for mention in message.mentions.0 {
+                    if !last.mentions.0.contains(&mention) {
+                        last.mentions.0.push(mention);
+                    }
+                }
+                last.created_at = last.created_at.max(message.created_at);
+            }
+            _ => merged.push((key, message)),
+        }
+    }
+    merged.into_iter().map(|(_, message)| message).collect()
+}
 
-    if let Err(err) = persist_compression_result(pool, session_id, &entry).await {
-        tracing::warn!(
-            session_id = %session_id,
-            error = %err,
-            "Failed to persist compression cache entry"
+/// Debug-log the size of a built context. Token counting is skipped unless
+/// debug logging is enabled.
+fn log_context_totals(messages: &[SimplifiedMessage], compacted: bool) {
+    if tracing::enabled!(tracing::Level::DEBUG) {
+        tracing::debug!(
+            messages = messages.len(),
+            tokens = estimate_token_count(messages),
+            compacted,
+            "Built chat context"
         );
     }
 }
 
-async fn load_persisted_compression_result(
+/// Like [`build_compacted_context`], with explicit [`ContextBuildOptions`].
+pub async fn build_compacted_context_with_options(
     pool: &SqlitePool,
     session_id: Uuid,
-) -> Result<Option<CompressionCacheEntry>, ChatServiceError> {
-    let query = format!(
-        "SELECT
-            source_fingerprint,
-            source_message_count,
-            token_threshold,
-            compression_percentage,
-            source_token_count,
-            effective_token_count,
-            compression_type,
-            warning_json,
-            result_messages_json
-         FROM {COMPRESSION_STATE_TABLE}
-         WHERE session_id = ?1"
-    );
+    runner_type: Option<&str>,
+    workspace_path: Option<&std::path::Path>,
+    context_dir: Option<&std::path::Path>,
+    options: &ContextBuildOptions,
+) -> Result<CompactedContext, ChatServiceError> {
+    let agent_map = build_agent_map(pool).await?;
+    build_compacted_context_with_agent_map(
+        pool,
+        session_id,
+        runner_type,
+        workspace_path,
+        context_dir,
+        options,
+        &agent_map,
+    )
+    .await
+}
 
-    let row = match sqlx::query(&query)
-        .bind(session_id)
-        .fetch_optional(pool)
-        .await
-    {
-        Ok(row) => row,
-        Err(err) if is_missing_compression_state_table_error(&err) => {
-            tracing::debug!(
-                table = COMPRESSION_STATE_TABLE,
-                "Compression state table is missing; skip loading persisted compression cache"
-            );
-            return Ok(None);
-        }
-        Err(err) => return Err(ChatServiceError::Database(err)),
-    };
+/// Like [`build_compacted_context_with_options`], labelling agents from a
+/// map built with [`build_agent_map`].
+#[tracing::instrument(level = "debug", skip_all, fields(session_id = %session_id))]
+pub async fn build_compacted_context_with_agent_map(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    _runner_type: Option<&str>,
+    workspace_path: Option<&std::path::Path>,
+    context_dir: Option<&std::path::Path>,
+    options: &ContextBuildOptions,
+    agent_map: &HashMap<Uuid, String>,
+) -> Result<CompactedContext, ChatServiceError> {
+    let ContextInput {
+        messages: simplified_messages,
+        preamble,
+        token_threshold,
+        compression_percentage,
+    } = prepare_context_input(pool, session_id, options, agent_map).await?;
+    let session_agents = ChatSessionAgent::find_all_for_session(pool, session_id).await?;
+    let workspace_path = workspace_path.unwrap_or(std::path::Path::new("."));
 
-    let Some(row) = row else {
-        return Ok(None);
-    };
+    let compression_result = compress_messages_if_needed(
+        pool,
+        session_id,
+        simplified_messages,
+        token_threshold,
+        compression_percentage,
+        &session_agents,
+        workspace_path,
+        context_dir,
+    )
+    .await?;
 
-    let source_fingerprint_raw: String = row.try_get("source_fingerprint")?;
-    let source_fingerprint = match source_fingerprint_raw.parse::<u64>() {
-        Ok(value) => value,
-        Err(err) => {
-            tracing::warn!(
+    let mut context_messages = compression_result.messages;
+    let has_preamble = preamble.is_some();
+    if let Some(preamble) = preamble {
+        context_messages.insert(0, preamble);
+    }
+    let context_compacted = compression_result.compression_type != CompressionType::None;
+    log_context_totals(&context_messages, context_compacted);
+    let mut values: Vec<Value> = context_messages
+        .iter()
+        .map(simplified_to_context_value)
+        .collect();
+    if options.include_compressed_refs
+        && compression_result.compression_type == CompressionType::Truncated
+        && let Some(warning) = &compression_result.warning
+    {
+        match compressed_ref_values(warning).await {
+            Ok(stubs) => {
+                // Right after the fallback summary marker.
+                let at = (usize::from(has_preamble) + 1).min(values.len());
+                values.splice(at..at, stubs);
+            }
+            Err(err) => tracing::warn!(
                 session_id = %session_id,
-                source_fingerprint = %source_fingerprint_raw,
                 error = %err,
-                "Invalid persisted compression fingerprint; ignoring persisted state"
-            );
-            return Ok(None);
+                "Could not list references to compressed messages"
+            ),
         }
+    }
+    let (messages, jsonl) = context_values_to_jsonl(values, options.include_token_counts);
+
+    Ok(CompactedContext {
+        messages,
+        jsonl,
+        context_compacted,
+        compression_warning: compression_result.warning,
+    })
+}
+
+/// Characters of a compressed message kept in its reference stub.
+const COMPRESSED_REF_PREVIEW_LEN: usize = 120;
+
+/// Context stubs for the messages a truncation archived: sender and time as
+/// usual, a short preview as content, and `"original_ref"` in the
+/// `{file}#{timestamp}` form [`resolve_original_ref`] reads. The timestamp
+/// rather than the position identifies the message, since merging sessions
+/// re-sorts split files.
+async fn compressed_ref_values(
+    warning: &CompressionWarning,
+) -> Result<Vec<Value>, ChatServiceError> {
+    let Some(range) = warning.archived_range else {
+        return Ok(Vec::new());
     };
+    let archived = read_archived_messages(Path::new(&warning.split_file_path)).await?;
+    Ok(archived
+        .iter()
+        .skip(range.start)
+        .take(range.count)
+        .map(|message| {
+            let preview = utils::text::truncate_to_char_boundary(
+                &message.content,
+                COMPRESSED_REF_PREVIEW_LEN,
+            );
+            let ellipsis = if preview.len() < message.content.len() {
+                "…"
+            } else {
+                ""
+            };
+            let mut value = simplified_to_context_value(&SimplifiedMessage {
+                sender: message.sender.clone(),
+                content: format!("[compressed] {preview}{ellipsis}"),
+                timestamp: message.timestamp.clone(),
+                record: None,
+            });
+            value["original_ref"] =
+                Value::String(format!("{}#{}", warning.split_file_path, message.timestamp));
+            value
+        })
+        .collect())
+}
+
+/// The `messages` array of a split history file or cutoff file.
+async fn read_archived_messages(path: &Path) -> Result<Vec<SimplifiedMessage>, ChatServiceError> {
+    let raw = fs::read_to_string(path).await?;
+    let mut file: Value = serde_json::from_str(&raw).map_err(|err| {
+        ChatServiceError::Validation(format!("invalid archive file {}: {err}", path.display()))
+    })?;
+    serde_json::from_value(file["messages"].take()).map_err(|err| {
+        ChatServiceError::Validation(format!(
+            "invalid messages in archive file {}: {err}",
+            path.display()
+        ))
+    })
+}
+
+/// Full message behind an `"original_ref"` produced with
+/// [`ContextBuildOptions::include_compressed_refs`].
+///
+/// Only files under the chat history directory or `context_dir` (where the
+/// context's cutoff files were written) are read; refs pointing anywhere else
+/// are rejected.
+pub async fn resolve_original_ref(
+    original_ref: &str,
+    context_dir: Option<&Path>,
+) -> Result<SimplifiedMessage, ChatServiceError> {
+    let mut allowed_dirs: Vec<PathBuf> = context_dir.map(Path::to_path_buf).into_iter().collect();
+    if let Ok(history_dir) = chat_history_dir() {
+        allowed_dirs.push(history_dir);
+    }
+    resolve_original_ref_within(original_ref, &allowed_dirs).await
+}
+
+async fn resolve_original_ref_within(
+    original_ref: &str,
+    allowed_dirs: &[PathBuf],
+) -> Result<SimplifiedMessage, ChatServiceError> {
+    let invalid = || ChatServiceError::Validation(format!("invalid original_ref: {original_ref}"));
+    let (path, timestamp) = original_ref.rsplit_once('#').ok_or_else(invalid)?;
+    let path = fs::canonicalize(path).await.map_err(|_| invalid())?;
+    let mut allowed = false;
+    for dir in allowed_dirs {
+        if let Ok(dir) = fs::canonicalize(dir).await
+            && path.starts_with(&dir)
+        {
+            allowed = true;
+            break;
+        }
+    }
+    if !allowed {
+        return Err(ChatServiceError::Validation(format!(
+            "original_ref points outside the chat history directories: {original_ref}"
+        )));
+    }
+    read_archived_messages(&path)
+        .await?
+        .into_iter()
+        .find(|message| message.timestamp == timestamp)
+        .ok_or_else(invalid)
+}
+
+/// Session history and settings a context is built from, before compression.
+struct ContextInput {
+    messages: Vec<SimplifiedMessage>,
+    preamble: Option<SimplifiedMessage>,
+    /// Compression threshold with the preamble's tokens already taken out.
+    token_threshold: u32,
+    compression_percentage: u8,
+}
+
+async fn prepare_context_input(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    options: &ContextBuildOptions,
+    agent_map: &HashMap<Uuid, String>,
+) -> Result<ContextInput, ChatServiceError> {
+    // Fetch all messages for the session
+    let mut all_messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+
+    all_messages.retain(|message| {
+        options.system_messages.keeps(message)
+            && !is_pending_reply(&message.meta.0)
+            && !is_cancelled_reply(&message.meta.0)
+    });
+    let all_messages = if options.merge_consecutive_senders {
+        merge_consecutive_sender_messages(all_messages, agent_map)
+    } else {
+        all_messages
+    };
+
+    let messages: Vec<SimplifiedMessage> = all_messages
+        .iter()
+        .map(|message| to_simplified_message(message, agent_map))
+        .collect();
+    let (token_threshold, compression_percentage) = chat_compression_settings(&options.compression);
+    let preamble = context_preamble_message(options.preamble.as_deref());
+    let preamble_tokens = preamble.as_ref().map_or(0, |message| {
+        estimate_token_count(std::slice::from_ref(message))
+    });
+    let token_threshold = token_threshold.saturating_sub(preamble_tokens).max(1);
+
+    Ok(ContextInput {
+        messages,
+        preamble,
+        token_threshold,
+        compression_percentage,
+    })
+}
+
+/// Token cost of the context [`build_compacted_context_with_options`] would
+/// build for the session right now, counted over its JSONL form.
+///
+/// Nothing is persisted: no summarizer runs and no cache entries or cutoff
+/// files are written. A history that would need fresh compression is
+/// estimated as the truncation fallback, the most it can cost.
+pub async fn estimate_context_tokens(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    options: &ContextBuildOptions,
+) -> Result<u32, ChatServiceError> {
+    let agent_map = build_agent_map(pool).await?;
+    let input = prepare_context_input(pool, session_id, options, &agent_map).await?;
+    let source_fingerprint = calculate_messages_fingerprint(&input.messages);
+    let cached_entry = get_compression_cache_entry(pool, session_id).await?;
+
+    let mut context_messages = match compression_start(
+        session_id,
+        cached_entry.as_ref(),
+        &input.messages,
+        source_fingerprint,
+        input.token_threshold,
+        input.compression_percentage,
+    ) {
+        CompressionStart::Cached(result) => result.messages,
+        CompressionStart::Base(base) => {
+            let token_count = estimate_token_count(&base.messages);
+            if token_count <= input.token_threshold || base.messages.is_empty() {
+                base.messages
+            } else {
+                let (compress_count, _, selected_tokens) = select_messages_to_compress_by_token(
+                    &base.messages,
+                    token_count,
+                    input.compression_percentage,
+                );
+                let mut kept = vec![SimplifiedMessage {
+                    sender: "system:summary".to_string(),
+                    content: format!(
+                        "[History Summary - Fallback]\nAI summarization failed; archived {} messages (~{} tokens)",
+                        compress_count, selected_tokens
+                    ),
+                    timestamp: Utc::now().to_rfc3339(),
+                    record: None,
+                }];
+                kept.extend_from_slice(&base.messages[compress_count..]);
+                kept
+            }
+        }
+    };
+    if let Some(preamble) = input.preamble {
+        context_messages.insert(0, preamble);
+    }
+
+    let (_, jsonl) = simplified_messages_to_jsonl(&context_messages, false);
+    Ok(estimate_string_tokens(&jsonl))
+}
+
+/// Root directory for per-session chat data such as archives.
+pub fn default_chat_archive_root() -> PathBuf {
+    asset_dir().join("chat")
+}
+
+/// Directory a session's archive export is written to under `chat_root`.
+pub fn session_archive_dir(chat_root: &Path, session_id: Uuid) -> PathBuf {
+    chat_root
+        .join(format!("session_{session_id}"))
+        .join("archive")
+}
+
+/// Written to `session_summary.md` when a session has no summary.
+const NO_SUMMARY_PLACEHOLDER: &str = "No summary available.";
+
+/// Session metadata file written by [`ArchiveLayout::Nested`] exports.
+pub const ARCHIVE_SESSION_FILE: &str = "session.json";
+
+/// Where an archive export puts its files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ArchiveLayout {
+    /// Directly in the given archive dir; suits single-session exports.
+    #[default]
+    Flat,
+    /// In `{archive_dir}/{session_id}/`, with a [`ARCHIVE_SESSION_FILE`]
+    /// holding the session's metadata, so many sessions can share a parent.
+    Nested,
+}
+
+/// Write a session's messages, summary and attachment files to `archive_dir`.
+/// Exported attachment `relative_path` values point into the archive's
+/// `attachments/` folder.
+pub async fn export_session_archive(
+    pool: &SqlitePool,
+    session: &ChatSession,
+    archive_dir: &Path,
+) -> Result<String, ChatServiceError> {
+    export_session_archive_with_progress(pool, session, archive_dir, no_progress).await
+}
+
+/// [`export_session_archive`] with an explicit [`ArchiveLayout`]. Returns the
+/// directory the files were written to.
+pub async fn export_session_archive_with_layout(
+    pool: &SqlitePool,
+    session: &ChatSession,
+    archive_dir: &Path,
+    layout: ArchiveLayout,
+) -> Result<String, ChatServiceError> {
+    let root = asset_dir();
+    let options = ArchiveWriteOptions {
+        layout,
+        ..ArchiveWriteOptions::new(&root)
+    };
+    write_session_archive(pool, session, archive_dir, &options).await
+}
+
+/// [`export_session_archive`], reporting exported messages to `progress`.
+pub async fn export_session_archive_with_progress(
+    pool: &SqlitePool,
+    session: &ChatSession,
+    archive_dir: &Path,
+    progress: impl Fn(ProgressUpdate),
+) -> Result<String, ChatServiceError> {
+    let root = asset_dir();
+    let options = ArchiveWriteOptions {
+        progress: &progress,
+        ..ArchiveWriteOptions::new(&root)
+    };
+    write_session_archive(pool, session, archive_dir, &options).await
+}
+
+/// [`export_session_archive`] with attachment `relative_path` values resolved
+/// against `attachments_root` instead of the asset dir.
+pub async fn export_session_archive_with_attachment_root(
+    pool: &SqlitePool,
+    session: &ChatSession,
+    archive_dir: &Path,
+    attachments_root: &Path,
+) -> Result<String, ChatServiceError> {
+    let options = ArchiveWriteOptions::new(attachments_root);
+    write_session_archive(pool, session, archive_dir, &options).await
+}
+
+/// Update a flat archive in `archive_dir` by appending only the messages
+/// created since its last export, as recorded in [`ARCHIVE_EXPORT_STATE`].
+/// Without a usable state file, or when messages already exported have
+/// changed since, the export is written from scratch.
+pub async fn export_session_incremental(
+    pool: &SqlitePool,
+    session: &ChatSession,
+    archive_dir: &Path,
+) -> Result<String, ChatServiceError> {
+    let root = asset_dir();
+    let options = ArchiveWriteOptions {
+        incremental: true,
+        ..ArchiveWriteOptions::new(&root)
+    };
+    write_session_archive(pool, session, archive_dir, &options).await
+}
+
+/// Bookkeeping file recording the last message written to an archive.
+pub const ARCHIVE_EXPORT_STATE: &str = "export_state.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ArchiveExportState {
+    last_message_id: Uuid,
+    /// Messages in the export. Absent in state files written before this was
+    /// tracked, which makes the next export start over.
+    #[serde(default)]
+    message_count: Option<usize>,
+    /// Messages exported as pending replies, whose lines go stale once the
+    /// reply is finalized or cancelled.
+    #[serde(default)]
+    pending_message_ids: Vec<Uuid>,
+}
+
+async fn read_export_state(archive_dir: &Path) -> Option<ArchiveExportState> {
+    let raw = fs::read(archive_dir.join(ARCHIVE_EXPORT_STATE))
+        .await
+        .ok()?;
+    serde_json::from_slice(&raw)
+        .inspect_err(|err| {
+            tracing::warn!(
+                archive_dir = %archive_dir.display(),
+                error = %err,
+                "Ignoring unreadable archive export state"
+            );
+        })
+        .ok()
+}
+
+/// Keyset position of a message in the export order, if it still exists.
+async fn message_export_cursor(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    message_id: Uuid,
+) -> Result<Option<(String, i64)>, ChatServiceError> {
+    let row = sqlx::query(
+        "SELECT created_at, rowid AS row_id FROM chat_messages WHERE id = ?1 AND session_id = ?2",
+    )
+    .bind(message_id)
+    .bind(session_id)
+    .fetch_optional(pool)
+    .await?;
+    row.map(|row| Ok((row.try_get("created_at")?, row.try_get("row_id")?)))
+        .transpose()
+}
+
+/// Whether the messages up to `cursor` are still the ones that were exported:
+/// none were added or removed behind it (e.g. moved in by a session merge)
+/// and no exported placeholder has been finalized or cancelled since.
+async fn export_state_is_current(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    state: &ArchiveExportState,
+    cursor: &(String, i64),
+) -> Result<bool, ChatServiceError> {
+    let Some(exported) = state.message_count else {
+        return Ok(false);
+    };
+    let behind: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM chat_messages WHERE session_id = ?1 AND (created_at, rowid) <= (?2, ?3)",
+    )
+    .bind(session_id)
+    .bind(&cursor.0)
+    .bind(cursor.1)
+    .fetch_one(pool)
+    .await?;
+    if behind as usize != exported {
+        return Ok(false);
+    }
+    for message_id in &state.pending_message_ids {
+        let still_pending = ChatMessage::find_by_id(pool, *message_id)
+            .await?
+            .is_some_and(|message| is_pending_reply(&message.meta.0));
+        if !still_pending {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+struct ArchiveWriteOptions<'a> {
+    attachments_root: &'a Path,
+    layout: ArchiveLayout,
+    /// Append messages newer than the last export instead of rewriting them.
+    incremental: bool,
+    progress: &'a dyn Fn(ProgressUpdate),
+}
+
+impl<'a> ArchiveWriteOptions<'a> {
+    fn new(attachments_root: &'a Path) -> Self {
+        Self {
+            attachments_root,
+            layout: ArchiveLayout::Flat,
+            incremental: false,
+            progress: &no_progress,
+        }
+    }
+}
+
+#[tracing::instrument(
+    name = "export_session_archive",
+    level = "debug",
+    skip_all,
+    fields(session_id = %session.id, incremental = options.incremental)
+)]
+async fn write_session_archive(
+    pool: &SqlitePool,
+    session: &ChatSession,
+    archive_dir: &Path,
+    options: &ArchiveWriteOptions<'_>,
+) -> Result<String, ChatServiceError> {
+    let nested_dir;
+    let archive_dir = match options.layout {
+        ArchiveLayout::Flat => archive_dir,
+        ArchiveLayout::Nested => {
+            nested_dir = archive_dir.join(session.id.to_string());
+            &nested_dir
+        }
+    };
+    fs::create_dir_all(archive_dir).await?;
+
+    let attachments =
+        copy_archive_attachments(pool, session.id, options.attachments_root, archive_dir).await?;
+
+    let export_path = archive_dir.join("messages_export.jsonl");
+    let previous = if options.incremental {
+        read_export_state(archive_dir).await
+    } else {
+        None
+    };
+    let mut start_after = None;
+    if let Some(state) = &previous {
+        start_after = message_export_cursor(pool, session.id, state.last_message_id).await?;
+        match &start_after {
+            None => {
+                tracing::warn!(
+                    session_id = %session.id,
+                    last_message_id = %state.last_message_id,
+                    "Last exported message no longer exists; rewriting archive"
+                );
+            }
+            Some(cursor) if !export_state_is_current(pool, session.id, state, cursor).await? => {
+                tracing::info!(
+                    session_id = %session.id,
+                    "Exported messages changed since the last export; rewriting archive"
+                );
+                start_after = None;
+            }
+            Some(_) => {}
+        }
+    }
+    let file = match start_after {
+        Some(_) if export_path.exists() => {
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&export_path)
+                .await?
+        }
+        _ => {
+            start_after = None;
+            fs::File::create(&export_path).await?
+        }
+    };
+    let appending = start_after.is_some();
+    let mut file = tokio::io::BufWriter::new(file);
+    let written = write_structured_messages_jsonl(
+        pool,
+        session.id,
+        &mut file,
+        EXPORT_PAGE_SIZE,
+        &attachments.paths,
+        start_after,
+        options.progress,
+    )
+    .await?;
+
+    let previous = previous.filter(|_| appending);
+    let last_message_id = written
+        .last_id
+        .or(previous.as_ref().map(|state| state.last_message_id));
+    if let Some(last_message_id) = last_message_id {
+        let (previous_count, mut pending_message_ids) = previous
+            .map(|state| (state.message_count.unwrap_or(0), state.pending_message_ids))
+            .unwrap_or_default();
+        pending_message_ids.extend(written.pending_ids);
+        let state = serde_json::to_vec(&ArchiveExportState {
+            last_message_id,
+            message_count: Some(previous_count + written.count),
+            pending_message_ids,
+        })
+        .map_err(std::io::Error::from)?;
+        fs::write(archive_dir.join(ARCHIVE_EXPORT_STATE), state).await?;
+    }
+
+    let summary_path = archive_dir.join("session_summary.md");
+    let summary = session
+        .summary_text
+        .clone()
+        .unwrap_or_else(|| NO_SUMMARY_PLACEHOLDER.to_string());
+    fs::write(&summary_path, summary).await?;
+
+    let mut files = vec!["messages_export.jsonl", "session_summary.md"];
+    if options.layout == ArchiveLayout::Nested {
+        let metadata = serde_json::json!({
+            "id": session.id,
+            "title": session.title,
+            "status": session.status,
+            "created_at": session.created_at,
+            "updated_at": session.updated_at,
+            "archived_at": session.archived_at,
+        });
+        let json = serde_json::to_vec_pretty(&metadata).map_err(std::io::Error::from)?;
+        fs::write(archive_dir.join(ARCHIVE_SESSION_FILE), json).await?;
+        files.push(ARCHIVE_SESSION_FILE);
+    }
+    let mut copied: Vec<&str> = attachments.paths.values().map(String::as_str).collect();
+    copied.sort_unstable();
+    files.extend(copied);
+    tracing::debug!(
+        attachments = attachments.paths.len(),
+        missing_attachments = attachments.missing.len(),
+        "Copied attachments into chat session archive"
+    );
+    write_archive_manifest(archive_dir, &files, attachments.missing).await?;
+
+    Ok(archive_dir.to_string_lossy().to_string())
+}
+
+/// Folder inside a session archive that holds copied attachment files.
+const ARCHIVE_ATTACHMENTS_DIR: &str = "attachments";
+
+/// Attachments copied into an archive by [`copy_archive_attachments`].
+#[derive(Debug, Default)]
+struct ArchivedAttachments {
+    /// Stored `relative_path` to archive-relative path.
+    paths: HashMap<String, String>,
+    /// Stored `relative_path` values whose source file wasn't found.
+    missing: Vec<String>,
+}
+
+/// Copy every attachment referenced by the session's messages into
+/// `{archive_dir}/attachments/{message_id}/`. Missing or unsafe source paths
+/// are logged and reported instead of failing the export.
+async fn copy_archive_attachments(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    attachments_root: &Path,
+    archive_dir: &Path,
+) -> Result<ArchivedAttachments, ChatServiceError> {
+    let rows = sqlx::query(
+        "SELECT id, meta FROM chat_messages WHERE session_id = ?1 ORDER BY created_at ASC, rowid ASC",
+    )
+    .bind(session_id)
+    .fetch_all(pool)
+    .await?;
+
+    let mut archived = ArchivedAttachments::default();
+    for row in rows {
+        let message_id: Uuid = row.try_get("id")?;
+        let sqlx::types::Json(meta): sqlx::types::Json<Value> = row.try_get("meta")?;
+        for attachment in extract_attachments(&meta) {
+            let relative_path = attachment.relative_path;
+            if archived.paths.contains_key(&relative_path)
+                || archived.missing.contains(&relative_path)
+            {
+                continue;
+            }
+
+            let source = Path::new(&relative_path);
+            let is_safe = !source.is_absolute()
+                && source
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)));
+            let file_name = source.file_name().map(|name| name.to_string_lossy());
+            let (true, Some(file_name)) = (is_safe, file_name) else {
+                tracing::warn!(
+                    session_id = %session_id,
+                    relative_path = %relative_path,
+                    "Skipping attachment with unsafe path in archive export"
+                );
+                archived.missing.push(relative_path);
+                continue;
+            };
+
+            let target = format!("{ARCHIVE_ATTACHMENTS_DIR}/{message_id}/{file_name}");
+            let target_path = archive_dir.join(&target);
+            if let Some(parent) = target_path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            match fs::copy(attachments_root.join(source), &target_path).await {
+                Ok(_) => {
+                    archived.paths.insert(relative_path, target);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    tracing::warn!(
+                        session_id = %session_id,
+                        relative_path = %relative_path,
+                        "Attachment file missing; recording it in the archive manifest"
+                    );
+                    archived.missing.push(relative_path);
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+    Ok(archived)
+}
+
+/// Write a session transcript to `path` as a standalone HTML page. Message
+/// content is escaped and fenced code blocks are highlighted; attachments are
+/// linked relative to the written file, pointing into the chat data directory.
+pub async fn export_session_html(
+    pool: &SqlitePool,
+    session: &ChatSession,
+    path: &Path,
+) -> Result<(), ChatServiceError> {
+    write_session_html(pool, session, path, &asset_dir()).await
+}
+
+async fn write_session_html(
+    pool: &SqlitePool,
+    session: &ChatSession,
+    path: &Path,
+    attachments_root: &Path,
+) -> Result<(), ChatServiceError> {
+    let html_dir = path.parent().unwrap_or(Path::new("."));
+    let messages = ChatMessage::find_by_session_id(pool, session.id, None).await?;
+    let agent_map = build_agent_map(pool).await?;
+    let html_messages: Vec<HtmlMessage> = messages
+        .iter()
+        .map(|message| HtmlMessage {
+            sender_label: message_sender_label(message, &agent_map),
+            sender_kind: match message.sender_type {
+                ChatSenderType::User => "user",
+                ChatSenderType::Agent => "agent",
+                ChatSenderType::System => "system",
+            },
+            created_at: message
+                .created_at
+                .format("%Y-%m-%d %H:%M:%S UTC")
+                .to_string(),
+            content: message.content.clone(),
+            attachments: extract_attachments(&message.meta.0)
+                .into_iter()
+                .map(|attachment| {
+                    let href =
+                        attachment_href(&attachment.relative_path, attachments_root, html_dir);
+                    (attachment.name, href)
+                })
+                .collect(),
+        })
+        .collect();
+    let title = session
+        .title
+        .clone()
+        .unwrap_or_else(|| format!("Chat session {}", session.id));
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(path, render_transcript_html(&title, &html_messages)).await?;
+    Ok(())
+}
+
+/// File name of the manifest written at the root of a bulk session export.
+pub const SESSION_ARCHIVE_MANIFEST: &str = "manifest.json";
+
+/// Manifest describing a bulk export of every session.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionArchiveManifest {
+    pub exported_at: String,
+    pub sessions: Vec<SessionArchiveManifestEntry>,
+    /// Sessions that could not be exported, with the error message.
+    #[serde(default)]
+    pub failures: Vec<SessionArchiveFailure>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionArchiveManifestEntry {
+    pub session_id: Uuid,
+    pub title: Option<String>,
+    pub status: ChatSessionStatus,
+    /// Folder relative to the archive root holding this session's export.
+    pub folder: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionArchiveFailure {
+    pub session_id: Uuid,
+    pub error: String,
+}
+
+/// Chat tables copied by [`export_session_sqlite`], parents first.
+const SQLITE_EXPORT_TABLES: &[&str] = &[
+    "chat_sessions",
+    "chat_agents",
+    "chat_session_agents",
+    "chat_messages",
+];
+
+/// Write one session to a new SQLite database at `dest_path`: its session
+/// row, messages, session agents and every agent they reference, in tables
+/// with the same schema and indexes as the app database, so the file can be
+/// re-imported row for row. `dest_path` must not exist yet; on failure no
+/// partial file is left behind.
+pub async fn export_session_sqlite(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    dest_path: &Path,
+) -> Result<(), ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+    if fs::try_exists(dest_path).await? {
+        return Err(ChatServiceError::Validation(format!(
+            "{} already exists",
+            dest_path.display()
+        )));
+    }
+    if let Some(parent) = dest_path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let mut conn = pool.acquire().await?;
+    sqlx::query("ATTACH DATABASE ?1 AS export")
+        .bind(sqlite_file_uri(dest_path))
+        .execute(&mut *conn)
+        .await?;
+    let copied = copy_session_rows(&mut conn, session_id).await;
+    let detached = sqlx::query("DETACH DATABASE export")
+        .execute(&mut *conn)
+        .await;
+    if let Err(err) = copied.and(detached.map(|_| ()).map_err(ChatServiceError::from)) {
+        let _ = fs::remove_file(dest_path).await;
+        return Err(err);
+    }
+
+    tracing::info!(
+        session_id = %session_id,
+        dest = %dest_path.display(),
+        "Exported chat session to SQLite"
+    );
+    Ok(())
+}
+
+/// `file:` URI that opens `path` read-write, creating it. A plain path would
+/// inherit the main connection's open mode, which is in-memory for test pools.
+fn sqlite_file_uri(path: &Path) -> String {
+    let path = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let path = path.to_string_lossy().replace('\\', "/");
+    let mut uri = String::from("file:");
+    if !path.starts_with('/') {
+        // Windows drive paths become `file:/C:/...`.
+        uri.push('/');
+    }
+    for c in path.chars() {
+        match c {
+            '%' => uri.push_str("%25"),
+            '?' => uri.push_str("%3f"),
+            '#' => uri.push_str("%23"),
+            c => uri.push(c),
+        }
+    }
+    uri.push_str("?mode=rwc");
+    uri
+}
+
+/// Create the chat tables in the attached `export` database and copy the
+/// session's rows into them.
+async fn copy_session_rows(
+    conn: &mut sqlx::SqliteConnection,
+    session_id: Uuid,
+) -> Result<(), ChatServiceError> {
+    let mut tx = sqlx::Connection::begin(&mut *conn).await?;
+    for table in SQLITE_EXPORT_TABLES {
+        let schema: Vec<(String, String)> = sqlx::query_as(
+            "SELECT type, sql FROM main.sqlite_master
+             WHERE tbl_name = ?1 AND type IN ('table', 'index') AND sql IS NOT NULL
+             ORDER BY type = 'index'",
+        )
+        .bind(table)
+        .fetch_all(&mut *tx)
+        .await?;
+        for (kind, sql) in schema {
+            sqlx::query(&qualify_schema_sql(&kind, &sql, table))
+                .execute(&mut *tx)
+                .await?;
+        }
+    }
+
+    let copies = [
+        "INSERT INTO export.chat_sessions SELECT * FROM main.chat_sessions WHERE id = ?1",
+        "INSERT INTO export.chat_agents SELECT * FROM main.chat_agents
+         WHERE id IN (
+             SELECT agent_id FROM main.chat_session_agents WHERE session_id = ?1
+             UNION
+             SELECT sender_id FROM main.chat_messages
+             WHERE session_id = ?1 AND sender_type = 'agent'
+         )",
+        "INSERT INTO export.chat_session_agents SELECT * FROM main.chat_session_agents
+         WHERE session_id = ?1",
+        "INSERT INTO export.chat_messages SELECT * FROM main.chat_messages
+         WHERE session_id = ?1 ORDER BY created_at, rowid",
+    ];
+    for copy in copies {
+        sqlx::query(copy).bind(session_id).execute(&mut *tx).await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Point a `CREATE TABLE` / `CREATE INDEX` statement from `sqlite_master` at
+/// the attached `export` database.
+fn qualify_schema_sql(kind: &str, sql: &str, table: &str) -> String {
+    if kind == "table" {
+        // Keep everything from the column list on, whatever quoting the
+        // stored statement uses for the name.
+        let columns = sql.find('(').map_or("", |start| &sql[start..]);
+        return format!("CREATE TABLE export.{table} {columns}");
+    }
+    match sql.find(" IF NOT EXISTS ") {
+        Some(at) => {
+            let end = at + " IF NOT EXISTS ".len();
+            format!("{}export.{}", &sql[..end], &sql[end..])
+        }
+        None => sql.replacen("INDEX ", "INDEX export.", 1),
+    }
+}
+
+/// Export every session into `{archive_root}/{session_id}/` and write a
+/// manifest at the root. Individual failures are logged and recorded in the
+/// manifest instead of aborting the export. Returns the created session folders.
+pub async fn export_all_sessions(
+    pool: &SqlitePool,
+    archive_root: &Path,
+) -> Result<Vec<String>, ChatServiceError> {
+    fs::create_dir_all(archive_root).await?;
+
+    let sessions = ChatSession::find_all(pool, None).await?;
+    let mut exported = Vec::with_capacity(sessions.len());
+    let mut entries = Vec::with_capacity(sessions.len());
+    let mut failures = Vec::new();
+
+    for session in sessions {
+        let folder = session.id.to_string();
+        match export_session_archive_with_layout(
+            pool,
+            &session,
+            archive_root,
+            ArchiveLayout::Nested,
+        )
+        .await
+        {
+            Ok(path) => {
+                exported.push(path);
+                entries.push(SessionArchiveManifestEntry {
+                    session_id: session.id,
+                    title: session.title.clone(),
+                    status: session.status.clone(),
+                    folder,
+                });
+            }
+            Err(err) => {
+                tracing::warn!(
+                    session_id = %session.id,
+                    error = %err,
+                    "Failed to export chat session; continuing with remaining sessions"
+                );
+                failures.push(SessionArchiveFailure {
+                    session_id: session.id,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+
+    let manifest = SessionArchiveManifest {
+        exported_at: Utc::now().to_rfc3339(),
+        sessions: entries,
+        failures,
+    };
+    let manifest_json = serde_json::to_string_pretty(&manifest).map_err(std::io::Error::from)?;
+    fs::write(archive_root.join(SESSION_ARCHIVE_MANIFEST), manifest_json).await?;
+
+    Ok(exported)
+}
+
+/// Outcome of [`import_all_sessions`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct SessionImportReport {
+    pub imported: Vec<Uuid>,
+    /// Sessions whose ID already exists locally.
+    pub skipped: Vec<Uuid>,
+    pub failed: Vec<SessionArchiveFailure>,
+}
+
+/// A line of `messages_export.jsonl` as written by [`build_structured_messages`].
+#[derive(Debug, Deserialize)]
+struct ExportedMessage {
+    id: Uuid,
+    created_at: chrono::DateTime<Utc>,
+    sender: ExportedSender,
+    content: String,
+    #[serde(default)]
+    mentions: Vec<String>,
+    #[serde(default)]
+    meta: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct ExportedSender {
+    #[serde(rename = "type")]
+    sender_type: ChatSenderType,
+    id: Option<Uuid>,
+}
+
+impl From<ChatMessage> for ExportedMessage {
+    fn from(message: ChatMessage) -> Self {
+        Self {
+            id: message.id,
+            created_at: message.created_at,
+            sender: ExportedSender {
+                sender_type: message.sender_type,
+                id: message.sender_id,
+            },
+            content: message.content,
+            mentions: message.mentions.0,
+            meta: message.meta.0,
+        }
+    }
+}
+
+async fn read_archive_messages(
+    archive_dir: &Path,
+    label: &str,
+) -> Result<Vec<ExportedMessage>, ChatServiceError> {
+    let export = fs::read_to_string(archive_dir.join("messages_export.jsonl")).await?;
+    let mut messages = Vec::new();
+    for (index, line) in export.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message: ExportedMessage = serde_json::from_str(line).map_err(|err| {
+            ChatServiceError::Validation(format!(
+                "invalid message on line {} of {} export: {}",
+                index + 1,
+                label,
+                err
+            ))
+        })?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+async fn read_archive_summary(archive_dir: &Path) -> Result<Option<String>, ChatServiceError> {
+    match fs::read_to_string(archive_dir.join("session_summary.md")).await {
+        Ok(summary) if summary.trim() != NO_SUMMARY_PLACEHOLDER && !summary.trim().is_empty() => {
+            Ok(Some(summary))
+        }
+        Ok(_) => Ok(None),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Where archived messages are written by [`insert_archived_session`].
+struct ArchiveImportTarget<'a> {
+    session_id: Uuid,
+    title: Option<&'a str>,
+    status: ChatSessionStatus,
+    summary_text: Option<String>,
+    /// Give every message a new ID (and remap reply references) instead of
+    /// keeping the archived IDs.
+    fresh_message_ids: bool,
+    /// Archive whose copied attachment files are restored under the
+    /// attachments root. `None` when the messages already point at stored
+    /// attachments (e.g. a fork of a live session).
+    attachments: Option<ArchiveAttachmentSource<'a>>,
+}
+
+/// Where [`insert_archived_session`] finds and restores archived attachments.
+#[derive(Clone, Copy)]
+struct ArchiveAttachmentSource<'a> {
+    archive_dir: &'a Path,
+    attachments_root: &'a Path,
+}
+
+/// Copy the attachments of one imported message out of the archive and point
+/// their `relative_path` back at `chat/session_{id}/attachments/...` under the
+/// attachments root. Only archive-relative paths written by
+/// [`copy_archive_attachments`] are restored; files missing from the archive
+/// keep their recorded path. Restored files are pushed to `restored`.
+async fn restore_archived_attachments(
+    meta: &mut Value,
+    session_id: Uuid,
+    source: ArchiveAttachmentSource<'_>,
+    restored: &mut Vec<PathBuf>,
+) -> Result<(), ChatServiceError> {
+    let Some(attachments) = meta
+        .get_mut("attachments")
+        .and_then(|attachments| attachments.as_array_mut())
+    else {
+        return Ok(());
+    };
+    for attachment in attachments {
+        let Some(archived) = attachment
+            .get("relative_path")
+            .and_then(|path| path.as_str())
+            .map(str::to_owned)
+        else {
+            continue;
+        };
+        let archived_path = Path::new(&archived);
+        let is_archive_relative = archived_path.starts_with(ARCHIVE_ATTACHMENTS_DIR)
+            && archived_path
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)));
+        if !is_archive_relative {
+            continue;
+        }
+
+        let stored = format!("chat/session_{session_id}/{archived}");
+        let target_path = source.attachments_root.join(&stored);
+        if let Some(parent) = target_path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        match fs::copy(source.archive_dir.join(archived_path), &target_path).await {
+            Ok(_) => {
+                restored.push(target_path);
+                attachment["relative_path"] = Value::String(stored);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                tracing::warn!(
+                    session_id = %session_id,
+                    relative_path = %archived,
+                    "Archived attachment file missing; keeping its recorded path"
+                );
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Create the session and its messages in one transaction so a bad archive
+/// never leaves a partial session behind.
+#[tracing::instrument(
+    name = "import_session_archive",
+    level = "debug",
+    skip_all,
+    fields(session_id = %target.session_id, messages = messages.len())
+)]
+async fn insert_archived_session(
+    pool: &SqlitePool,
+    target: ArchiveImportTarget<'_>,
+    messages: Vec<ExportedMessage>,
+    progress: &dyn Fn(ProgressUpdate),
+) -> Result<ChatSession, ChatServiceError> {
+    let mut restored = Vec::new();
+    if let Err(err) = insert_archived_rows(pool, &target, messages, progress, &mut restored).await {
+        // The transaction rolled back; don't leave its attachment copies behind.
+        for path in restored {
+            let _ = fs::remove_file(path).await;
+        }
+        return Err(err);
+    }
+
+    ChatSession::find_by_id(pool, target.session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)
+}
+
+async fn insert_archived_rows(
+    pool: &SqlitePool,
+    target: &ArchiveImportTarget<'_>,
+    messages: Vec<ExportedMessage>,
+    progress: &dyn Fn(ProgressUpdate),
+    restored: &mut Vec<PathBuf>,
+) -> Result<(), ChatServiceError> {
+    let total = messages.len();
+    let archived_at = (target.status == ChatSessionStatus::Archived).then(Utc::now);
+    let id_map: HashMap<Uuid, Uuid> = if target.fresh_message_ids {
+        messages
+            .iter()
+            .map(|message| (message.id, Uuid::new_v4()))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    let mut tx = pool.begin().await?;
+    sqlx::query(
+        r#"INSERT INTO chat_sessions (id, title, status, summary_text, archived_at)
+           VALUES (?1, ?2, ?3, ?4, ?5)"#,
+    )
+    .bind(target.session_id)
+    .bind(target.title)
+    .bind(&target.status)
+    .bind(&target.summary_text)
+    .bind(archived_at)
+    .execute(&mut *tx)
+    .await?;
+
+    for (index, message) in messages.into_iter().enumerate() {
+        // Match the `datetime('now', 'subsec')` format so imported and new
+        // messages sort together.
+        let created_at = message
+            .created_at
+            .format("%Y-%m-%d %H:%M:%S%.3f")
+            .to_string();
+        let mut meta = if message.meta.is_object() {
+            message.meta
+        } else {
+            serde_json::json!({})
+        };
+        if let Some(new_reference) =
+            extract_reference_message_id(&meta).and_then(|id| id_map.get(&id))
+        {
+            if let Some(map) = meta.as_object_mut() {
+                map.remove("reference_message_id");
+            }
+            meta["reference"] = serde_json::json!({ "message_id": new_reference });
+        }
+        if let Some(source) = target.attachments {
+            restore_archived_attachments(&mut meta, target.session_id, source, restored).await?;
+        }
+        let message_id = id_map.get(&message.id).copied().unwrap_or(message.id);
+
+        sqlx::query(
+            r#"INSERT INTO chat_messages
+                   (id, session_id, sender_type, sender_id, content, mentions, meta, created_at)
+               VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)"#,
+        )
+        .bind(message_id)
+        .bind(target.session_id)
+        .bind(message.sender.sender_type)
+        .bind(message.sender.id)
+        .bind(message.content)
+        .bind(sqlx::types::Json(message.mentions))
+        .bind(sqlx::types::Json(meta))
+        .bind(created_at)
+        .execute(&mut *tx)
+        .await?;
+        progress(ProgressUpdate {
+            batch: 1,
+            processed: index + 1,
+            total,
+        });
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Import one session folder written by [`export_session_archive`], keeping the
+/// original session and message IDs. Runs in a single transaction so a bad
+/// archive never leaves a partial session behind.
+pub async fn import_session_archive(
+    pool: &SqlitePool,
+    entry: &SessionArchiveManifestEntry,
+    archive_dir: &Path,
+) -> Result<ChatSession, ChatServiceError> {
+    import_session_archive_with_progress(pool, entry, archive_dir, no_progress).await
+}
+
+/// [`import_session_archive`], reporting inserted messages to `progress`.
+pub async fn import_session_archive_with_progress(
+    pool: &SqlitePool,
+    entry: &SessionArchiveManifestEntry,
+    archive_dir: &Path,
+    progress: impl Fn(ProgressUpdate),
+) -> Result<ChatSession, ChatServiceError> {
+    let root = asset_dir();
+    read_session_archive(pool, entry, archive_dir, &root, &progress).await
+}
+
+/// [`import_session_archive`] with archived attachments restored under
+/// `attachments_root` instead of the asset dir.
+pub async fn import_session_archive_with_attachment_root(
+    pool: &SqlitePool,
+    entry: &SessionArchiveManifestEntry,
+    archive_dir: &Path,
+    attachments_root: &Path,
+) -> Result<ChatSession, ChatServiceError> {
+    read_session_archive(pool, entry, archive_dir, attachments_root, &no_progress).await
+}
+
+async fn read_session_archive(
+    pool: &SqlitePool,
+    entry: &SessionArchiveManifestEntry,
+    archive_dir: &Path,
+    attachments_root: &Path,
+    progress: &dyn Fn(ProgressUpdate),
+) -> Result<ChatSession, ChatServiceError> {
+    let messages =
+        read_archive_messages(archive_dir, &format!("session {}", entry.session_id)).await?;
+    let summary_text = read_archive_summary(archive_dir).await?;
+
+    insert_archived_session(
+        pool,
+        ArchiveImportTarget {
+            session_id: entry.session_id,
+            title: entry.title.as_deref(),
+            status: entry.status.clone(),
+            summary_text,
+            fresh_message_ids: false,
+            attachments: Some(ArchiveAttachmentSource {
+                archive_dir,
+                attachments_root,
+            }),
+        },
+        messages,
+        progress,
+    )
+    .await
+}
+
+/// Re-hydrate an exported session into a new active session so the
+/// conversation can carry on. Messages get new IDs, so this works whether or
+/// not the original session still exists. Returns the new session ID.
+pub async fn continue_session_from_archive(
+    pool: &SqlitePool,
+    archive_dir: &Path,
+) -> Result<Uuid, ChatServiceError> {
+    continue_session_from_archive_with_attachment_root(pool, archive_dir, &asset_dir()).await
+}
+
+async fn continue_session_from_archive_with_attachment_root(
+    pool: &SqlitePool,
+    archive_dir: &Path,
+    attachments_root: &Path,
+) -> Result<Uuid, ChatServiceError> {
+    let messages = read_archive_messages(archive_dir, &archive_dir.display().to_string()).await?;
+    let summary_text = read_archive_summary(archive_dir).await?;
+    let session_id = Uuid::new_v4();
+
+    insert_archived_session(
+        pool,
+        ArchiveImportTarget {
+            session_id,
+            title: None,
+            status: ChatSessionStatus::Active,
+            summary_text,
+            fresh_message_ids: true,
+            attachments: Some(ArchiveAttachmentSource {
+                archive_dir,
+                attachments_root,
+            }),
+        },
+        messages,
+        &no_progress,
+    )
+    .await?;
+
+    tracing::info!(
+        session_id = %session_id,
+        archive_dir = %archive_dir.display(),
+        "Continued chat session from archive"
+    );
+    Ok(session_id)
+}
+
+/// Fork a session into a new active session holding copies of its messages up
+/// to and including `up_to_message_id`. Messages get new IDs; order, sender
+/// and meta are kept, while pending and cancelled reply placeholders are left
+/// out. The session's agents and participants are copied too. The original
+/// session is not modified. Returns the new session ID.
+pub async fn clone_session(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    up_to_message_id: Uuid,
+) -> Result<Uuid, ChatServiceError> {
+    clone_session_with_store(
+        pool,
+        session_id,
+        up_to_message_id,
+        &FsHistoryStore::default(),
+    )
+    .await
+}
+
+async fn clone_session_with_store(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    up_to_message_id: Uuid,
+    history_store: &dyn HistoryStore,
+) -> Result<Uuid, ChatServiceError> {
+    let source = ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+    let mut messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    let cut = messages
+        .iter()
+        .position(|message| message.id == up_to_message_id)
+        .ok_or_else(|| {
+            ChatServiceError::Validation(format!(
+                "message {up_to_message_id} does not belong to session {session_id}"
+            ))
+        })?;
+    messages.truncate(cut + 1);
+    let cutoff = messages[cut].created_at;
+    // A placeholder's reply never lands in the fork, so drop it.
+    messages.retain(|message| {
+        !is_pending_reply(&message.meta.0) && !is_cancelled_reply(&message.meta.0)
+    });
+
+    let fork_note = format!("Forked from session {session_id} at message {up_to_message_id}.");
+    let summary_text = match source.summary_text.as_deref() {
+        Some(summary) if !summary.trim().is_empty() => format!("{fork_note}\n\n{summary}"),
+        _ => fork_note,
+    };
+    let title = source
+        .title
+        .as_deref()
+        .map(|title| format!("{title} (fork)"));
+    let clone_id = Uuid::new_v4();
+
+    insert_archived_session(
+        pool,
+        ArchiveImportTarget {
+            session_id: clone_id,
+            title: title.as_deref(),
+            status: ChatSessionStatus::Active,
+            summary_text: Some(summary_text),
+            fresh_message_ids: true,
+            attachments: None,
+        },
+        messages.into_iter().map(ExportedMessage::from).collect(),
+        &no_progress,
+    )
+    .await?;
+    copy_session_members(pool, session_id, clone_id).await?;
+
+    if let Err(err) = copy_history_file(history_store, session_id, clone_id, cutoff).await {
+        tracing::warn!(
+            session_id = %session_id,
+            clone_id = %clone_id,
+            error = %err,
+            "Failed to copy chat history file to forked session"
+        );
+    }
+
+    tracing::info!(
+        session_id = %session_id,
+        clone_id = %clone_id,
+        up_to_message_id = %up_to_message_id,
+        "Forked chat session"
+    );
+    Ok(clone_id)
+}
+
+/// Move every message of `from_session_id` into `into_session_id` and archive
+/// `from`. Messages keep their IDs and timestamps, so they interleave with
+/// `into`'s history by time. Split history files are merged the same way and
+/// `from`'s files are removed. Returns the number of messages moved.
+pub async fn merge_sessions(
+    pool: &SqlitePool,
+    into_session_id: Uuid,
+    from_session_id: Uuid,
+) -> Result<usize, ChatServiceError> {
+    merge_sessions_with_store(
+        pool,
+        into_session_id,
+        from_session_id,
+        &FsHistoryStore::default(),
+    )
+    .await
+}
+
+async fn merge_sessions_with_store(
+    pool: &SqlitePool,
+    into_session_id: Uuid,
+    from_session_id: Uuid,
+    history_store: &dyn HistoryStore,
+) -> Result<usize, ChatServiceError> {
+    if into_session_id == from_session_id {
+        return Err(ChatServiceError::Validation(
+            "can't merge a chat session into itself".to_string(),
+        ));
+    }
+    ensure_session_active(pool, into_session_id).await?;
+    let from = ChatSession::find_by_id(pool, from_session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+
+    let mut tx = pool.begin().await?;
+    let moved = sqlx::query("UPDATE chat_messages SET session_id = ?1 WHERE session_id = ?2")
+        .bind(into_session_id)
+        .bind(from_session_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected() as usize;
+    ChatSession::touch_tx(&mut *tx, into_session_id).await?;
+    tx.commit().await?;
+    COMPRESSION_RESULT_CACHE.remove(&into_session_id);
+    COMPRESSION_RESULT_CACHE.remove(&from_session_id);
+
+    if let Err(err) = merge_split_history(history_store, into_session_id, from_session_id).await {
+        tracing::warn!(
+            into_session_id = %into_session_id,
+            from_session_id = %from_session_id,
+            error = %err,
+            "Failed to merge chat history files"
+        );
+    }
+
+    if from.status != ChatSessionStatus::Archived {
+        ChatSession::update(
+            pool,
+            from_session_id,
+            &UpdateChatSession {
+                title: None,
+                status: Some(ChatSessionStatus::Archived),
+                summary_text: Some(format!("Merged into session {into_session_id}.")),
+                archive_ref: None,
+            },
+        )
+        .await?;
+    }
+
+    tracing::info!(
+        into_session_id = %into_session_id,
+        from_session_id = %from_session_id,
+        moved,
+        "Merged chat sessions"
+    );
+    Ok(moved)
+}
+
+/// Fold `from`'s split history file into `into`'s, ordered by timestamp, then
+/// delete `from`'s history files.
+async fn merge_split_history(
+    store: &dyn HistoryStore,
+    into: Uuid,
+    from: Uuid,
+) -> Result<(), ChatHistoryFileError> {
+    if let Some(from_history) = store.read(from, HistoryFileKind::Split).await? {
+        let mut messages = store
+            .read(into, HistoryFileKind::Split)
+            .await?
+            .map(|history| history.messages)
+            .unwrap_or_default();
+        messages.extend(from_history.messages);
+        // Stable; entries without a parseable timestamp sort first.
+        messages
+            .sort_by_key(|message| chrono::DateTime::parse_from_rfc3339(&message.timestamp).ok());
+        store
+            .write(
+                HistoryFileKind::Split,
+                &build_history_file(into, &messages, false, None),
+            )
+            .await?;
+    }
+    store.delete(from).await
+}
+
+/// Mark a session as a template (or back to a normal session). Templates keep
+/// their messages and team as seeds for [`create_session_from_template`] and
+/// reject new messages.
+pub async fn set_session_template(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    is_template: bool,
+) -> Result<(), ChatServiceError> {
+    match SessionMeta::modify(pool, session_id, |meta| meta.set_template(is_template)).await {
+        Ok(_) => Ok(()),
+        Err(sqlx::Error::RowNotFound) => Err(ChatServiceError::SessionNotFound),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Start a new active session from a template: its messages are copied with
+/// new IDs (re-timed to start now, keeping their spacing) and its agents and
+/// participants are added to the new session. Returns the new session ID.
+pub async fn create_session_from_template(
+    pool: &SqlitePool,
+    template_session_id: Uuid,
+) -> Result<Uuid, ChatServiceError> {
+    let template = ChatSession::find_by_id(pool, template_session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+    let template_meta = SessionMeta::load(pool, template_session_id).await?;
+    if !template_meta.is_template() {
+        return Err(ChatServiceError::Validation(format!(
+            "chat session {template_session_id} is not a template"
+        )));
+    }
+
+    let messages = ChatMessage::find_by_session_id(pool, template_session_id, None).await?;
+    let offset = messages.first().map_or(chrono::Duration::zero(), |first| {
+        Utc::now() - first.created_at
+    });
+    let session_id = Uuid::new_v4();
+    insert_archived_session(
+        pool,
+        ArchiveImportTarget {
+            session_id,
+            title: template.title.as_deref(),
+            status: ChatSessionStatus::Active,
+            summary_text: None,
+            fresh_message_ids: true,
+            attachments: None,
+        },
+        messages
+            .into_iter()
+            .map(|mut message| {
+                message.created_at += offset;
+                ExportedMessage::from(message)
+            })
+            .collect(),
+        &no_progress,
+    )
+    .await?;
+
+    copy_session_members(pool, template_session_id, session_id).await?;
+
+    tracing::info!(
+        session_id = %session_id,
+        template_session_id = %template_session_id,
+        "Created chat session from template"
+    );
+    Ok(session_id)
+}
+
+/// Give session `to` the agents (with their workspaces) and participant list
+/// of session `from`.
+async fn copy_session_members(
+    pool: &SqlitePool,
+    from: Uuid,
+    to: Uuid,
+) -> Result<(), ChatServiceError> {
+    for member in ChatSessionAgent::find_all_for_session(pool, from).await? {
+        ChatSessionAgent::create(
+            pool,
+            &CreateChatSessionAgent {
+                session_id: to,
+                agent_id: member.agent_id,
+                workspace_path: member.workspace_path,
+            },
+            Uuid::new_v4(),
+        )
+        .await?;
+    }
+    let participants = SessionMeta::load(pool, from).await?.participants().to_vec();
+    if !participants.is_empty() {
+        SessionMeta::modify(pool, to, |meta| meta.set_participants(participants)).await?;
+    }
+    Ok(())
+}
+
+/// Copy the split history file of `from` to `to`, dropping entries newer than
+/// `cutoff` so the fork doesn't inherit messages past the fork point.
+async fn copy_history_file(
+    store: &dyn HistoryStore,
+    from: Uuid,
+    to: Uuid,
+    cutoff: chrono::DateTime<Utc>,
+) -> Result<(), ChatHistoryFileError> {
+    let Some(history) = store.read(from, HistoryFileKind::Split).await? else {
+        return Ok(());
+    };
+    let messages: Vec<SimplifiedMessage> = history
+        .messages
+        .into_iter()
+        .filter(|message| {
+            chrono::DateTime::parse_from_rfc3339(&message.timestamp)
+                .map_or(true, |timestamp| timestamp <= cutoff)
+        })
+        .collect();
+    if messages.is_empty() {
+        return Ok(());
+    }
+    store
+        .write(
+            HistoryFileKind::Split,
+            &build_history_file(to, &messages, false, None),
+        )
+        .await
+}
+
+/// Import every session listed in the manifest written by
+/// [`export_all_sessions`]. Sessions that already exist are skipped, so
+/// re-running an import is safe.
+pub async fn import_all_sessions(
+    pool: &SqlitePool,
+    archive_root: &Path,
+) -> Result<SessionImportReport, ChatServiceError> {
+    let manifest_json = fs::read_to_string(archive_root.join(SESSION_ARCHIVE_MANIFEST)).await?;
+    let manifest: SessionArchiveManifest = serde_json::from_str(&manifest_json).map_err(|err| {
+        ChatServiceError::Validation(format!("invalid session archive manifest: {err}"))
+    })?;
+
+    let mut report = SessionImportReport::default();
+    for entry in &manifest.sessions {
+        if ChatSession::find_by_id(pool, entry.session_id)
+            .await?
+            .is_some()
+        {
+            report.skipped.push(entry.session_id);
+            continue;
+        }
+
+        // Folders are single path segments; anything else could point outside the archive.
+        let mut components = Path::new(&entry.folder).components();
+        let is_plain_folder = matches!(
+            (components.next(), components.next()),
+            (Some(std::path::Component::Normal(_)), None)
+        );
+        let result = if is_plain_folder {
+            import_session_archive(pool, entry, &archive_root.join(&entry.folder)).await
+        } else {
+            Err(ChatServiceError::Validation(format!(
+                "manifest folder escapes the archive root: {}",
+                entry.folder
+            )))
+        };
+        match result {
+            Ok(_) => report.imported.push(entry.session_id),
+            Err(err) => {
+                tracing::warn!(
+                    session_id = %entry.session_id,
+                    error = %err,
+                    "Failed to import chat session; continuing with remaining sessions"
+                );
+                report.failed.push(SessionArchiveFailure {
+                    session_id: entry.session_id,
+                    error: err.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Outcome of [`prune_sessions`].
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PruneReport {
+    pub archived: usize,
+    pub deleted: usize,
+    /// Stale sessions kept because they are pinned.
+    pub skipped_pinned: usize,
+    pub failed: Vec<SessionArchiveFailure>,
+}
+
+/// Archive and delete sessions with no activity in the last `older_than_days`
+/// days, writing archives under [`default_chat_archive_root`].
+pub async fn prune_sessions(
+    pool: &SqlitePool,
+    older_than_days: u32,
+    keep_pinned: bool,
+) -> Result<PruneReport, ChatServiceError> {
+    prune_sessions_into(
+        pool,
+        &default_chat_archive_root(),
+        older_than_days,
+        keep_pinned,
+    )
+    .await
+}
+
+/// Like [`prune_sessions`], writing archives under `chat_root`.
+pub async fn prune_sessions_into(
+    pool: &SqlitePool,
+    chat_root: &Path,
+    older_than_days: u32,
+    keep_pinned: bool,
+) -> Result<PruneReport, ChatServiceError> {
+    let cutoff = Utc::now() - chrono::Duration::days(i64::from(older_than_days));
+    let mut report = PruneReport::default();
+
+    for session in ChatSession::find_all(pool, None).await? {
+        if session.updated_at >= cutoff {
+            continue;
+        }
+        if keep_pinned {
+            let meta = SessionMeta::load(pool, session.id).await?;
+            if meta.is_pinned() {
+                report.skipped_pinned += 1;
+                continue;
+            }
+        }
+
+        let archive_dir = session_archive_dir(chat_root, session.id);
+        if let Err(err) = export_session_archive(pool, &session, &archive_dir).await {
+            tracing::warn!(
+                session_id = %session.id,
+                error = %err,
+                "Failed to archive stale chat session; keeping it"
+            );
+            report.failed.push(SessionArchiveFailure {
+                session_id: session.id,
+                error: err.to_string(),
+            });
+            continue;
+        }
+        report.archived += 1;
+
+        if ChatSession::delete(pool, session.id).await? > 0 {
+            report.deleted += 1;
+        }
+        if let Err(err) = delete_chat_history(session.id).await {
+            tracing::warn!(
+                session_id = %session.id,
+                error = %err,
+                "Failed to delete chat history files for pruned session"
+            );
+        }
+        COMPRESSION_RESULT_CACHE.remove(&session.id);
+    }
+
+    tracing::info!(
+        archived = report.archived,
+        deleted = report.deleted,
+        skipped_pinned = report.skipped_pinned,
+        failed = report.failed.len(),
+        "Pruned stale chat sessions"
+    );
+    Ok(report)
+}
+
+/// Every attachment path, resolved against `attachments_dir`, that some
+/// message meta still references: stored rows, plus the rows the session size
+/// cap moved into split history files.
+async fn referenced_attachment_paths(
+    pool: &SqlitePool,
+    attachments_dir: &Path,
+    history_store: &dyn HistoryStore,
+) -> Result<HashSet<PathBuf>, ChatServiceError> {
+    let rows = sqlx::query("SELECT meta FROM chat_messages")
+        .fetch_all(pool)
+        .await?;
+    let mut referenced = HashSet::new();
+    for row in rows {
+        let sqlx::types::Json(meta): sqlx::types::Json<Value> = row.try_get("meta")?;
+        for attachment in extract_attachments(&meta) {
+            referenced.insert(attachments_dir.join(&attachment.relative_path));
+        }
+    }
+
+    let session_ids: Vec<Uuid> = sqlx::query_scalar("SELECT id FROM chat_sessions")
+        .fetch_all(pool)
+        .await?;
+    for session_id in session_ids {
+        for record in split_file_records(history_store, session_id).await? {
+            for attachment in extract_attachments(&record.meta.0) {
+                referenced.insert(attachments_dir.join(&attachment.relative_path));
+            }
+        }
+    }
+    Ok(referenced)
+}
+
+/// Full rows kept in a session's split history file by
+/// [`trim_session_messages`].
+async fn split_file_records(
+    history_store: &dyn HistoryStore,
+    session_id: Uuid,
+) -> Result<Vec<ChatMessage>, ChatServiceError> {
+    let split = history_store
+        .read(session_id, HistoryFileKind::Split)
+        .await
+        .map_err(|e| {
+            ChatServiceError::Io(std::io::Error::other(format!(
+                "Failed to read split file: {}",
+                e
+            )))
+        })?;
+    Ok(split
+        .into_iter()
+        .flat_map(|history| history.messages)
+        .filter_map(|message| message.record)
+        .collect())
+}
+
+/// Attachment files under `attachments_dir` that no message meta references.
+///
+/// `attachments_dir` is the directory `relative_path` values resolve against
+/// (the asset dir in production). Only `chat/session_*/attachments` folders are
+/// scanned, so archives and history files next to them are never reported.
+pub async fn find_orphaned_attachments(
+    pool: &SqlitePool,
+    attachments_dir: &Path,
+) -> Result<Vec<PathBuf>, ChatServiceError> {
+    find_orphaned_attachments_with_store(pool, attachments_dir, &FsHistoryStore::default()).await
+}
+
+async fn find_orphaned_attachments_with_store(
+    pool: &SqlitePool,
+    attachments_dir: &Path,
+    history_store: &dyn HistoryStore,
+) -> Result<Vec<PathBuf>, ChatServiceError> {
+    let referenced = referenced_attachment_paths(pool, attachments_dir, history_store).await?;
+
+    let chat_dir = attachments_dir.join("chat");
+    if !chat_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut pending = Vec::new();
+    let mut sessions = fs::read_dir(&chat_dir).await?;
+    while let Some(entry) = sessions.next_entry().await? {
+        let is_session_dir = entry.file_name().to_string_lossy().starts_with("session_");
+        let attachments = entry.path().join("attachments");
+        if is_session_dir && attachments.is_dir() {
+            pending.push(attachments);
+        }
+    }
+
+    let mut orphaned = Vec::new();
+    while let Some(dir) = pending.pop() {
+        let mut entries = fs::read_dir(&dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if entry.file_type().await?.is_dir() {
+                pending.push(path);
+            } else if !referenced.contains(&path) {
+                orphaned.push(path);
+            }
+        }
+    }
+    orphaned.sort();
+    Ok(orphaned)
+}
+
+/// Delete the files reported by [`find_orphaned_attachments`]. Files that
+/// can't be removed are logged and left in place; returns the deleted paths.
+pub async fn gc_orphaned_attachments(
+    pool: &SqlitePool,
+    attachments_dir: &Path,
+) -> Result<Vec<PathBuf>, ChatServiceError> {
+    let mut deleted = Vec::new();
+    for path in find_orphaned_attachments(pool, attachments_dir).await? {
+        match fs::remove_file(&path).await {
+            Ok(()) => deleted.push(path),
+            Err(err) => tracing::warn!(
+                path = %path.display(),
+                error = %err,
+                "Failed to delete orphaned chat attachment"
+            ),
+        }
+    }
+    if !deleted.is_empty() {
+        tracing::info!(deleted = deleted.len(), "Deleted orphaned chat attachments");
+    }
+    Ok(deleted)
+}
+
+/// What [`purge_session`] removed.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PurgeReport {
+    pub messages_deleted: u64,
+    pub session_deleted: bool,
+    /// History files (main and split) that existed and were deleted.
+    pub history_files_deleted: usize,
+    pub attachments_deleted: Vec<PathBuf>,
+}
+
+/// Remove a session completely: its messages and session row (in one
+/// transaction; agents, reads and other per-session rows cascade), its history
+/// files, and its attachments under `attachments_dir`. Attachments are the
+/// files referenced by its messages plus anything left in the session's
+/// `chat/session_{id}/attachments` folder, minus any file a message in another
+/// session still references (clones and continuations share attachment
+/// files). File cleanup runs after the commit and is best-effort: failures are
+/// logged, and the report lists what was actually removed.
+pub async fn purge_session(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    attachments_dir: &Path,
+) -> Result<PurgeReport, ChatServiceError> {
+    purge_session_with_store(
+        pool,
+        session_id,
+        attachments_dir,
+        &FsHistoryStore::default(),
+    )
+    .await
+}
+
+async fn purge_session_with_store(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    attachments_dir: &Path,
+    history_store: &dyn HistoryStore,
+) -> Result<PurgeReport, ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+
+    let mut attachment_files = Vec::new();
+    let mut messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    messages.extend(split_file_records(history_store, session_id).await?);
+    for message in messages {
+        for attachment in extract_attachments(&message.meta.0) {
+            let relative = Path::new(&attachment.relative_path);
+            let is_safe = !relative.is_absolute()
+                && relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)));
+            if is_safe {
+                attachment_files.push(attachments_dir.join(relative));
+            }
+        }
+    }
+    let session_attachments = attachments_dir
+        .join("chat")
+        .join(format!("session_{session_id}"))
+        .join("attachments");
+
+    let mut report = PurgeReport::default();
+    let mut tx = pool.begin().await?;
+    report.messages_deleted = sqlx::query("DELETE FROM chat_messages WHERE session_id = ?1")
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    report.session_deleted = sqlx::query("DELETE FROM chat_sessions WHERE id = ?1")
+        .bind(session_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected()
+        > 0;
+    tx.commit().await?;
+    COMPRESSION_RESULT_CACHE.remove(&session_id);
+
+    for kind in [HistoryFileKind::Main, HistoryFileKind::Split] {
+        if history_store
+            .exists(session_id, kind)
+            .await
+            .unwrap_or(false)
+        {
+            report.history_files_deleted += 1;
+        }
+    }
+    if let Err(err) = history_store.delete(session_id).await {
+        report.history_files_deleted = 0;
+        tracing::warn!(
+            session_id = %session_id,
+            error = %err,
+            "Failed to delete chat history files for purged session"
+        );
+    }
+
+    let mut session_dirs = Vec::new();
+    let mut pending = vec![session_attachments];
+    while let Some(dir) = pending.pop() {
+        let Ok(mut entries) = fs::read_dir(&dir).await else {
+            continue;
+        };
+        session_dirs.push(dir.clone());
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.file_type().await?.is_dir() {
+                pending.push(entry.path());
+            } else {
+                attachment_files.push(entry.path());
+            }
+        }
+    }
+    let still_referenced =
+        referenced_attachment_paths(pool, attachments_dir, history_store).await?;
+    attachment_files.retain(|path| !still_referenced.contains(path));
+    attachment_files.sort();
+    attachment_files.dedup();
+    for path in attachment_files {
+        match fs::remove_file(&path).await {
+            Ok(()) => report.attachments_deleted.push(path),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => tracing::warn!(
+                session_id = %session_id,
+                path = %path.display(),
+                error = %err,
+                "Failed to delete chat attachment for purged session"
+            ),
+        }
+    }
+    // Deepest first; folders still holding shared files are left in place.
+    for dir in session_dirs.iter().rev() {
+        let _ = fs::remove_dir(dir).await;
+    }
+
+    tracing::info!(
+        session_id = %session_id,
+        messages = report.messages_deleted,
+        history_files = report.history_files_deleted,
+        attachments = report.attachments_deleted.len(),
+        "Purged chat session"
+    );
+    Ok(report)
+}
+
+// ==========================================
+// New Token-Based Compression System
+// ==========================================
+
+use super::chat_history_file::{
+    ChatHistoryFileError, FsHistoryStore, HistoryFileKind, HistoryStore, SimplifiedMessage,
+    append_to_split_file, build_history_file, chat_history_dir, delete_chat_history,
+    estimate_string_tokens, estimate_token_count,
+};
+
+/// Convert ChatMessage to SimplifiedMessage format (sender + content only)
+pub fn to_simplified_message(
+    message: &ChatMessage,
+    agent_map: &HashMap<Uuid, String>,
+) -> SimplifiedMessage {
+    let sender_handle = message
+        .meta
+        .0
+        .get("sender_handle")
+        .and_then(|value| value.as_str());
+    let sender_name = message.sender_id.and_then(|id| agent_map.get(&id).cloned());
+
+    let sender = match message.sender_type {
+        ChatSenderType::User => format!("user:{}", sender_handle.unwrap_or("user")),
+        ChatSenderType::Agent => format!(
+            "agent:{}",
+            sender_name.unwrap_or_else(|| "agent".to_string())
+        ),
+        ChatSenderType::System => match extract_system_actor(&message.meta.0) {
+            Some(actor) => format!("system:{actor}"),
+            None => "system".to_string(),
+        },
+    };
+
+    SimplifiedMessage {
+        sender,
+        content: context_content(&message.content, &message.meta.0),
+        timestamp: message.created_at.to_rfc3339(),
+        record: None,
+    }
+}
+
+/// Convert all messages in a session to SimplifiedMessage format
+#[tracing::instrument(level = "debug", skip_all, fields(session_id = %session_id))]
+pub async fn build_simplified_messages(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Vec<SimplifiedMessage>, ChatServiceError> {
+    let messages = ChatMessage::find_by_session_id(pool, session_id, None).await?;
+    let agent_map = build_agent_map(pool).await?;
+
+    Ok(messages
+        .iter()
+        .map(|msg| to_simplified_message(msg, &agent_map))
+        .collect())
+}
+
+// ==========================================
+// Session Summary
+// ==========================================
+
+/// Longest snippet of a single message quoted in a heuristic summary (bytes).
+const SUMMARY_SNIPPET_MAX_LEN: usize = 280;
+/// Phrases that mark a message as recording a decision.
+const SUMMARY_DECISION_MARKERS: &[&str] = &[
+    "decided",
+    "decision",
+    "agreed",
+    "conclusion",
+    "we will",
+    "final",
+];
+
+/// Produces an LLM-written summary of a session transcript.
+/// Returns None when no model is available or the call fails.
+#[async_trait]
+pub trait SessionSummarizer: Send + Sync {
+    async fn summarize(&self, session_id: Uuid, transcript: &[SimplifiedMessage])
+    -> Option<String>;
+}
+
+/// Summarizes with the agents that are members of the session, using the same
+/// executor path as context compression.
+pub struct AgentSessionSummarizer {
+    pool: SqlitePool,
+    workspace_path: PathBuf,
+}
+
+impl AgentSessionSummarizer {
+    pub fn new(pool: SqlitePool, workspace_path: PathBuf) -> Self {
+        Self {
+            pool,
+            workspace_path,
+        }
+    }
+}
+
+#[async_trait]
+impl SessionSummarizer for AgentSessionSummarizer {
+    async fn summarize(
+        &self,
+        session_id: Uuid,
+        transcript: &[SimplifiedMessage],
+    ) -> Option<String> {
+        let session_agents = ChatSessionAgent::find_all_for_session(&self.pool, session_id)
+            .await
+            .ok()?;
+        try_summarize_with_agents(
+            &self.pool,
+            session_id,
+            &session_agents,
+            transcript,
+            &self.workspace_path,
+        )
+        .await
+    }
+}
+
+fn summary_snippet(content: &str) -> String {
+    let line = content.split_whitespace().collect::<Vec<_>>().join(" ");
+    let snippet = utils::text::truncate_to_char_boundary(&line, SUMMARY_SNIPPET_MAX_LEN);
+    if snippet.len() < line.len() {
+        format!("{snippet}...")
+    } else {
+        snippet.to_string()
+    }
+}
+
+/// Summary built without a model: the first user request, the agents that
+/// took part, and the most recent message that reads like a decision.
+fn build_heuristic_summary(messages: &[SimplifiedMessage]) -> String {
+    let first_request = messages
+        .iter()
+        .find(|msg| msg.sender.starts_with("user:") && !msg.content.trim().is_empty())
+        .map(|msg| summary_snippet(&msg.content));
+
+    let mut participants: Vec<&str> = Vec::new();
+    for msg in messages {
+        if let Some(name) = msg.sender.strip_prefix("agent:")
+            && !participants.contains(&name)
+        {
+            participants.push(name);
+        }
+    }
+
+    let last_decision = messages
+        .iter()
+        .rev()
+        .find(|msg| {
+            let content = msg.content.to_lowercase();
+            SUMMARY_DECISION_MARKERS
+                .iter()
+                .any(|marker| content.contains(marker))
+        })
+        .or_else(|| {
+            messages
+                .iter()
+                .rev()
+                .find(|msg| msg.sender.starts_with("agent:"))
+        })
+        .or(messages.last())
+        .map(|msg| format!("{} ({})", summary_snippet(&msg.content), msg.sender));
+
+    let participants = if participants.is_empty() {
+        "none".to_string()
+    } else {
+        participants.join(", ")
+    };
+
+    format!(
+        "- Request: {}\n- Participants: {}\n- Last decision: {}\n",
+        first_request.unwrap_or_else(|| "none".to_string()),
+        participants,
+        last_decision.unwrap_or_else(|| "none".to_string()),
+    )
+}
+
+/// Generate a summary for a session and store it as the session's `summary_text`.
+///
+/// Uses the session's agents to write the summary when possible and falls back
+/// to a heuristic summary otherwise.
+pub async fn generate_session_summary(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    max_tokens: u32,
+) -> Result<String, ChatServiceError> {
+    let summarizer = AgentSessionSummarizer::new(pool.clone(), PathBuf::from("."));
+    generate_session_summary_with(pool, session_id, max_tokens, Some(&summarizer)).await
+}
+
+/// Like [`generate_session_summary`], with an explicit summarizer.
+/// `max_tokens` bounds the transcript handed to the summarizer; the most
+/// recent messages are kept when the session is larger.
+pub async fn generate_session_summary_with(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    max_tokens: u32,
+    summarizer: Option<&dyn SessionSummarizer>,
+) -> Result<String, ChatServiceError> {
+    ChatSession::find_by_id(pool, session_id)
+        .await?
+        .ok_or(ChatServiceError::SessionNotFound)?;
+
+    let messages = build_simplified_messages(pool, session_id).await?;
+    if messages.is_empty() {
+        return Err(ChatServiceError::Validation(
+            "cannot summarize a session without messages".to_string(),
+        ));
+    }
+
+    let (transcript, _, _) = limit_summary_input_messages(&messages, max_tokens.max(1));
+    let llm_summary = match summarizer {
+        Some(summarizer) => summarizer
+            .summarize(session_id, &transcript)
+            .await
+            .filter(|summary| !summary.trim().is_empty()),
+        None => None,
+    };
+    let summary = llm_summary.unwrap_or_else(|| {
+        tracing::debug!(
+            session_id = %session_id,
+            "No summarizer produced a session summary; using heuristic summary"
+        );
+        build_heuristic_summary(&messages)
+    });
+
+    ChatSession::update(
+        pool,
+        session_id,
+        &UpdateChatSession {
+            title: None,
+            status: None,
+            summary_text: Some(summary.clone()),
+            archive_ref: None,
+        },
+    )
+    .await?;
+
+    Ok(summary)
+}
+
+/// Build the prompt for AI summarization
+fn build_summarization_prompt(messages_to_compress: &[SimplifiedMessage]) -> String {
+    let mut prompt = String::from(
+        "Summarize the following chat history while preserving key tasks, decisions, \
+constraints, and references. Keep the summary concise (under 500 words).\n\
+Return only the summary body. Do not ask follow-up questions. Do not run any tools or shell commands.\n\nMessages:\n",
+    );
+
+    for msg in messages_to_compress {
+        prompt.push_str(&format!("{}: {}\n", msg.sender, msg.content));
+    }
+
+    prompt
+}
+
+fn limit_summary_input_messages(
+    messages_to_compress: &[SimplifiedMessage],
+    token_limit: u32,
+) -> (Vec<SimplifiedMessage>, u32, u32) {
+    let total_tokens = estimate_token_count(messages_to_compress);
+    if messages_to_compress.is_empty() || total_tokens <= token_limit {
+        return (messages_to_compress.to_vec(), total_tokens, total_tokens);
+    }
+
+    // Keep the most recent part of the compressed segment under token budget.
+    let mut selected_rev = Vec::new();
+    let mut selected_tokens = 0u32;
+    for message in messages_to_compress.iter().rev() {
+        let message_tokens = estimate_token_count(std::slice::from_ref(message)).max(1);
+        if !selected_rev.is_empty() && selected_tokens.saturating_add(message_tokens) > token_limit
+        {
+            break;
+        }
+        selected_rev.push(message.clone());
+        selected_tokens = selected_tokens.saturating_add(message_tokens);
+        if selected_tokens >= token_limit {
+            break;
+        }
+    }
+
+    if selected_rev.is_empty() {
+        selected_rev.push(
+            messages_to_compress
+                .last()
+                .expect("messages_to_compress must be non-empty")
+                .clone(),
+        );
+        selected_tokens = estimate_token_count(&selected_rev);
+    }
+
+    selected_rev.reverse();
+    (selected_rev, total_tokens, selected_tokens)
+}
+
+fn all_agents_running(session_agents: &[ChatSessionAgent]) -> bool {
+    !session_agents.is_empty()
+        && session_agents
+            .iter()
+            .all(|agent| agent.state == ChatSessionAgentState::Running)
+}
+
+fn summary_agent_priority(state: ChatSessionAgentState) -> u8 {
+    match state {
+        ChatSessionAgentState::Idle => 0,
+        ChatSessionAgentState::WaitingApproval => 1,
+        ChatSessionAgentState::Dead => 2,
+        ChatSessionAgentState::Running => 3,
+    }
+}
+
+fn prioritize_summary_agents(session_agents: &[ChatSessionAgent]) -> Vec<ChatSessionAgent> {
+    let mut agents = session_agents.to_vec();
+    agents.sort_by_key(|agent| summary_agent_priority(agent.state.clone()));
+    agents
+}
+
+async fn wait_for_idle_agent_if_needed(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    session_agents: &[ChatSessionAgent],
+) -> Result<Vec<ChatSessionAgent>, ChatServiceError> {
+    if !all_agents_running(session_agents) {
+        return Ok(session_agents.to_vec());
+    }
+
+    // Avoid waiting forever in single-agent sessions where the current run just marked it running.
+    if session_agents.len() == 1 {
+        tracing::debug!(
+            session_id = %session_id,
+            session_agent_id = %session_agents[0].id,
+            "Skipping idle-agent wait for summarization in single-agent running session"
+        );
+        return Ok(session_agents.to_vec());
+    }
+
+    // Do not block the active mention execution path.
+    // When all agents are currently running, summarization should quickly fall back
+    // so normal group chat delivery is not stalled.
+    tracing::info!(
+        session_id = %session_id,
+        "All session agents are running; skipping idle wait to avoid blocking chat flow"
+    );
+    ChatSessionAgent::find_all_for_session(pool, session_id)
+        .await
+        .map_err(ChatServiceError::from)
+}
+
+/// Try to summarize messages using available AI agents
+/// Returns Some(summary) if any agent succeeds, None if all fail
+async fn try_summarize_with_agents(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    session_agents: &[ChatSessionAgent],
+    messages_to_compress: &[SimplifiedMessage],
+    workspace_path: &Path,
+) -> Option<String> {
+    let (summary_input_messages, input_tokens_before_limit, input_tokens_after_limit) =
+        limit_summary_input_messages(messages_to_compress, SUMMARY_INPUT_TOKEN_LIMIT);
+    if summary_input_messages.len() < messages_to_compress.len() {
+        tracing::warn!(
+            session_id = %session_id,
+            original_messages = messages_to_compress.len(),
+            included_messages = summary_input_messages.len(),
+            original_tokens = input_tokens_before_limit,
+            included_tokens = input_tokens_after_limit,
+            token_limit = SUMMARY_INPUT_TOKEN_LIMIT,
+            "Summarization input exceeded token limit; truncating to most recent messages"
+        );
+    }
+    let summarize_prompt = build_summarization_prompt(&summary_input_messages);
+    let candidate_agents =
+        match wait_for_idle_agent_if_needed(pool, session_id, session_agents).await {
+            Ok(agents) => agents,
+            Err(err) => {
+                tracing::warn!(
+                    session_id = %session_id,
+                    error = %err,
+                    "Failed to refresh session agents before summarization; using initial snapshot"
+                );
+                session_agents.to_vec()
+            }
+        };
+
+    if all_agents_running(&candidate_agents) {
+        tracing::warn!(
+            session_id = %session_id,
+            "Skipping AI summarization because all agents are still running"
+        );
+        return None;
+    }
+
+    for session_agent in prioritize_summary_agents(&candidate_agents) {
+        // Get the agent details
+        let agent = match ChatAgent::find_by_id(pool, session_agent.agent_id).await {
+            Ok(Some(agent)) => agent,
+            _ => continue,
+        };
+
+        tracing::debug!(
+            "Attempting to summarize with agent: {} ({})",
+            agent.name,
+            agent.id
+        );
+
+        let workspace_override = session_agent.workspace_path.as_deref().map(Path::new);
+        let effective_workspace_path = workspace_override.unwrap_or(workspace_path);
+
+        // Try to call the agent for summarization
+        match call_agent_for_summary(&agent, &summarize_prompt, effective_workspace_path).await {
+            Ok(summary) => {
+                tracing::info!(
+                    session_id = %session_id,
+                    agent = %agent.name,
+                    "AI summarization successful"
+                );
+                return Some(summary);
+            }
+            Err(e) => {
+                tracing::warn!(
+                    session_id = %session_id,
+                    agent = %agent.name,
+                    error = %e,
+                    "Agent failed to summarize, trying next agent"
+                );
+                continue;
+            }
+        }
+    }
+
+    tracing::warn!(
+        session_id = %session_id,
+        "All agents failed to summarize messages"
+    );
+    None
+}
+
+/// Call an agent to generate a summary
+/// This spawns a temporary agent process to summarize messages
+async fn call_agent_for_summary(
+    agent: &ChatAgent,
+    prompt: &str,
+    workspace_path: &Path,
+) -> Result<String, ChatServiceError> {
+    let executor_profile_id = parse_executor_profile_id(agent)?;
+    let mut executor =
+        ExecutorConfigs::get_cached().get_coding_agent_or_default(&executor_profile_id);
+    executor.use_approvals(Arc::new(NoopExecutorApprovalService));
+
+    let repo_context = RepoContext::new(workspace_path.to_path_buf(), Vec::new());
+    let env = ExecutionEnv::new(repo_context, false, String::new());
+    let mut spawned = executor
+        .spawn(workspace_path, prompt, &env)
+        .await
+        .map_err(map_executor_error)?;
+
+    let msg_store = Arc::new(MsgStore::new());
+    spawn_summary_log_forwarders(&mut spawned.child, msg_store.clone())?;
+    executor.normalize_logs(msg_store.clone(), workspace_path);
+
+    let mut failed_by_signal = false;
+    let mut status = None;
+    if let Some(exit_signal) = spawned.exit_signal.take() {
+        // Prefer explicit completion signal from executor, then reap child process.
+        match tokio::time::timeout(SUMMARY_EXECUTION_TIMEOUT, exit_signal).await {
+            Ok(Ok(ExecutorExitResult::Success)) => {}
+            Ok(Ok(ExecutorExitResult::Failure)) => failed_by_signal = true,
+            Ok(Err(err)) => {
+                tracing::warn!(
+                    agent_name = %agent.name,
+                    error = %err,
+                    "Summarization exit signal dropped; falling back to process wait"
+                );
+                status = Some(wait_for_summary_process_exit(&mut spawned, &agent.name).await?);
+            }
+            Err(_) => {
+                terminate_summary_child(&mut spawned).await;
+                return Err(ChatServiceError::Validation(format!(
+                    "AI summarization timed out for agent {} after {} seconds",
+                    agent.name,
+                    SUMMARY_EXECUTION_TIMEOUT.as_secs()
+                )));
+            }
+        }
+
+        if status.is_none() {
+            match tokio::time::timeout(SUMMARY_REAP_TIMEOUT, spawned.child.wait()).await {
+                Ok(Ok(exit_status)) => status = Some(exit_status),
+                Ok(Err(err)) => return Err(ChatServiceError::Io(err)),
+                Err(_) => {
+                    tracing::debug!(
+                        agent_name = %agent.name,
+                        timeout_ms = SUMMARY_REAP_TIMEOUT.as_millis(),
+                        "Summarization process did not exit after completion signal; forcing shutdown"
+                    );
+                    terminate_summary_child(&mut spawned).await;
+                }
+            }
+        }
+    } else {
+        status = Some(wait_for_summary_process_exit(&mut spawned, &agent.name).await?);
+    }
+
+    msg_store.push_finished();
+    tokio::time::sleep(SUMMARY_DRAIN_TIMEOUT).await;
+
+    if failed_by_signal {
+        return Err(ChatServiceError::Validation(format!(
+            "AI summarization process failed for agent {}",
+            agent.name
+        )));
+    }
+
+    if let Some(exit_status) = status
+        && !exit_status.success()
+    {
+        return Err(ChatServiceError::Validation(format!(
+            "AI summarization process failed for agent {}",
+            agent.name
+        )));
+    }
+
+    extract_latest_assistant_from_history(&msg_store.get_history()).ok_or_else(|| {
+        ChatServiceError::Validation(format!(
+            "No assistant summary output generated by agent {}",
+            agent.name
+        ))
+    })
+}
+
+async fn wait_for_summary_process_exit(
+    spawned: &mut SpawnedChild,
+    agent_name: &str,
+) -> Result<std::process::ExitStatus, ChatServiceError> {
+    match tokio::time::timeout(SUMMARY_EXECUTION_TIMEOUT, spawned.child.wait()).await {
+        Ok(Ok(status)) => Ok(status),
+        Ok(Err(err)) => Err(ChatServiceError::Io(err)),
+        Err(_) => {
+            terminate_summary_child(spawned).await;
+            Err(ChatServiceError::Validation(format!(
+                "AI summarization timed out for agent {} after {} seconds",
+                agent_name,
+                SUMMARY_EXECUTION_TIMEOUT.as_secs()
+            )))
+        }
+    }
+}
+
+async fn terminate_summary_child(spawned: &mut SpawnedChild) {
+    if let Some(cancel) = spawned.cancel.take() {
+        cancel.cancel();
+    }
+    let _ = spawned.child.kill().await;
+    let _ = tokio::time::timeout(SUMMARY_KILL_WAIT_TIMEOUT, spawned.child.wait()).await;
+}
+
+fn parse_runner_type(agent: &ChatAgent) -> Result<BaseCodingAgent, ChatServiceError> {
+    let raw = agent.runner_type.trim();
+    let normalized = raw.replace(['-', ' '], "_").to_ascii_uppercase();
+    BaseCodingAgent::from_str(&normalized)
+        .map_err(|_| ChatServiceError::Validation(format!("unknown runner type: {raw}")))
+}
+
+fn extract_executor_profile_variant(tools_enabled: &serde_json::Value) -> Option<String> {
+    let variant = tools_enabled
+        .as_object()
+        .and_then(|value| value.get(EXECUTOR_PROFILE_VARIANT_KEY))
+        .and_then(serde_json::Value::as_str)?
+        .trim();
+    if variant.is_empty() || variant.eq_ignore_ascii_case("DEFAULT") {
+        return None;
+    }
+    Some(canonical_variant_key(variant))
+}
+
+fn parse_executor_profile_id(agent: &ChatAgent) -> Result<ExecutorProfileId, ChatServiceError> {
+    let executor = parse_runner_type(agent)?;
+    let variant = extract_executor_profile_variant(&agent.tools_enabled.0);
+    Ok(match variant {
+        Some(variant) => ExecutorProfileId::with_variant(executor, variant),
+        None => ExecutorProfileId::new(executor),
+    })
+}
+
+fn map_executor_error(err: ExecutorError) -> ChatServiceError {
+    ChatServiceError::Validation(format!("executor error: {err}"))
+}
+
+fn spawn_summary_log_forwarders(
+    child: &mut command_group::AsyncGroupChild,
+    msg_store: Arc<MsgStore>,
+) -> Result<(), ChatServiceError> {
+    let stdout = child.inner().stdout.take().ok_or_else(|| {
+        ChatServiceError::Validation("summarization child missing stdout".to_string())
+    })?;
+    let stderr = child.inner().stderr.take().ok_or_else(|| {
+        ChatServiceError::Validation("summarization child missing stderr".to_string())
+    })?;
+
+    let stdout_store = msg_store.clone();
+    tokio::spawn(async move {
+        let mut stream = ReaderStream::new(stdout);
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(bytes) => {
+                    let text = String::from_utf8_lossy(&bytes).into_owned();
+                    stdout_store.push(LogMsg::Stdout(text));
+                }
+                Err(err) => {
+                    stdout_store.push(LogMsg::Stderr(format!("stdout error: {err}")));
+                }
+            }
+        }
+    });
+
+    let stderr_store = msg_store;
+    tokio::spawn(async move {
+        let mut stream = ReaderStream::new(stderr);
+        while let Some(chunk) = stream.next().await {
+            match chunk {
+                Ok(bytes) => {
+                    let text = String::from_utf8_lossy(&bytes).into_owned();
+                    stderr_store.push(LogMsg::Stderr(text));
+                }
+                Err(err) => {
+                    stderr_store.push(LogMsg::Stderr(format!("stderr error: {err}")));
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+fn extract_latest_assistant_from_history(history: &[LogMsg]) -> Option<String> {
+    let mut assistant_entries: HashMap<usize, String> = HashMap::new();
+
+    for message in history {
+        let LogMsg::JsonPatch(patch) = message else {
+            continue;
+        };
+
+        let Some((index, entry)) = extract_normalized_entry_from_patch(patch) else {
+            continue;
+        };
+
+        if matches!(entry.entry_type, NormalizedEntryType::AssistantMessage) {
+            assistant_entries.insert(index, entry.content);
+        }
+    }
+
+    assistant_entries
+        .into_iter()
+        .max_by_key(|(index, _)| *index)
+        .map(|(_, content)| content.trim().to_string())
+        .filter(|content| !content.is_empty())
+}
+
+fn select_messages_to_compress_by_token(
+    messages: &[SimplifiedMessage],
+    total_tokens: u32,
+    compression_percentage: u8,
+) -> (usize, u32, u32) {
+    if messages.is_empty() {
+        return (0, 0, 0);
+    }
+
+    let target_tokens = ((total_tokens as u64) * (compression_percentage as u64)).div_ceil(100);
+    let target_tokens = (target_tokens as u32).max(1);
+
+    let mut selected_tokens = 0u32;
+    let mut selected_count = 0usize;
+
+    for message in messages {
+        // Use per-message token estimates so we can choose a prefix by token budget.
+        let message_tokens = estimate_token_count(std::slice::from_ref(message)).max(1);
+        selected_tokens = selected_tokens.saturating_add(message_tokens);
+        selected_count += 1;
+        if selected_tokens >= target_tokens {
+            break;
+        }
+    }
+
+    (
+        selected_count.max(1).min(messages.len()),
+        target_tokens,
+        selected_tokens,
+    )
+}
+
+fn calculate_messages_fingerprint(messages: &[SimplifiedMessage]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    for message in messages {
+        hasher.write(message.sender.as_bytes());
+        hasher.write_u8(0x1f);
+        hasher.write(message.content.as_bytes());
+        hasher.write_u8(0x1e);
+        hasher.write(message.timestamp.as_bytes());
+        hasher.write_u8(0x1d);
+    }
+    hasher.finish()
+}
+
+fn compression_type_to_db_value(value: &CompressionType) -> &'static str {
+    match value {
+        CompressionType::None => "none",
+        CompressionType::AiSummarized => "ai_summarized",
+        CompressionType::Truncated => "truncated",
+    }
+}
+
+fn compression_type_from_db_value(value: &str) -> Option<CompressionType> {
+    match value {
+        "none" => Some(CompressionType::None),
+        "ai_summarized" => Some(CompressionType::AiSummarized),
+        "truncated" => Some(CompressionType::Truncated),
+        _ => None,
+    }
+}
+
+fn is_missing_compression_state_table_error(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Database(db_err) => {
+            let message = db_err.message();
+            message.contains("no such table") && message.contains(COMPRESSION_STATE_TABLE)
+        }
+        _ => false,
+    }
+}
+
+fn parse_required_u32(row: &sqlx::sqlite::SqliteRow, field: &str) -> Result<u32, sqlx::Error> {
+    let value: i64 = row.try_get(field)?;
+    u32::try_from(value).map_err(|_| {
+        sqlx::Error::Decode(Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("field {field} is out of range for u32: {value}"),
+        )))
+    })
+}
+
+fn parse_required_usize(row: &sqlx::sqlite::SqliteRow, field: &str) -> Result<usize, sqlx::Error> {
+    let value: i64 = row.try_get(field)?;
+    usize::try_from(value).map_err(|_| {
+        sqlx::Error::Decode(Box::new(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("field {field} is out of range for usize: {value}"),
+        )))
+    })
+}
+
+fn cache_compression_result_in_memory(
+    session_id: Uuid,
+    source_fingerprint: u64,
+    source_message_count: usize,
+    token_threshold: u32,
+    compression_percentage: u8,
+    source_token_count: u32,
+    result: &CompressionResult,
+) -> CompressionCacheEntry {
+    let effective_token_count = estimate_token_count(&result.messages);
+    let entry = CompressionCacheEntry {
+        source_fingerprint,
+        source_message_count,
+        token_threshold,
+        compression_percentage,
+        source_token_count,
+        effective_token_count,
+        result: result.clone(),
+    };
+    COMPRESSION_RESULT_CACHE.insert(session_id, entry.clone());
+    entry
+}
+
+async fn persist_compression_result(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    entry: &CompressionCacheEntry,
+) -> Result<(), ChatServiceError> {
+    let warning_json = entry
+        .result
+        .warning
+        .as_ref()
+        .map(serde_json::to_string)
+        .transpose()
+        .map_err(|err| {
+            ChatServiceError::Validation(format!("failed to serialize compression warning: {err}"))
+        })?;
+    let result_messages_json = serde_json::to_string(&entry.result.messages).map_err(|err| {
+        ChatServiceError::Validation(format!(
+            "failed to serialize compression result messages: {err}"
+        ))
+    })?;
+
+    let query = format!(
+        "INSERT INTO {COMPRESSION_STATE_TABLE} (
+            session_id,
+            source_fingerprint,
+            source_message_count,
+            token_threshold,
+            compression_percentage,
+            source_token_count,
+            effective_token_count,
+            compression_type,
+            warning_json,
+            result_messages_json,
+            updated_at
+        ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, datetime('now', 'subsec'))
+        ON CONFLICT(session_id) DO UPDATE SET
+            source_fingerprint = excluded.source_fingerprint,
+            source_message_count = excluded.source_message_count,
+            token_threshold = excluded.token_threshold,
+            compression_percentage = excluded.compression_percentage,
+            source_token_count = excluded.source_token_count,
+            effective_token_count = excluded.effective_token_count,
+            compression_type = excluded.compression_type,
+            warning_json = excluded.warning_json,
+            result_messages_json = excluded.result_messages_json,
+            updated_at = datetime('now', 'subsec')"
+    );
+
+    let execute_result = sqlx::query(&query)
+        .bind(session_id)
+        .bind(entry.source_fingerprint.to_string())
+        .bind(entry.source_message_count as i64)
+        .bind(entry.token_threshold as i64)
+        .bind(entry.compression_percentage as i64)
+        .bind(entry.source_token_count as i64)
+        .bind(entry.effective_token_count as i64)
+        .bind(compression_type_to_db_value(&entry.result.compression_type))
+        .bind(warning_json)
+        .bind(result_messages_json)
+        .execute(pool)
+        .await;
+
+    match execute_result {
+        Ok(_) => Ok(()),
+        Err(err) if is_missing_compression_state_table_error(&err) => {
+            tracing::debug!(
+                table = COMPRESSION_STATE_TABLE,
+                "Compression state table is missing; skip persisting compression cache"
+            );
+            Ok(())
+        }
+        Err(err) => Err(ChatServiceError::Database(err)),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn cache_compression_result(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    source_fingerprint: u64,
+    source_message_count: usize,
+    token_threshold: u32,
+    compression_percentage: u8,
+    source_token_count: u32,
+    result: &CompressionResult,
+) {
+    let entry = cache_compression_result_in_memory(
+        session_id,
+        source_fingerprint,
+        source_message_count,
+        token_threshold,
+        compression_percentage,
+        source_token_count,
+        result,
+    );
+
+    if let Err(err) = persist_compression_result(pool, session_id, &entry).await {
+        tracing::warn!(
+            session_id = %session_id,
+            error = %err,
+            "Failed to persist compression cache entry"
+        );
+    }
+}
+
+async fn load_persisted_compression_result(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Option<CompressionCacheEntry>, ChatServiceError> {
+    let query = format!(
+        "SELECT
+            source_fingerprint,
+            source_message_count,
+            token_threshold,
+            compression_percentage,
+            source_token_count,
+            effective_token_count,
+            compression_type,
+            warning_json,
+            result_messages_json
+         FROM {COMPRESSION_STATE_TABLE}
+         WHERE session_id = ?1"
+    );
+
+    let row = match sqlx::query(&query)
+        .bind(session_id)
+        .fetch_optional(pool)
+        .await
+    {
+        Ok(row) => row,
+        Err(err) if is_missing_compression_state_table_error(&err) => {
+            tracing::debug!(
+                table = COMPRESSION_STATE_TABLE,
+                "Compression state table is missing; skip loading persisted compression cache"
+            );
+            return Ok(None);
+        }
+        Err(err) => return Err(ChatServiceError::Database(err)),
+    };
+
+    let Some(row) = row else {
+        return Ok(None);
+    };
+
+    let source_fingerprint_raw: String = row.try_get("source_fingerprint")?;
+    let source_fingerprint = match source_fingerprint_raw.parse::<u64>() {
+        Ok(value) => value,
+        Err(err) => {
+            tracing::warn!(
+                session_id = %session_id,
+                source_fingerprint = %source_fingerprint_raw,
+                error = %err,
+                "Invalid persisted compression fingerprint; ignoring persisted state"
+            );
+            return Ok(None);
+        }
+    };
+
+    let source_message_count = parse_required_usize(&row, "source_message_count")?;
+    let token_threshold = parse_required_u32(&row, "token_threshold")?;
+    let compression_percentage = parse_required_u32(&row, "compression_percentage")?;
+    let compression_percentage = match u8::try_from(compression_percentage) {
+        Ok(value) => value,
+        Err(err) => {
+            tracing::warn!(
+                session_id = %session_id,
+                compression_percentage = compression_percentage,
+                error = %err,
+                "Persisted compression percentage is invalid; ignoring persisted state"
+            );
+            return Ok(None);
+        }
+    };
+    let source_token_count = parse_required_u32(&row, "source_token_count")?;
+    let effective_token_count = parse_required_u32(&row, "effective_token_count")?;
+
+    let compression_type_raw: String = row.try_get("compression_type")?;
+    let Some(compression_type) = compression_type_from_db_value(&compression_type_raw) else {
+        tracing::warn!(
+            session_id = %session_id,
+            compression_type = %compression_type_raw,
+            "Persisted compression type is invalid; ignoring persisted state"
+        );
+        return Ok(None);
+    };
+
+    let warning = row
+        .try_get::<Option<String>, _>("warning_json")?
+        .and_then(|raw| serde_json::from_str::<CompressionWarning>(&raw).ok());
+
+    let result_messages_json: String = row.try_get("result_messages_json")?;
+    let result_messages =
+        match serde_json::from_str::<Vec<SimplifiedMessage>>(&result_messages_json) {
+            Ok(messages) => messages,
+            Err(err) => {
+                tracing::warn!(
+                    session_id = %session_id,
+                    error = %err,
+                    "Persisted compression result messages are invalid; ignoring persisted state"
+                );
+                return Ok(None);
+            }
+        };
+
+    Ok(Some(CompressionCacheEntry {
+        source_fingerprint,
+        source_message_count,
+        token_threshold,
+        compression_percentage,
+        source_token_count,
+        effective_token_count,
+        result: CompressionResult {
+            messages: result_messages,
+            compression_type,
+            warning,
+        },
+    }))
+}
+
+async fn get_compression_cache_entry(
+    pool: &SqlitePool,
+    session_id: Uuid,
+) -> Result<Option<CompressionCacheEntry>, ChatServiceError> {
+    if let Some(cached) = COMPRESSION_RESULT_CACHE.get(&session_id) {
+        return Ok(Some(cached.clone()));
+    }
+
+    let persisted = load_persisted_compression_result(pool, session_id).await?;
+    if let Some(entry) = persisted.as_ref() {
+        COMPRESSION_RESULT_CACHE.insert(session_id, entry.clone());
+        tracing::debug!(
+            session_id = %session_id,
+            source_messages = entry.source_message_count,
+            compression_type = ?entry.result.compression_type,
+            "Loaded persisted compression cache state from database"
+        );
+    }
+
+    Ok(persisted)
+}
+
+/// Messages compression starts from, possibly carried over from the cache.
+struct CompressionBase {
+    messages: Vec<SimplifiedMessage>,
+    inherited_compression_type: Option<CompressionType>,
+    inherited_warning: Option<CompressionWarning>,
+}
+
+enum CompressionStart {
+    /// The cached result covers exactly these messages and settings.
+    Cached(CompressionResult),
+    Base(CompressionBase),
+}
+
+/// Decide how much of the cached compression state applies to
+/// `source_messages`: all of it when nothing changed, the cached result plus
+/// the newer messages when the history only grew, otherwise none.
+fn compression_start(
+    session_id: Uuid,
+    cached: Option<&CompressionCacheEntry>,
+    source_messages: &[SimplifiedMessage],
+    source_fingerprint: u64,
+    token_threshold: u32,
+    compression_percentage: u8,
+) -> CompressionStart {
+    let mut base = CompressionBase {
+        messages: source_messages.to_vec(),
+        inherited_compression_type: None,
+        inherited_warning: None,
+    };
+    let Some(cached) = cached.filter(|cached| {
+        cached.token_threshold == token_threshold
+            && cached.compression_percentage == compression_percentage
+    }) else {
+        return CompressionStart::Base(base);
+    };
+
+    if cached.source_fingerprint == source_fingerprint {
+        tracing::debug!(
+            session_id = %session_id,
+            source_tokens = cached.source_token_count,
+            effective_tokens = cached.effective_token_count,
+            compression_type = ?cached.result.compression_type,
+            "Using cached compression result for unchanged session history"
+        );
+        return CompressionStart::Cached(cached.result.clone());
+    }
+    if cached.source_message_count <= source_messages.len() {
+        let prefix_fingerprint =
+            calculate_messages_fingerprint(&source_messages[..cached.source_message_count]);
+        if prefix_fingerprint == cached.source_fingerprint {
+            let mut merged = cached.result.messages.clone();
+            merged.extend_from_slice(&source_messages[cached.source_message_count..]);
+            base.messages = merged;
+            if cached.result.compression_type != CompressionType::None {
+                base.inherited_compression_type = Some(cached.result.compression_type.clone());
+                base.inherited_warning = cached.result.warning.clone();
+            }
+            tracing::debug!(
+                session_id = %session_id,
+                base_source_messages = cached.source_message_count,
+                new_messages = source_messages.len().saturating_sub(cached.source_message_count),
+                inherited_compression_type = ?cached.result.compression_type,
+                "Using incremental compression base for appended session history"
+            );
+        }
+    }
+    CompressionStart::Base(base)
+}
+
+/// Compress messages if they exceed the token threshold
+///
+/// This function implements the compression strategy:
+/// 1. Calculate total token count using tiktoken
+/// 2. If under threshold, return messages unchanged
+/// 3. If over threshold:
+///    - Select a prefix whose tokens are >= configured compression percentage
+///    - Try AI summarization with each session agent
+///    - If all agents fail, truncate to cutoff file and return warning
+///
+/// # Arguments
+/// * `pool` - Database connection pool
+/// * `session_id` - Chat session ID
+/// * `messages` - Messages to potentially compress
+/// * `token_threshold` - Token count that triggers compression
+/// * `compression_percentage` - Percentage of messages to compress (default 25)
+/// * `session_agents` - AI agents in the session for summarization
+/// * `workspace_path` - Workspace path for running agents
+/// * `context_dir` - Path to context directory for storing cutoff files
+#[allow(clippy::too_many_arguments)]
+pub async fn compress_messages_if_needed(
+    pool: &SqlitePool,
+    session_id: Uuid,
+    messages: Vec<SimplifiedMessage>,
+    token_threshold: u32,
+    compression_percentage: u8,
+    session_agents: &[ChatSessionAgent],
+    workspace_path: &Path,
+    context_dir: Option<&Path>,
+) -> Result<CompressionResult, ChatServiceError> {
+    let source_messages = messages;
+    let source_fingerprint = calculate_messages_fingerprint(&source_messages);
+    let source_token_count = estimate_token_count(&source_messages);
+    let cached_entry = get_compression_cache_entry(pool, session_id).await?;
+
+    let CompressionBase {
+        messages: effective_messages,
+        inherited_compression_type,
+        inherited_warning,
+    } = match compression_start(
+        session_id,
+        cached_entry.as_ref(),
+        &source_messages,
+        source_fingerprint,
+        token_threshold,
+        compression_percentage,
+    ) {
+        CompressionStart::Cached(result) => return Ok(result),
+        CompressionStart::Base(base) => base,
+    };
+
+    let token_count = estimate_token_count(&effective_messages);
+
+    tracing::debug!(
+        session_id = %session_id,
+        source_token_count = source_token_count,
+        effective_token_count = token_count,
+        token_count = token_count,
+        threshold = token_threshold,
+        "Checking if compression is needed"
+    );
+
+    // If under threshold, no compression needed
+    if token_count <= token_threshold {
+        let compression_type = inherited_compression_type.unwrap_or(CompressionType::None);
+        let warning = if compression_type == CompressionType::None {
+            None
+        } else {
+            inherited_warning
+        };
+        let result = CompressionResult {
+            messages: effective_messages,
+            compression_type,
+            warning,
+        };
+        cache_compression_result(
+            pool,
+            session_id,
+            source_fingerprint,
+            source_messages.len(),
+            token_threshold,
+            compression_percentage,
+            source_token_count,
+            &result,
+        )
+        .await;
+        return Ok(result);
+    }
+
+    let total_messages = effective_messages.len();
+    if total_messages == 0 {
+        let result = CompressionResult {
+            messages: effective_messages,
+            compression_type: CompressionType::None,
+            warning: None,
+        };
+        cache_compression_result(
+            pool,
+            session_id,
+            source_fingerprint,
+            source_messages.len(),
+            token_threshold,
+            compression_percentage,
+            source_token_count,
+            &result,
+        )
+        .await;
+        return Ok(result);
+    }
+
+    let (messages_to_compress_count, target_compress_tokens, selected_compress_tokens) =
+        select_messages_to_compress_by_token(
+            &effective_messages,
+            token_count,
+            compression_percentage,
+        );
+
+    let (messages_to_compress, messages_to_keep) =
+        effective_messages.split_at(messages_to_compress_count);
+
+    tracing::info!(
+        session_id = %session_id,
+        total = total_messages,
+        total_tokens = token_count,
+        target_compress_tokens = target_compress_tokens,
+        selected_compress_tokens = selected_compress_tokens,
+        to_compress = messages_to_compress_count,
+        to_keep = messages_to_keep.len(),
+        "Compressing messages"
+    );
+
+    // Try AI summarization with available agents
+    if !session_agents.is_empty()
+        && let Some(summary) = try_summarize_with_agents(
+            pool,
+            session_id,
+            session_agents,
+            messages_to_compress,
+            workspace_path,
+        )
+        .await
+    {
+        // Create summary message and prepend to kept messages
+        let summary_message = SimplifiedMessage {
+            sender: "system:summary".to_string(),
+            content: format!("[History Summary]\n{}", summary),
+            timestamp: Utc::now().to_rfc3339(),
+            record: None,
+        };
+
+        let mut result_messages = vec![summary_message];
+        result_messages.extend(messages_to_keep.to_vec());
+        let compressed_token_count = estimate_token_count(&result_messages);
+
+        if compressed_token_count >= token_count {
+            tracing::warn!(
+                session_id = %session_id,
+                before_tokens = token_count,
+                after_tokens = compressed_token_count,
+                "AI summarization did not reduce token usage, falling back to truncation"
+            );
+        } else {
+            tracing::info!(
+                session_id = %session_id,
+                before_tokens = token_count,
+                after_tokens = compressed_token_count,
+                "AI summarization reduced token usage"
+            );
+            let result = CompressionResult {
+                messages: result_messages,
+                compression_type: CompressionType::AiSummarized,
+                warning: None,
+            };
+            cache_compression_result(
+                pool,
+                session_id,
+                source_fingerprint,
+                source_messages.len(),
+                token_threshold,
+                compression_percentage,
+                source_token_count,
+                &result,
+            )
+            .await;
+            return Ok(result);
+        }
+    }
+
+    // All agents failed - fallback to truncation
+    tracing::warn!(
+        session_id = %session_id,
+        "AI summarization failed, falling back to truncation"
+    );
+
+    // Write messages to cutoff file in context directory
+    let mut archived_start = 0;
+    let cutoff_path = if let Some(ctx_dir) = context_dir {
+        // Find next available cutoff index
+        let mut index = 0;
+        loop {
+            let candidate = ctx_dir.join(format!("cutoff_message_{}.json", index));
+            if !candidate.exists() {
+                break candidate;
+            }
+            index += 1;
+        }
+    } else {
+        // Fallback to legacy split file if no context_dir provided
+        if let Ok(Some(existing)) = FsHistoryStore::default()
+            .read(session_id, HistoryFileKind::Split)
+            .await
+        {
+            archived_start = existing.messages.len();
+        }
+        append_to_split_file(session_id, messages_to_compress)
+            .await
+            .map_err(|e| {
+                ChatServiceError::Io(std::io::Error::other(format!(
+                    "Failed to create split file: {}",
+                    e
+                )))
+            })?
+    };
+
+    // Write cutoff messages to file
+    if context_dir.is_some() {
+        let cutoff_data = serde_json::json!({
+            "session_id": session_id,
+            "cutoff_at": chrono::Utc::now().to_rfc3339(),
+            "message_count": messages_to_compress_count,
+            "messages": messages_to_compress,
+        });
+        let json_str = serde_json::to_string_pretty(&cutoff_data).map_err(|e| {
+            ChatServiceError::Io(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Failed to serialize cutoff data: {}", e),
+            ))
+        })?;
+        fs::write(&cutoff_path, json_str).await?;
+    }
+
+    let cutoff_path_str = cutoff_path.to_string_lossy().to_string();
+
+    // Keep a compact summary marker at the front so history file always contains
+    // "compressed context + remaining uncompressed messages".
+    let mut result_messages = vec![SimplifiedMessage {
+        sender: "system:summary".to_string(),
+        content: format!(
+            "[History Summary - Fallback]\nAI summarization failed; archived {} messages (~{} tokens) to {}",
+            messages_to_compress_count, selected_compress_tokens, cutoff_path_str
+        ),
+        timestamp: Utc::now().to_rfc3339(),
+        record: None,
+    }];
+    result_messages.extend(messages_to_keep.to_vec());
+
+    // Return summary marker + remaining messages with warning
+    let result = CompressionResult {
+        messages: result_messages,
+        compression_type: CompressionType::Truncated,
+        warning: Some(CompressionWarning {
+            code: "COMPRESSION_FALLBACK".to_string(),
+            message: format!(
+                "AI summarization failed or was ineffective; archived {} messages (~{} tokens) to cutoff file",
+                messages_to_compress_count, selected_compress_tokens
+            ),
+            split_file_path: cutoff_path_str,
+            archived_range: Some(ArchivedMessageRange {
+                start: archived_start,
+                count: messages_to_compress_count,
+            }),
+        }),
+    };
+    cache_compression_result(
+        pool,
+        session_id,
+        source_fingerprint,
+        source_messages.len(),
+        token_threshold,
+        compression_percentage,
+        source_token_count,
+        &result,
+    )
+    .await;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_trait::async_trait;
+    use db::models::{
+        chat_agent::{ChatAgent, CreateChatAgent},
+        chat_message::{ChatMessage, ChatSenderType},
+        chat_session::{ChatSession, ChatSessionStatus, CreateChatSession, UpdateChatSession},
+        chat_session_agent::{ChatSessionAgent, ChatSessionAgentState, CreateChatSessionAgent},
+    };
+    use sqlx::SqlitePool;
+    use uuid::Uuid;
+
+    use super::{
+        ActivityBucketSize, Arc, ArchiveLayout, CONTEXT_PREAMBLE_SENDER, ChatAttachmentMeta,
+        ChatServiceError, CompressionType, ContextBuildOptions, CreateChatMessage,
+        DERIVED_TITLE_MAX_CHARS, Duration, HistoryFileKind, HistoryStore, IDEMPOTENT_CREATE_LOCKS,
+        MAX_QUEUED_MENTIONS, MentionEvent, MentionNotifier, MessageSettings, NewMessage,
+        NotificationSchedule, QuietHoursNotifier, RenameAgentOptions, SESSION_ARCHIVE_MANIFEST,
+        STRUCTURED_MESSAGE_SCHEMA_VERSION, SessionArchiveManifest, SessionArchiveManifestEntry,
+        SessionMeta, SessionSummarizer, SimplifiedMessage, SystemMessageFilter, TurnOrder,
+        agent_color, all_agents_running, begin_agent_reply, build_agent_map,
+        build_compacted_context_with_agent_map, build_compacted_context_with_options,
+        build_full_context, build_history_file, build_simplified_messages,
+        build_structured_messages, build_structured_messages_filtered,
+        build_structured_messages_with_agent_map, cancel_agent_reply, chat_compression_settings,
+        check_mention_limit, clone_session, clone_session_with_store, compress_messages_if_needed,
+        compressed_ref_values, continue_session_from_archive,
+        continue_session_from_archive_with_attachment_root, create_message,
+        create_message_idempotent, create_message_with_settings, create_messages_batch,
+        create_session_from_template, estimate_context_tokens, export_all_sessions,
+        export_session_archive, export_session_archive_with_attachment_root,
+        export_session_archive_with_layout, export_session_archive_with_progress,
+        export_session_html, export_session_incremental, export_session_sqlite,
+        extract_attachments, finalize_agent_reply, find_orphaned_attachments,
+        find_orphaned_attachments_with_store, gc_orphaned_attachments,
+        generate_session_summary_with, import_all_sessions,
+        import_session_archive_with_attachment_root, import_session_archive_with_progress,
+        insert_message_and_touch, is_cancelled_reply, is_pending_reply,
+        limit_summary_input_messages, list_sessions_with_preview, mark_all_read, mark_session_read,
+        merge_consecutive_sender_messages, merge_sessions_with_store, messages_mentioning,
+        next_responders, normalize_content, parse_mentions, parse_send_message_directives,
+        parse_tokens, parse_topics, prioritize_summary_agents, prune_sessions_into, purge_session,
+        purge_session_with_store, register_mention_notifier, rename_agent, resolve_original_ref,
+        retry_transient, rewrite_handle_in_content, select_messages_to_compress_by_token,
+        session_activity_timeseries, session_archive_dir, session_mention_frequencies,
+        session_mention_graph, session_participants, set_session_template,
+        threshold_with_safety_margin, to_anthropic_messages, to_openai_messages, truncate_title,
+        unread_count, write_session_html, write_structured_messages_jsonl,
+    };
+    use crate::services::{
+        chat_archive_checksum::verify_session_archive,
+        chat_history_file::{InMemoryHistoryStore, estimate_string_tokens},
+        config::{ChatMemberPreset, ChatPresetsConfig, Config},
+    };
+
+    async fn setup_chat_pool() -> SqlitePool {
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        sqlx::migrate!("../db/migrations")
+            .run(&pool)
+            .await
+            .expect("run migrations");
+        pool
+    }
+
+    async fn create_test_session(pool: &SqlitePool) -> Uuid {
+        ChatSession::create(
+            pool,
+            &CreateChatSession {
+                title: Some("Test session".to_string()),
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create session")
+        .id
+    }
+
+    async fn create_test_agent(pool: &SqlitePool, name: &str) -> Uuid {
+        ChatAgent::create(
+            pool,
+            &CreateChatAgent {
+                name: name.to_string(),
+                runner_type: "CLAUDE_CODE".to_string(),
+                system_prompt: None,
+                tools_enabled: None,
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("create agent")
+        .id
+    }
+
+    async fn seed_two_agent_conversation(pool: &SqlitePool) -> Uuid {
+        let session_id = create_test_session(pool).await;
+        let planner = create_test_agent(pool, "planner").await;
+        let coder = create_test_agent(pool, "coder").await;
+
+        let messages = [
+            (
+                ChatSenderType::User,
+                None,
+                "Please add retry logic to the uploader",
+            ),
+            (
+                ChatSenderType::Agent,
+                Some(planner),
+                "Plan: wrap upload in a retry loop",
+            ),
+            (
+                ChatSenderType::Agent,
+                Some(coder),
+                "Done, we agreed on three retries",
+            ),
+        ];
+        for (sender_type, sender_id, content) in messages {
+            create_message(
+                pool,
+                session_id,
+                sender_type,
+                sender_id,
+                content.to_string(),
+                Some(serde_json::json!({ "sender_handle": "alice" })),
+            )
+            .await
+            .expect("create message");
+        }
+
+        session_id
+    }
+
+    #[test]
+    fn parses_mentions_with_basic_tokens() {
+        let mentions = parse_mentions("@coder please check @planner");
+        assert_eq!(mentions, vec!["coder", "planner"]);
+    }
+
+    #[test]
+    fn ignores_email_addresses() {
+        let mentions = parse_mentions("email me at test@example.com");
+        assert!(mentions.is_empty());
+    }
+
+    #[test]
+    fn de_dupes_mentions_in_order() {
+        let mentions = parse_mentions("@a @a @b");
+        assert_eq!(mentions, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn parses_send_message_directives_and_dedupes_targets() {
+        let mentions = parse_send_message_directives(
+            "notify [sendMessageTo@@{alice}] then [sendMessageTo@@{bob}] and [sendMessageTo@@{alice}]",
+        );
+        assert_eq!(mentions, vec!["alice", "bob"]);
+    }
+
+    #[test]
+    fn parse_send_message_directives_ignores_plain_at_mentions() {
+        let mentions = parse_send_message_directives("@alice please review this");
+        assert!(mentions.is_empty());
+    }
+
+    #[test]
+    fn parse_send_message_directives_ignores_invalid_targets() {
+        let mentions = parse_send_message_directives(
+            "[sendMessageTo@@] [sendMessageTo@@{bad target}] [sendMessageTo@@{ok_agent}]",
+        );
+        assert_eq!(mentions, vec!["ok_agent"]);
+    }
+
+    #[test]
+    fn parse_send_message_directives_supports_non_brace_targets() {
+        let mentions = parse_send_message_directives(
+            "[sendMessageTo@@researcher] and [sendMessageTo@@{planner}]",
+        );
+        assert_eq!(mentions, vec!["researcher", "planner"]);
+    }
+
+    #[test]
+    fn parse_send_message_directives_supports_unicode_targets() {
+        let mentions = parse_send_message_directives(
+            "[sendMessageTo@@{\u{5C0F}\u{660E}}] and [sendMessageTo@@{\u{30C6}\u{30B9}\u{30C8}-agent}]",
+        );
+        assert_eq!(
+            mentions,
+            vec!["\u{5C0F}\u{660E}", "\u{30C6}\u{30B9}\u{30C8}-agent"]
+        );
+    }
+
+    fn make_session_agent(state: ChatSessionAgentState) -> ChatSessionAgent {
+        ChatSessionAgent {
+            id: Uuid::new_v4(),
+            session_id: Uuid::new_v4(),
+            agent_id: Uuid::new_v4(),
+            state,
+            workspace_path: None,
+            pty_session_key: None,
+            agent_session_id: None,
+            agent_message_id: None,
+            created_at: chrono::Utc::now(),
+            updated_at: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn prioritize_summary_agents_prefers_idle_then_running_last() {
+        let running = make_session_agent(ChatSessionAgentState::Running);
+        let waiting = make_session_agent(ChatSessionAgentState::WaitingApproval);
+        let idle = make_session_agent(ChatSessionAgentState::Idle);
+        let dead = make_session_agent(ChatSessionAgentState::Dead);
+
+        let prioritized = prioritize_summary_agents(&[
+            running.clone(),
+            waiting.clone(),
+            idle.clone(),
+            dead.clone(),
+        ]);
+
+        assert_eq!(prioritized[0].id, idle.id);
+        assert_eq!(prioritized[1].id, waiting.id);
+        assert_eq!(prioritized[2].id, dead.id);
+        assert_eq!(prioritized[3].id, running.id);
+    }
+
+    #[test]
+    fn all_agents_running_only_true_when_non_empty_and_all_running() {
+        assert!(!all_agents_running(&[]));
+        assert!(!all_agents_running(&[
+            make_session_agent(ChatSessionAgentState::Running),
+            make_session_agent(ChatSessionAgentState::Idle),
+        ]));
+        assert!(all_agents_running(&[
+            make_session_agent(ChatSessionAgentState::Running),
+            make_session_agent(ChatSessionAgentState::Running),
+        ]));
+    }
+
+    #[test]
+    fn select_messages_to_compress_uses_token_budget() {
+        let messages = vec![
+            SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: "heavy ".repeat(500),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "user:bob".to_string(),
+                content: "small".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "small".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "small".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+        ];
+
+        let total_tokens = super::estimate_token_count(&messages);
+        let (count, target_tokens, selected_tokens) =
+            select_messages_to_compress_by_token(&messages, total_tokens, 50);
+
+        // 50% by message count would be 2, but token-based should pick only the heavy first message.
+        assert_eq!(count, 1);
+        assert!(selected_tokens >= target_tokens);
+    }
+
+    #[test]
+    fn limit_summary_input_messages_keeps_all_when_under_limit() {
+        let messages = vec![
+            SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: "short".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "short reply".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+        ];
+
+        let (limited, before, after) = limit_summary_input_messages(&messages, u32::MAX);
+        assert_eq!(limited.len(), messages.len());
+        assert_eq!(before, after);
+    }
+
+    #[test]
+    fn limit_summary_input_messages_keeps_recent_slice_when_over_limit() {
+        let messages = vec![
+            SimplifiedMessage {
+                sender: "user:a".to_string(),
+                content: "old ".repeat(300),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:b".to_string(),
+                content: "middle ".repeat(300),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "user:c".to_string(),
+                content: "recent ".repeat(300),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+        ];
+
+        let (limited, before, after) = limit_summary_input_messages(&messages, 200);
+        assert!(limited.len() < messages.len());
+        assert_eq!(
+            limited.last().map(|m| m.content.as_str()),
+            Some(messages[2].content.as_str())
+        );
+        assert!(before > after);
+        assert!(after <= 200 || limited.len() == 1);
+    }
+
+    #[test]
+    fn parses_mentions_with_unicode_names() {
+        let mentions = parse_mentions(
+            "@\u{5C0F}\u{660E} please check @\u{30C6}\u{30B9}\u{30C8}-agent and @\u{0645}\u{0637}\u{0648}\u{0631}_1",
+        );
+        assert_eq!(
+            mentions,
+            vec![
+                "\u{5C0F}\u{660E}",
+                "\u{30C6}\u{30B9}\u{30C8}-agent",
+                "\u{0645}\u{0637}\u{0648}\u{0631}_1",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn compress_messages_falls_back_to_truncation_without_agents() {
+        if dirs::data_dir().is_none() {
+            return;
+        }
+
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        let session_id = Uuid::new_v4();
+        let workspace = std::path::Path::new(".");
+        let messages = vec![
+            SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: "A very long message that should exceed tiny threshold quickly".repeat(8),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "Second long message for compression coverage".repeat(8),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "user:bob".to_string(),
+                content: "Recent message to keep".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "Another recent message to keep".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+        ];
+
+        let result = compress_messages_if_needed(
+            &pool,
+            session_id,
+            messages.clone(),
+            1,   // force compression
+            50,  // compress half
+            &[], // no agents available
+            workspace,
+            None, // no context_dir, use legacy split file
+        )
+        .await
+        .expect("compression should succeed with fallback");
+
+        assert_eq!(result.compression_type, CompressionType::Truncated);
+        assert!(result.messages.len() <= messages.len());
+        assert!(
+            super::estimate_token_count(&result.messages) < super::estimate_token_count(&messages),
+            "fallback truncation should reduce token count"
+        );
+        assert_eq!(
+            result
+                .messages
+                .first()
+                .map(|message| message.sender.as_str()),
+            Some("system:summary"),
+            "fallback should keep a compact summary marker at the front"
+        );
+        assert!(
+            result
+                .messages
+                .first()
+                .map(|message| message.content.contains("[History Summary - Fallback]"))
+                .unwrap_or(false),
+            "fallback summary marker should describe archival"
+        );
+
+        let warning = result.warning.expect("fallback should include warning");
+        assert_eq!(warning.code, "COMPRESSION_FALLBACK");
+        assert!(
+            std::path::Path::new(&warning.split_file_path).exists(),
+            "split file should be created"
+        );
+
+        let _ = tokio::fs::remove_file(&warning.split_file_path).await;
+    }
+
+    #[tokio::test]
+    async fn compress_messages_reuses_cached_result_for_unchanged_history() {
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        let session_id = Uuid::new_v4();
+        let workspace = std::path::Path::new(".");
+        let context_dir = tempfile::tempdir().expect("create temp context dir");
+        let messages = vec![
+            SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: "A very long message that should exceed tiny threshold quickly".repeat(8),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "Second long message for compression coverage".repeat(8),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "user:bob".to_string(),
+                content: "Recent message to keep".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+        ];
+
+        let first = compress_messages_if_needed(
+            &pool,
+            session_id,
+            messages.clone(),
+            1,
+            50,
+            &[],
+            workspace,
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("first compression should succeed");
+        assert_eq!(first.compression_type, CompressionType::Truncated);
+        let first_path = first
+            .warning
+            .as_ref()
+            .expect("warning expected")
+            .split_file_path
+            .clone();
+
+        let second = compress_messages_if_needed(
+            &pool,
+            session_id,
+            messages.clone(),
+            1,
+            50,
+            &[],
+            workspace,
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("second compression should succeed");
+        assert_eq!(second.compression_type, CompressionType::Truncated);
+        let second_path = second
+            .warning
+            .as_ref()
+            .expect("warning expected")
+            .split_file_path
+            .clone();
+
+        assert_eq!(
+            first_path, second_path,
+            "unchanged history should reuse cached compression output"
+        );
+
+        let cutoff_count = std::fs::read_dir(context_dir.path())
+            .expect("read context dir")
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("cutoff_message_")
+            })
+            .count();
+        assert_eq!(
+            cutoff_count, 1,
+            "cached compression should avoid creating extra cutoff files"
+        );
+    }
+
+    #[tokio::test]
+    async fn compress_messages_reuses_persisted_state_after_cache_clear() {
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        let create_state_table_sql = format!(
+            "CREATE TABLE {} (
+                session_id BLOB PRIMARY KEY,
+                source_fingerprint TEXT NOT NULL,
+                source_message_count INTEGER NOT NULL,
+                token_threshold INTEGER NOT NULL,
+                compression_percentage INTEGER NOT NULL,
+                source_token_count INTEGER NOT NULL,
+                effective_token_count INTEGER NOT NULL,
+                compression_type TEXT NOT NULL,
+                warning_json TEXT,
+                result_messages_json TEXT NOT NULL,
+                created_at TEXT NOT NULL DEFAULT (datetime('now', 'subsec')),
+                updated_at TEXT NOT NULL DEFAULT (datetime('now', 'subsec'))
+            )",
+            super::COMPRESSION_STATE_TABLE
+        );
+        sqlx::query(&create_state_table_sql)
+            .execute(&pool)
+            .await
+            .expect("create compression state table");
+
+        let session_id = Uuid::new_v4();
+        let workspace = std::path::Path::new(".");
+        let context_dir = tempfile::tempdir().expect("create temp context dir");
+        let messages = vec![
+            SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: "A very long message that should exceed tiny threshold quickly".repeat(8),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "Second long message for compression coverage".repeat(8),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "user:bob".to_string(),
+                content: "Recent message to keep".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+        ];
+
+        let first = compress_messages_if_needed(
+            &pool,
+            session_id,
+            messages.clone(),
+            1,
+            50,
+            &[],
+            workspace,
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("first compression should succeed");
+        assert_eq!(first.compression_type, CompressionType::Truncated);
+        let first_path = first
+            .warning
+            .as_ref()
+            .expect("warning expected")
+            .split_file_path
+            .clone();
+
+        let persisted_count = sqlx::query_scalar::<_, i64>(&format!(
+            "SELECT COUNT(1) FROM {} WHERE session_id = ?1",
+            super::COMPRESSION_STATE_TABLE
+        ))
+        .bind(session_id)
+        .fetch_one(&pool)
+        .await
+        .expect("query persisted compression rows");
+        assert_eq!(persisted_count, 1);
+
+        super::COMPRESSION_RESULT_CACHE.remove(&session_id);
+
+        let second = compress_messages_if_needed(
+            &pool,
+            session_id,
+            messages,
+            1,
+            50,
+            &[],
+            workspace,
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("second compression should succeed from persisted state");
+        assert_eq!(second.compression_type, CompressionType::Truncated);
+        let second_path = second
+            .warning
+            .as_ref()
+            .expect("warning expected")
+            .split_file_path
+            .clone();
+
+        assert_eq!(
+            first_path, second_path,
+            "persisted cache should avoid re-compressing unchanged history after cache reset"
+        );
+
+        let cutoff_count = std::fs::read_dir(context_dir.path())
+            .expect("read context dir")
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("cutoff_message_")
+            })
+            .count();
+        assert_eq!(
+            cutoff_count, 1,
+            "persisted cache should avoid creating extra cutoff files after cache reset"
+        );
+    }
+
+    #[tokio::test]
+    async fn compress_messages_uses_compacted_base_for_appended_history() {
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        let session_id = Uuid::new_v4();
+        let workspace = std::path::Path::new(".");
+        let context_dir = tempfile::tempdir().expect("create temp context dir");
+        let base_messages = vec![
+            SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: "A very long message that should exceed threshold".repeat(200),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "Another very long message for compression".repeat(200),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "user:bob".to_string(),
+                content: "small keep".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "small keep too".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+        ];
+
+        let base_tokens = super::estimate_token_count(&base_messages);
+        let threshold = base_tokens.saturating_sub(1).max(1);
+
+        let first = compress_messages_if_needed(
+            &pool,
+            session_id,
+            base_messages.clone(),
+            threshold,
+            50,
+            &[],
+            workspace,
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("first compression should succeed");
+        assert_eq!(first.compression_type, CompressionType::Truncated);
+
+        let mut appended = base_messages.clone();
+        appended.push(SimplifiedMessage {
+            sender: "user:charlie".to_string(),
+            content: "new tail message".to_string(),
+            timestamp: chrono::Utc::now().to_rfc3339(),
+            record: None,
+        });
+
+        let second = compress_messages_if_needed(
+            &pool,
+            session_id,
+            appended,
+            threshold,
+            50,
+            &[],
+            workspace,
+            Some(context_dir.path()),
+        )
+        .await
+        .expect("second compression should succeed");
+
+        // Should keep using compacted base and just append new tail without re-compressing old long prefix.
+        assert!(second.messages.len() >= first.messages.len());
+
+        let cutoff_count = std::fs::read_dir(context_dir.path())
+            .expect("read context dir")
+            .filter_map(Result::ok)
+            .filter(|entry| {
+                entry
+                    .file_name()
+                    .to_string_lossy()
+                    .starts_with("cutoff_message_")
+            })
+            .count();
+        assert_eq!(
+            cutoff_count, 1,
+            "appended history should not trigger another cutoff for already compressed prefix"
+        );
+    }
+
+    #[tokio::test]
+    async fn compress_messages_keeps_original_when_under_threshold() {
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        let session_id = Uuid::new_v4();
+        let workspace = std::path::Path::new(".");
+        let messages = vec![
+            SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: "short message".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:bot".to_string(),
+                content: "another short one".to_string(),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            },
+        ];
+
+        let result = compress_messages_if_needed(
+            &pool,
+            session_id,
+            messages.clone(),
+            u32::MAX, // never trigger compression
+            25,
+            &[],
+            workspace,
+            None, // no context_dir
+        )
+        .await
+        .expect("compression should pass");
+
+        assert_eq!(result.compression_type, CompressionType::None);
+        assert_eq!(result.messages.len(), messages.len());
+        assert!(result.warning.is_none());
+    }
+
+    #[tokio::test]
+    async fn heuristic_session_summary_names_participating_agents() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+
+        let summary = generate_session_summary_with(&pool, session_id, 1_000, None)
+            .await
+            .expect("generate summary");
+
+        assert!(summary.contains("Please add retry logic to the uploader"));
+        assert!(summary.contains("Participants: planner, coder"));
+        assert!(summary.contains("we agreed on three retries"));
+
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .expect("load session")
+            .expect("session exists");
+        assert_eq!(session.summary_text.as_deref(), Some(summary.as_str()));
+    }
+
+    struct StubSummarizer;
+
+    #[async_trait]
+    impl SessionSummarizer for StubSummarizer {
+        async fn summarize(
+            &self,
+            _session_id: Uuid,
+            transcript: &[SimplifiedMessage],
+        ) -> Option<String> {
+            Some(format!("stub summary of {} messages", transcript.len()))
+        }
+    }
+
+    #[tokio::test]
+    async fn session_summary_prefers_summarizer_output() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+
+        let summary =
+            generate_session_summary_with(&pool, session_id, 1_000, Some(&StubSummarizer))
+                .await
+                .expect("generate summary");
+
+        assert_eq!(summary, "stub summary of 3 messages");
+    }
+
+    #[tokio::test]
+    async fn session_participants_are_ordered_newest_active_first() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+
+        let names = |agents: Vec<ChatAgent>| {
+            agents
+                .into_iter()
+                .map(|agent| agent.name)
+                .collect::<Vec<_>>()
+        };
+
+        let participants = session_participants(&pool, session_id)
+            .await
+            .expect("load participants");
+        assert_eq!(names(participants), vec!["coder", "planner"]);
+
+        let planner = ChatAgent::find_by_name(&pool, "planner")
+            .await
+            .expect("find planner")
+            .expect("planner exists");
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::Agent,
+            Some(planner.id),
+            "Follow-up from planner".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+
+        let participants = session_participants(&pool, session_id)
+            .await
+            .expect("load participants");
+        assert_eq!(names(participants), vec!["planner", "coder"]);
+    }
+
+    #[tokio::test]
+    async fn export_all_sessions_writes_manifest_for_each_session() {
+        let pool = setup_chat_pool().await;
+        let first = seed_two_agent_conversation(&pool).await;
+        let second = create_test_session(&pool).await;
+        let archive_root = tempfile::tempdir().expect("create archive root");
+
+        let exported = export_all_sessions(&pool, archive_root.path())
+            .await
+            .expect("export sessions");
+        assert_eq!(exported.len(), 2);
+
+        let manifest: SessionArchiveManifest = serde_json::from_str(
+            &std::fs::read_to_string(archive_root.path().join(SESSION_ARCHIVE_MANIFEST))
+                .expect("read manifest"),
+        )
+        .expect("parse manifest");
+        let mut listed: Vec<Uuid> = manifest
+            .sessions
+            .iter()
+            .map(|entry| entry.session_id)
+            .collect();
+        listed.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(listed, expected);
+        assert!(manifest.failures.is_empty());
+
+        for entry in &manifest.sessions {
+            let folder = archive_root.path().join(&entry.folder);
+            assert!(folder.join("messages_export.jsonl").exists());
+            assert!(folder.join("session_summary.md").exists());
+        }
+    }
+
+    #[tokio::test]
+    async fn bulk_export_then_import_round_trips_sessions() {
+        let source = setup_chat_pool().await;
+        let first = seed_two_agent_conversation(&source).await;
+        let second = seed_two_agent_conversation(&source).await;
+        let archive_root = tempfile::tempdir().expect("create archive root");
+        export_all_sessions(&source, archive_root.path())
+            .await
+            .expect("export sessions");
+
+        let target = setup_chat_pool().await;
+        let report = import_all_sessions(&target, archive_root.path())
+            .await
+            .expect("import sessions");
+        let mut imported = report.imported.clone();
+        imported.sort();
+        let mut expected = vec![first, second];
+        expected.sort();
+        assert_eq!(imported, expected);
+        assert!(report.skipped.is_empty());
+        assert!(report.failed.is_empty());
+
+        for session_id in [first, second] {
+            let original = build_simplified_messages(&source, session_id)
+                .await
+                .expect("load source messages");
+            let restored = build_simplified_messages(&target, session_id)
+                .await
+                .expect("load imported messages");
+            let contents = |messages: &[SimplifiedMessage]| {
+                messages
+                    .iter()
+                    .map(|message| message.content.clone())
+                    .collect::<Vec<_>>()
+            };
+            assert_eq!(contents(&restored), contents(&original));
+        }
+
+        let report = import_all_sessions(&target, archive_root.path())
+            .await
+            .expect("re-import sessions");
+        assert!(report.imported.is_empty());
+        assert_eq!(report.skipped.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn device_id_round_trips_into_structured_messages() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "Sent from my phone".to_string(),
+            Some(serde_json::json!({ "sender_handle": "alice", "device_id": "mobile-1" })),
+        )
+        .await
+        .expect("create message");
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "Sent without a device".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+
+        let messages = build_structured_messages(&pool, session_id)
+            .await
+            .expect("build structured messages");
+        assert_eq!(messages[0]["device_id"], "mobile-1");
+        assert_eq!(messages[0]["meta"]["origin"]["device_id"], "mobile-1");
+        assert!(messages[1]["device_id"].is_null());
+    }
+
+    #[tokio::test]
+    async fn mentioning_a_handle_fires_mention_event() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let (sender, mut receiver) = tokio::sync::broadcast::channel(16);
+        let registration = register_mention_notifier(std::sync::Arc::new(sender));
+
+        let message = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "@alice can you take a look?".to_string(),
+            Some(serde_json::json!({ "sender_handle": "bob" })),
+        )
+        .await
+        .expect("create message");
+
+        // Other tests share the global notifier list, so skip their events.
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) if event.session_id == session_id => event,
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(err) => panic!("mention channel closed: {err}"),
+            };
+            assert_eq!(event.message_id, message.id);
+            assert_eq!(event.handles, vec!["alice"]);
+            assert!(!event.mentions_user);
+            break;
+        }
+
+        // "bob" has sent messages as the user, so an agent routing a message
+        // to them addresses the user.
+        let coder = create_test_agent(&pool, "coder").await;
+        let reply = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::Agent,
+            Some(coder),
+            "[sendMessageTo@@alice] please review [sendMessageTo@@bob] done".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+        loop {
+            let event = match receiver.recv().await {
+                Ok(event) if event.message_id == reply.id => event,
+                Ok(_) | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(err) => panic!("mention channel closed: {err}"),
+            };
+            assert!(event.mentions_user);
+            break;
+        }
+
+        // Once unregistered, the channel's only sender is gone and later
+        // mentions no longer reach it.
+        drop(registration);
+        let unheard = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "@alice are you there?".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+        loop {
+            match receiver.recv().await {
+                Ok(event) => assert_ne!(event.message_id, unheard.id),
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn openai_export_maps_sender_types_to_roles() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::System,
+            None,
+            "Session archived soon".to_string(),
+            None,
+        )
+        .await
+        .expect("create system message");
+
+        let messages = to_openai_messages(&pool, session_id)
+            .await
+            .expect("export openai messages");
+        let roles: Vec<&str> = messages
+            .iter()
+            .map(|message| message["role"].as_str().unwrap())
+            .collect();
+        assert_eq!(roles, vec!["user", "assistant", "assistant", "system"]);
+        assert_eq!(
+            messages[0]["content"],
+            "Please add retry logic to the uploader"
+        );
+        assert_eq!(
+            messages[1]["content"],
+            "[planner]: Plan: wrap upload in a retry loop"
+        );
+        assert_eq!(messages[3]["content"], "Session archived soon");
+
+        assert!(matches!(
+            to_openai_messages(&pool, Uuid::new_v4()).await,
+            Err(ChatServiceError::SessionNotFound)
+        ));
+        assert!(matches!(
+            to_anthropic_messages(&pool, Uuid::new_v4(), None).await,
+            Err(ChatServiceError::SessionNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn anthropic_export_merges_consecutive_agent_turns() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::System,
+            None,
+            "Budget is two hours".to_string(),
+            None,
+        )
+        .await
+        .expect("create system message");
+
+        let export = to_anthropic_messages(&pool, session_id, Some("You are reviewing a chat."))
+            .await
+            .expect("export anthropic messages");
+
+        assert_eq!(
+            export["system"],
+            "You are reviewing a chat.\n\nBudget is two hours"
+        );
+        let messages = export["messages"].as_array().expect("messages array");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "user");
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(
+            messages[1]["content"],
+            "[planner]: Plan: wrap upload in a retry loop\n\n[coder]: Done, we agreed on three retries"
+        );
+    }
+
+    #[tokio::test]
+    async fn anthropic_export_starts_with_a_user_turn_when_an_agent_spoke_first() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let planner = create_test_agent(&pool, "planner").await;
+        for (sender_type, sender_id, content) in [
+            (ChatSenderType::Agent, Some(planner), "Standup starts now"),
+            (ChatSenderType::User, None, "I fixed the uploader"),
+            (ChatSenderType::Agent, Some(planner), "Thanks"),
+        ] {
+            create_message(
+                &pool,
+                session_id,
+                sender_type,
+                sender_id,
+                content.to_string(),
+                None,
+            )
+            .await
+            .expect("create message");
+        }
+
+        let export = to_anthropic_messages(&pool, session_id, Some("Be brief."))
+            .await
+            .expect("export anthropic messages");
+
+        assert_eq!(
+            export["system"],
+            "Be brief.\n\n[planner]: Standup starts now"
+        );
+        let messages = export["messages"].as_array().expect("messages array");
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0]["role"], "user");
+        assert_eq!(messages[0]["content"], "I fixed the uploader");
+        assert_eq!(messages[1]["role"], "assistant");
+        assert_eq!(messages[1]["content"], "[planner]: Thanks");
+    }
+
+    #[tokio::test]
+    async fn prune_sessions_removes_only_stale_unpinned_sessions() {
+        let pool = setup_chat_pool().await;
+        let stale = seed_two_agent_conversation(&pool).await;
+        let stale_pinned = create_test_session(&pool).await;
+        let fresh = create_test_session(&pool).await;
+        for session_id in [stale, stale_pinned] {
+            sqlx::query(
+                "UPDATE chat_sessions SET updated_at = datetime('now', '-40 days') WHERE id = ?1",
+            )
+            .bind(session_id)
+            .execute(&pool)
+            .await
+            .expect("age session");
+        }
+        SessionMeta::modify(&pool, stale_pinned, |meta| meta.set_pinned(true))
+            .await
+            .expect("pin session");
+        let chat_root = tempfile::tempdir().expect("create chat root");
+
+        let report = prune_sessions_into(&pool, chat_root.path(), 30, true)
+            .await
+            .expect("prune sessions");
+
+        assert_eq!(report.archived, 1);
+        assert_eq!(report.deleted, 1);
+        assert_eq!(report.skipped_pinned, 1);
+        assert!(
+            ChatSession::find_by_id(&pool, stale)
+                .await
+                .unwrap()
+                .is_none()
+        );
+        assert!(
+            ChatSession::find_by_id(&pool, stale_pinned)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            ChatSession::find_by_id(&pool, fresh)
+                .await
+                .unwrap()
+                .is_some()
+        );
+        assert!(
+            session_archive_dir(chat_root.path(), stale)
+                .join("messages_export.jsonl")
+                .exists()
+        );
+    }
+
+    #[tokio::test]
+    async fn rename_agent_optionally_rewrites_mentions_and_content() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let agent_id = create_test_agent(&pool, "planner").await;
+        ChatSessionAgent::create(
+            &pool,
+            &CreateChatSessionAgent {
+                session_id,
+                agent_id,
+                workspace_path: None,
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("add agent to session");
+        let message = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "@planner please plan, cc @planner-bot".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+
+        let renamed = rename_agent(&pool, agent_id, "architect", RenameAgentOptions::default())
+            .await
+            .expect("rename agent");
+        assert_eq!(renamed.name, "architect");
+        let unchanged = ChatMessage::find_by_id(&pool, message.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(unchanged.mentions.0, vec!["planner", "planner-bot"]);
+
+        // Rename back and forth to exercise the rewrite with the stored handle.
+        rename_agent(&pool, agent_id, "planner", RenameAgentOptions::default())
+            .await
+            .expect("rename agent back");
+        rename_agent(
+            &pool,
+            agent_id,
+            "architect",
+            RenameAgentOptions {
+                rewrite_mentions: true,
+                rewrite_content: true,
+            },
+        )
+        .await
+        .expect("rename agent with rewrite");
+
+        let rewritten = ChatMessage::find_by_id(&pool, message.id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(rewritten.mentions.0, vec!["architect", "planner-bot"]);
+        assert_eq!(rewritten.content, "@architect please plan, cc @planner-bot");
+        assert_eq!(
+            rewritten.meta.0["structured"]["content"],
+            "@architect please plan, cc @planner-bot"
+        );
+
+        assert!(matches!(
+            rename_agent(
+                &pool,
+                Uuid::new_v4(),
+                "ghost",
+                RenameAgentOptions::default()
+            )
+            .await,
+            Err(ChatServiceError::AgentNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn rename_agent_rejects_names_taken_in_its_sessions() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let planner = create_test_agent(&pool, "planner").await;
+        let reviewer = create_test_agent(&pool, "reviewer").await;
+        let outsider = create_test_agent(&pool, "tester").await;
+        for agent_id in [planner, reviewer] {
+            ChatSessionAgent::create(
+                &pool,
+                &CreateChatSessionAgent {
+                    session_id,
+                    agent_id,
+                    workspace_path: None,
+                },
+                Uuid::new_v4(),
+            )
+            .await
+            .expect("add agent to session");
+        }
+
+        let result =
+            rename_agent(&pool, planner, " Reviewer ", RenameAgentOptions::default()).await;
+        assert!(matches!(result, Err(ChatServiceError::Validation(_))));
+        let unchanged = ChatAgent::find_by_id(&pool, planner)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(unchanged.name, "planner");
+
+        // Agents that share no session may share a name.
+        let renamed = rename_agent(&pool, outsider, "reviewer", RenameAgentOptions::default())
+            .await
+            .expect("rename agent outside the session");
+        assert_eq!(renamed.name, "reviewer");
+        // Keeping its own name (in another case) isn't a clash either.
+        rename_agent(&pool, planner, "Planner", RenameAgentOptions::default())
+            .await
+            .expect("rename agent to its own name");
+    }
+
+    #[test]
+    fn rewrite_handle_in_content_treats_directives_like_mentions() {
+        let content = "[sendMessageTo@@{Planner}] go, `[sendMessageTo@@{planner}]` stays, \
+                       [sendMessageTo@@{planner-bot}] too";
+        assert_eq!(
+            rewrite_handle_in_content(content, "planner", "architect").as_deref(),
+            Some(
+                "[sendMessageTo@@{architect}] go, `[sendMessageTo@@{planner}]` stays, \
+                 [sendMessageTo@@{planner-bot}] too"
+            )
+        );
+        assert_eq!(
+            rewrite_handle_in_content("```\n[sendMessageTo@@{planner}]\n```", "planner", "x"),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn gc_orphaned_attachments_only_removes_unreferenced_files() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let root = tempfile::tempdir().expect("create asset root");
+        let message_id = Uuid::new_v4();
+        let attachments = root.path().join(format!(
+            "chat/session_{session_id}/attachments/{message_id}"
+        ));
+        std::fs::create_dir_all(&attachments).unwrap();
+        std::fs::write(attachments.join("kept_notes.txt"), "kept").unwrap();
+        std::fs::write(attachments.join("orphan_notes.txt"), "orphan").unwrap();
+
+        let meta = serde_json::json!({
+            "attachments": [ChatAttachmentMeta {
+                id: Uuid::new_v4(),
+                name: "notes.txt".to_string(),
+                mime_type: Some("text/plain".to_string()),
+                size_bytes: 4,
+                kind: "text".to_string(),
+                relative_path: format!(
+                    "chat/session_{session_id}/attachments/{message_id}/kept_notes.txt"
+                ),
+            }]
+        });
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            String::new(),
+            Some(meta),
+        )
+        .await
+        .expect("create message with attachment");
+
+        let orphan = attachments.join("orphan_notes.txt");
+        let found = find_orphaned_attachments(&pool, root.path())
+            .await
+            .expect("find orphans");
+        assert_eq!(found, vec![orphan.clone()]);
+
+        let deleted = gc_orphaned_attachments(&pool, root.path())
+            .await
+            .expect("gc orphans");
+        assert_eq!(deleted, vec![orphan.clone()]);
+        assert!(!orphan.exists());
+        assert!(attachments.join("kept_notes.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn create_message_validates_reply_references() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let other_session_id = create_test_session(&pool).await;
+        let original = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "original".to_string(),
+            None,
+        )
+        .await
+        .expect("create original");
+        let elsewhere = create_message(
+            &pool,
+            other_session_id,
+            ChatSenderType::User,
+            None,
+            "elsewhere".to_string(),
+            None,
+        )
+        .await
+        .expect("create message in other session");
+
+        let reply = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "reply".to_string(),
+            Some(serde_json::json!({ "reference_message_id": original.id.to_string() })),
+        )
+        .await
+        .expect("same-session reference is accepted");
+        assert_eq!(
+            reply.meta.0["reference"],
+            serde_json::json!({ "message_id": original.id })
+        );
+        assert!(reply.meta.0.get("reference_message_id").is_none());
+
+        let cross_session = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "reply".to_string(),
+            Some(serde_json::json!({ "reference": { "message_id": elsewhere.id } })),
+        )
+        .await;
+        assert!(matches!(
+            cross_session,
+            Err(ChatServiceError::Validation(_))
+        ));
+
+        let missing = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "reply".to_string(),
+            Some(serde_json::json!({ "reference": { "message_id": Uuid::new_v4() } })),
+        )
+        .await;
+        assert!(matches!(missing, Err(ChatServiceError::Validation(_))));
+    }
+
+    #[tokio::test]
+    async fn system_actor_overrides_system_label() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let message = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::System,
+            None,
+            "Nightly run starts in 5 minutes".to_string(),
+            Some(serde_json::json!({ "system_actor": "scheduler" })),
+        )
+        .await
+        .expect("create system message");
+        assert_eq!(message.sender_type, ChatSenderType::System);
+        assert_eq!(message.meta.0["sender"]["label"], "scheduler");
+        assert_eq!(message.meta.0["structured"]["sender_label"], "scheduler");
+
+        let structured = build_structured_messages(&pool, session_id)
+            .await
+            .expect("build structured messages");
+        assert_eq!(structured[0]["sender"]["label"], "scheduler");
+
+        let simplified = build_simplified_messages(&pool, session_id)
+            .await
+            .expect("build simplified messages");
+        assert_eq!(simplified[0].sender, "system:scheduler");
+    }
+
+    #[tokio::test]
+    async fn create_messages_batch_inserts_in_order() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let batch = (0..50)
+            .map(|i| NewMessage {
+                sender_type: ChatSenderType::User,
+                sender_id: None,
+                content: format!("message {i} for @coder"),
+                meta: None,
+            })
+            .collect();
+
+        let created = create_messages_batch(&pool, session_id, batch)
+            .await
+            .expect("create batch");
+        assert_eq!(created.len(), 50);
+        assert_eq!(created[7].mentions.0, vec!["coder"]);
+
+        let stored = ChatMessage::find_by_session_id(&pool, session_id, None)
+            .await
+            .expect("load messages");
+        let contents: Vec<String> = stored.into_iter().map(|m| m.content).collect();
+        let expected: Vec<String> = (0..50).map(|i| format!("message {i} for @coder")).collect();
+        assert_eq!(contents, expected);
+    }
+
+    #[tokio::test]
+    async fn failed_message_transaction_leaves_no_message_or_touch() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let before = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .updated_at;
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let data = CreateChatMessage {
+            session_id,
+            sender_type: ChatSenderType::User,
+            sender_id: None,
+            content: "never stored".to_string(),
+            mentions: Vec::new(),
+            meta: serde_json::json!({}),
+        };
+        let result = insert_message_and_touch(&pool, &data, Uuid::new_v4(), |_| {
+            Err(ChatServiceError::Validation("injected failure".to_string()))
+        })
+        .await;
+        assert!(matches!(result, Err(ChatServiceError::Validation(_))));
+
+        let messages = ChatMessage::find_by_session_id(&pool, session_id, None)
+            .await
+            .expect("load messages");
+        assert!(messages.is_empty());
+        let after = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .updated_at;
+        assert_eq!(after, before);
+
+        let message = insert_message_and_touch(&pool, &data, Uuid::new_v4(), |_| Ok(()))
+            .await
+            .expect("commit message");
+        let stored = ChatMessage::find_by_id(&pool, message.id)
+            .await
+            .unwrap()
+            .expect("committed message");
+        assert_eq!(stored.created_at, message.created_at);
+        let touched = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap()
+            .updated_at;
+        assert!(touched > before);
+    }
+
+    #[test]
+    fn mention_limit_allows_cap_and_rejects_above_it() {
+        let handles = |count: usize| -> Vec<String> {
+            parse_mentions(
+                &(0..count)
+                    .map(|i| format!("@agent{i}"))
+                    .collect::<Vec<_>>()
+                    .join(" "),
+            )
+        };
+
+        assert!(check_mention_limit(&handles(20), 20).is_ok());
+        assert!(matches!(
+            check_mention_limit(&handles(21), 20),
+            Err(ChatServiceError::Validation(_))
+        ));
+
+        // Repeated mentions of the same handle count once.
+        let repeated = parse_mentions(&"@coder @Coder ".repeat(30));
+        assert!(check_mention_limit(&repeated, 1).is_ok());
+    }
+
+    #[tokio::test]
+    async fn list_sessions_with_preview_shows_latest_message() {
+        let pool = setup_chat_pool().await;
+        let empty_session = create_test_session(&pool).await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        let long_reply = "x".repeat(200);
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            long_reply,
+            Some(serde_json::json!({ "sender_handle": "alice" })),
+        )
+        .await
+        .expect("create latest message");
+
+        let previews = list_sessions_with_preview(&pool, 10, 0)
+            .await
+            .expect("list previews");
+        assert_eq!(previews.len(), 2);
+
+        let latest = &previews[0];
+        assert_eq!(latest.session_id, session_id);
+        assert_eq!(latest.last_sender_label.as_deref(), Some("alice"));
+        let snippet = latest.last_message_snippet.as_deref().unwrap();
+        assert_eq!(snippet, format!("{}…", "x".repeat(120)));
+
+        assert_eq!(previews[1].session_id, empty_session);
+        assert!(previews[1].last_message_snippet.is_none());
+        assert!(previews[1].last_sender_label.is_none());
+
+        let paged = list_sessions_with_preview(&pool, 1, 1)
+            .await
+            .expect("list second page");
+        assert_eq!(paged.len(), 1);
+        assert_eq!(paged[0].session_id, empty_session);
+    }
+
+    #[tokio::test]
+    async fn unread_count_tracks_read_cursor() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        // One user message and two agent replies.
+        assert_eq!(unread_count(&pool, session_id).await.unwrap(), 2);
+
+        let messages = ChatMessage::find_by_session_id(&pool, session_id, None)
+            .await
+            .expect("load messages");
+        mark_session_read(&pool, session_id, messages[1].id)
+            .await
+            .expect("mark read");
+        assert_eq!(unread_count(&pool, session_id).await.unwrap(), 1);
+
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "thanks, looks good".to_string(),
+            None,
+        )
+        .await
+        .expect("create user message");
+        assert_eq!(unread_count(&pool, session_id).await.unwrap(), 1);
+
+        mark_session_read(&pool, session_id, messages[2].id)
+            .await
+            .expect("mark all read");
+        assert_eq!(unread_count(&pool, session_id).await.unwrap(), 0);
+
+        let other_session = create_test_session(&pool).await;
+        assert!(matches!(
+            mark_session_read(&pool, other_session, messages[0].id).await,
+            Err(ChatServiceError::Validation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn context_preamble_is_prepended_first() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "please draft the plan".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+
+        let mut config = Config::default();
+        config.chat_presets.context_preamble = Some("  Respond in Markdown.  ".to_string());
+        let options = ContextBuildOptions::from_config(&config);
+        let compacted =
+            build_compacted_context_with_options(&pool, session_id, None, None, None, &options)
+                .await
+                .expect("build context");
+        let full = build_full_context(&pool, session_id, &options)
+            .await
+            .expect("build full context");
+
+        for context in [compacted, full] {
+            assert_eq!(context.messages.len(), 2);
+            assert_eq!(context.messages[0]["sender"], CONTEXT_PREAMBLE_SENDER);
+            assert_eq!(context.messages[0]["content"], "Respond in Markdown.");
+            assert_eq!(context.messages[1]["content"], "please draft the plan");
+            assert!(context.jsonl.starts_with('{'));
+            assert!(
+                context
+                    .jsonl
+                    .lines()
+                    .next()
+                    .is_some_and(|line| line.contains("Respond in Markdown."))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn mention_graph_counts_cross_mentions() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let coder = create_test_agent(&pool, "coder").await;
+        for (sender_type, sender_id, handle, content) in [
+            (
+                ChatSenderType::User,
+                None,
+                Some("alice"),
+                "@coder please start",
+            ),
+            (
+                ChatSenderType::Agent,
+                Some(coder),
+                None,
+                "done [sendMessageTo@@alice] please review",
+            ),
+            (
+                ChatSenderType::User,
+                None,
+                Some("alice"),
+                "@coder one more fix, cc @ghost",
+            ),
+            (
+                ChatSenderType::Agent,
+                Some(coder),
+                None,
+                "[sendMessageTo@@{alice}] fixed",
+            ),
+        ] {
+            create_message(
+                &pool,
+                session_id,
+                sender_type,
+                sender_id,
+                content.to_string(),
+                handle.map(|handle| serde_json::json!({ "sender_handle": handle })),
+            )
+            .await
+            .expect("create message");
+        }
+
+        let graph = session_mention_graph(&pool, session_id)
+            .await
+            .expect("build mention graph");
+
+        // Agents address others with send-message directives, not `@`.
+
+        let edges: Vec<(&str, &str, u32)> = graph
+            .edges
+            .iter()
+            .map(|edge| (edge.from.as_str(), edge.to.as_str(), edge.count))
+            .collect();
+        assert_eq!(
+            edges,
+            vec![
+                ("alice", "coder", 2),
+                ("alice", "ghost", 1),
+                ("coder", "alice", 2),
+            ]
+        );
+        let ghost = graph
+            .nodes
+            .iter()
+            .find(|node| node.label == "ghost")
+            .expect("unresolved handle kept as node");
+        assert!(!ghost.resolved);
+        assert!(
+            graph
+                .nodes
+                .iter()
+                .filter(|node| node.label != "ghost")
+                .all(|node| node.resolved)
+        );
+    }
+
+    #[tokio::test]
+    async fn clone_session_copies_messages_up_to_fork_point() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let coder = create_test_agent(&pool, "coder").await;
+        ChatSessionAgent::create(
+            &pool,
+            &CreateChatSessionAgent {
+                session_id,
+                agent_id: coder,
+                workspace_path: Some("/tmp/coder".to_string()),
+            },
+            Uuid::new_v4(),
+        )
+        .await
+        .expect("add agent to session");
+        let mut originals = Vec::new();
+        for content in ["first", "second", "third"] {
+            originals.push(
+                create_message(
+                    &pool,
+                    session_id,
+                    ChatSenderType::User,
+                    None,
+                    content.to_string(),
+                    Some(serde_json::json!({ "sender_handle": "alice" })),
+                )
+                .await
+                .expect("create message"),
+            );
+            if content == "first" {
+                begin_agent_reply(&pool, session_id, coder)
+                    .await
+                    .expect("begin pending reply");
+                let cancelled = begin_agent_reply(&pool, session_id, coder)
+                    .await
+                    .expect("begin cancelled reply");
+                cancel_agent_reply(&pool, cancelled)
+                    .await
+                    .expect("cancel reply");
+            }
+        }
+        let store = InMemoryHistoryStore::default();
+        store
+            .write(
+                HistoryFileKind::Split,
+                &build_history_file(
+                    session_id,
+                    &[SimplifiedMessage {
+                        sender: "user:alice".to_string(),
+                        content: "older".to_string(),
+                        timestamp: "2000-01-01T00:00:00Z".to_string(),
+                        record: None,
+                    }],
+                    false,
+                    None,
+                ),
+            )
+            .await
+            .expect("seed split file");
+
+        let clone_id = clone_session_with_store(&pool, session_id, originals[1].id, &store)
+            .await
+            .expect("clone session");
+
+        let cloned = ChatMessage::find_by_session_id(&pool, clone_id, None)
+            .await
+            .expect("load clone");
+        let contents: Vec<&str> = cloned.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["first", "second"]);
+        assert!(
+            cloned
+                .iter()
+                .all(|m| originals.iter().all(|o| o.id != m.id))
+        );
+        assert_eq!(cloned[0].meta.0["sender_handle"], "alice");
+        let members = ChatSessionAgent::find_all_for_session(&pool, clone_id)
+            .await
+            .expect("load clone members");
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].agent_id, coder);
+        assert_eq!(members[0].workspace_path.as_deref(), Some("/tmp/coder"));
+
+        let clone = ChatSession::find_by_id(&pool, clone_id)
+            .await
+            .expect("load clone session")
+            .expect("clone exists");
+        assert_eq!(clone.status, ChatSessionStatus::Active);
+        assert!(
+            clone
+                .summary_text
+                .as_deref()
+                .is_some_and(|summary| summary.starts_with("Forked from session"))
+        );
+        assert!(
+            store
+                .exists(clone_id, HistoryFileKind::Split)
+                .await
+                .expect("check split file")
+        );
+
+        let original = ChatMessage::find_by_session_id(&pool, session_id, None)
+            .await
+            .expect("load original");
+        assert_eq!(original.len(), 5);
+        assert_eq!(
+            original
+                .iter()
+                .filter(|m| m.sender_type == ChatSenderType::User)
+                .map(|m| m.id)
+                .collect::<Vec<_>>(),
+            originals.iter().map(|m| m.id).collect::<Vec<_>>()
+        );
+
+        assert!(matches!(
+            clone_session(&pool, session_id, Uuid::new_v4()).await,
+            Err(ChatServiceError::Validation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn consecutive_agent_messages_merge_into_one_context_entry() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let coder = create_test_agent(&pool, "coder").await;
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "@coder status?".to_string(),
+            None,
+        )
+        .await
+        .expect("create user message");
+        for content in [
+            "step one [sendMessageTo@@alice]",
+            "step two [sendMessageTo@@bob]",
+            "done [sendMessageTo@@alice]",
+        ] {
+            create_message(
+                &pool,
+                session_id,
+                ChatSenderType::Agent,
+                Some(coder),
+                content.to_string(),
+                None,
+            )
+            .await
+            .expect("create agent message");
+        }
+
+        let messages = ChatMessage::find_by_session_id(&pool, session_id, None)
+            .await
+            .expect("load messages");
+        let latest = messages.last().expect("has messages").created_at;
+        let agent_map = std::collections::HashMap::from([(coder, "coder".to_string())]);
+        let merged = merge_consecutive_sender_messages(messages, &agent_map);
+        assert_eq!(merged.len(), 2);
+        assert_eq!(
+            merged[1].content,
+            "step one [sendMessageTo@@alice]\nstep two [sendMessageTo@@bob]\ndone [sendMessageTo@@alice]"
+        );
+        assert_eq!(merged[1].mentions.0, vec!["alice", "bob"]);
+        assert_eq!(merged[1].created_at, latest);
+
+        let context = build_compacted_context_with_options(
+            &pool,
+            session_id,
+            None,
+            None,
+            None,
+            &ContextBuildOptions {
+                merge_consecutive_senders: true,
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("build context");
+        assert_eq!(context.messages.len(), 2);
+        assert_eq!(context.messages[1]["sender"], "agent:coder");
+    }
+
+    #[test]
+    fn truncate_title_keeps_emoji_whole() {
+        // Cutting by chars would split the thumbs-up from its skin tone.
+        let title = format!("{}👍🏽👍🏽 done", "x".repeat(DERIVED_TITLE_MAX_CHARS - 1));
+        assert_eq!(
+            truncate_title(&title),
+            format!("{}👍🏽…", "x".repeat(DERIVED_TITLE_MAX_CHARS - 1))
+        );
+
+        let family = "👨‍👩‍👧".repeat(DERIVED_TITLE_MAX_CHARS + 1);
+        assert_eq!(
+            truncate_title(&family),
+            format!("{}…", "👨‍👩‍👧".repeat(DERIVED_TITLE_MAX_CHARS))
+        );
+        assert_eq!(truncate_title("Fix 🐛 in export"), "Fix 🐛 in export");
+    }
+
+    #[tokio::test]
+    async fn first_user_message_sets_truncated_session_title() {
+        let pool = setup_chat_pool().await;
+        let session_id =
+            ChatSession::create(&pool, &CreateChatSession { title: None }, Uuid::new_v4())
+                .await
+                .expect("create untitled session")
+                .id;
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "@coder @reviewer   Please refactor the session export pipeline so it streams pages instead of loading everything"
+                .to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .expect("load session")
+            .expect("session exists");
+        assert_eq!(
+            session.title.as_deref(),
+            Some("Please refactor the session export pipeline so it streams…")
+        );
+
+        // Later messages don't replace the title.
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "something else".to_string(),
+            None,
+        )
+        .await
+        .expect("create second message");
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .expect("load session")
+            .expect("session exists");
+        assert!(
+            session
+                .title
+                .as_deref()
+                .is_some_and(|title| title.starts_with("Please refactor"))
+        );
+    }
+
+    #[tokio::test]
+    async fn html_export_escapes_content_and_renders_code_blocks() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "<script>alert('x')</script> see:\n```js\nconst a = 1 < 2;\n```".to_string(),
+            Some(serde_json::json!({ "sender_handle": "alice" })),
+        )
+        .await
+        .expect("create message");
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .expect("load session")
+            .expect("session exists");
+
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("transcript.html");
+        export_session_html(&pool, &session, &path)
+            .await
+            .expect("export html");
+
+        let html = std::fs::read_to_string(&path).expect("read html");
+        assert!(html.contains("&lt;script&gt;alert(&#39;x&#39;)&lt;/script&gt;"));
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("<pre><code class=\"language-js\">"));
+        assert!(html.contains("<span class=\"tok-num\">1</span> &lt; "));
+    }
+
+    #[tokio::test]
+    async fn html_export_links_attachments_relative_to_the_file() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let attachment = |name: &str, relative_path: &str| {
+            serde_json::json!({
+                "id": Uuid::new_v4(),
+                "name": name,
+                "relative_path": relative_path,
+                "kind": "file",
+                "mime_type": "text/plain",
+                "size_bytes": 5,
+            })
+        };
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "files".to_string(),
+            Some(serde_json::json!({
+                "attachments": [
+                    attachment("notes.txt", "chat/uploads/notes.txt"),
+                    attachment("evil", "javascript:alert(1)"),
+                    attachment("remote", "https://example.com/x"),
+                ]
+            })),
+        )
+        .await
+        .expect("create message");
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap();
 
-    let source_message_count = parse_required_usize(&row, "source_message_count")?;
-    let token_threshold = parse_required_u32(&row, "token_threshold")?;
-    let compression_percentage = parse_required_u32(&row, "compression_percentage")?;
-    let compression_percentage = match u8::try_from(compression_percentage) {
-        Ok(value) => value,
-        Err(err) => {
-            tracing::warn!(
-                session_id = %session_id,
-                compression_percentage = compression_percentage,
-                error = %err,
-                "Persisted compression percentage is invalid; ignoring persisted state"
-            );
-            return Ok(None);
+        let root = tempfile::tempdir().expect("tempdir");
+        let attachments_root = root.path().join("assets");
+        let path = root.path().join("shared").join("transcript.html");
+        write_session_html(&pool, &session, &path, &attachments_root)
+            .await
+            .expect("export html");
+
+        let html = std::fs::read_to_string(&path).expect("read html");
+        assert!(html.contains("<a href=\"../assets/chat/uploads/notes.txt\">notes.txt</a>"));
+        assert!(html.contains("<li>evil</li>"));
+        assert!(html.contains("<li>remote</li>"));
+        assert!(!html.contains("javascript:"));
+        assert!(!html.contains("https://example.com"));
+    }
+
+    fn turn_order_presets() -> ChatPresetsConfig {
+        let mut presets = ChatPresetsConfig {
+            members: Vec::new(),
+            teams: Vec::new(),
+            context_preamble: None,
+        };
+        for (name, sort_order) in [("reviewer", None), ("coder", Some(2)), ("planner", Some(1))] {
+            presets.members.push(ChatMemberPreset {
+                id: name.to_string(),
+                name: name.to_string(),
+                description: String::new(),
+                runner_type: None,
+                system_prompt: String::new(),
+                default_workspace_path: None,
+                tools_enabled: serde_json::json!({}),
+                is_builtin: false,
+                enabled: true,
+                tags: Vec::new(),
+                sort_order,
+                extends: None,
+            });
         }
-    };
-    let source_token_count = parse_required_u32(&row, "source_token_count")?;
-    let effective_token_count = parse_required_u32(&row, "effective_token_count")?;
+        presets
+    }
 
-    let compression_type_raw: String = row.try_get("compression_type")?;
-    let Some(compression_type) = compression_type_from_db_value(&compression_type_raw) else {
-        tracing::warn!(
-            session_id = %session_id,
-            compression_type = %compression_type_raw,
-            "Persisted compression type is invalid; ignoring persisted state"
-        );
-        return Ok(None);
-    };
+    #[test]
+    fn next_responders_follows_each_turn_order() {
+        let presets = turn_order_presets();
+        let mentions: Vec<String> = ["reviewer", "ghost", "coder", "Planner", "coder"]
+            .iter()
+            .map(|handle| handle.to_string())
+            .collect();
 
-    let warning = row
-        .try_get::<Option<String>, _>("warning_json")?
-        .and_then(|raw| serde_json::from_str::<CompressionWarning>(&raw).ok());
+        assert_eq!(
+            next_responders(&presets, &mentions, TurnOrder::MentionOrder),
+            vec!["reviewer", "ghost", "coder", "Planner"]
+        );
+        assert_eq!(
+            next_responders(&presets, &mentions, TurnOrder::PresetOrder),
+            vec!["Planner", "coder", "reviewer", "ghost"]
+        );
+        assert_eq!(
+            next_responders(&presets, &mentions, TurnOrder::RoundRobin { turn: 1 }),
+            vec!["ghost", "coder", "Planner", "reviewer"]
+        );
+        assert_eq!(
+            next_responders(&presets, &mentions, TurnOrder::RoundRobin { turn: 5 }),
+            vec!["ghost", "coder", "Planner", "reviewer"]
+        );
+    }
 
-    let result_messages_json: String = row.try_get("result_messages_json")?;
-    let result_messages =
-        match serde_json::from_str::<Vec<SimplifiedMessage>>(&result_messages_json) {
-            Ok(messages) => messages,
-            Err(err) => {
-                tracing::warn!(
-                    session_id = %session_id,
-                    error = %err,
-                    "Persisted compression result messages are invalid; ignoring persisted state"
-                );
-                return Ok(None);
-            }
+    #[tokio::test]
+    async fn system_messages_are_dropped_when_filtered() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        for (sender_type, content, meta) in [
+            (ChatSenderType::User, "hello", None),
+            (ChatSenderType::System, "agent joined", None),
+            (
+                ChatSenderType::System,
+                "workspace switched to /repo",
+                Some(serde_json::json!({ "context_relevant": true })),
+            ),
+        ] {
+            create_message(
+                &pool,
+                session_id,
+                sender_type,
+                None,
+                content.to_string(),
+                meta,
+            )
+            .await
+            .expect("create message");
+        }
+        let contents = |messages: Vec<serde_json::Value>| -> Vec<String> {
+            messages
+                .iter()
+                .map(|message| message["content"].as_str().unwrap_or_default().to_string())
+                .collect()
         };
 
-    Ok(Some(CompressionCacheEntry {
-        source_fingerprint,
-        source_message_count,
-        token_threshold,
-        compression_percentage,
-        source_token_count,
-        effective_token_count,
-        result: CompressionResult {
-            messages: result_messages,
-            compression_type,
-            warning,
-        },
-    }))
-}
+        let all = build_structured_messages(&pool, session_id)
+            .await
+            .expect("build all");
+        assert_eq!(all.len(), 3);
+        let relevant = build_structured_messages_filtered(
+            &pool,
+            session_id,
+            SystemMessageFilter::ContextRelevantOnly,
+        )
+        .await
+        .expect("build relevant");
+        assert_eq!(
+            contents(relevant),
+            vec!["hello", "workspace switched to /repo"]
+        );
 
-async fn get_compression_cache_entry(
-    pool: &SqlitePool,
-    session_id: Uuid,
-) -> Result<Option<CompressionCacheEntry>, ChatServiceError> {
-    if let Some(cached) = COMPRESSION_RESULT_CACHE.get(&session_id) {
-        return Ok(Some(cached.clone()));
+        let context = build_compacted_context_with_options(
+            &pool,
+            session_id,
+            None,
+            None,
+            None,
+            &ContextBuildOptions {
+                system_messages: SystemMessageFilter::Exclude,
+                ..Default::default()
+            },
+        )
+        .await
+        .expect("build context");
+        assert_eq!(contents(context.messages), vec!["hello"]);
     }
 
-    let persisted = load_persisted_compression_result(pool, session_id).await?;
-    if let Some(entry) = persisted.as_ref() {
-        COMPRESSION_RESULT_CACHE.insert(session_id, entry.clone());
-        tracing::debug!(
-            session_id = %session_id,
-            source_messages = entry.source_message_count,
-            compression_type = ?entry.result.compression_type,
-            "Loaded persisted compression cache state from database"
+    #[test]
+    fn normalize_content_collapses_blank_lines_outside_code() {
+        assert_eq!(
+            normalize_content("first   \n\n\n\n\nsecond\t\n  \n \nthird"),
+            "first\n\nsecond\n\nthird"
         );
     }
 
-    Ok(persisted)
-}
+    #[test]
+    fn normalize_content_preserves_code_blocks() {
+        let code = "```\nfn main() {   \n\n\n\n}\n```";
+        assert_eq!(
+            normalize_content(&format!("intro\n\n\n{code}\n\n\n\nend  ")),
+            format!("intro\n\n{code}\n\nend")
+        );
+        // An unterminated fence keeps everything after it.
+        assert_eq!(
+            normalize_content("x\n```\na  \n\n\n\nb"),
+            "x\n```\na  \n\n\n\nb"
+        );
+    }
 
-/// Compress messages if they exceed the token threshold
-///
-/// This function implements the compression strategy:
-/// 1. Calculate total token count using tiktoken
-/// 2. If under threshold, return messages unchanged
-/// 3. If over threshold:
-///    - Select a prefix whose tokens are >= configured compression percentage
-///    - Try AI summarization with each session agent
-///    - If all agents fail, truncate to cutoff file and return warning
-///
-/// # Arguments
-/// * `pool` - Database connection pool
-/// * `session_id` - Chat session ID
-/// * `messages` - Messages to potentially compress
-/// * `token_threshold` - Token count that triggers compression
-/// * `compression_percentage` - Percentage of messages to compress (default 25)
-/// * `session_agents` - AI agents in the session for summarization
-/// * `workspace_path` - Workspace path for running agents
-/// * `context_dir` - Path to context directory for storing cutoff files
-#[allow(clippy::too_many_arguments)]
-pub async fn compress_messages_if_needed(
-    pool: &SqlitePool,
-    session_id: Uuid,
-    messages: Vec<SimplifiedMessage>,
-    token_threshold: u32,
-    compression_percentage: u8,
-    session_agents: &[ChatSessionAgent],
-    workspace_path: &Path,
-    context_dir: Option<&Path>,
-) -> Result<CompressionResult, ChatServiceError> {
-    let source_messages = messages;
-    let source_fingerprint = calculate_messages_fingerprint(&source_messages);
-    let source_token_count = estimate_token_count(&source_messages);
-    let mut effective_messages = source_messages.clone();
-    let mut inherited_compression_type: Option<CompressionType> = None;
-    let mut inherited_warning: Option<CompressionWarning> = None;
-    let cached_entry = get_compression_cache_entry(pool, session_id).await?;
+    #[test]
+    fn parse_mentions_accepts_unicode_handles() {
+        assert_eq!(
+            parse_mentions("请 @架构师 review, then @agent架构 implement"),
+            vec!["架构师", "agent架构"]
+        );
+        // Full-width punctuation ends a handle.
+        assert_eq!(parse_mentions("@架构师，请看一下"), vec!["架构师"]);
+        // A handle character before `@` still means it isn't a mention.
+        assert!(parse_mentions("联系架构师@example.com").is_empty());
+    }
 
-    if let Some(cached) = cached_entry.as_ref()
-        && cached.source_fingerprint == source_fingerprint
-        && cached.token_threshold == token_threshold
-        && cached.compression_percentage == compression_percentage
-    {
-        tracing::debug!(
-            session_id = %session_id,
-            source_tokens = cached.source_token_count,
-            effective_tokens = cached.effective_token_count,
-            compression_type = ?cached.result.compression_type,
-            "Using cached compression result for unchanged session history"
+    #[test]
+    fn parse_mentions_skips_fenced_code_blocks() {
+        let content =
+            "@alice please check:\n```python\n@decorator\ndef f(): pass\n```\nthanks @bob";
+        assert_eq!(parse_mentions(content), vec!["alice", "bob"]);
+        // An unterminated fence runs to the end of the message.
+        assert_eq!(
+            parse_mentions("@alice\n```\n@coder never pinged"),
+            vec!["alice"]
         );
-        return Ok(cached.result.clone());
     }
-    if let Some(cached) = cached_entry.as_ref()
-        && cached.token_threshold == token_threshold
-        && cached.compression_percentage == compression_percentage
-        && cached.source_message_count <= source_messages.len()
-    {
-        let prefix_fingerprint =
-            calculate_messages_fingerprint(&source_messages[..cached.source_message_count]);
-        if prefix_fingerprint == cached.source_fingerprint {
-            let mut merged = cached.result.messages.clone();
-            merged.extend_from_slice(&source_messages[cached.source_message_count..]);
-            effective_messages = merged;
-            if cached.result.compression_type != CompressionType::None {
-                inherited_compression_type = Some(cached.result.compression_type.clone());
-                inherited_warning = cached.result.warning.clone();
-            }
-            tracing::debug!(
-                session_id = %session_id,
-                base_source_messages = cached.source_message_count,
-                new_messages = source_messages.len().saturating_sub(cached.source_message_count),
-                inherited_compression_type = ?cached.result.compression_type,
-                "Using incremental compression base for appended session history"
-            );
-        }
+
+    #[test]
+    fn parse_mentions_skips_inline_code() {
+        assert_eq!(
+            parse_mentions("use `@Override` here, @alice, and ``a ` @b``"),
+            vec!["alice"]
+        );
+        // An unmatched backtick is literal, so the mention still counts.
+        assert_eq!(parse_mentions("it's a ` tick @alice"), vec!["alice"]);
     }
 
-    let token_count = estimate_token_count(&effective_messages);
+    #[tokio::test]
+    async fn streamed_export_matches_in_memory_export() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        for i in 0..4 {
+            create_message(
+                &pool,
+                session_id,
+                ChatSenderType::User,
+                None,
+                format!("follow-up {i}"),
+                None,
+            )
+            .await
+            .expect("create message");
+        }
 
-    tracing::debug!(
-        session_id = %session_id,
-        source_token_count = source_token_count,
-        effective_token_count = token_count,
-        token_count = token_count,
-        threshold = token_threshold,
-        "Checking if compression is needed"
-    );
+        let mut expected = Vec::new();
+        for message in build_structured_messages(&pool, session_id).await.unwrap() {
+            expected.extend_from_slice(serde_json::to_string(&message).unwrap().as_bytes());
+            expected.push(b'\n');
+        }
 
-    // If under threshold, no compression needed
-    if token_count <= token_threshold {
-        let compression_type = inherited_compression_type.unwrap_or(CompressionType::None);
-        let warning = if compression_type == CompressionType::None {
-            None
-        } else {
-            inherited_warning
-        };
-        let result = CompressionResult {
-            messages: effective_messages,
-            compression_type,
-            warning,
-        };
-        cache_compression_result(
-            pool,
+        // A small page size forces several keyset pages.
+        let mut streamed = Vec::new();
+        write_structured_messages_jsonl(
+            &pool,
             session_id,
-            source_fingerprint,
-            source_messages.len(),
-            token_threshold,
-            compression_percentage,
-            source_token_count,
-            &result,
+            &mut streamed,
+            2,
+            &std::collections::HashMap::new(),
+            None,
+            &|_| {},
         )
-        .await;
-        return Ok(result);
+        .await
+        .expect("stream export");
+        assert_eq!(streamed, expected);
+
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let archive_dir = tempfile::tempdir().expect("create archive dir");
+        export_session_archive(&pool, &session, archive_dir.path())
+            .await
+            .expect("export archive");
+        let written = std::fs::read(archive_dir.path().join("messages_export.jsonl")).unwrap();
+        assert_eq!(written, expected);
     }
 
-    let total_messages = effective_messages.len();
-    if total_messages == 0 {
-        let result = CompressionResult {
-            messages: effective_messages,
-            compression_type: CompressionType::None,
-            warning: None,
+    #[tokio::test]
+    async fn archive_export_copies_attachments() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let attachments_root = tempfile::tempdir().expect("create attachments root");
+        let stored = format!("chat/session_{session_id}/attachments/notes.txt");
+        let source = attachments_root.path().join(&stored);
+        std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+        std::fs::write(&source, "attachment body").unwrap();
+        let missing = format!("chat/session_{session_id}/attachments/gone.png");
+        let attachment = |name: &str, relative_path: &str| {
+            serde_json::json!({
+                "id": Uuid::new_v4(),
+                "name": name,
+                "mime_type": null,
+                "size_bytes": 0,
+                "kind": "file",
+                "relative_path": relative_path,
+            })
         };
-        cache_compression_result(
-            pool,
+        let message = create_message(
+            &pool,
             session_id,
-            source_fingerprint,
-            source_messages.len(),
-            token_threshold,
-            compression_percentage,
-            source_token_count,
-            &result,
+            ChatSenderType::User,
+            None,
+            "see attached".to_string(),
+            Some(serde_json::json!({
+                "attachments": [attachment("notes.txt", &stored), attachment("gone.png", &missing)],
+            })),
         )
-        .await;
-        return Ok(result);
-    }
+        .await
+        .expect("create message");
+
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let archive_dir = tempfile::tempdir().expect("create archive dir");
+        export_session_archive_with_attachment_root(
+            &pool,
+            &session,
+            archive_dir.path(),
+            attachments_root.path(),
+        )
+        .await
+        .expect("export archive");
 
-    let (messages_to_compress_count, target_compress_tokens, selected_compress_tokens) =
-        select_messages_to_compress_by_token(
-            &effective_messages,
-            token_count,
-            compression_percentage,
+        let archived = format!("attachments/{}/notes.txt", message.id);
+        assert_eq!(
+            std::fs::read_to_string(archive_dir.path().join(&archived)).unwrap(),
+            "attachment body"
         );
+        let exported =
+            std::fs::read_to_string(archive_dir.path().join("messages_export.jsonl")).unwrap();
+        let line: serde_json::Value =
+            serde_json::from_str(exported.lines().next().unwrap()).unwrap();
+        let paths: Vec<&str> = line["meta"]["attachments"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|attachment| attachment["relative_path"].as_str().unwrap())
+            .collect();
+        assert_eq!(paths, vec![archived.as_str(), missing.as_str()]);
 
-    let (messages_to_compress, messages_to_keep) =
-        effective_messages.split_at(messages_to_compress_count);
-
-    tracing::info!(
-        session_id = %session_id,
-        total = total_messages,
-        total_tokens = token_count,
-        target_compress_tokens = target_compress_tokens,
-        selected_compress_tokens = selected_compress_tokens,
-        to_compress = messages_to_compress_count,
-        to_keep = messages_to_keep.len(),
-        "Compressing messages"
-    );
+        let manifest: serde_json::Value = serde_json::from_slice(
+            &std::fs::read(archive_dir.path().join("manifest.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(
+            manifest["missing_attachments"],
+            serde_json::json!([missing])
+        );
+        verify_session_archive(archive_dir.path())
+            .await
+            .expect("archive with attachments verifies");
+    }
 
-    // Try AI summarization with available agents
-    if !session_agents.is_empty()
-        && let Some(summary) = try_summarize_with_agents(
-            pool,
+    #[tokio::test]
+    async fn archive_import_restores_attachments() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let export_root = tempfile::tempdir().expect("create export root");
+        let stored = format!("chat/session_{session_id}/attachments/notes.txt");
+        let source = export_root.path().join(&stored);
+        std::fs::create_dir_all(source.parent().unwrap()).unwrap();
+        std::fs::write(&source, "attachment body").unwrap();
+        create_message(
+            &pool,
             session_id,
-            session_agents,
-            messages_to_compress,
-            workspace_path,
+            ChatSenderType::User,
+            None,
+            "see attached".to_string(),
+            Some(serde_json::json!({
+                "attachments": [{
+                    "id": Uuid::new_v4(),
+                    "name": "notes.txt",
+                    "mime_type": "text/plain",
+                    "size_bytes": 15,
+                    "kind": "file",
+                    "relative_path": stored,
+                }],
+            })),
         )
         .await
-    {
-        // Create summary message and prepend to kept messages
-        let summary_message = SimplifiedMessage {
-            sender: "system:summary".to_string(),
-            content: format!("[History Summary]\n{}", summary),
-            timestamp: Utc::now().to_rfc3339(),
+        .expect("create message");
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let archive_dir = tempfile::tempdir().expect("create archive dir");
+        export_session_archive_with_attachment_root(
+            &pool,
+            &session,
+            archive_dir.path(),
+            export_root.path(),
+        )
+        .await
+        .expect("export archive");
+
+        let open_attachment = |root: &std::path::Path, message: &ChatMessage| {
+            let attachments = extract_attachments(&message.meta.0);
+            let relative_path = &attachments[0].relative_path;
+            assert!(
+                relative_path.starts_with(&format!("chat/session_{}/", message.session_id)),
+                "not asset-relative: {relative_path}"
+            );
+            std::fs::read_to_string(root.join(relative_path)).expect("open attachment")
         };
 
-        let mut result_messages = vec![summary_message];
-        result_messages.extend(messages_to_keep.to_vec());
-        let compressed_token_count = estimate_token_count(&result_messages);
+        let import_pool = setup_chat_pool().await;
+        let import_root = tempfile::tempdir().expect("create import root");
+        let entry = SessionArchiveManifestEntry {
+            session_id,
+            title: session.title.clone(),
+            status: session.status.clone(),
+            folder: String::new(),
+        };
+        import_session_archive_with_attachment_root(
+            &import_pool,
+            &entry,
+            archive_dir.path(),
+            import_root.path(),
+        )
+        .await
+        .expect("import archive");
+        let imported = ChatMessage::find_by_session_id(&import_pool, session_id, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            open_attachment(import_root.path(), &imported[0]),
+            "attachment body"
+        );
 
-        if compressed_token_count >= token_count {
-            tracing::warn!(
-                session_id = %session_id,
-                before_tokens = token_count,
-                after_tokens = compressed_token_count,
-                "AI summarization did not reduce token usage, falling back to truncation"
-            );
-        } else {
-            tracing::info!(
-                session_id = %session_id,
-                before_tokens = token_count,
-                after_tokens = compressed_token_count,
-                "AI summarization reduced token usage"
-            );
-            let result = CompressionResult {
-                messages: result_messages,
-                compression_type: CompressionType::AiSummarized,
-                warning: None,
-            };
-            cache_compression_result(
-                pool,
-                session_id,
-                source_fingerprint,
-                source_messages.len(),
-                token_threshold,
-                compression_percentage,
-                source_token_count,
-                &result,
-            )
-            .await;
-            return Ok(result);
-        }
+        let continued_id = continue_session_from_archive_with_attachment_root(
+            &import_pool,
+            archive_dir.path(),
+            import_root.path(),
+        )
+        .await
+        .expect("continue from archive");
+        let continued = ChatMessage::find_by_session_id(&import_pool, continued_id, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            open_attachment(import_root.path(), &continued[0]),
+            "attachment body"
+        );
     }
 
-    // All agents failed - fallback to truncation
-    tracing::warn!(
-        session_id = %session_id,
-        "AI summarization failed, falling back to truncation"
-    );
+    #[tokio::test]
+    async fn export_and_import_report_progress() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let total = ChatMessage::find_by_session_id(&pool, session_id, None)
+            .await
+            .unwrap()
+            .len();
 
-    // Write messages to cutoff file in context directory
-    let cutoff_path = if let Some(ctx_dir) = context_dir {
-        // Find next available cutoff index
-        let mut index = 0;
-        loop {
-            let candidate = ctx_dir.join(format!("cutoff_message_{}.json", index));
-            if !candidate.exists() {
-                break candidate;
-            }
-            index += 1;
-        }
-    } else {
-        // Fallback to legacy split file if no context_dir provided
-        append_to_split_file(session_id, messages_to_compress)
+        let archive_dir = tempfile::tempdir().expect("create archive dir");
+        let exported = std::sync::Mutex::new(Vec::new());
+        export_session_archive_with_progress(&pool, &session, archive_dir.path(), |update| {
+            exported.lock().unwrap().push(update)
+        })
+        .await
+        .expect("export archive");
+        let exported = exported.into_inner().unwrap();
+        assert_eq!(
+            exported.iter().map(|update| update.batch).sum::<usize>(),
+            total
+        );
+        assert_eq!(exported.last().map(|update| update.processed), Some(total));
+
+        ChatSession::delete(&pool, session_id)
             .await
-            .map_err(|e| {
-                ChatServiceError::Io(std::io::Error::other(format!(
-                    "Failed to create split file: {}",
-                    e
-                )))
-            })?
-    };
+            .expect("delete original");
+        let entry = SessionArchiveManifestEntry {
+            session_id,
+            title: session.title.clone(),
+            status: session.status.clone(),
+            folder: String::new(),
+        };
+        let imported = std::sync::Mutex::new(Vec::new());
+        import_session_archive_with_progress(&pool, &entry, archive_dir.path(), |update| {
+            imported.lock().unwrap().push(update)
+        })
+        .await
+        .expect("import archive");
+        let imported = imported.into_inner().unwrap();
+        assert_eq!(
+            imported.iter().map(|update| update.batch).sum::<usize>(),
+            total
+        );
+        assert!(imported.iter().all(|update| update.total == total));
+    }
 
-    // Write cutoff messages to file
-    if context_dir.is_some() {
-        let cutoff_data = serde_json::json!({
-            "session_id": session_id,
-            "cutoff_at": chrono::Utc::now().to_rfc3339(),
-            "message_count": messages_to_compress_count,
-            "messages": messages_to_compress,
-        });
-        let json_str = serde_json::to_string_pretty(&cutoff_data).map_err(|e| {
-            ChatServiceError::Io(std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                format!("Failed to serialize cutoff data: {}", e),
-            ))
-        })?;
-        fs::write(&cutoff_path, json_str).await?;
+    /// Span name and field names.
+    type CapturedSpan = (String, Vec<String>);
+
+    /// Records every span created.
+    struct SpanCapture(std::sync::Arc<std::sync::Mutex<Vec<CapturedSpan>>>);
+
+    impl<S: tracing::Subscriber> tracing_subscriber::Layer<S> for SpanCapture {
+        fn on_new_span(
+            &self,
+            attrs: &tracing::span::Attributes<'_>,
+            _id: &tracing::span::Id,
+            _ctx: tracing_subscriber::layer::Context<'_, S>,
+        ) {
+            let fields = attrs
+                .metadata()
+                .fields()
+                .iter()
+                .map(|field| field.name().to_string())
+                .collect();
+            self.0
+                .lock()
+                .unwrap()
+                .push((attrs.metadata().name().to_string(), fields));
+        }
     }
 
-    let cutoff_path_str = cutoff_path.to_string_lossy().to_string();
+    #[tokio::test]
+    async fn create_message_runs_in_a_span() {
+        use tracing_subscriber::layer::SubscriberExt;
 
-    // Keep a compact summary marker at the front so history file always contains
-    // "compressed context + remaining uncompressed messages".
-    let mut result_messages = vec![SimplifiedMessage {
-        sender: "system:summary".to_string(),
-        content: format!(
-            "[History Summary - Fallback]\nAI summarization failed; archived {} messages (~{} tokens) to {}",
-            messages_to_compress_count, selected_compress_tokens, cutoff_path_str
-        ),
-        timestamp: Utc::now().to_rfc3339(),
-    }];
-    result_messages.extend(messages_to_keep.to_vec());
+        let spans = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let subscriber = tracing_subscriber::registry().with(SpanCapture(spans.clone()));
+        let _guard = tracing::subscriber::set_default(subscriber);
 
-    // Return summary marker + remaining messages with warning
-    let result = CompressionResult {
-        messages: result_messages,
-        compression_type: CompressionType::Truncated,
-        warning: Some(CompressionWarning {
-            code: "COMPRESSION_FALLBACK".to_string(),
-            message: format!(
-                "AI summarization failed or was ineffective; archived {} messages (~{} tokens) to cutoff file",
-                messages_to_compress_count, selected_compress_tokens
-            ),
-            split_file_path: cutoff_path_str,
-        }),
-    };
-    cache_compression_result(
-        pool,
-        session_id,
-        source_fingerprint,
-        source_messages.len(),
-        token_threshold,
-        compression_percentage,
-        source_token_count,
-        &result,
-    )
-    .await;
-    Ok(result)
-}
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "traced".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
 
-#[cfg(test)]
-mod tests {
-    use db::models::chat_session_agent::{ChatSessionAgent, ChatSessionAgentState};
-    use sqlx::SqlitePool;
-    use uuid::Uuid;
+        let spans = spans.lock().unwrap();
+        let (_, fields) = spans
+            .iter()
+            .find(|(name, _)| name == "create_message")
+            .expect("create_message span");
+        assert!(fields.iter().any(|field| field == "session_id"));
+    }
 
-    use super::{
-        CompressionType, SimplifiedMessage, all_agents_running, compress_messages_if_needed,
-        limit_summary_input_messages, parse_mentions, parse_send_message_directives,
-        prioritize_summary_agents, select_messages_to_compress_by_token,
-    };
+    #[tokio::test]
+    async fn nested_archive_layout_uses_session_folder() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let archive_root = tempfile::tempdir().expect("create archive root");
 
-    #[test]
-    fn parses_mentions_with_basic_tokens() {
-        let mentions = parse_mentions("@coder please check @planner");
-        assert_eq!(mentions, vec!["coder", "planner"]);
+        let written = export_session_archive_with_layout(
+            &pool,
+            &session,
+            archive_root.path(),
+            ArchiveLayout::Nested,
+        )
+        .await
+        .expect("export archive");
+
+        let session_dir = archive_root.path().join(session_id.to_string());
+        assert_eq!(written, session_dir.to_string_lossy());
+        for file in [
+            "messages_export.jsonl",
+            "session_summary.md",
+            "manifest.json",
+        ] {
+            assert!(session_dir.join(file).is_file(), "{file} in session folder");
+            assert!(!archive_root.path().join(file).exists());
+        }
+        let metadata: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(session_dir.join("session.json")).unwrap())
+                .unwrap();
+        assert_eq!(metadata["id"], serde_json::json!(session_id));
+        assert_eq!(metadata["status"], serde_json::json!(session.status));
+        verify_session_archive(&session_dir)
+            .await
+            .expect("nested archive verifies");
     }
 
-    #[test]
-    fn ignores_email_addresses() {
-        let mentions = parse_mentions("email me at test@example.com");
-        assert!(mentions.is_empty());
-    }
+    #[tokio::test]
+    async fn incremental_export_appends_only_new_messages() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let archive_dir = tempfile::tempdir().expect("create archive dir");
+        let export_path = archive_dir.path().join("messages_export.jsonl");
 
-    #[test]
-    fn de_dupes_mentions_in_order() {
-        let mentions = parse_mentions("@a @a @b");
-        assert_eq!(mentions, vec!["a", "b"]);
+        export_session_incremental(&pool, &session, archive_dir.path())
+            .await
+            .expect("first export");
+        let first = std::fs::read_to_string(&export_path).unwrap();
+
+        for content in ["new one", "new two"] {
+            create_message(
+                &pool,
+                session_id,
+                ChatSenderType::User,
+                None,
+                content.to_string(),
+                None,
+            )
+            .await
+            .expect("create message");
+        }
+        export_session_incremental(&pool, &session, archive_dir.path())
+            .await
+            .expect("incremental export");
+        let second = std::fs::read_to_string(&export_path).unwrap();
+
+        let appended = second
+            .strip_prefix(first.as_str())
+            .expect("earlier lines untouched");
+        let contents: Vec<String> = appended
+            .lines()
+            .map(|line| {
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                value["content"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(contents, vec!["new one", "new two"]);
+
+        // Nothing new: the export is left as it was.
+        export_session_incremental(&pool, &session, archive_dir.path())
+            .await
+            .expect("no-op export");
+        assert_eq!(std::fs::read_to_string(&export_path).unwrap(), second);
+        verify_session_archive(archive_dir.path())
+            .await
+            .expect("incremental archive verifies");
     }
 
-    #[test]
-    fn parses_send_message_directives_and_dedupes_targets() {
-        let mentions = parse_send_message_directives(
-            "notify [sendMessageTo@@{alice}] then [sendMessageTo@@{bob}] and [sendMessageTo@@{alice}]",
-        );
-        assert_eq!(mentions, vec!["alice", "bob"]);
+    #[tokio::test]
+    async fn incremental_export_rewrites_when_messages_change_behind_it() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        let agent_id = create_test_agent(&pool, "reviewer").await;
+        let placeholder = begin_agent_reply(&pool, session_id, agent_id)
+            .await
+            .unwrap();
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let archive_dir = tempfile::tempdir().expect("create archive dir");
+        let export_path = archive_dir.path().join("messages_export.jsonl");
+        let exported_contents = || -> Vec<String> {
+            std::fs::read_to_string(&export_path)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                    value["content"].as_str().unwrap().to_string()
+                })
+                .collect()
+        };
+
+        export_session_incremental(&pool, &session, archive_dir.path())
+            .await
+            .expect("first export");
+        assert_eq!(exported_contents().len(), 4);
+
+        // A message timestamped before the last exported one, as a merge
+        // would move in, plus a newer one so the cursor message survives.
+        let backdated = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "moved in by a merge".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+        sqlx::query(
+            "UPDATE chat_messages SET created_at = datetime('now', '-1 day') WHERE id = ?1",
+        )
+        .bind(backdated.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        export_session_incremental(&pool, &session, archive_dir.path())
+            .await
+            .expect("export after backdated message");
+        let contents = exported_contents();
+        assert_eq!(contents.len(), 5);
+        assert_eq!(contents[0], "moved in by a merge");
+
+        // Finalizing an exported placeholder rewrites its line too.
+        finalize_agent_reply(&pool, placeholder, "Reviewed, looks good".to_string())
+            .await
+            .unwrap();
+        export_session_incremental(&pool, &session, archive_dir.path())
+            .await
+            .expect("export after finalized reply");
+        let contents = exported_contents();
+        assert_eq!(contents.len(), 5);
+        assert!(contents.contains(&"Reviewed, looks good".to_string()));
+        assert!(!contents.contains(&String::new()));
+        verify_session_archive(archive_dir.path())
+            .await
+            .expect("rewritten archive verifies");
     }
 
-    #[test]
-    fn parse_send_message_directives_ignores_plain_at_mentions() {
-        let mentions = parse_send_message_directives("@alice please review this");
-        assert!(mentions.is_empty());
-    }
+    #[tokio::test]
+    async fn context_token_estimate_matches_built_context() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        let options = ContextBuildOptions::default();
 
-    #[test]
-    fn parse_send_message_directives_ignores_invalid_targets() {
-        let mentions = parse_send_message_directives(
-            "[sendMessageTo@@] [sendMessageTo@@{bad target}] [sendMessageTo@@{ok_agent}]",
-        );
-        assert_eq!(mentions, vec!["ok_agent"]);
-    }
+        let estimate = estimate_context_tokens(&pool, session_id, &options)
+            .await
+            .expect("estimate context");
+        let context_dir = tempfile::tempdir().expect("create context dir");
+        let context = build_compacted_context_with_options(
+            &pool,
+            session_id,
+            None,
+            None,
+            Some(context_dir.path()),
+            &options,
+        )
+        .await
+        .expect("build context");
 
-    #[test]
-    fn parse_send_message_directives_supports_non_brace_targets() {
-        let mentions = parse_send_message_directives(
-            "[sendMessageTo@@researcher] and [sendMessageTo@@{planner}]",
-        );
-        assert_eq!(mentions, vec!["researcher", "planner"]);
+        assert!(estimate > 0);
+        assert_eq!(estimate, estimate_string_tokens(&context.jsonl));
     }
 
-    #[test]
-    fn parse_send_message_directives_supports_unicode_targets() {
-        let mentions = parse_send_message_directives(
-            "[sendMessageTo@@{\u{5C0F}\u{660E}}] and [sendMessageTo@@{\u{30C6}\u{30B9}\u{30C8}-agent}]",
-        );
+    #[tokio::test]
+    async fn attachment_only_message_gets_placeholder_content() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            String::new(),
+            Some(serde_json::json!({
+                "attachments": [{
+                    "id": Uuid::new_v4(),
+                    "name": "diagram.png",
+                    "mime_type": "image/png",
+                    "size_bytes": 10,
+                    "kind": "image",
+                    "relative_path": format!("chat/session_{session_id}/attachments/diagram.png"),
+                }],
+            })),
+        )
+        .await
+        .expect("create attachment-only message");
+
+        let structured = build_structured_messages(&pool, session_id).await.unwrap();
         assert_eq!(
-            mentions,
-            vec!["\u{5C0F}\u{660E}", "\u{30C6}\u{30B9}\u{30C8}-agent"]
+            structured[0]["content"],
+            "[attachment: diagram.png (image)]"
         );
+        let simplified = build_simplified_messages(&pool, session_id).await.unwrap();
+        assert_eq!(simplified[0].content, "[attachment: diagram.png (image)]");
     }
 
-    fn make_session_agent(state: ChatSessionAgentState) -> ChatSessionAgent {
-        ChatSessionAgent {
-            id: Uuid::new_v4(),
-            session_id: Uuid::new_v4(),
-            agent_id: Uuid::new_v4(),
-            state,
-            workspace_path: None,
-            pty_session_key: None,
-            agent_session_id: None,
-            agent_message_id: None,
-            created_at: chrono::Utc::now(),
-            updated_at: chrono::Utc::now(),
+    /// Stand-in for a driver error carrying an SQLite result code.
+    #[derive(Debug)]
+    struct FakeSqliteError(&'static str);
+
+    impl std::fmt::Display for FakeSqliteError {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "sqlite error {}", self.0)
         }
     }
 
-    #[test]
-    fn prioritize_summary_agents_prefers_idle_then_running_last() {
-        let running = make_session_agent(ChatSessionAgentState::Running);
-        let waiting = make_session_agent(ChatSessionAgentState::WaitingApproval);
-        let idle = make_session_agent(ChatSessionAgentState::Idle);
-        let dead = make_session_agent(ChatSessionAgentState::Dead);
+    impl std::error::Error for FakeSqliteError {}
 
-        let prioritized = prioritize_summary_agents(&[
-            running.clone(),
-            waiting.clone(),
-            idle.clone(),
-            dead.clone(),
-        ]);
+    impl sqlx::error::DatabaseError for FakeSqliteError {
+        fn message(&self) -> &str {
+            "database is locked"
+        }
 
-        assert_eq!(prioritized[0].id, idle.id);
-        assert_eq!(prioritized[1].id, waiting.id);
-        assert_eq!(prioritized[2].id, dead.id);
-        assert_eq!(prioritized[3].id, running.id);
-    }
+        fn code(&self) -> Option<std::borrow::Cow<'_, str>> {
+            Some(self.0.into())
+        }
 
-    #[test]
-    fn all_agents_running_only_true_when_non_empty_and_all_running() {
-        assert!(!all_agents_running(&[]));
-        assert!(!all_agents_running(&[
-            make_session_agent(ChatSessionAgentState::Running),
-            make_session_agent(ChatSessionAgentState::Idle),
-        ]));
-        assert!(all_agents_running(&[
-            make_session_agent(ChatSessionAgentState::Running),
-            make_session_agent(ChatSessionAgentState::Running),
-        ]));
-    }
+        fn as_error(&self) -> &(dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
 
-    #[test]
-    fn select_messages_to_compress_uses_token_budget() {
-        let messages = vec![
-            SimplifiedMessage {
-                sender: "user:alice".to_string(),
-                content: "heavy ".repeat(500),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "user:bob".to_string(),
-                content: "small".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "agent:bot".to_string(),
-                content: "small".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "agent:bot".to_string(),
-                content: "small".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-        ];
+        fn as_error_mut(&mut self) -> &mut (dyn std::error::Error + Send + Sync + 'static) {
+            self
+        }
 
-        let total_tokens = super::estimate_token_count(&messages);
-        let (count, target_tokens, selected_tokens) =
-            select_messages_to_compress_by_token(&messages, total_tokens, 50);
+        fn into_error(self: Box<Self>) -> Box<dyn std::error::Error + Send + Sync + 'static> {
+            self
+        }
 
-        // 50% by message count would be 2, but token-based should pick only the heavy first message.
-        assert_eq!(count, 1);
-        assert!(selected_tokens >= target_tokens);
+        fn kind(&self) -> sqlx::error::ErrorKind {
+            sqlx::error::ErrorKind::Other
+        }
     }
 
-    #[test]
-    fn limit_summary_input_messages_keeps_all_when_under_limit() {
-        let messages = vec![
-            SimplifiedMessage {
-                sender: "user:alice".to_string(),
-                content: "short".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "agent:bot".to_string(),
-                content: "short reply".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-        ];
+    #[tokio::test]
+    async fn retry_transient_retries_busy_errors_only() {
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let result = retry_transient(3, || async {
+            match attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                0 => Err(ChatServiceError::Database(sqlx::Error::Database(Box::new(
+                    FakeSqliteError("5"),
+                )))),
+                _ => Ok("stored"),
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), "stored");
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
 
-        let (limited, before, after) = limit_summary_input_messages(&messages, u32::MAX);
-        assert_eq!(limited.len(), messages.len());
-        assert_eq!(before, after);
+        // Extended busy codes count as busy too.
+        assert!(
+            ChatServiceError::Database(sqlx::Error::Database(Box::new(FakeSqliteError("517"))))
+                .is_transient()
+        );
+
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let result: Result<(), _> = retry_transient(3, || async {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Err(ChatServiceError::Database(sqlx::Error::Database(Box::new(
+                FakeSqliteError("19"),
+            ))))
+        })
+        .await;
+        assert!(result.is_err());
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 1);
     }
 
-    #[test]
-    fn limit_summary_input_messages_keeps_recent_slice_when_over_limit() {
-        let messages = vec![
-            SimplifiedMessage {
-                sender: "user:a".to_string(),
-                content: "old ".repeat(300),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "agent:b".to_string(),
-                content: "middle ".repeat(300),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "user:c".to_string(),
-                content: "recent ".repeat(300),
-                timestamp: chrono::Utc::now().to_rfc3339(),
+    #[tokio::test]
+    async fn session_from_template_copies_seed_messages_and_team() {
+        let pool = setup_chat_pool().await;
+        let template_id = create_test_session(&pool).await;
+        let agent_id = create_test_agent(&pool, "planner").await;
+        ChatSessionAgent::create(
+            &pool,
+            &CreateChatSessionAgent {
+                session_id: template_id,
+                agent_id,
+                workspace_path: None,
             },
-        ];
+            Uuid::new_v4(),
+        )
+        .await
+        .unwrap();
+        for (sender_type, content) in [
+            (ChatSenderType::System, "Standup template"),
+            (ChatSenderType::User, "What did everyone ship yesterday?"),
+        ] {
+            create_message(
+                &pool,
+                template_id,
+                sender_type,
+                None,
+                content.to_string(),
+                None,
+            )
+            .await
+            .expect("seed template");
+        }
+        set_session_template(&pool, template_id, true)
+            .await
+            .expect("mark template");
 
-        let (limited, before, after) = limit_summary_input_messages(&messages, 200);
-        assert!(limited.len() < messages.len());
+        let rejected = create_message(
+            &pool,
+            template_id,
+            ChatSenderType::User,
+            None,
+            "hello".to_string(),
+            None,
+        )
+        .await;
+        assert!(matches!(rejected, Err(ChatServiceError::Validation(_))));
+
+        let session_id = create_session_from_template(&pool, template_id)
+            .await
+            .expect("create from template");
+        let copied: Vec<String> = ChatMessage::find_by_session_id(&pool, session_id, None)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|message| message.content)
+            .collect();
         assert_eq!(
-            limited.last().map(|m| m.content.as_str()),
-            Some(messages[2].content.as_str())
+            copied,
+            vec!["Standup template", "What did everyone ship yesterday?"]
         );
-        assert!(before > after);
-        assert!(after <= 200 || limited.len() == 1);
+        let team = ChatSessionAgent::find_all_for_session(&pool, session_id)
+            .await
+            .unwrap();
+        assert_eq!(team.len(), 1);
+        assert_eq!(team[0].agent_id, agent_id);
+
+        create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "I shipped retries".to_string(),
+            None,
+        )
+        .await
+        .expect("new session accepts messages");
+        assert!(matches!(
+            create_session_from_template(&pool, session_id).await,
+            Err(ChatServiceError::Validation(_))
+        ));
     }
 
     #[test]
-    fn parses_mentions_with_unicode_names() {
-        let mentions = parse_mentions(
-            "@\u{5C0F}\u{660E} please check @\u{30C6}\u{30B9}\u{30C8}-agent and @\u{0645}\u{0637}\u{0648}\u{0631}_1",
+    fn topics_and_mentions_parse_independently() {
+        let content = "@coder look at #billing and #auth-flow, cc @reviewer #billing";
+        assert_eq!(parse_mentions(content), vec!["coder", "reviewer"]);
+        assert_eq!(parse_topics(content), vec!["billing", "auth-flow"]);
+
+        assert!(parse_topics("# Heading\nwritten in C# with `#[derive]`").is_empty());
+        assert_eq!(
+            parse_tokens("ping @coder", '@'),
+            parse_mentions("ping @coder")
         );
+    }
+
+    #[tokio::test]
+    async fn topics_are_stored_in_message_meta() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+
+        let message = create_message(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "#release notes for @coder".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(message.meta.0["topics"], serde_json::json!(["release"]));
         assert_eq!(
-            mentions,
-            vec![
-                "\u{5C0F}\u{660E}",
-                "\u{30C6}\u{30B9}\u{30C8}-agent",
-                "\u{0645}\u{0637}\u{0648}\u{0631}_1",
-            ]
+            message.meta.0["structured"]["topics"],
+            serde_json::json!(["release"])
         );
+        assert_eq!(message.mentions.0, vec!["coder".to_string()]);
     }
 
     #[tokio::test]
-    async fn compress_messages_falls_back_to_truncation_without_agents() {
-        if dirs::data_dir().is_none() {
-            return;
-        }
+    async fn idempotency_key_prevents_duplicate_messages() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let meta = serde_json::json!({ "idempotency_key": "submit-1" });
 
-        let pool = SqlitePool::connect("sqlite::memory:")
+        let first = create_message_idempotent(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "hello".to_string(),
+            Some(meta.clone()),
+        )
+        .await
+        .unwrap();
+        let second = create_message_idempotent(
+            &pool,
+            session_id,
+            ChatSenderType::User,
+            None,
+            "hello".to_string(),
+            Some(meta),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(first.id, second.id);
+        let messages = ChatMessage::find_by_session_id(&pool, session_id, None)
             .await
-            .expect("create sqlite memory pool");
-        let session_id = Uuid::new_v4();
-        let workspace = std::path::Path::new(".");
-        let messages = vec![
-            SimplifiedMessage {
-                sender: "user:alice".to_string(),
-                content: "A very long message that should exceed tiny threshold quickly".repeat(8),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "agent:bot".to_string(),
-                content: "Second long message for compression coverage".repeat(8),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "user:bob".to_string(),
-                content: "Recent message to keep".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "agent:bot".to_string(),
-                content: "Another recent message to keep".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
+            .unwrap();
+        assert_eq!(messages.len(), 1);
+        // The per-session lock is dropped once nobody holds it.
+        assert!(!IDEMPOTENT_CREATE_LOCKS.locks.contains_key(&session_id));
+    }
+
+    #[tokio::test]
+    async fn messages_mentioning_returns_only_that_handle() {
+        let pool = setup_chat_pool().await;
+        let first = create_test_session(&pool).await;
+        let second = create_test_session(&pool).await;
+
+        for (session_id, content) in [
+            (first, "@alice can you check this?"),
+            (first, "@bob please review"),
+            (second, "thoughts, @Alice?"),
+            (second, "no mentions here"),
+        ] {
+            create_message(
+                &pool,
+                session_id,
+                ChatSenderType::User,
+                None,
+                content.to_string(),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+
+        let inbox = messages_mentioning(&pool, "ALICE", 10).await.unwrap();
+        let contents: Vec<&str> = inbox.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(
+            contents,
+            vec!["thoughts, @Alice?", "@alice can you check this?"]
+        );
+
+        let limited = messages_mentioning(&pool, "@alice", 1).await.unwrap();
+        assert_eq!(limited.len(), 1);
+        assert_eq!(limited[0].session_id, second);
+    }
+
+    #[tokio::test]
+    async fn token_counts_are_reported_per_message_when_requested() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+
+        let plain = build_compacted_context_with_options(
+            &pool,
+            session_id,
+            None,
+            None,
+            None,
+            &ContextBuildOptions::default(),
+        )
+        .await
+        .unwrap();
+        assert!(plain.messages.iter().all(|m| m.get("tokens").is_none()));
+
+        let context = build_compacted_context_with_options(
+            &pool,
+            session_id,
+            None,
+            None,
+            None,
+            &ContextBuildOptions {
+                include_token_counts: true,
+                ..Default::default()
             },
-        ];
+        )
+        .await
+        .unwrap();
+        assert_eq!(context.jsonl, plain.jsonl);
+        let counts: Vec<u64> = context
+            .messages
+            .iter()
+            .map(|m| m["tokens"].as_u64().unwrap())
+            .collect();
+        assert!(!counts.is_empty());
+        assert!(counts.iter().all(|&tokens| tokens > 0));
+        let whole: u64 = context
+            .jsonl
+            .lines()
+            .map(|line| u64::from(estimate_string_tokens(line)))
+            .sum();
+        assert_eq!(counts.iter().sum::<u64>(), whole);
+    }
 
-        let result = compress_messages_if_needed(
+    #[tokio::test]
+    async fn purge_session_removes_rows_history_and_attachments() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let other_session = create_test_session(&pool).await;
+        let attachments_dir = tempfile::tempdir().unwrap();
+
+        let relative = format!("chat/session_{session_id}/attachments/notes.txt");
+        let stored = attachments_dir.path().join(&relative);
+        std::fs::create_dir_all(stored.parent().unwrap()).unwrap();
+        std::fs::write(&stored, "notes").unwrap();
+        let stray = attachments_dir.path().join(format!(
+            "chat/session_{session_id}/attachments/draft/upload.png"
+        ));
+        std::fs::create_dir_all(stray.parent().unwrap()).unwrap();
+        std::fs::write(&stray, "png").unwrap();
+
+        create_message(
             &pool,
             session_id,
-            messages.clone(),
-            1,   // force compression
-            50,  // compress half
-            &[], // no agents available
-            workspace,
-            None, // no context_dir, use legacy split file
+            ChatSenderType::User,
+            None,
+            "see attached".to_string(),
+            Some(serde_json::json!({
+                "attachments": [{
+                    "id": Uuid::new_v4(),
+                    "name": "notes.txt",
+                    "mime_type": "text/plain",
+                    "size_bytes": 5,
+                    "kind": "text",
+                    "relative_path": relative,
+                }]
+            })),
         )
         .await
-        .expect("compression should succeed with fallback");
+        .unwrap();
+        create_message(
+            &pool,
+            other_session,
+            ChatSenderType::User,
+            None,
+            "unrelated".to_string(),
+            None,
+        )
+        .await
+        .unwrap();
 
-        assert_eq!(result.compression_type, CompressionType::Truncated);
-        assert!(result.messages.len() <= messages.len());
+        let store = InMemoryHistoryStore::default();
+        store
+            .write(
+                HistoryFileKind::Main,
+                &build_history_file(session_id, &[], false, None),
+            )
+            .await
+            .unwrap();
+
+        let report = purge_session_with_store(&pool, session_id, attachments_dir.path(), &store)
+            .await
+            .unwrap();
+
+        assert_eq!(report.messages_deleted, 1);
+        assert!(report.session_deleted);
+        assert_eq!(report.history_files_deleted, 1);
+        assert_eq!(report.attachments_deleted.len(), 2);
+        assert!(!stored.exists());
+        assert!(!stray.exists());
         assert!(
-            super::estimate_token_count(&result.messages) < super::estimate_token_count(&messages),
-            "fallback truncation should reduce token count"
+            !attachments_dir
+                .path()
+                .join(format!("chat/session_{session_id}"))
+                .join("attachments")
+                .exists()
         );
-        assert_eq!(
-            result
-                .messages
-                .first()
-                .map(|message| message.sender.as_str()),
-            Some("system:summary"),
-            "fallback should keep a compact summary marker at the front"
+        assert!(
+            !store
+                .exists(session_id, HistoryFileKind::Main)
+                .await
+                .unwrap()
         );
         assert!(
-            result
-                .messages
-                .first()
-                .map(|message| message.content.contains("[History Summary - Fallback]"))
-                .unwrap_or(false),
-            "fallback summary marker should describe archival"
+            ChatSession::find_by_id(&pool, session_id)
+                .await
+                .unwrap()
+                .is_none()
         );
-
-        let warning = result.warning.expect("fallback should include warning");
-        assert_eq!(warning.code, "COMPRESSION_FALLBACK");
         assert!(
-            std::path::Path::new(&warning.split_file_path).exists(),
-            "split file should be created"
+            ChatMessage::find_by_session_id(&pool, session_id, None)
+                .await
+                .unwrap()
+                .is_empty()
         );
-
-        let _ = tokio::fs::remove_file(&warning.split_file_path).await;
+        assert_eq!(
+            ChatMessage::find_by_session_id(&pool, other_session, None)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(matches!(
+            purge_session(&pool, session_id, attachments_dir.path()).await,
+            Err(ChatServiceError::SessionNotFound)
+        ));
     }
 
     #[tokio::test]
-    async fn compress_messages_reuses_cached_result_for_unchanged_history() {
-        let pool = SqlitePool::connect("sqlite::memory:")
-            .await
-            .expect("create sqlite memory pool");
-        let session_id = Uuid::new_v4();
-        let workspace = std::path::Path::new(".");
-        let context_dir = tempfile::tempdir().expect("create temp context dir");
-        let messages = vec![
-            SimplifiedMessage {
-                sender: "user:alice".to_string(),
-                content: "A very long message that should exceed tiny threshold quickly".repeat(8),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "agent:bot".to_string(),
-                content: "Second long message for compression coverage".repeat(8),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "user:bob".to_string(),
-                content: "Recent message to keep".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-        ];
-
-        let first = compress_messages_if_needed(
+    async fn purge_session_keeps_attachments_shared_with_clones() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let attachments_dir = tempfile::tempdir().unwrap();
+
+        let relative = format!("chat/session_{session_id}/attachments/notes.txt");
+        let stored = attachments_dir.path().join(&relative);
+        std::fs::create_dir_all(stored.parent().unwrap()).unwrap();
+        std::fs::write(&stored, "notes").unwrap();
+        let message = create_message(
             &pool,
             session_id,
-            messages.clone(),
-            1,
-            50,
-            &[],
-            workspace,
-            Some(context_dir.path()),
+            ChatSenderType::User,
+            None,
+            "see attached".to_string(),
+            Some(serde_json::json!({
+                "attachments": [{
+                    "id": Uuid::new_v4(),
+                    "name": "notes.txt",
+                    "mime_type": "text/plain",
+                    "size_bytes": 5,
+                    "kind": "text",
+                    "relative_path": relative,
+                }]
+            })),
         )
         .await
-        .expect("first compression should succeed");
-        assert_eq!(first.compression_type, CompressionType::Truncated);
-        let first_path = first
-            .warning
-            .as_ref()
-            .expect("warning expected")
-            .split_file_path
-            .clone();
+        .unwrap();
 
-        let second = compress_messages_if_needed(
-            &pool,
-            session_id,
-            messages.clone(),
-            1,
-            50,
-            &[],
-            workspace,
-            Some(context_dir.path()),
-        )
-        .await
-        .expect("second compression should succeed");
-        assert_eq!(second.compression_type, CompressionType::Truncated);
-        let second_path = second
-            .warning
-            .as_ref()
-            .expect("warning expected")
-            .split_file_path
-            .clone();
+        let store = InMemoryHistoryStore::default();
+        let first_clone = clone_session_with_store(&pool, session_id, message.id, &store)
+            .await
+            .unwrap();
+        let second_clone = clone_session_with_store(&pool, session_id, message.id, &store)
+            .await
+            .unwrap();
 
-        assert_eq!(
-            first_path, second_path,
-            "unchanged history should reuse cached compression output"
-        );
+        let report = purge_session_with_store(&pool, first_clone, attachments_dir.path(), &store)
+            .await
+            .unwrap();
+        assert!(report.attachments_deleted.is_empty());
+        assert!(stored.exists());
 
-        let cutoff_count = std::fs::read_dir(context_dir.path())
-            .expect("read context dir")
-            .filter_map(Result::ok)
-            .filter(|entry| {
-                entry
-                    .file_name()
-                    .to_string_lossy()
-                    .starts_with("cutoff_message_")
-            })
-            .count();
+        // Purging the original leaves the file its remaining clone points at.
+        let report = purge_session_with_store(&pool, session_id, attachments_dir.path(), &store)
+            .await
+            .unwrap();
+        assert!(report.attachments_deleted.is_empty());
+        assert!(stored.exists());
+
+        let report = purge_session_with_store(&pool, second_clone, attachments_dir.path(), &store)
+            .await
+            .unwrap();
+        assert_eq!(report.attachments_deleted, vec![stored.clone()]);
+        assert!(!stored.exists());
+    }
+
+    #[tokio::test]
+    async fn structured_messages_carry_schema_version() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+
+        let all = build_structured_messages(&pool, session_id).await.unwrap();
+        let filtered =
+            build_structured_messages_filtered(&pool, session_id, SystemMessageFilter::Exclude)
+                .await
+                .unwrap();
+        assert!(!all.is_empty());
+        assert!(!filtered.is_empty());
+        for message in all.iter().chain(&filtered) {
+            assert_eq!(
+                message["schema_version"],
+                serde_json::json!(STRUCTURED_MESSAGE_SCHEMA_VERSION)
+            );
+        }
+        assert_eq!(STRUCTURED_MESSAGE_SCHEMA_VERSION, 3);
+    }
+
+    #[tokio::test]
+    async fn activity_timeseries_buckets_messages_by_utc_day() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let coder = create_test_agent(&pool, "coder").await;
+
+        let day_one = chrono::DateTime::parse_from_rfc3339("2026-03-01T09:15:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        let day_two = chrono::DateTime::parse_from_rfc3339("2026-03-02T23:59:00Z")
+            .unwrap()
+            .with_timezone(&chrono::Utc);
+        for (sender_type, sender_id, content, created_at) in [
+            (ChatSenderType::User, None, "kick off the release", day_one),
+            (ChatSenderType::Agent, Some(coder), "on it", day_one),
+            (ChatSenderType::User, None, "status?", day_two),
+        ] {
+            let message = create_message(
+                &pool,
+                session_id,
+                sender_type,
+                sender_id,
+                content.to_string(),
+                None,
+            )
+            .await
+            .unwrap();
+            sqlx::query("UPDATE chat_messages SET created_at = ?2 WHERE id = ?1")
+                .bind(message.id)
+                .bind(created_at)
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+
+        let buckets = session_activity_timeseries(&pool, session_id, ActivityBucketSize::Day)
+            .await
+            .unwrap();
+        assert_eq!(buckets.len(), 2);
+        assert_eq!(buckets[0].start.to_rfc3339(), "2026-03-01T00:00:00+00:00");
+        assert_eq!(buckets[0].messages, 2);
+        assert_eq!(buckets[0].distinct_senders, 2);
         assert_eq!(
-            cutoff_count, 1,
-            "cached compression should avoid creating extra cutoff files"
+            buckets[0].estimated_tokens,
+            estimate_string_tokens("kick off the release") + estimate_string_tokens("on it")
         );
+        assert_eq!(buckets[1].start.to_rfc3339(), "2026-03-02T00:00:00+00:00");
+        assert_eq!(buckets[1].messages, 1);
+        assert_eq!(buckets[1].distinct_senders, 1);
+
+        let hourly = session_activity_timeseries(&pool, session_id, ActivityBucketSize::Hour)
+            .await
+            .unwrap();
+        assert_eq!(hourly.len(), 2);
+        assert_eq!(hourly[1].start.to_rfc3339(), "2026-03-02T23:00:00+00:00");
     }
 
     #[tokio::test]
-    async fn compress_messages_reuses_persisted_state_after_cache_clear() {
-        let pool = SqlitePool::connect("sqlite::memory:")
+    async fn posting_past_the_cap_moves_oldest_message_to_split_file() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let attachments_dir = tempfile::tempdir().unwrap();
+        let relative = format!("chat/session_{session_id}/attachments/notes.txt");
+        let stored = attachments_dir.path().join(&relative);
+        std::fs::create_dir_all(stored.parent().unwrap()).unwrap();
+        std::fs::write(&stored, "notes").unwrap();
+
+        let settings = MessageSettings {
+            max_messages_per_session: Some(2),
+            ..MessageSettings::from_config(&Config::default())
+        };
+        let store = InMemoryHistoryStore::default();
+        let mut created = Vec::new();
+        for (content, meta) in [
+            (
+                "first @coder",
+                Some(serde_json::json!({
+                    "attachments": [{
+                        "id": Uuid::new_v4(),
+                        "name": "notes.txt",
+                        "mime_type": "text/plain",
+                        "size_bytes": 5,
+                        "kind": "text",
+                        "relative_path": relative,
+                    }]
+                })),
+            ),
+            ("second", None),
+            ("third", None),
+        ] {
+            created.push(
+                create_message_with_settings(
+                    &pool,
+                    session_id,
+                    NewMessage {
+                        sender_type: ChatSenderType::User,
+                        sender_id: None,
+                        content: content.to_string(),
+                        meta,
+                    },
+                    Uuid::new_v4(),
+                    &settings,
+                    &store,
+                )
+                .await
+                .unwrap(),
+            );
+        }
+
+        let remaining = ChatMessage::find_by_session_id(&pool, session_id, None)
             .await
-            .expect("create sqlite memory pool");
-        let create_state_table_sql = format!(
-            "CREATE TABLE {} (
-                session_id BLOB PRIMARY KEY,
-                source_fingerprint TEXT NOT NULL,
-                source_message_count INTEGER NOT NULL,
-                token_threshold INTEGER NOT NULL,
-                compression_percentage INTEGER NOT NULL,
-                source_token_count INTEGER NOT NULL,
-                effective_token_count INTEGER NOT NULL,
-                compression_type TEXT NOT NULL,
-                warning_json TEXT,
-                result_messages_json TEXT NOT NULL,
-                created_at TEXT NOT NULL DEFAULT (datetime('now', 'subsec')),
-                updated_at TEXT NOT NULL DEFAULT (datetime('now', 'subsec'))
-            )",
-            super::COMPRESSION_STATE_TABLE
+            .unwrap();
+        let contents: Vec<&str> = remaining.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["second", "third"]);
+        assert!(
+            ChatMessage::find_by_id(&pool, created[0].id)
+                .await
+                .unwrap()
+                .is_none()
         );
-        sqlx::query(&create_state_table_sql)
-            .execute(&pool)
+
+        let split = store
+            .read(session_id, HistoryFileKind::Split)
             .await
-            .expect("create compression state table");
+            .unwrap()
+            .expect("split file written");
+        assert_eq!(split.messages.len(), 1);
+        assert_eq!(split.messages[0].content, "first @coder");
+        let record = split.messages[0].record.as_ref().expect("full row kept");
+        assert_eq!(record.id, created[0].id);
+        assert_eq!(record.mentions.0, vec!["coder".to_string()]);
+        assert_eq!(record.meta.0, created[0].meta.0);
+
+        // The moved message still owns its attachment.
+        assert!(
+            find_orphaned_attachments_with_store(&pool, attachments_dir.path(), &store)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+    }
 
-        let session_id = Uuid::new_v4();
-        let workspace = std::path::Path::new(".");
-        let context_dir = tempfile::tempdir().expect("create temp context dir");
+    #[tokio::test]
+    async fn truncated_messages_get_resolvable_original_refs() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let context_dir = tempfile::tempdir().unwrap();
+        let long = "An early design discussion that gets compressed away. ".repeat(10);
         let messages = vec![
             SimplifiedMessage {
                 sender: "user:alice".to_string(),
-                content: "A very long message that should exceed tiny threshold quickly".repeat(8),
+                content: long.clone(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
             },
             SimplifiedMessage {
                 sender: "agent:bot".to_string(),
-                content: "Second long message for compression coverage".repeat(8),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "user:bob".to_string(),
-                content: "Recent message to keep".to_string(),
+                content: "Recent reply to keep".to_string(),
                 timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
             },
         ];
 
-        let first = compress_messages_if_needed(
+        let result = compress_messages_if_needed(
             &pool,
-            session_id,
-            messages.clone(),
+            Uuid::new_v4(),
+            messages,
             1,
             50,
             &[],
-            workspace,
+            std::path::Path::new("."),
             Some(context_dir.path()),
         )
         .await
-        .expect("first compression should succeed");
-        assert_eq!(first.compression_type, CompressionType::Truncated);
-        let first_path = first
-            .warning
-            .as_ref()
-            .expect("warning expected")
-            .split_file_path
-            .clone();
+        .unwrap();
+        assert_eq!(result.compression_type, CompressionType::Truncated);
+        let warning = result.warning.expect("truncation warning");
+
+        let stubs = compressed_ref_values(&warning).await.unwrap();
+        assert_eq!(stubs.len(), 1);
+        assert_eq!(stubs[0]["sender"], "user:alice");
+        assert!(stubs[0]["content"].as_str().unwrap().len() < long.len());
+        let original_ref = stubs[0]["original_ref"].as_str().unwrap();
+        assert!(original_ref.starts_with(&warning.split_file_path));
+
+        let original = resolve_original_ref(original_ref, Some(context_dir.path()))
+            .await
+            .unwrap();
+        assert_eq!(original.content, long);
+        assert!(
+            resolve_original_ref("no-timestamp", Some(context_dir.path()))
+                .await
+                .is_err()
+        );
+
+        // Refs survive the archive being re-sorted, e.g. by a session merge.
+        let mut archive: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&warning.split_file_path).unwrap())
+                .unwrap();
+        archive["messages"].as_array_mut().unwrap().insert(
+            0,
+            serde_json::json!({
+                "sender": "user:bob",
+                "content": "An even older message",
+                "timestamp": "2020-01-01T00:00:00+00:00",
+            }),
+        );
+        std::fs::write(&warning.split_file_path, archive.to_string()).unwrap();
+        let original = resolve_original_ref(original_ref, Some(context_dir.path()))
+            .await
+            .unwrap();
+        assert_eq!(original.content, long);
+
+        // The same file outside the allowed directories is refused.
+        let elsewhere = tempfile::tempdir().unwrap();
+        let copied = elsewhere.path().join("cutoff_message_0.json");
+        std::fs::copy(&warning.split_file_path, &copied).unwrap();
+        let (_, timestamp) = original_ref.rsplit_once('#').unwrap();
+        assert!(matches!(
+            resolve_original_ref(
+                &format!("{}#{timestamp}", copied.display()),
+                Some(context_dir.path())
+            )
+            .await,
+            Err(ChatServiceError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn compression_settings_come_from_the_given_config() {
+        let mut config = Config::default();
+        config.chat_compression.token_threshold = 1000;
+        config.chat_compression.compression_percentage = 0;
+        config.chat_compression.token_safety_margin = 2.0;
+        let options = ContextBuildOptions::from_config(&config);
+        assert_eq!(chat_compression_settings(&options.compression), (500, 1));
+    }
+
+    #[tokio::test]
+    async fn token_safety_margin_splits_history_earlier() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        let messages: Vec<SimplifiedMessage> = (0..6)
+            .map(|i| SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: format!("Message {i} about the release checklist and rollout plan"),
+                timestamp: chrono::Utc::now().to_rfc3339(),
+                record: None,
+            })
+            .collect();
+        // Room for the messages as estimated, but not with 20% headroom.
+        let tokens = super::estimate_token_count(&messages);
+        let token_threshold = tokens + tokens / 10;
+
+        let mut compression_types = Vec::new();
+        for margin in [1.0, 1.2] {
+            let context_dir = tempfile::tempdir().unwrap();
+            let result = compress_messages_if_needed(
+                &pool,
+                Uuid::new_v4(),
+                messages.clone(),
+                threshold_with_safety_margin(token_threshold, margin),
+                50,
+                &[],
+                std::path::Path::new("."),
+                Some(context_dir.path()),
+            )
+            .await
+            .unwrap();
+            compression_types.push(result.compression_type);
+        }
+
+        assert_eq!(
+            compression_types,
+            vec![CompressionType::None, CompressionType::Truncated]
+        );
+        assert_eq!(threshold_with_safety_margin(1000, f32::NAN), 1000);
+        assert_eq!(threshold_with_safety_margin(1000, 100.0), 250);
+    }
+
+    #[tokio::test]
+    async fn merge_sessions_interleaves_messages_by_timestamp() {
+        let pool = setup_chat_pool().await;
+        let into_id = create_test_session(&pool).await;
+        let from_id = create_test_session(&pool).await;
+        let base = chrono::Utc::now() - chrono::Duration::hours(1);
+        for (session_id, content, minute) in [
+            (into_id, "into 1", 0),
+            (from_id, "from 1", 1),
+            (into_id, "into 2", 2),
+            (from_id, "from 2", 3),
+        ] {
+            let message = create_message(
+                &pool,
+                session_id,
+                ChatSenderType::User,
+                None,
+                content.to_string(),
+                None,
+            )
+            .await
+            .unwrap();
+            sqlx::query("UPDATE chat_messages SET created_at = ?2 WHERE id = ?1")
+                .bind(message.id)
+                .bind(base + chrono::Duration::minutes(minute))
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        let store = InMemoryHistoryStore::default();
+        for (session_id, content, timestamp) in [
+            (into_id, "into split", "2000-01-02T00:00:00Z"),
+            (from_id, "from split", "2000-01-01T00:00:00Z"),
+        ] {
+            let message = SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: content.to_string(),
+                timestamp: timestamp.to_string(),
+                record: None,
+            };
+            store
+                .append(session_id, HistoryFileKind::Split, &[message])
+                .await
+                .unwrap();
+        }
+
+        let moved = merge_sessions_with_store(&pool, into_id, from_id, &store)
+            .await
+            .unwrap();
+
+        assert_eq!(moved, 2);
+        let merged = ChatMessage::find_by_session_id(&pool, into_id, None)
+            .await
+            .unwrap();
+        let contents: Vec<&str> = merged.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["into 1", "from 1", "into 2", "from 2"]);
+        assert!(
+            ChatMessage::find_by_session_id(&pool, from_id, None)
+                .await
+                .unwrap()
+                .is_empty()
+        );
+        let from = ChatSession::find_by_id(&pool, from_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(from.status, ChatSessionStatus::Archived);
 
-        let persisted_count = sqlx::query_scalar::<_, i64>(&format!(
-            "SELECT COUNT(1) FROM {} WHERE session_id = ?1",
-            super::COMPRESSION_STATE_TABLE
-        ))
-        .bind(session_id)
-        .fetch_one(&pool)
-        .await
-        .expect("query persisted compression rows");
-        assert_eq!(persisted_count, 1);
+        let split = store
+            .read(into_id, HistoryFileKind::Split)
+            .await
+            .unwrap()
+            .unwrap();
+        let split_contents: Vec<&str> = split.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(split_contents, vec!["from split", "into split"]);
+        assert!(!store.exists(from_id, HistoryFileKind::Split).await.unwrap());
+        assert!(matches!(
+            merge_sessions_with_store(&pool, into_id, into_id, &store).await,
+            Err(ChatServiceError::Validation(_))
+        ));
+    }
 
-        super::COMPRESSION_RESULT_CACHE.remove(&session_id);
+    #[tokio::test]
+    async fn agent_reply_placeholder_is_pending_until_finalized() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let agent_id = create_test_agent(&pool, "coder").await;
 
-        let second = compress_messages_if_needed(
+        let message_id = begin_agent_reply(&pool, session_id, agent_id)
+            .await
+            .unwrap();
+        let structured = build_structured_messages(&pool, session_id).await.unwrap();
+        assert_eq!(structured.len(), 1);
+        assert_eq!(structured[0]["id"], serde_json::json!(message_id));
+        assert_eq!(structured[0]["pending"], true);
+        assert_eq!(structured[0]["content"], "");
+
+        let finalized = finalize_agent_reply(&pool, message_id, "Done, see PR".to_string())
+            .await
+            .unwrap();
+        assert_eq!(finalized.id, message_id);
+        assert_eq!(finalized.content, "Done, see PR");
+        assert!(!is_pending_reply(&finalized.meta.0));
+        let structured = build_structured_messages(&pool, session_id).await.unwrap();
+        assert_eq!(structured[0]["pending"], false);
+        assert_eq!(structured[0]["sender"]["label"], "coder");
+
+        assert!(matches!(
+            finalize_agent_reply(&pool, message_id, "again".to_string()).await,
+            Err(ChatServiceError::Validation(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn finalizing_a_reply_in_an_archived_session_is_rejected() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let agent_id = create_test_agent(&pool, "coder").await;
+        let message_id = begin_agent_reply(&pool, session_id, agent_id)
+            .await
+            .unwrap();
+        ChatSession::update(
             &pool,
             session_id,
-            messages,
-            1,
-            50,
-            &[],
-            workspace,
-            Some(context_dir.path()),
+            &UpdateChatSession {
+                title: None,
+                status: Some(ChatSessionStatus::Archived),
+                summary_text: None,
+                archive_ref: None,
+            },
         )
         .await
-        .expect("second compression should succeed from persisted state");
-        assert_eq!(second.compression_type, CompressionType::Truncated);
-        let second_path = second
-            .warning
-            .as_ref()
-            .expect("warning expected")
-            .split_file_path
-            .clone();
+        .unwrap();
 
-        assert_eq!(
-            first_path, second_path,
-            "persisted cache should avoid re-compressing unchanged history after cache reset"
-        );
+        assert!(matches!(
+            finalize_agent_reply(&pool, message_id, "Done".to_string()).await,
+            Err(ChatServiceError::SessionArchived)
+        ));
+        let stored = ChatMessage::find_by_id(&pool, message_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(is_pending_reply(&stored.meta.0));
+        assert_eq!(stored.content, "");
+    }
 
-        let cutoff_count = std::fs::read_dir(context_dir.path())
-            .expect("read context dir")
-            .filter_map(Result::ok)
-            .filter(|entry| {
-                entry
-                    .file_name()
-                    .to_string_lossy()
-                    .starts_with("cutoff_message_")
-            })
-            .count();
-        assert_eq!(
-            cutoff_count, 1,
-            "persisted cache should avoid creating extra cutoff files after cache reset"
+    #[tokio::test]
+    async fn cancelling_a_pending_reply_hides_the_placeholder() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let agent_id = create_test_agent(&pool, "coder").await;
+        let message_id = begin_agent_reply(&pool, session_id, agent_id)
+            .await
+            .unwrap();
+
+        cancel_agent_reply(&pool, message_id).await.unwrap();
+
+        let stored = ChatMessage::find_by_id(&pool, message_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(!is_pending_reply(&stored.meta.0));
+        assert!(is_cancelled_reply(&stored.meta.0));
+        assert!(stored.meta.0["cancelled_at"].is_string());
+        assert!(
+            build_structured_messages(&pool, session_id)
+                .await
+                .unwrap()
+                .is_empty()
         );
+        assert!(matches!(
+            finalize_agent_reply(&pool, message_id, "late".to_string()).await,
+            Err(ChatServiceError::Validation(_))
+        ));
+        // Cancelling twice is fine.
+        cancel_agent_reply(&pool, message_id).await.unwrap();
     }
 
     #[tokio::test]
-    async fn compress_messages_uses_compacted_base_for_appended_history() {
-        let pool = SqlitePool::connect("sqlite::memory:")
+    async fn cancelling_a_finalized_reply_is_a_no_op() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let agent_id = create_test_agent(&pool, "coder").await;
+        let message_id = begin_agent_reply(&pool, session_id, agent_id)
             .await
-            .expect("create sqlite memory pool");
-        let session_id = Uuid::new_v4();
-        let workspace = std::path::Path::new(".");
-        let context_dir = tempfile::tempdir().expect("create temp context dir");
-        let base_messages = vec![
-            SimplifiedMessage {
-                sender: "user:alice".to_string(),
-                content: "A very long message that should exceed threshold".repeat(200),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "agent:bot".to_string(),
-                content: "Another very long message for compression".repeat(200),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "user:bob".to_string(),
-                content: "small keep".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-            SimplifiedMessage {
-                sender: "agent:bot".to_string(),
-                content: "small keep too".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
-            },
-        ];
+            .unwrap();
+        let finalized = finalize_agent_reply(&pool, message_id, "All done".to_string())
+            .await
+            .unwrap();
 
-        let base_tokens = super::estimate_token_count(&base_messages);
-        let threshold = base_tokens.saturating_sub(1).max(1);
+        cancel_agent_reply(&pool, message_id).await.unwrap();
 
-        let first = compress_messages_if_needed(
+        let stored = ChatMessage::find_by_id(&pool, message_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.content, "All done");
+        assert_eq!(stored.meta.0, finalized.meta.0);
+        assert_eq!(
+            build_structured_messages(&pool, session_id)
+                .await
+                .unwrap()
+                .len(),
+            1
+        );
+    }
+
+    #[tokio::test]
+    async fn agent_map_builders_match_self_fetching_builders() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        let agent_map = build_agent_map(&pool).await.unwrap();
+        assert!(!agent_map.is_empty());
+
+        let structured = build_structured_messages(&pool, session_id).await.unwrap();
+        let structured_shared = build_structured_messages_with_agent_map(
             &pool,
             session_id,
-            base_messages.clone(),
-            threshold,
-            50,
-            &[],
-            workspace,
-            Some(context_dir.path()),
+            SystemMessageFilter::default(),
+            &agent_map,
         )
         .await
-        .expect("first compression should succeed");
-        assert_eq!(first.compression_type, CompressionType::Truncated);
+        .unwrap();
+        assert_eq!(structured_shared, structured);
+
+        let options = ContextBuildOptions::default();
+        let context =
+            build_compacted_context_with_options(&pool, session_id, None, None, None, &options)
+                .await
+                .unwrap();
+        let context_shared = build_compacted_context_with_agent_map(
+            &pool, session_id, None, None, None, &options, &agent_map,
+        )
+        .await
+        .unwrap();
+        assert_eq!(context_shared.messages, context.messages);
+        assert_eq!(context_shared.jsonl, context.jsonl);
+        assert_eq!(context_shared.context_compacted, context.context_compacted);
+    }
 
-        let mut appended = base_messages.clone();
-        appended.push(SimplifiedMessage {
-            sender: "user:charlie".to_string(),
-            content: "new tail message".to_string(),
-            timestamp: chrono::Utc::now().to_rfc3339(),
-        });
+    #[test]
+    fn agent_colors_are_stable_per_agent() {
+        let first = Uuid::parse_str("3f2b8c1e-0000-4000-8000-000000000001").unwrap();
+        let second = Uuid::parse_str("3f2b8c1e-0000-4000-8000-000000000002").unwrap();
+
+        let color = agent_color(first);
+        assert_eq!(color, agent_color(first));
+        assert_eq!(color.len(), 7);
+        assert!(color.starts_with('#'));
+        assert!(color[1..].chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(color, agent_color(second));
+    }
 
-        let second = compress_messages_if_needed(
+    #[tokio::test]
+    async fn structured_agent_senders_carry_their_color() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+
+        let messages = build_structured_messages(&pool, session_id).await.unwrap();
+        for message in &messages {
+            let sender = &message["sender"];
+            if sender["type"] == "agent" {
+                let id = Uuid::parse_str(sender["id"].as_str().unwrap()).unwrap();
+                assert_eq!(sender["color"], agent_color(id));
+            } else {
+                assert!(sender["color"].is_null());
+            }
+        }
+        assert!(messages.iter().any(|m| m["sender"]["color"].is_string()));
+    }
+
+    #[tokio::test]
+    async fn mention_frequencies_are_sorted_by_count() {
+        let pool = setup_chat_pool().await;
+        let session_id = create_test_session(&pool).await;
+        let other_session = create_test_session(&pool).await;
+        for (session, content) in [
+            (session_id, "@coder please look"),
+            (session_id, "@Coder and @reviewer, thoughts?"),
+            (session_id, "@qa @reviewer @coder sync up"),
+            (session_id, "no mentions here"),
+            (other_session, "@qa @qa @qa elsewhere"),
+        ] {
+            create_message(
+                &pool,
+                session,
+                ChatSenderType::User,
+                None,
+                content.to_string(),
+                None,
+            )
+            .await
+            .unwrap();
+        }
+
+        let frequencies = session_mention_frequencies(&pool, session_id)
+            .await
+            .unwrap();
+        assert_eq!(
+            frequencies,
+            vec![
+                ("coder".to_string(), 3),
+                ("reviewer".to_string(), 2),
+                ("qa".to_string(), 1),
+            ]
+        );
+        assert!(matches!(
+            session_mention_frequencies(&pool, Uuid::new_v4()).await,
+            Err(ChatServiceError::SessionNotFound)
+        ));
+    }
+
+    #[tokio::test]
+    async fn sqlite_export_contains_the_session_rows() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        let other_session = create_test_session(&pool).await;
+        create_message(
             &pool,
-            session_id,
-            appended,
-            threshold,
-            50,
-            &[],
-            workspace,
-            Some(context_dir.path()),
+            other_session,
+            ChatSenderType::User,
+            None,
+            "not exported".to_string(),
+            None,
         )
         .await
-        .expect("second compression should succeed");
+        .unwrap();
+        let expected = ChatMessage::find_by_session_id(&pool, session_id, None)
+            .await
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("exports").join("session.sqlite");
 
-        // Should keep using compacted base and just append new tail without re-compressing old long prefix.
-        assert!(second.messages.len() >= first.messages.len());
+        export_session_sqlite(&pool, session_id, &dest)
+            .await
+            .unwrap();
 
-        let cutoff_count = std::fs::read_dir(context_dir.path())
-            .expect("read context dir")
-            .filter_map(Result::ok)
-            .filter(|entry| {
-                entry
-                    .file_name()
-                    .to_string_lossy()
-                    .starts_with("cutoff_message_")
-            })
-            .count();
+        let exported = SqlitePool::connect(&format!("sqlite://{}", dest.display()))
+            .await
+            .unwrap();
+        let messages = ChatMessage::find_by_session_id(&exported, session_id, None)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), expected.len());
+        assert_eq!(messages[0].id, expected[0].id);
+        let sessions: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM chat_sessions")
+            .fetch_one(&exported)
+            .await
+            .unwrap();
+        assert_eq!(sessions, 1);
+        let agents: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM chat_agents")
+            .fetch_one(&exported)
+            .await
+            .unwrap();
+        assert_eq!(agents, 2);
+        exported.close().await;
+
+        assert!(matches!(
+            export_session_sqlite(&pool, session_id, &dest).await,
+            Err(ChatServiceError::Validation(_))
+        ));
+    }
+
+    #[test]
+    fn quiet_hours_notifier_queues_until_quiet_hours_end() {
+        #[derive(Default)]
+        struct Recorder(std::sync::Mutex<Vec<Uuid>>);
+        impl MentionNotifier for Recorder {
+            fn notify(&self, event: &MentionEvent) {
+                self.0.lock().unwrap().push(event.message_id);
+            }
+        }
+        let at = |hour| chrono::NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        let event = || MentionEvent {
+            session_id: Uuid::new_v4(),
+            message_id: Uuid::new_v4(),
+            sender_type: ChatSenderType::User,
+            handles: vec!["coder".to_string()],
+            mentions_user: false,
+        };
+        let recorder = Arc::new(Recorder::default());
+        let notifier = QuietHoursNotifier::new(
+            recorder.clone(),
+            NotificationSchedule {
+                quiet_hours: vec![crate::services::config::QuietHours {
+                    start: at(22),
+                    end: at(7),
+                }],
+            },
+        );
+
+        let (night, early) = (event(), event());
+        notifier.notify_at(&night, at(23));
+        notifier.notify_at(&early, at(6));
+        assert!(recorder.0.lock().unwrap().is_empty());
+        assert_eq!(notifier.queued_len(), 2);
+
+        let morning = event();
+        notifier.notify_at(&morning, at(7));
         assert_eq!(
-            cutoff_count, 1,
-            "appended history should not trigger another cutoff for already compressed prefix"
+            *recorder.0.lock().unwrap(),
+            vec![night.message_id, early.message_id, morning.message_id]
         );
+        assert_eq!(notifier.queued_len(), 0);
     }
 
-    #[tokio::test]
-    async fn compress_messages_keeps_original_when_under_threshold() {
-        let pool = SqlitePool::connect("sqlite::memory:")
-            .await
-            .expect("create sqlite memory pool");
-        let session_id = Uuid::new_v4();
-        let workspace = std::path::Path::new(".");
-        let messages = vec![
-            SimplifiedMessage {
-                sender: "user:alice".to_string(),
-                content: "short message".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
+    #[derive(Default)]
+    struct BatchRecorder(std::sync::Mutex<Vec<usize>>);
+
+    impl MentionNotifier for BatchRecorder {
+        fn notify(&self, _: &MentionEvent) {
+            self.0.lock().unwrap().push(1);
+        }
+
+        fn notify_held(&self, events: &[MentionEvent]) {
+            self.0.lock().unwrap().push(events.len());
+        }
+    }
+
+    fn mention_event() -> MentionEvent {
+        MentionEvent {
+            session_id: Uuid::new_v4(),
+            message_id: Uuid::new_v4(),
+            sender_type: ChatSenderType::Agent,
+            handles: vec!["you".to_string()],
+            mentions_user: true,
+        }
+    }
+
+    #[test]
+    fn quiet_hours_queue_is_capped_and_flushed_as_one_batch() {
+        let at = |hour| chrono::NaiveTime::from_hms_opt(hour, 0, 0).unwrap();
+        let recorder = Arc::new(BatchRecorder::default());
+        let notifier = QuietHoursNotifier::new(
+            recorder.clone(),
+            NotificationSchedule {
+                quiet_hours: vec![crate::services::config::QuietHours {
+                    start: at(22),
+                    end: at(7),
+                }],
             },
-            SimplifiedMessage {
-                sender: "agent:bot".to_string(),
-                content: "another short one".to_string(),
-                timestamp: chrono::Utc::now().to_rfc3339(),
+        );
+
+        for _ in 0..MAX_QUEUED_MENTIONS + 5 {
+            notifier.notify_at(&mention_event(), at(23));
+        }
+        assert_eq!(notifier.queued_len(), MAX_QUEUED_MENTIONS);
+
+        notifier.notify_at(&mention_event(), at(8));
+        assert_eq!(*recorder.0.lock().unwrap(), vec![MAX_QUEUED_MENTIONS, 1]);
+        assert_eq!(notifier.queued_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn quiet_hours_queue_flushes_when_quiet_hours_end() {
+        let now = chrono::Local::now().time();
+        let recorder = Arc::new(BatchRecorder::default());
+        let notifier = QuietHoursNotifier::new(
+            recorder.clone(),
+            NotificationSchedule {
+                quiet_hours: vec![crate::services::config::QuietHours {
+                    start: now - chrono::Duration::hours(1),
+                    end: now + chrono::Duration::milliseconds(300),
+                }],
             },
-        ];
+        );
 
-        let result = compress_messages_if_needed(
+        notifier.notify(&mention_event());
+        notifier.notify(&mention_event());
+        assert_eq!(notifier.queued_len(), 2);
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while notifier.queued_len() > 0 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .expect("queue flushed after quiet hours");
+        assert_eq!(*recorder.0.lock().unwrap(), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn mark_all_read_clears_every_unread_session() {
+        let pool = setup_chat_pool().await;
+        let first = seed_two_agent_conversation(&pool).await;
+        let second = seed_two_agent_conversation(&pool).await;
+        create_test_session(&pool).await;
+        assert!(unread_count(&pool, first).await.unwrap() > 0);
+        assert!(unread_count(&pool, second).await.unwrap() > 0);
+
+        assert_eq!(mark_all_read(&pool).await.unwrap(), 2);
+
+        assert_eq!(unread_count(&pool, first).await.unwrap(), 0);
+        assert_eq!(unread_count(&pool, second).await.unwrap(), 0);
+        assert_eq!(mark_all_read(&pool).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn continued_session_accepts_new_messages() {
+        let pool = setup_chat_pool().await;
+        let session_id = seed_two_agent_conversation(&pool).await;
+        ChatSession::update(
             &pool,
             session_id,
-            messages.clone(),
-            u32::MAX, // never trigger compression
-            25,
-            &[],
-            workspace,
-            None, // no context_dir
+            &UpdateChatSession {
+                title: None,
+                status: None,
+                summary_text: Some("Planner and coder discussed retries".to_string()),
+                archive_ref: None,
+            },
         )
         .await
-        .expect("compression should pass");
+        .expect("set summary");
+        let session = ChatSession::find_by_id(&pool, session_id)
+            .await
+            .unwrap()
+            .unwrap();
+        let archive_dir = tempfile::tempdir().expect("create archive dir");
+        export_session_archive(&pool, &session, archive_dir.path())
+            .await
+            .expect("export archive");
+        ChatSession::delete(&pool, session_id)
+            .await
+            .expect("delete original");
 
-        assert_eq!(result.compression_type, CompressionType::None);
-        assert_eq!(result.messages.len(), messages.len());
-        assert!(result.warning.is_none());
+        let continued_id = continue_session_from_archive(&pool, archive_dir.path())
+            .await
+            .expect("continue from archive");
+        assert_ne!(continued_id, session_id);
+        let continued = ChatSession::find_by_id(&pool, continued_id)
+            .await
+            .unwrap()
+            .expect("continued session");
+        assert_eq!(continued.status, ChatSessionStatus::Active);
+        assert_eq!(
+            continued.summary_text.as_deref(),
+            Some("Planner and coder discussed retries")
+        );
+
+        create_message(
+            &pool,
+            continued_id,
+            ChatSenderType::User,
+            None,
+            "Picking this back up".to_string(),
+            None,
+        )
+        .await
+        .expect("continued session accepts messages");
+        let messages = ChatMessage::find_by_session_id(&pool, continued_id, None)
+            .await
+            .unwrap();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[3].content, "Picking this back up");
     }
 }
@@ -0,0 +1,174 @@
+//! Integrity manifest for exported session archives.
+//!
+//! `export_session_archive` records the SHA-256 and size of every file it
+//! writes in `manifest.json`; [`verify_session_archive`] recomputes them so a
+//! corrupted or edited archive is caught before it is imported.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::{fs, io::AsyncReadExt};
+
+/// Checksum manifest written next to the exported files.
+pub const ARCHIVE_CHECKSUM_MANIFEST: &str = "manifest.json";
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ArchiveFileChecksum {
+    /// File name relative to the archive directory.
+    pub path: String,
+    pub sha256: String,
+    pub size_bytes: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArchiveChecksumManifest {
+    pub files: Vec<ArchiveFileChecksum>,
+    /// Attachment paths (as stored in message meta) whose source file was
+    /// missing at export time, so the archive doesn't contain them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub missing_attachments: Vec<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("invalid archive manifest: {0}")]
+    InvalidManifest(String),
+    #[error("archive file {0} is missing")]
+    MissingFile(String),
+    #[error("archive file {path} is {actual} bytes, expected {expected}")]
+    SizeMismatch {
+        path: String,
+        expected: u64,
+        actual: u64,
+    },
+    #[error("archive file {0} does not match its checksum")]
+    ChecksumMismatch(String),
+}
+
+async fn checksum_file(path: &Path) -> std::io::Result<(String, u64)> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut size = 0u64;
+    loop {
+        let read = file.read(&mut buffer).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    Ok((format!("{:x}", hasher.finalize()), size))
+}
+
+/// Hash `files` (names relative to `archive_dir`) and write the manifest.
+pub async fn write_archive_checksums(archive_dir: &Path, files: &[&str]) -> std::io::Result<()> {
+    write_archive_manifest(archive_dir, files, Vec::new()).await
+}
+
+/// Like [`write_archive_checksums`], also recording attachments that could
+/// not be copied into the archive.
+pub async fn write_archive_manifest(
+    archive_dir: &Path,
+    files: &[&str],
+    missing_attachments: Vec<String>,
+) -> std::io::Result<()> {
+    let mut entries = Vec::with_capacity(files.len());
+    for name in files {
+        let (sha256, size_bytes) = checksum_file(&archive_dir.join(name)).await?;
+        entries.push(ArchiveFileChecksum {
+            path: (*name).to_string(),
+            sha256,
+            size_bytes,
+        });
+    }
+
+    let manifest = ArchiveChecksumManifest {
+        files: entries,
+        missing_attachments,
+    };
+    let json = serde_json::to_vec_pretty(&manifest).map_err(std::io::Error::from)?;
+    fs::write(archive_dir.join(ARCHIVE_CHECKSUM_MANIFEST), json).await
+}
+
+/// Recompute every checksum listed in the archive's manifest.
+pub async fn verify_session_archive(archive_dir: &Path) -> Result<(), VerifyError> {
+    let raw = fs::read(archive_dir.join(ARCHIVE_CHECKSUM_MANIFEST)).await?;
+    let manifest: ArchiveChecksumManifest = serde_json::from_slice(&raw)
+        .map_err(|err| VerifyError::InvalidManifest(err.to_string()))?;
+
+    for entry in &manifest.files {
+        let relative = Path::new(&entry.path);
+        if relative.is_absolute()
+            || relative
+                .components()
+                .any(|component| !matches!(component, std::path::Component::Normal(_)))
+        {
+            return Err(VerifyError::InvalidManifest(format!(
+                "unsafe path {}",
+                entry.path
+            )));
+        }
+
+        let (sha256, size_bytes) = match checksum_file(&archive_dir.join(relative)).await {
+            Ok(result) => result,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                return Err(VerifyError::MissingFile(entry.path.clone()));
+            }
+            Err(err) => return Err(err.into()),
+        };
+        if size_bytes != entry.size_bytes {
+            return Err(VerifyError::SizeMismatch {
+                path: entry.path.clone(),
+                expected: entry.size_bytes,
+                actual: size_bytes,
+            });
+        }
+        if sha256 != entry.sha256 {
+            return Err(VerifyError::ChecksumMismatch(entry.path.clone()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{VerifyError, verify_session_archive, write_archive_checksums};
+
+    #[tokio::test]
+    async fn valid_archive_verifies_and_tampering_fails() {
+        let dir = tempfile::tempdir().expect("create archive dir");
+        std::fs::write(dir.path().join("messages_export.jsonl"), "{\"id\":1}\n").unwrap();
+        std::fs::write(dir.path().join("session_summary.md"), "summary").unwrap();
+        write_archive_checksums(dir.path(), &["messages_export.jsonl", "session_summary.md"])
+            .await
+            .expect("write manifest");
+
+        verify_session_archive(dir.path())
+            .await
+            .expect("untouched archive verifies");
+
+        // Same size, different content.
+        std::fs::write(dir.path().join("session_summary.md"), "SUMMARY").unwrap();
+        assert!(matches!(
+            verify_session_archive(dir.path()).await,
+            Err(VerifyError::ChecksumMismatch(path)) if path == "session_summary.md"
+        ));
+
+        std::fs::write(dir.path().join("session_summary.md"), "longer summary").unwrap();
+        assert!(matches!(
+            verify_session_archive(dir.path()).await,
+            Err(VerifyError::SizeMismatch { .. })
+        ));
+
+        std::fs::remove_file(dir.path().join("messages_export.jsonl")).unwrap();
+        assert!(matches!(
+            verify_session_archive(dir.path()).await,
+            Err(VerifyError::MissingFile(_))
+        ));
+    }
+}
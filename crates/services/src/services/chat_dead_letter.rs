@@ -0,0 +1,150 @@
+//! Last-resort record of chat messages that failed to persist.
+//!
+//! When inserting a message fails, its prepared payload is appended as one
+//! JSON line to `failed_messages.jsonl` in the data dir before the error is
+//! returned, so the content can be recovered or retried. Writing the record is
+//! best-effort: a failure here is logged and never replaces the original error.
+
+use std::{fmt::Display, path::PathBuf};
+
+use chrono::Utc;
+use db::models::chat_message::CreateChatMessage;
+use once_cell::sync::Lazy;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+use utils::assets::asset_dir;
+use uuid::Uuid;
+
+/// Dead-letter file name under the data dir.
+pub const DEAD_LETTER_FILE: &str = "failed_messages.jsonl";
+
+static DEAD_LETTER_PATH_OVERRIDE: Lazy<std::sync::RwLock<Option<PathBuf>>> =
+    Lazy::new(|| std::sync::RwLock::new(None));
+
+/// Serializes appends so concurrent failures don't interleave lines.
+static DEAD_LETTER_WRITE_LOCK: Lazy<Mutex<()>> = Lazy::new(|| Mutex::new(()));
+
+/// Write dead letters to `path` instead of the data dir.
+pub fn set_dead_letter_path(path: PathBuf) {
+    *DEAD_LETTER_PATH_OVERRIDE
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(path);
+}
+
+pub fn dead_letter_path() -> PathBuf {
+    DEAD_LETTER_PATH_OVERRIDE
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+        .unwrap_or_else(|| asset_dir().join(DEAD_LETTER_FILE))
+}
+
+async fn append_dead_letter(line: &str) -> std::io::Result<()> {
+    let path = dead_letter_path();
+    let _guard = DEAD_LETTER_WRITE_LOCK.lock().await;
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent).await?;
+    }
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .await?;
+    file.write_all(line.as_bytes()).await?;
+    file.flush().await
+}
+
+/// Append a message that could not be stored to the dead-letter file.
+pub async fn record_failed_message(
+    message_id: Uuid,
+    data: &CreateChatMessage,
+    error: &dyn Display,
+) {
+    let record = serde_json::json!({
+        "message_id": message_id,
+        "session_id": data.session_id,
+        "sender_type": data.sender_type,
+        "sender_id": data.sender_id,
+        "content": data.content,
+        "mentions": data.mentions,
+        "meta": data.meta,
+        "error": error.to_string(),
+        "failed_at": Utc::now().to_rfc3339(),
+    });
+    let mut line = record.to_string();
+    line.push('\n');
+
+    if let Err(err) = append_dead_letter(&line).await {
+        tracing::error!(
+            session_id = %data.session_id,
+            message_id = %message_id,
+            error = %err,
+            "Failed to write chat message to dead-letter file"
+        );
+    } else {
+        tracing::warn!(
+            session_id = %data.session_id,
+            message_id = %message_id,
+            "Chat message could not be stored; payload written to dead-letter file"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::models::{
+        chat_message::ChatSenderType,
+        chat_session::{ChatSession, CreateChatSession},
+    };
+    use sqlx::SqlitePool;
+    use uuid::Uuid;
+
+    use super::set_dead_letter_path;
+    use crate::services::chat::create_message;
+
+    #[tokio::test]
+    async fn failed_insert_lands_in_dead_letter_file() {
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../db/migrations").run(&pool).await.unwrap();
+        let session =
+            ChatSession::create(&pool, &CreateChatSession { title: None }, Uuid::new_v4())
+                .await
+                .unwrap();
+        sqlx::query(
+            "CREATE TRIGGER fail_message_insert BEFORE INSERT ON chat_messages
+             BEGIN SELECT RAISE(ABORT, 'injected failure'); END",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("failed_messages.jsonl");
+        set_dead_letter_path(path.clone());
+
+        let result = create_message(
+            &pool,
+            session.id,
+            ChatSenderType::User,
+            None,
+            "please keep this".to_string(),
+            None,
+        )
+        .await;
+        assert!(result.is_err());
+
+        let written = std::fs::read_to_string(&path).unwrap();
+        let records: Vec<serde_json::Value> = written
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0]["content"], "please keep this");
+        assert_eq!(records[0]["session_id"], serde_json::json!(session.id));
+        assert!(
+            records[0]["error"]
+                .as_str()
+                .unwrap()
+                .contains("injected failure")
+        );
+    }
+}
@@ -6,25 +6,37 @@
 //! - Token estimation using tiktoken
 //! - Creating split files for archived messages
 
-use std::path::PathBuf;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
 
+use async_trait::async_trait;
 use chrono::{DateTime, Utc};
+use db::models::chat_message::ChatMessage;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
-use tiktoken_rs::cl100k_base;
-use tokio::fs;
+use tiktoken_rs::{CoreBPE, cl100k_base};
+use tokio::{fs, sync::Semaphore};
+use utils::profile;
 use uuid::Uuid;
 
 /// Simplified message format for chat history files.
 /// Only contains sender and content to minimize storage and token usage.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SimplifiedMessage {
-    /// Sender identifier in format "user:{handle}" or "agent:{name}" or "system"
+    /// Sender identifier in format "user:{handle}", "agent:{name}", "system" or
+    /// "system:{actor}"
     pub sender: String,
     /// Message content
     pub content: String,
     /// ISO 8601 timestamp
     pub timestamp: String,
+    /// The full database row, kept for messages whose row was deleted when
+    /// the session hit its size cap. Never counted towards tokens.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub record: Option<ChatMessage>,
 }
 
 /// Metadata about the chat history file
@@ -64,133 +76,463 @@ pub enum ChatHistoryFileError {
 }
 
 /// Get the chat history directory path.
-/// Returns `{UserDir}/.agents-chatgroup/chat_history/`
+/// Returns `{UserDir}/.agents-chatgroup/chat_history/`, or
+/// `{UserDir}/.agents-chatgroup-{profile}/chat_history/` when a profile is active.
 pub fn chat_history_dir() -> Result<PathBuf, ChatHistoryFileError> {
     let data_dir = dirs::data_dir().ok_or(ChatHistoryFileError::NoDataDir)?;
-    Ok(data_dir.join(".agents-chatgroup").join("chat_history"))
+    Ok(data_dir
+        .join(format!(".{}", profile::app_name()))
+        .join("chat_history"))
 }
 
 /// Get the path to the main chat history file for a session.
 pub fn chat_history_path(session_id: Uuid) -> Result<PathBuf, ChatHistoryFileError> {
-    Ok(chat_history_dir()?.join(format!("{}.json", session_id)))
+    FsHistoryStore::default().path(session_id, HistoryFileKind::Main)
 }
 
 /// Get the path to the split file for archived messages.
 pub fn chat_history_split_path(session_id: Uuid) -> Result<PathBuf, ChatHistoryFileError> {
-    Ok(chat_history_dir()?.join(format!("{}_split.json", session_id)))
+    FsHistoryStore::default().path(session_id, HistoryFileKind::Split)
+}
+
+/// Cached cl100k_base encoder. Holds `None` once loading has failed so callers
+/// go straight to the character-based fallback instead of retrying every call.
+static TOKENIZER: OnceLock<Option<CoreBPE>> = OnceLock::new();
+
+fn cached_tokenizer<F>(cell: &OnceLock<Option<CoreBPE>>, load: F) -> Option<&CoreBPE>
+where
+    F: FnOnce() -> anyhow::Result<CoreBPE>,
+{
+    cell.get_or_init(|| match load() {
+        Ok(bpe) => Some(bpe),
+        Err(err) => {
+            tracing::warn!(
+                error = %err,
+                "Failed to load tiktoken encoder; token counts will use character-based estimates"
+            );
+            None
+        }
+    })
+    .as_ref()
+}
+
+fn tokenizer() -> Option<&'static CoreBPE> {
+    cached_tokenizer(&TOKENIZER, cl100k_base)
+}
+
+/// Build the tiktoken encoder once at startup.
+/// Returns false (after logging a single warning) when tokenization is degraded
+/// and all estimates will use the character-based fallback.
+pub fn verify_tokenizer() -> bool {
+    tokenizer().is_some()
 }
 
 /// Estimate the token count for a list of messages using tiktoken (cl100k_base).
 pub fn estimate_token_count(messages: &[SimplifiedMessage]) -> u32 {
-    let bpe = match cl100k_base() {
-        Ok(bpe) => bpe,
-        Err(_) => {
-            // Fallback to character-based estimation if tiktoken fails
-            return estimate_token_count_fallback(messages);
-        }
-    };
+    estimate_token_count_with(tokenizer(), messages)
+}
+
+/// Estimate the token count for a single piece of text (e.g. a prospective
+/// message or a system prompt), sharing the cached encoder and fallback.
+pub fn estimate_string_tokens(text: &str) -> u32 {
+    estimate_string_tokens_with(tokenizer(), text)
+}
 
-    let mut total_tokens: u32 = 0;
-    for msg in messages {
-        // Count tokens in sender and content
-        let text = format!("{}: {}", msg.sender, msg.content);
-        total_tokens += bpe.encode_with_special_tokens(&text).len() as u32;
+/// Text that is tokenized for a message: `"{sender}: {content}"`.
+fn message_token_text(msg: &SimplifiedMessage) -> String {
+    format!("{}: {}", msg.sender, msg.content)
+}
+
+fn estimate_string_tokens_with(bpe: Option<&CoreBPE>, text: &str) -> u32 {
+    match bpe {
+        Some(bpe) => bpe.encode_with_special_tokens(text).len() as u32,
+        // Fallback to character-based estimation if tiktoken is unavailable
+        None => estimate_string_tokens_fallback(text),
     }
-    total_tokens
+}
+
+fn estimate_token_count_with(bpe: Option<&CoreBPE>, messages: &[SimplifiedMessage]) -> u32 {
+    messages
+        .iter()
+        .map(|msg| estimate_string_tokens_with(bpe, &message_token_text(msg)))
+        .sum()
+}
+
+/// Characters-per-token ratios used when tiktoken is unavailable, picked by
+/// the dominant script of the text.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FallbackTokenRatios {
+    /// Chars per token for mostly CJK text.
+    pub cjk_chars_per_token: f32,
+    /// Chars per token for everything else (Latin and similar scripts).
+    pub latin_chars_per_token: f32,
+    /// Share of CJK characters (0.0-1.0) at which text counts as CJK.
+    pub cjk_share_threshold: f32,
+}
+
+impl Default for FallbackTokenRatios {
+    fn default() -> Self {
+        Self {
+            cjk_chars_per_token: 1.5,
+            latin_chars_per_token: 4.0,
+            cjk_share_threshold: 0.3,
+        }
+    }
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(
+        c as u32,
+        0x3000..=0x303F // CJK symbols and punctuation
+            | 0x3040..=0x30FF // Hiragana, Katakana
+            | 0x3400..=0x4DBF // CJK extension A
+            | 0x4E00..=0x9FFF // CJK unified ideographs
+            | 0xAC00..=0xD7AF // Hangul syllables
+            | 0xF900..=0xFAFF // CJK compatibility ideographs
+            | 0xFF00..=0xFFEF // Half/full-width forms
+            | 0x20000..=0x2FA1F // CJK extensions B-F and supplements
+    )
 }
 
 /// Fallback token estimation using character count.
-/// Assumes roughly 4 characters per token for English, 2 for Chinese.
-fn estimate_token_count_fallback(messages: &[SimplifiedMessage]) -> u32 {
-    let mut total_chars: usize = 0;
-    for msg in messages {
-        total_chars += msg.sender.len() + msg.content.len() + 2; // +2 for ": " separator
+fn estimate_string_tokens_fallback(text: &str) -> u32 {
+    estimate_string_tokens_fallback_with(&FallbackTokenRatios::default(), text)
+}
+
+/// Character-based estimate using the ratio for the text's dominant script.
+/// Invalid ratios fall back to a flat 3 bytes per token.
+pub fn estimate_string_tokens_fallback_with(ratios: &FallbackTokenRatios, text: &str) -> u32 {
+    let mut chars = 0usize;
+    let mut cjk = 0usize;
+    let mut non_whitespace = 0usize;
+    for c in text.chars() {
+        chars += 1;
+        if !c.is_whitespace() {
+            non_whitespace += 1;
+            if is_cjk(c) {
+                cjk += 1;
+            }
+        }
+    }
+
+    let cjk_share = if non_whitespace == 0 {
+        0.0
+    } else {
+        cjk as f32 / non_whitespace as f32
+    };
+    let ratio = if cjk_share >= ratios.cjk_share_threshold {
+        ratios.cjk_chars_per_token
+    } else {
+        ratios.latin_chars_per_token
+    };
+    if !ratio.is_finite() || ratio <= 0.0 {
+        return (text.len() / 3) as u32;
     }
-    // Use a conservative estimate of 3 chars per token (accounting for mixed content)
-    (total_chars / 3) as u32
+
+    (chars as f32 / ratio).ceil() as u32
 }
 
-/// Write chat history to a file.
-/// Creates the directory if it doesn't exist.
-pub async fn write_chat_history(
+/// Which of a session's history files an operation targets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HistoryFileKind {
+    /// `{session_id}.json`, the current history.
+    Main,
+    /// `{session_id}_split.json`, messages cut from the history.
+    Split,
+}
+
+/// Storage for chat history files. [`FsHistoryStore`] backs the free
+/// functions in this module; [`InMemoryHistoryStore`] keeps tests off disk.
+#[async_trait]
+pub trait HistoryStore: Send + Sync {
+    async fn read(
+        &self,
+        session_id: Uuid,
+        kind: HistoryFileKind,
+    ) -> Result<Option<ChatHistoryFile>, ChatHistoryFileError>;
+
+    async fn write(
+        &self,
+        kind: HistoryFileKind,
+        history: &ChatHistoryFile,
+    ) -> Result<(), ChatHistoryFileError>;
+
+    /// Add messages to a history file, creating it if needed. Metadata is
+    /// recomputed for the combined messages.
+    async fn append(
+        &self,
+        session_id: Uuid,
+        kind: HistoryFileKind,
+        messages: &[SimplifiedMessage],
+    ) -> Result<(), ChatHistoryFileError> {
+        let mut combined = self
+            .read(session_id, kind)
+            .await?
+            .map(|history| history.messages)
+            .unwrap_or_default();
+        combined.extend(messages.iter().cloned());
+        self.write(
+            kind,
+            &build_history_file(session_id, &combined, false, None),
+        )
+        .await
+    }
+
+    /// Remove both history files for a session. Missing files are not an error.
+    async fn delete(&self, session_id: Uuid) -> Result<(), ChatHistoryFileError>;
+
+    async fn exists(
+        &self,
+        session_id: Uuid,
+        kind: HistoryFileKind,
+    ) -> Result<bool, ChatHistoryFileError>;
+}
+
+/// Build a history file with fresh timestamps and token count.
+pub fn build_history_file(
     session_id: Uuid,
     messages: &[SimplifiedMessage],
     compression_applied: bool,
     split_file: Option<String>,
-) -> Result<PathBuf, ChatHistoryFileError> {
-    let dir = chat_history_dir()?;
-    fs::create_dir_all(&dir).await?;
-
-    let path = chat_history_path(session_id)?;
+) -> ChatHistoryFile {
     let now = Utc::now().to_rfc3339();
-
-    let token_count = estimate_token_count(messages);
-
-    let history = ChatHistoryFile {
+    ChatHistoryFile {
         session_id,
         created_at: now.clone(),
         updated_at: now,
         messages: messages.to_vec(),
         metadata: ChatHistoryMetadata {
-            token_count,
+            token_count: estimate_token_count(messages),
             compression_applied,
             split_file,
         },
-    };
+    }
+}
+
+/// History files stored as JSON under a directory, [`chat_history_dir`] by
+/// default.
+#[derive(Debug, Clone, Default)]
+pub struct FsHistoryStore {
+    dir: Option<PathBuf>,
+}
+
+impl FsHistoryStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir: Some(dir) }
+    }
 
-    let json = serde_json::to_string_pretty(&history)?;
-    fs::write(&path, json).await?;
+    pub fn dir(&self) -> Result<PathBuf, ChatHistoryFileError> {
+        match &self.dir {
+            Some(dir) => Ok(dir.clone()),
+            None => chat_history_dir(),
+        }
+    }
 
-    Ok(path)
+    pub fn path(
+        &self,
+        session_id: Uuid,
+        kind: HistoryFileKind,
+    ) -> Result<PathBuf, ChatHistoryFileError> {
+        let file_name = match kind {
+            HistoryFileKind::Main => format!("{}.json", session_id),
+            HistoryFileKind::Split => format!("{}_split.json", session_id),
+        };
+        Ok(self.dir()?.join(file_name))
+    }
 }
 
-/// Read chat history from a file.
-/// Returns None if the file doesn't exist.
-pub async fn read_chat_history(
-    session_id: Uuid,
-) -> Result<Option<ChatHistoryFile>, ChatHistoryFileError> {
-    let path = chat_history_path(session_id)?;
+#[async_trait]
+impl HistoryStore for FsHistoryStore {
+    async fn read(
+        &self,
+        session_id: Uuid,
+        kind: HistoryFileKind,
+    ) -> Result<Option<ChatHistoryFile>, ChatHistoryFileError> {
+        let path = self.path(session_id, kind)?;
+        if !path.exists() {
+            return Ok(None);
+        }
 
-    if !path.exists() {
-        return Ok(None);
+        let content = fs::read_to_string(&path).await?;
+        Ok(Some(serde_json::from_str(&content)?))
+    }
+
+    async fn write(
+        &self,
+        kind: HistoryFileKind,
+        history: &ChatHistoryFile,
+    ) -> Result<(), ChatHistoryFileError> {
+        fs::create_dir_all(self.dir()?).await?;
+        let json = serde_json::to_string_pretty(history)?;
+        fs::write(self.path(history.session_id, kind)?, json).await?;
+        Ok(())
     }
 
-    let content = fs::read_to_string(&path).await?;
-    let history: ChatHistoryFile = serde_json::from_str(&content)?;
+    async fn delete(&self, session_id: Uuid) -> Result<(), ChatHistoryFileError> {
+        for kind in [HistoryFileKind::Main, HistoryFileKind::Split] {
+            let path = self.path(session_id, kind)?;
+            if path.exists() {
+                fs::remove_file(&path).await?;
+            }
+        }
+        Ok(())
+    }
 
-    Ok(Some(history))
+    async fn exists(
+        &self,
+        session_id: Uuid,
+        kind: HistoryFileKind,
+    ) -> Result<bool, ChatHistoryFileError> {
+        Ok(self.path(session_id, kind)?.exists())
+    }
 }
 
-/// Create a split file for archived messages.
-/// This is used when compression fails and we need to truncate messages.
-pub async fn create_split_file(
+/// History kept in memory, for tests.
+#[derive(Debug, Default)]
+pub struct InMemoryHistoryStore {
+    files: Mutex<HashMap<(Uuid, HistoryFileKind), ChatHistoryFile>>,
+}
+
+#[async_trait]
+impl HistoryStore for InMemoryHistoryStore {
+    async fn read(
+        &self,
+        session_id: Uuid,
+        kind: HistoryFileKind,
+    ) -> Result<Option<ChatHistoryFile>, ChatHistoryFileError> {
+        Ok(self.files.lock().unwrap().get(&(session_id, kind)).cloned())
+    }
+
+    async fn write(
+        &self,
+        kind: HistoryFileKind,
+        history: &ChatHistoryFile,
+    ) -> Result<(), ChatHistoryFileError> {
+        self.files
+            .lock()
+            .unwrap()
+            .insert((history.session_id, kind), history.clone());
+        Ok(())
+    }
+
+    async fn delete(&self, session_id: Uuid) -> Result<(), ChatHistoryFileError> {
+        self.files
+            .lock()
+            .unwrap()
+            .retain(|(id, _), _| *id != session_id);
+        Ok(())
+    }
+
+    async fn exists(
+        &self,
+        session_id: Uuid,
+        kind: HistoryFileKind,
+    ) -> Result<bool, ChatHistoryFileError> {
+        Ok(self.files.lock().unwrap().contains_key(&(session_id, kind)))
+    }
+}
+
+/// Write chat history to a file.
+/// Creates the directory if it doesn't exist.
+pub async fn write_chat_history(
     session_id: Uuid,
     messages: &[SimplifiedMessage],
+    compression_applied: bool,
+    split_file: Option<String>,
 ) -> Result<PathBuf, ChatHistoryFileError> {
-    let dir = chat_history_dir()?;
-    fs::create_dir_all(&dir).await?;
+    write_chat_history_to(
+        &FsHistoryStore::default(),
+        session_id,
+        messages,
+        compression_applied,
+        split_file,
+    )
+    .await
+}
 
-    let path = chat_history_split_path(session_id)?;
-    let now = Utc::now().to_rfc3339();
+/// Like [`write_chat_history`] for an explicit store.
+pub async fn write_chat_history_to(
+    store: &FsHistoryStore,
+    session_id: Uuid,
+    messages: &[SimplifiedMessage],
+    compression_applied: bool,
+    split_file: Option<String>,
+) -> Result<PathBuf, ChatHistoryFileError> {
+    let history = build_history_file(session_id, messages, compression_applied, split_file);
+    store.write(HistoryFileKind::Main, &history).await?;
+    store.path(session_id, HistoryFileKind::Main)
+}
 
-    let token_count = estimate_token_count(messages);
+/// Default cap on history files [`flush_histories`] writes at once.
+pub const DEFAULT_HISTORY_FLUSH_CONCURRENCY: usize = 16;
 
-    let split_history = ChatHistoryFile {
-        session_id,
-        created_at: now.clone(),
-        updated_at: now,
-        messages: messages.to_vec(),
-        metadata: ChatHistoryMetadata {
-            token_count,
-            compression_applied: false,
-            split_file: None,
-        },
-    };
+/// One session's history for [`flush_histories`].
+#[derive(Debug, Clone)]
+pub struct HistoryBatch {
+    pub session_id: Uuid,
+    pub messages: Vec<SimplifiedMessage>,
+    pub compression_applied: bool,
+    pub split_file: Option<String>,
+}
 
-    let json = serde_json::to_string_pretty(&split_history)?;
-    fs::write(&path, json).await?;
+/// Write the main history file for many sessions, with at most
+/// `max_concurrency` files open at once so a large flush can't exhaust file
+/// descriptors. Returns one result per batch, in input order.
+pub async fn flush_histories(
+    batches: Vec<HistoryBatch>,
+    max_concurrency: usize,
+) -> Vec<(Uuid, Result<PathBuf, ChatHistoryFileError>)> {
+    flush_histories_to(&FsHistoryStore::default(), batches, max_concurrency).await
+}
 
-    Ok(path)
+/// Like [`flush_histories`] for an explicit store.
+pub async fn flush_histories_to(
+    store: &FsHistoryStore,
+    batches: Vec<HistoryBatch>,
+    max_concurrency: usize,
+) -> Vec<(Uuid, Result<PathBuf, ChatHistoryFileError>)> {
+    let semaphore = Semaphore::new(max_concurrency.max(1));
+    let writes = batches.into_iter().map(|batch| {
+        let semaphore = &semaphore;
+        async move {
+            let _permit = semaphore
+                .acquire()
+                .await
+                .expect("history flush semaphore is never closed");
+            let result = write_chat_history_to(
+                store,
+                batch.session_id,
+                &batch.messages,
+                batch.compression_applied,
+                batch.split_file,
+            )
+            .await;
+            (batch.session_id, result)
+        }
+    });
+    futures::future::join_all(writes).await
+}
+
+/// Read chat history from a file.
+/// Returns None if the file doesn't exist.
+pub async fn read_chat_history(
+    session_id: Uuid,
+) -> Result<Option<ChatHistoryFile>, ChatHistoryFileError> {
+    FsHistoryStore::default()
+        .read(session_id, HistoryFileKind::Main)
+        .await
+}
+
+/// Create a split file for archived messages.
+/// This is used when compression fails and we need to truncate messages.
+pub async fn create_split_file(
+    session_id: Uuid,
+    messages: &[SimplifiedMessage],
+) -> Result<PathBuf, ChatHistoryFileError> {
+    let store = FsHistoryStore::default();
+    let history = build_history_file(session_id, messages, false, None);
+    store.write(HistoryFileKind::Split, &history).await?;
+    store.path(session_id, HistoryFileKind::Split)
 }
 
 /// Append messages to an existing split file or create a new one.
@@ -198,34 +540,67 @@ pub async fn append_to_split_file(
     session_id: Uuid,
     new_messages: &[SimplifiedMessage],
 ) -> Result<PathBuf, ChatHistoryFileError> {
-    let path = chat_history_split_path(session_id)?;
-
-    let mut existing_messages = if path.exists() {
-        let content = fs::read_to_string(&path).await?;
-        let history: ChatHistoryFile = serde_json::from_str(&content)?;
-        history.messages
-    } else {
-        Vec::new()
-    };
-
-    existing_messages.extend(new_messages.iter().cloned());
-    create_split_file(session_id, &existing_messages).await
+    let store = FsHistoryStore::default();
+    store
+        .append(session_id, HistoryFileKind::Split, new_messages)
+        .await?;
+    store.path(session_id, HistoryFileKind::Split)
 }
 
 /// Delete chat history files for a session.
 pub async fn delete_chat_history(session_id: Uuid) -> Result<(), ChatHistoryFileError> {
-    let main_path = chat_history_path(session_id)?;
-    let split_path = chat_history_split_path(session_id)?;
+    FsHistoryStore::default().delete(session_id).await
+}
 
-    if main_path.exists() {
-        fs::remove_file(&main_path).await?;
-    }
+/// Disk footprint of the chat history directory.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryDiskStats {
+    pub total_files: u64,
+    pub total_bytes: u64,
+    /// Largest history file and its size in bytes.
+    pub largest_file: Option<(PathBuf, u64)>,
+    /// Number of `{session_id}_split.json` files.
+    pub split_files: u64,
+}
+
+/// Scan [`chat_history_dir`] for file counts and sizes.
+pub async fn chat_history_disk_stats() -> Result<HistoryDiskStats, ChatHistoryFileError> {
+    chat_history_disk_stats_in(&chat_history_dir()?).await
+}
+
+/// Like [`chat_history_disk_stats`] for an explicit directory. A missing
+/// directory reports all zeros.
+pub async fn chat_history_disk_stats_in(
+    dir: &Path,
+) -> Result<HistoryDiskStats, ChatHistoryFileError> {
+    let mut stats = HistoryDiskStats::default();
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(stats),
+        Err(err) => return Err(err.into()),
+    };
 
-    if split_path.exists() {
-        fs::remove_file(&split_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let metadata = entry.metadata().await?;
+        if !metadata.is_file() {
+            continue;
+        }
+        let size = metadata.len();
+        stats.total_files += 1;
+        stats.total_bytes += size;
+        if entry.file_name().to_string_lossy().ends_with("_split.json") {
+            stats.split_files += 1;
+        }
+        if stats
+            .largest_file
+            .as_ref()
+            .is_none_or(|(_, largest)| size > *largest)
+        {
+            stats.largest_file = Some((entry.path(), size));
+        }
     }
 
-    Ok(())
+    Ok(stats)
 }
 
 /// Convert a DateTime to SimplifiedMessage timestamp format
@@ -244,11 +619,13 @@ mod tests {
                 sender: "user:alice".to_string(),
                 content: "Hello, how are you?".to_string(),
                 timestamp: "2026-02-27T10:00:00Z".to_string(),
+                record: None,
             },
             SimplifiedMessage {
                 sender: "agent:assistant".to_string(),
                 content: "I'm doing well, thank you!".to_string(),
                 timestamp: "2026-02-27T10:00:01Z".to_string(),
+                record: None,
             },
         ];
 
@@ -264,9 +641,234 @@ mod tests {
             sender: "user:alice".to_string(),
             content: "你好，世界！".to_string(),
             timestamp: "2026-02-27T10:00:00Z".to_string(),
+            record: None,
         }];
 
         let token_count = estimate_token_count(&messages);
         assert!(token_count > 0);
     }
+
+    #[test]
+    fn test_string_estimates_sum_to_message_estimate() {
+        let messages = vec![
+            SimplifiedMessage {
+                sender: "user:alice".to_string(),
+                content: "Please review the migration plan.".to_string(),
+                timestamp: "2026-02-27T10:00:00Z".to_string(),
+                record: None,
+            },
+            SimplifiedMessage {
+                sender: "agent:reviewer".to_string(),
+                content: "Looks good, one nit on the rollback step.".to_string(),
+                timestamp: "2026-02-27T10:00:01Z".to_string(),
+                record: None,
+            },
+        ];
+
+        let per_message: u32 = messages
+            .iter()
+            .map(|msg| estimate_string_tokens(&format!("{}: {}", msg.sender, msg.content)))
+            .sum();
+        assert!(per_message > 0);
+        assert_eq!(per_message, estimate_token_count(&messages));
+    }
+
+    #[test]
+    fn test_failed_tokenizer_uses_fallback_without_retrying() {
+        let messages = vec![SimplifiedMessage {
+            sender: "user:alice".to_string(),
+            content: "Hello, how are you?".to_string(),
+            timestamp: "2026-02-27T10:00:00Z".to_string(),
+            record: None,
+        }];
+
+        let cell = OnceLock::new();
+        let attempts = std::cell::Cell::new(0);
+        let failing_load = || {
+            attempts.set(attempts.get() + 1);
+            Err(anyhow::anyhow!("simulated encoder failure"))
+        };
+
+        assert!(cached_tokenizer(&cell, failing_load).is_none());
+        assert!(cached_tokenizer(&cell, failing_load).is_none());
+        assert_eq!(attempts.get(), 1, "a failed load should not be retried");
+
+        assert_eq!(
+            estimate_token_count_with(cached_tokenizer(&cell, failing_load), &messages),
+            estimate_string_tokens_fallback("user:alice: Hello, how are you?")
+        );
+    }
+
+    #[tokio::test]
+    async fn disk_stats_summarize_history_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let session = Uuid::new_v4();
+        std::fs::write(dir.path().join(format!("{session}.json")), vec![b'a'; 300]).unwrap();
+        std::fs::write(
+            dir.path().join(format!("{session}_split.json")),
+            vec![b'b'; 120],
+        )
+        .unwrap();
+
+        let stats = chat_history_disk_stats_in(dir.path()).await.unwrap();
+        assert_eq!(stats.total_files, 2);
+        assert_eq!(stats.total_bytes, 420);
+        assert_eq!(stats.split_files, 1);
+        assert_eq!(
+            stats.largest_file,
+            Some((dir.path().join(format!("{session}.json")), 300))
+        );
+
+        let missing = chat_history_disk_stats_in(&dir.path().join("missing"))
+            .await
+            .unwrap();
+        assert_eq!(missing, HistoryDiskStats::default());
+    }
+
+    #[test]
+    fn fallback_estimate_depends_on_script() {
+        let chinese = "请帮我检查上传模块的重试逻辑";
+        let english = "Please check the retry logic";
+        assert_eq!(chinese.chars().count(), 14);
+        assert_eq!(english.chars().count(), 28);
+
+        // Half as many characters, but more tokens for the Chinese message.
+        let ratios = FallbackTokenRatios::default();
+        assert_eq!(estimate_string_tokens_fallback_with(&ratios, chinese), 10);
+        assert_eq!(estimate_string_tokens_fallback_with(&ratios, english), 7);
+
+        let broken = FallbackTokenRatios {
+            latin_chars_per_token: 0.0,
+            ..ratios
+        };
+        assert_eq!(
+            estimate_string_tokens_fallback_with(&broken, english),
+            (english.len() / 3) as u32
+        );
+    }
+
+    fn message(sender: &str, content: &str) -> SimplifiedMessage {
+        SimplifiedMessage {
+            sender: sender.to_string(),
+            content: content.to_string(),
+            timestamp: "2026-02-27T10:00:00Z".to_string(),
+            record: None,
+        }
+    }
+
+    async fn exercise_store(store: &dyn HistoryStore) {
+        let session_id = Uuid::new_v4();
+        assert!(
+            !store
+                .exists(session_id, HistoryFileKind::Main)
+                .await
+                .unwrap()
+        );
+
+        let history =
+            build_history_file(session_id, &[message("user:alice", "hello")], false, None);
+        store.write(HistoryFileKind::Main, &history).await.unwrap();
+        let read = store
+            .read(session_id, HistoryFileKind::Main)
+            .await
+            .unwrap()
+            .expect("history written");
+        assert_eq!(read.messages.len(), 1);
+        assert_eq!(read.metadata.token_count, history.metadata.token_count);
+
+        store
+            .append(
+                session_id,
+                HistoryFileKind::Split,
+                &[message("agent:coder", "one")],
+            )
+            .await
+            .unwrap();
+        store
+            .append(
+                session_id,
+                HistoryFileKind::Split,
+                &[message("agent:coder", "two")],
+            )
+            .await
+            .unwrap();
+        let split = store
+            .read(session_id, HistoryFileKind::Split)
+            .await
+            .unwrap()
+            .expect("split written");
+        let contents: Vec<&str> = split.messages.iter().map(|m| m.content.as_str()).collect();
+        assert_eq!(contents, vec!["one", "two"]);
+
+        store.delete(session_id).await.unwrap();
+        assert!(
+            !store
+                .exists(session_id, HistoryFileKind::Main)
+                .await
+                .unwrap()
+        );
+        assert!(
+            !store
+                .exists(session_id, HistoryFileKind::Split)
+                .await
+                .unwrap()
+        );
+        assert!(
+            store
+                .read(session_id, HistoryFileKind::Main)
+                .await
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_round_trips_through_trait() {
+        exercise_store(&InMemoryHistoryStore::default()).await;
+    }
+
+    #[tokio::test]
+    async fn fs_store_round_trips_through_trait() {
+        let dir = tempfile::tempdir().unwrap();
+        exercise_store(&FsHistoryStore::new(dir.path().join("chat_history"))).await;
+    }
+
+    #[tokio::test]
+    async fn flush_histories_writes_every_session_with_capped_concurrency() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = FsHistoryStore::new(dir.path().join("chat_history"));
+        let batches: Vec<HistoryBatch> = (0..40)
+            .map(|index| HistoryBatch {
+                session_id: Uuid::new_v4(),
+                messages: vec![SimplifiedMessage {
+                    sender: "user:alice".to_string(),
+                    content: format!("message {index}"),
+                    timestamp: "2026-02-27T10:00:00Z".to_string(),
+                    record: None,
+                }],
+                compression_applied: false,
+                split_file: None,
+            })
+            .collect();
+        let expected: Vec<Uuid> = batches.iter().map(|batch| batch.session_id).collect();
+
+        let results = flush_histories_to(&store, batches, 4).await;
+
+        assert_eq!(
+            results.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            expected
+        );
+        for (index, (session_id, result)) in results.into_iter().enumerate() {
+            assert_eq!(
+                result.unwrap(),
+                store.path(session_id, HistoryFileKind::Main).unwrap()
+            );
+            let history = store
+                .read(session_id, HistoryFileKind::Main)
+                .await
+                .unwrap()
+                .expect("history written");
+            assert_eq!(history.messages[0].content, format!("message {index}"));
+        }
+    }
 }
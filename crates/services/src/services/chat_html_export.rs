@@ -0,0 +1,405 @@
+//! Standalone HTML rendering of chat transcripts.
+//!
+//! All message text is escaped before it is wrapped in markup, so a transcript
+//! can be shared as a web page without user content injecting HTML. Fenced
+//! code blocks get a small built-in highlighter (comments, strings, numbers
+//! and common keywords) rather than a full grammar-based one. Attachment
+//! links are only emitted for plain relative paths, so a crafted path can't
+//! turn into a `javascript:` or off-site link.
+
+use std::path::{Component, Path};
+
+/// One message as shown in the HTML transcript.
+#[derive(Debug, Clone)]
+pub struct HtmlMessage {
+    pub sender_label: String,
+    /// `user`, `agent` or `system`; used as a CSS class.
+    pub sender_kind: &'static str,
+    pub created_at: String,
+    pub content: String,
+    /// `(name, href)` pairs listed under the message; attachments without an
+    /// href (see [`attachment_href`]) are shown as plain text.
+    pub attachments: Vec<(String, Option<String>)>,
+}
+
+const STYLE: &str = r#"
+body { font-family: -apple-system, BlinkMacSystemFont, "Segoe UI", sans-serif; max-width: 860px; margin: 2rem auto; padding: 0 1rem; color: #1f2328; background: #fff; }
+h1 { font-size: 1.4rem; }
+.message { border-left: 3px solid #d0d7de; margin: 1rem 0; padding: 0.25rem 0.75rem; }
+.message.user { border-color: #0969da; }
+.message.agent { border-color: #1a7f37; }
+.message.system { border-color: #8c959f; color: #57606a; }
+.meta { font-size: 0.8rem; color: #57606a; }
+.sender { font-weight: 600; color: #1f2328; }
+pre { background: #f6f8fa; padding: 0.75rem; overflow-x: auto; border-radius: 6px; }
+code { font-family: ui-monospace, SFMono-Regular, Menlo, monospace; font-size: 0.85rem; }
+.tok-kw { color: #cf222e; }
+.tok-str { color: #0a3069; }
+.tok-com { color: #6e7781; font-style: italic; }
+.tok-num { color: #0550ae; }
+.attachments { font-size: 0.85rem; }
+"#;
+
+/// Keywords highlighted in code blocks, across the languages agents usually write.
+const KEYWORDS: &str = "as async await break case class const continue def default elif else \
+    enum export extends false False fn for from func function if impl import in interface let \
+    match mod mut new None null pub return self static struct switch this trait true True try \
+    type use var while with yield";
+
+/// Comment and string rules for a fenced block's language.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Syntax {
+    line_comments: &'static [&'static str],
+    /// Whether `'` opens a string (rather than e.g. a Rust lifetime).
+    single_quote_strings: bool,
+}
+
+/// Rules for a fence info string such as `rust` or `python title="x"`.
+/// Unknown languages only get `//` comments and `"` strings.
+fn syntax_for(language: &str) -> Syntax {
+    let language = language
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '{')
+        .next()
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+    match language.as_str() {
+        "python" | "py" | "ruby" | "rb" | "perl" | "r" | "yaml" | "yml" | "toml" | "sh"
+        | "bash" | "zsh" | "shell" | "console" | "dockerfile" | "makefile" | "make" | "ini"
+        | "conf" => Syntax {
+            line_comments: &["#"],
+            single_quote_strings: true,
+        },
+        "sql" | "lua" | "haskell" | "hs" => Syntax {
+            line_comments: &["--"],
+            single_quote_strings: true,
+        },
+        "php" => Syntax {
+            line_comments: &["//", "#"],
+            single_quote_strings: true,
+        },
+        "js" | "javascript" | "jsx" | "ts" | "typescript" | "tsx" | "json5" => Syntax {
+            line_comments: &["//"],
+            single_quote_strings: true,
+        },
+        _ => Syntax {
+            line_comments: &["//"],
+            single_quote_strings: false,
+        },
+    }
+}
+
+/// Link target for an attachment stored at `relative_path` under
+/// `attachments_root`, relative to the directory the HTML file is written to.
+/// `None` unless `relative_path` is a plain relative path (only normal
+/// components, so no scheme, root, drive or `..`).
+pub fn attachment_href(
+    relative_path: &str,
+    attachments_root: &Path,
+    html_dir: &Path,
+) -> Option<String> {
+    let relative = Path::new(relative_path);
+    if relative_path.is_empty()
+        || relative_path.contains(':')
+        || !relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+    {
+        return None;
+    }
+    let target = std::path::absolute(attachments_root.join(relative)).ok()?;
+    let html_dir = std::path::absolute(html_dir).ok()?;
+
+    let target_parts: Vec<Component> = target.components().collect();
+    let dir_parts: Vec<Component> = html_dir.components().collect();
+    let common = target_parts
+        .iter()
+        .zip(&dir_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common == 0 {
+        // Different drives: no relative link exists.
+        return None;
+    }
+    let mut segments: Vec<String> = vec!["..".to_string(); dir_parts.len() - common];
+    for part in &target_parts[common..] {
+        segments.push(percent_encode_segment(&part.as_os_str().to_string_lossy()));
+    }
+    Some(segments.join("/"))
+}
+
+/// Percent-encode one URL path segment, keeping unreserved characters.
+fn percent_encode_segment(segment: &str) -> String {
+    let mut encoded = String::with_capacity(segment.len());
+    for byte in segment.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.' | b'~') {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{byte:02X}"));
+        }
+    }
+    encoded
+}
+
+/// Escape text for use in HTML element content and quoted attributes.
+pub fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+fn push_token(out: &mut String, class: Option<&str>, text: &str) {
+    match class {
+        Some(class) => {
+            out.push_str("<span class=\"");
+            out.push_str(class);
+            out.push_str("\">");
+            out.push_str(&escape_html(text));
+            out.push_str("</span>");
+        }
+        None => out.push_str(&escape_html(text)),
+    }
+}
+
+/// Highlight one code block written in `language` (the fence info string).
+/// Returns escaped HTML.
+pub fn highlight_code(code: &str, language: &str) -> String {
+    let syntax = syntax_for(language);
+    let chars: Vec<char> = code.chars().collect();
+    let mut out = String::with_capacity(code.len() * 2);
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let start = i;
+        let starts_comment = syntax.line_comments.iter().any(|marker| {
+            marker
+                .chars()
+                .enumerate()
+                .all(|(offset, m)| chars.get(i + offset) == Some(&m))
+        });
+        if starts_comment {
+            while i < chars.len() && chars[i] != '\n' {
+                i += 1;
+            }
+            push_token(
+                &mut out,
+                Some("tok-com"),
+                &chars[start..i].iter().collect::<String>(),
+            );
+        } else if c == '"' || (c == '\'' && syntax.single_quote_strings) {
+            i += 1;
+            while i < chars.len() && chars[i] != c && chars[i] != '\n' {
+                if chars[i] == '\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            i = (i + 1).min(chars.len());
+            push_token(
+                &mut out,
+                Some("tok-str"),
+                &chars[start..i].iter().collect::<String>(),
+            );
+        } else if c.is_ascii_digit() {
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                i += 1;
+            }
+            push_token(
+                &mut out,
+                Some("tok-num"),
+                &chars[start..i].iter().collect::<String>(),
+            );
+        } else if c.is_alphabetic() || c == '_' {
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            let class = KEYWORDS
+                .split_whitespace()
+                .any(|keyword| keyword == word)
+                .then_some("tok-kw");
+            push_token(&mut out, class, &word);
+        } else {
+            i += 1;
+            push_token(&mut out, None, &c.to_string());
+        }
+    }
+    out
+}
+
+/// Render prose: escaped, with inline code spans and line breaks.
+fn render_prose(text: &str, out: &mut String) {
+    for (index, line) in text.split('\n').enumerate() {
+        if index > 0 {
+            out.push_str("<br>\n");
+        }
+        let mut rest = line;
+        while let Some(open) = rest.find('`') {
+            let Some(close) = rest[open + 1..].find('`') else {
+                break;
+            };
+            out.push_str(&escape_html(&rest[..open]));
+            out.push_str("<code>");
+            out.push_str(&escape_html(&rest[open + 1..open + 1 + close]));
+            out.push_str("</code>");
+            rest = &rest[open + 1 + close + 1..];
+        }
+        out.push_str(&escape_html(rest));
+    }
+}
+
+/// Render message content: fenced code blocks become highlighted `<pre>`
+/// blocks (an unterminated fence runs to the end), everything else is prose.
+pub fn render_content(content: &str) -> String {
+    let mut out = String::new();
+    let mut prose = Vec::new();
+    let mut code: Option<(String, Vec<&str>)> = None;
+
+    for line in content.split('\n') {
+        let fence = line.trim_start().strip_prefix("```");
+        match (&mut code, fence) {
+            (Some((language, lines)), Some(_)) => {
+                push_code_block(&mut out, language, &lines.join("\n"));
+                code = None;
+            }
+            (Some((_, lines)), None) => lines.push(line),
+            (None, Some(language)) => {
+                if !prose.is_empty() {
+                    render_prose(&prose.join("\n"), &mut out);
+                    prose.clear();
+                }
+                code = Some((language.trim().to_string(), Vec::new()));
+            }
+            (None, None) => prose.push(line),
+        }
+    }
+    if let Some((language, lines)) = code {
+        push_code_block(&mut out, &language, &lines.join("\n"));
+    }
+    if !prose.is_empty() {
+        render_prose(&prose.join("\n"), &mut out);
+    }
+    out
+}
+
+fn push_code_block(out: &mut String, language: &str, code: &str) {
+    out.push_str("<pre><code");
+    if !language.is_empty() {
+        out.push_str(" class=\"language-");
+        out.push_str(&escape_html(language));
+        out.push('"');
+    }
+    out.push('>');
+    out.push_str(&highlight_code(code, language));
+    out.push_str("</code></pre>\n");
+}
+
+/// Render a full HTML document for a transcript.
+pub fn render_transcript_html(title: &str, messages: &[HtmlMessage]) -> String {
+    let title = escape_html(title);
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n<style>{STYLE}</style>\n</head>\n<body>\n<h1>{title}</h1>\n"
+    );
+    for message in messages {
+        html.push_str(&format!(
+            "<div class=\"message {}\">\n<div class=\"meta\"><span class=\"sender\">{}</span> · {}</div>\n<div class=\"content\">",
+            message.sender_kind,
+            escape_html(&message.sender_label),
+            escape_html(&message.created_at),
+        ));
+        html.push_str(&render_content(&message.content));
+        html.push_str("</div>\n");
+        if !message.attachments.is_empty() {
+            html.push_str("<ul class=\"attachments\">\n");
+            for (name, href) in &message.attachments {
+                match href {
+                    Some(href) => html.push_str(&format!(
+                        "<li><a href=\"{}\">{}</a></li>\n",
+                        escape_html(href),
+                        escape_html(name)
+                    )),
+                    None => html.push_str(&format!("<li>{}</li>\n", escape_html(name))),
+                }
+            }
+            html.push_str("</ul>\n");
+        }
+        html.push_str("</div>\n");
+    }
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{attachment_href, highlight_code, render_content};
+
+    #[test]
+    fn highlights_code_and_escapes_markup() {
+        let html = highlight_code("let x = \"<b>\"; // done", "rust");
+        assert_eq!(
+            html,
+            "<span class=\"tok-kw\">let</span> x = <span class=\"tok-str\">&quot;&lt;b&gt;&quot;</span>; <span class=\"tok-com\">// done</span>"
+        );
+    }
+
+    #[test]
+    fn unterminated_fence_runs_to_end() {
+        let html = render_content("intro\n```rust\nfn main() {}");
+        assert!(html.starts_with("intro<pre><code class=\"language-rust\">"));
+        assert!(html.ends_with("</code></pre>\n"));
+    }
+
+    #[test]
+    fn comment_and_string_markers_depend_on_the_language() {
+        let rust = highlight_code("#[derive(Debug)]\nfn f<'a>(x: &'a str) {}", "rust");
+        assert!(!rust.contains("tok-com"));
+        assert!(!rust.contains("tok-str"));
+
+        let python = highlight_code("x = 'a' # note", "python");
+        assert!(python.contains("<span class=\"tok-str\">&#39;a&#39;</span>"));
+        assert!(python.contains("<span class=\"tok-com\"># note</span>"));
+
+        let sql = highlight_code("SELECT 1 -- one", "sql");
+        assert!(sql.contains("<span class=\"tok-com\">-- one</span>"));
+    }
+
+    #[test]
+    fn only_plain_relative_attachment_paths_are_linked() {
+        let root = Path::new("/data/assets");
+        assert_eq!(
+            attachment_href(
+                "chat/s1/notes v2.txt",
+                root,
+                Path::new("/data/assets/exports")
+            ),
+            Some("../chat/s1/notes%20v2.txt".to_string())
+        );
+        assert_eq!(
+            attachment_href("chat/s1/a.png", root, Path::new("/home/me/shared")),
+            Some("../../../data/assets/chat/s1/a.png".to_string())
+        );
+        for unsafe_path in [
+            "javascript:alert(1)",
+            "https://example.com/x",
+            "/etc/passwd",
+            "../outside.txt",
+            "",
+        ] {
+            assert_eq!(
+                attachment_href(unsafe_path, root, Path::new("/data/assets")),
+                None,
+                "{unsafe_path}"
+            );
+        }
+    }
+}
@@ -0,0 +1,276 @@
+//! Hook for feeding stored chat messages into an external index (e.g. a
+//! vector store for cross-session retrieval).
+//!
+//! Indexers are registered at startup and run on a spawned task after a
+//! message is committed, so a slow or failing index never affects message
+//! creation. An indexer stays registered while its
+//! [`MessageIndexerRegistration`] is kept.
+
+use std::{
+    path::PathBuf,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+use async_trait::async_trait;
+use db::models::chat_message::ChatMessage;
+use once_cell::sync::Lazy;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::Mutex};
+
+/// Receives every message stored through the chat service.
+#[async_trait]
+pub trait MessageIndexer: Send + Sync {
+    async fn index(&self, _message: &ChatMessage) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Indexer that does nothing.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoopIndexer;
+
+impl MessageIndexer for NoopIndexer {}
+
+/// Reference indexer that appends one JSON line of embeddable text per
+/// message to a file. Stands in for a real vector store.
+#[derive(Debug)]
+pub struct JsonlIndexer {
+    path: PathBuf,
+    /// Serializes appends so concurrent messages don't interleave lines.
+    write_lock: Mutex<()>,
+}
+
+impl JsonlIndexer {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            write_lock: Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &PathBuf {
+        &self.path
+    }
+}
+
+/// Text to embed for a message: the sender label followed by the content.
+pub fn embeddable_text(message: &ChatMessage) -> String {
+    let label = message
+        .meta
+        .0
+        .get("sender")
+        .and_then(|sender| sender.get("label"))
+        .and_then(|label| label.as_str());
+    match label {
+        Some(label) => format!("{label}: {}", message.content),
+        None => message.content.clone(),
+    }
+}
+
+#[async_trait]
+impl MessageIndexer for JsonlIndexer {
+    async fn index(&self, message: &ChatMessage) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(&serde_json::json!({
+            "message_id": message.id,
+            "session_id": message.session_id,
+            "sender_type": message.sender_type,
+            "created_at": message.created_at.to_rfc3339(),
+            "text": embeddable_text(message),
+        }))?;
+        line.push('\n');
+
+        let _guard = self.write_lock.lock().await;
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(line.as_bytes()).await?;
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+type MessageIndexerList = Vec<(u64, Arc<dyn MessageIndexer>)>;
+
+static MESSAGE_INDEXERS: Lazy<std::sync::RwLock<MessageIndexerList>> =
+    Lazy::new(|| std::sync::RwLock::new(Vec::new()));
+static NEXT_MESSAGE_INDEXER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Keeps an indexer added with [`register_message_indexer`] registered.
+/// Dropping it unregisters the indexer.
+#[derive(Debug)]
+#[must_use = "the indexer is unregistered when this is dropped"]
+pub struct MessageIndexerRegistration {
+    id: u64,
+}
+
+impl Drop for MessageIndexerRegistration {
+    fn drop(&mut self) {
+        MESSAGE_INDEXERS
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .retain(|(id, _)| *id != self.id);
+    }
+}
+
+/// Register an indexer that is called for every stored message, for as long
+/// as the returned registration is kept.
+pub fn register_message_indexer(indexer: Arc<dyn MessageIndexer>) -> MessageIndexerRegistration {
+    let id = NEXT_MESSAGE_INDEXER_ID.fetch_add(1, Ordering::Relaxed);
+    MESSAGE_INDEXERS
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .push((id, indexer));
+    MessageIndexerRegistration { id }
+}
+
+/// Hand a stored message to every registered indexer on a background task.
+/// Failures are logged and otherwise ignored.
+pub fn spawn_index_message(message: &ChatMessage) {
+    let indexers: Vec<Arc<dyn MessageIndexer>> = MESSAGE_INDEXERS
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .iter()
+        .map(|(_, indexer)| indexer.clone())
+        .collect();
+    if indexers.is_empty() {
+        return;
+    }
+
+    let message = message.clone();
+    tokio::spawn(async move {
+        for indexer in indexers {
+            if let Err(err) = indexer.index(&message).await {
+                tracing::warn!(
+                    session_id = %message.session_id,
+                    message_id = %message.id,
+                    error = %err,
+                    "Failed to index chat message"
+                );
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use async_trait::async_trait;
+    use db::models::{
+        chat_message::{ChatMessage, ChatSenderType},
+        chat_session::{ChatSession, CreateChatSession},
+    };
+    use sqlx::SqlitePool;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+
+    use super::{JsonlIndexer, MessageIndexer, register_message_indexer};
+    use crate::services::chat::create_message;
+
+    struct ChannelIndexer(mpsc::UnboundedSender<ChatMessage>);
+
+    #[async_trait]
+    impl MessageIndexer for ChannelIndexer {
+        async fn index(&self, message: &ChatMessage) -> anyhow::Result<()> {
+            let _ = self.0.send(message.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn posting_a_message_triggers_indexer() {
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        sqlx::migrate!("../db/migrations")
+            .run(&pool)
+            .await
+            .expect("run migrations");
+        let session =
+            ChatSession::create(&pool, &CreateChatSession { title: None }, Uuid::new_v4())
+                .await
+                .expect("create session");
+
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let registration = register_message_indexer(Arc::new(ChannelIndexer(sender)));
+
+        let message = create_message(
+            &pool,
+            session.id,
+            ChatSenderType::User,
+            None,
+            "index me".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+
+        // Other tests share the global indexer list, so skip their messages.
+        loop {
+            let indexed = receiver.recv().await.expect("indexer channel open");
+            if indexed.session_id == session.id {
+                assert_eq!(indexed.id, message.id);
+                break;
+            }
+        }
+
+        // Once unregistered, later messages no longer reach the indexer and
+        // the channel closes when in-flight tasks finish.
+        drop(registration);
+        let unindexed = create_message(
+            &pool,
+            session.id,
+            ChatSenderType::User,
+            None,
+            "not indexed".to_string(),
+            None,
+        )
+        .await
+        .expect("create message");
+        while let Some(indexed) = receiver.recv().await {
+            assert_ne!(indexed.id, unindexed.id);
+        }
+    }
+
+    #[tokio::test]
+    async fn jsonl_indexer_appends_one_line_per_message() {
+        let dir = tempfile::tempdir().unwrap();
+        let indexer = JsonlIndexer::new(dir.path().join("index").join("messages.jsonl"));
+        let pool = SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::migrate!("../db/migrations").run(&pool).await.unwrap();
+        let session =
+            ChatSession::create(&pool, &CreateChatSession { title: None }, Uuid::new_v4())
+                .await
+                .unwrap();
+        for content in ["first", "second"] {
+            let message = create_message(
+                &pool,
+                session.id,
+                ChatSenderType::User,
+                None,
+                content.to_string(),
+                Some(serde_json::json!({ "sender_handle": "alice" })),
+            )
+            .await
+            .unwrap();
+            indexer.index(&message).await.unwrap();
+        }
+
+        let written = std::fs::read_to_string(indexer.path()).unwrap();
+        let texts: Vec<String> = written
+            .lines()
+            .map(|line| {
+                let value: serde_json::Value = serde_json::from_str(line).unwrap();
+                value["text"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(texts, vec!["alice: first", "alice: second"]);
+    }
+}
@@ -39,14 +39,17 @@ use tokio::{
     fs,
     io::AsyncWriteExt,
     process::Command,
-    sync::{Mutex, broadcast},
+    sync::{Mutex, RwLock, broadcast},
 };
 use tokio_util::io::ReaderStream;
 use ts_rs::TS;
 use utils::{assets::asset_dir, log_msg::LogMsg, msg_store::MsgStore};
 use uuid::Uuid;
 
-use crate::services::chat::{self, ChatServiceError};
+use crate::services::{
+    chat::{self, ChatServiceError, ContextBuildOptions},
+    config::Config,
+};
 
 const UNTRACKED_FILE_LIMIT: u64 = 1024 * 1024;
 const MAX_AGENT_CHAIN_DEPTH: u32 = 5;
@@ -56,7 +59,7 @@ const RUNS_DIR_NAME: &str = "runs";
 const CONTEXT_DIR_NAME: &str = "context";
 const LEGACY_COMPACTED_CONTEXT_FILE_NAME: &str = "messages_compacted.background.jsonl";
 const RUN_RECORDS_DIR_NAME: &str = "run_records";
-const RESERVED_USER_HANDLE: &str = "you";
+const RESERVED_USER_HANDLE: &str = chat::USER_MENTION_HANDLE;
 const EXECUTOR_PROFILE_VARIANT_KEY: &str = "executor_profile_variant";
 
 struct DiffInfo {
@@ -206,6 +209,7 @@ struct PendingMessage {
 #[derive(Clone)]
 pub struct ChatRunner {
     db: DBService,
+    config: Arc<RwLock<Config>>,
     streams: Arc<DashMap<Uuid, broadcast::Sender<ChatStreamEvent>>>,
     // Store cancellation tokens for graceful shutdown, key = session_agent_id
     cancellation_tokens: Arc<DashMap<Uuid, CancellationToken>>,
@@ -218,9 +222,10 @@ pub struct ChatRunner {
 }
 
 impl ChatRunner {
-    pub fn new(db: DBService) -> Self {
+    pub fn new(db: DBService, config: Arc<RwLock<Config>>) -> Self {
         Self {
             db,
+            config,
             streams: Arc::new(DashMap::new()),
             cancellation_tokens: Arc::new(DashMap::new()),
             pending_messages: Arc::new(DashMap::new()),
@@ -1182,8 +1187,9 @@ impl ChatRunner {
         }
 
         // Main path must never block on summarization: always build full context synchronously.
+        let options = ContextBuildOptions::from_config(&*self.config.read().await);
         let full_context =
-            crate::services::chat::build_full_context(&self.db.pool, session_id).await?;
+            crate::services::chat::build_full_context(&self.db.pool, session_id, &options).await?;
         let jsonl = full_context.jsonl;
         let context_path = context_dir.join("messages.jsonl");
         fs::write(&context_path, jsonl.as_bytes()).await?;
@@ -1230,12 +1236,14 @@ impl ChatRunner {
         let runner = self.clone();
         tokio::spawn(async move {
             let workspace_path_buf = PathBuf::from(&workspace_path);
+            let config = runner.config.read().await.clone();
             let result = crate::services::chat::build_compacted_context(
                 &runner.db.pool,
                 session_id,
                 None,
                 Some(workspace_path_buf.as_path()),
                 Some(context_dir.as_path()),
+                &config,
             )
             .await;
 
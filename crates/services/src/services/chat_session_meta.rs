@@ -0,0 +1,294 @@
+//! Typed access to the `chat_sessions.meta` JSON column.
+//!
+//! Features that need per-session state read and write it through
+//! [`SessionMeta`] instead of mutating raw JSON. Keys this module doesn't know
+//! about are carried through untouched, so a save never drops data written by
+//! another feature (or a newer version of the app).
+
+use serde::{Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
+use sqlx::{Row, SqliteExecutor, SqlitePool};
+use uuid::Uuid;
+
+const PINNED_KEY: &str = "pinned";
+const PINNED_MESSAGE_IDS_KEY: &str = "pinned_message_ids";
+const PARTICIPANTS_KEY: &str = "participants";
+const READ_CURSOR_KEY: &str = "read_cursor";
+const TEMPLATE_KEY: &str = "template";
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SessionMeta {
+    pinned: bool,
+    pinned_message_ids: Vec<Uuid>,
+    participants: Vec<Uuid>,
+    read_cursor: Option<Uuid>,
+    template: bool,
+    /// Keys without a typed accessor, plus typed keys whose stored value could
+    /// not be parsed (kept as-is until a setter replaces them).
+    extra: Map<String, Value>,
+}
+
+/// Remove `key` from `map` and parse it, leaving it in place if it doesn't parse.
+fn take_typed<T: DeserializeOwned + Default>(map: &mut Map<String, Value>, key: &str) -> T {
+    let Some(value) = map.get(key) else {
+        return T::default();
+    };
+    match serde_json::from_value(value.clone()) {
+        Ok(parsed) => {
+            map.remove(key);
+            parsed
+        }
+        Err(err) => {
+            tracing::warn!(key, error = %err, "Ignoring malformed chat session meta value");
+            T::default()
+        }
+    }
+}
+
+fn insert_typed<T: Serialize>(map: &mut Map<String, Value>, key: &str, value: &T) {
+    map.insert(
+        key.to_string(),
+        serde_json::to_value(value).unwrap_or(Value::Null),
+    );
+}
+
+impl SessionMeta {
+    /// Parse stored meta. Non-object values are treated as empty meta.
+    pub fn from_value(value: Value) -> Self {
+        let mut extra = match value {
+            Value::Object(map) => map,
+            _ => Map::new(),
+        };
+        let pinned = take_typed(&mut extra, PINNED_KEY);
+        let pinned_message_ids = take_typed(&mut extra, PINNED_MESSAGE_IDS_KEY);
+        let participants = take_typed(&mut extra, PARTICIPANTS_KEY);
+        let read_cursor = take_typed(&mut extra, READ_CURSOR_KEY);
+        let template = take_typed(&mut extra, TEMPLATE_KEY);
+
+        Self {
+            pinned,
+            pinned_message_ids,
+            participants,
+            read_cursor,
+            template,
+            extra,
+        }
+    }
+
+    pub fn to_value(&self) -> Value {
+        let mut map = self.extra.clone();
+        if self.pinned {
+            insert_typed(&mut map, PINNED_KEY, &self.pinned);
+        }
+        if !self.pinned_message_ids.is_empty() {
+            insert_typed(&mut map, PINNED_MESSAGE_IDS_KEY, &self.pinned_message_ids);
+        }
+        if !self.participants.is_empty() {
+            insert_typed(&mut map, PARTICIPANTS_KEY, &self.participants);
+        }
+        if let Some(read_cursor) = self.read_cursor {
+            insert_typed(&mut map, READ_CURSOR_KEY, &read_cursor);
+        }
+        if self.template {
+            insert_typed(&mut map, TEMPLATE_KEY, &self.template);
+        }
+        Value::Object(map)
+    }
+
+    /// Pinned sessions are kept by retention pruning.
+    pub fn is_pinned(&self) -> bool {
+        self.pinned
+    }
+
+    pub fn set_pinned(&mut self, pinned: bool) {
+        self.extra.remove(PINNED_KEY);
+        self.pinned = pinned;
+    }
+
+    pub fn pinned_message_ids(&self) -> &[Uuid] {
+        &self.pinned_message_ids
+    }
+
+    pub fn set_pinned_message_ids(&mut self, ids: Vec<Uuid>) {
+        self.extra.remove(PINNED_MESSAGE_IDS_KEY);
+        self.pinned_message_ids = ids;
+    }
+
+    /// Agent IDs that have taken part in the session.
+    pub fn participants(&self) -> &[Uuid] {
+        &self.participants
+    }
+
+    pub fn set_participants(&mut self, participants: Vec<Uuid>) {
+        self.extra.remove(PARTICIPANTS_KEY);
+        self.participants = participants;
+    }
+
+    /// Last message the user has read; later messages count as unread.
+    pub fn read_cursor(&self) -> Option<Uuid> {
+        self.read_cursor
+    }
+
+    pub fn set_read_cursor(&mut self, message_id: Option<Uuid>) {
+        self.extra.remove(READ_CURSOR_KEY);
+        self.read_cursor = message_id;
+    }
+
+    /// Template sessions seed new sessions and don't accept messages.
+    pub fn is_template(&self) -> bool {
+        self.template
+    }
+
+    pub fn set_template(&mut self, template: bool) {
+        self.extra.remove(TEMPLATE_KEY);
+        self.template = template;
+    }
+
+    /// Raw value for a key without a typed accessor.
+    pub fn get_extra(&self, key: &str) -> Option<&Value> {
+        self.extra.get(key)
+    }
+
+    pub fn set_extra(&mut self, key: impl Into<String>, value: Value) {
+        self.extra.insert(key.into(), value);
+    }
+
+    pub fn remove_extra(&mut self, key: &str) -> Option<Value> {
+        self.extra.remove(key)
+    }
+
+    /// Load meta for a session. Fails with `RowNotFound` if the session doesn't exist.
+    pub async fn load<'e, E>(executor: E, session_id: Uuid) -> Result<Self, sqlx::Error>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let row = sqlx::query("SELECT meta FROM chat_sessions WHERE id = ?1")
+            .bind(session_id)
+            .fetch_optional(executor)
+            .await?
+            .ok_or(sqlx::Error::RowNotFound)?;
+        let raw: String = row.try_get("meta")?;
+        let value = serde_json::from_str(&raw).unwrap_or_else(|err| {
+            tracing::warn!(
+                session_id = %session_id,
+                error = %err,
+                "Chat session meta is not valid JSON; treating as empty"
+            );
+            Value::Null
+        });
+        Ok(Self::from_value(value))
+    }
+
+    /// Replace the stored meta for a session.
+    pub async fn save<'e, E>(&self, executor: E, session_id: Uuid) -> Result<(), sqlx::Error>
+    where
+        E: SqliteExecutor<'e>,
+    {
+        let result = sqlx::query("UPDATE chat_sessions SET meta = ?2 WHERE id = ?1")
+            .bind(session_id)
+            .bind(self.to_value().to_string())
+            .execute(executor)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(sqlx::Error::RowNotFound);
+        }
+        Ok(())
+    }
+
+    /// Load, modify and save a session's meta in one transaction.
+    pub async fn modify<F>(pool: &SqlitePool, session_id: Uuid, f: F) -> Result<Self, sqlx::Error>
+    where
+        F: FnOnce(&mut SessionMeta),
+    {
+        let mut tx = pool.begin().await?;
+        let mut meta = Self::load(&mut *tx, session_id).await?;
+        f(&mut meta);
+        meta.save(&mut *tx, session_id).await?;
+        tx.commit().await?;
+        Ok(meta)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use db::models::chat_session::{ChatSession, CreateChatSession};
+    use serde_json::json;
+    use sqlx::SqlitePool;
+    use uuid::Uuid;
+
+    use super::SessionMeta;
+
+    async fn setup_session() -> (SqlitePool, Uuid) {
+        let pool = SqlitePool::connect("sqlite::memory:")
+            .await
+            .expect("create sqlite memory pool");
+        sqlx::migrate!("../db/migrations")
+            .run(&pool)
+            .await
+            .expect("run migrations");
+        let session =
+            ChatSession::create(&pool, &CreateChatSession { title: None }, Uuid::new_v4())
+                .await
+                .expect("create session");
+        (pool, session.id)
+    }
+
+    #[tokio::test]
+    async fn typed_setters_preserve_unrelated_keys() {
+        let (pool, session_id) = setup_session().await;
+        sqlx::query("UPDATE chat_sessions SET meta = ?2 WHERE id = ?1")
+            .bind(session_id)
+            .bind(json!({ "theme": "dark", "future_feature": { "enabled": true } }).to_string())
+            .execute(&pool)
+            .await
+            .expect("seed meta");
+
+        let pinned = vec![Uuid::new_v4()];
+        let participants = vec![Uuid::new_v4(), Uuid::new_v4()];
+        SessionMeta::modify(&pool, session_id, |meta| {
+            meta.set_pinned_message_ids(pinned.clone());
+        })
+        .await
+        .expect("pin message");
+        SessionMeta::modify(&pool, session_id, |meta| {
+            meta.set_participants(participants.clone());
+        })
+        .await
+        .expect("set participants");
+
+        let meta = SessionMeta::load(&pool, session_id)
+            .await
+            .expect("load meta");
+        assert_eq!(meta.pinned_message_ids(), pinned.as_slice());
+        assert_eq!(meta.participants(), participants.as_slice());
+        assert_eq!(meta.get_extra("theme"), Some(&json!("dark")));
+        assert_eq!(
+            meta.get_extra("future_feature"),
+            Some(&json!({ "enabled": true }))
+        );
+    }
+
+    #[test]
+    fn malformed_typed_value_is_kept_until_replaced() {
+        let stored = json!({ "participants": "not-a-list", "other": 1 });
+        let mut meta = SessionMeta::from_value(stored.clone());
+        assert!(meta.participants().is_empty());
+        assert_eq!(meta.to_value(), stored);
+
+        let agent = Uuid::new_v4();
+        meta.set_participants(vec![agent]);
+        assert_eq!(
+            meta.to_value(),
+            json!({ "participants": [agent], "other": 1 })
+        );
+    }
+
+    #[tokio::test]
+    async fn load_missing_session_is_row_not_found() {
+        let (pool, _) = setup_session().await;
+        let err = SessionMeta::load(&pool, Uuid::new_v4())
+            .await
+            .expect_err("missing session");
+        assert!(matches!(err, sqlx::Error::RowNotFound));
+    }
+}
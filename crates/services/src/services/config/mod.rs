@@ -1,4 +1,4 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use thiserror::Error;
 
@@ -6,6 +6,7 @@ pub mod editor;
 mod versions;
 
 pub use editor::EditorOpenError;
+pub use versions::v9::{resolve_workspace_path, resolved_system_prompt};
 
 pub const DEFAULT_PR_DESCRIPTION_PROMPT: &str = r#"Update the PR that was just created with a better title and description.
 The PR number is #{pr_number} and the URL is {pr_url}.
@@ -30,6 +31,8 @@ pub enum ConfigError {
     Json(#[from] serde_json::Error),
     #[error("Validation error: {0}")]
     ValidationError(String),
+    #[error("Config version v{found} is newer than the supported v{supported}")]
+    NewerVersion { found: u32, supported: u32 },
 }
 
 pub type Config = versions::v9::Config;
@@ -45,25 +48,132 @@ pub type SendMessageShortcut = versions::v9::SendMessageShortcut;
 pub type ChatMemberPreset = versions::v9::ChatMemberPreset;
 pub type ChatTeamPreset = versions::v9::ChatTeamPreset;
 pub type ChatPresetsConfig = versions::v9::ChatPresetsConfig;
+pub type PresetResetError = versions::v9::ResetError;
 pub type ChatCompressionConfig = versions::v9::ChatCompressionConfig;
+pub type NotificationSchedule = versions::v9::NotificationSchedule;
+pub type QuietHours = versions::v9::QuietHours;
+pub type ConfigFieldChange = versions::v9::FieldChange;
+pub type ConfigIssue = versions::v9::ConfigIssue;
+pub type PresetInheritanceError = versions::v9::PresetInheritanceError;
+pub type ConfigPatchError = versions::v9::PatchError;
 
 /// Will always return config, trying old schemas or eventually returning default
 pub async fn load_config_from_file(config_path: &PathBuf) -> Config {
-    match std::fs::read_to_string(config_path) {
-        Ok(raw_config) => Config::from(raw_config),
+    load_config_checked(config_path).0
+}
+
+/// Reject configs written by a newer build, which the migration chain would
+/// otherwise silently downgrade.
+fn check_config_version(raw_config: &str) -> Result<(), ConfigError> {
+    match versions::raw_config_version(raw_config) {
+        Some(found) if found > versions::CURRENT_CONFIG_VERSION => Err(ConfigError::NewerVersion {
+            found,
+            supported: versions::CURRENT_CONFIG_VERSION,
+        }),
+        _ => Ok(()),
+    }
+}
+
+/// Copy a config this build can't read next to the original, so saving the
+/// default config over it doesn't lose the user's settings.
+fn backup_config_file(config_path: &Path, version: u32) -> std::io::Result<PathBuf> {
+    let file_name = config_path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_string())
+        .unwrap_or_else(|| "config.json".to_string());
+    let backup_path = config_path.with_file_name(format!(
+        "{file_name}.v{version}.{}.bak",
+        chrono::Utc::now().format("%Y%m%dT%H%M%S")
+    ));
+    std::fs::copy(config_path, &backup_path)?;
+    Ok(backup_path)
+}
+
+/// Load the config, also returning the error that forced a fallback to the
+/// default (currently only a config from a newer version).
+fn load_config_checked(config_path: &PathBuf) -> (Config, Option<ConfigError>) {
+    let raw_config = match std::fs::read_to_string(config_path) {
+        Ok(raw_config) => raw_config,
         Err(_) => {
             tracing::info!("No config file found, creating one");
-            Config::default()
+            return (Config::default(), None);
+        }
+    };
+
+    if let Err(err @ ConfigError::NewerVersion { found, .. }) = check_config_version(&raw_config) {
+        match backup_config_file(config_path, found) {
+            Ok(backup_path) => tracing::warn!(
+                "{}; backed up to {} and using default config",
+                err,
+                backup_path.display()
+            ),
+            Err(backup_err) => tracing::warn!(
+                "{}; failed to back up {}: {}; using default config",
+                err,
+                config_path.display(),
+                backup_err
+            ),
         }
+        return (Config::default(), Some(err));
     }
+
+    (Config::from(raw_config), None)
 }
 
-/// Saves the config to the given path
+/// Saves the config to the given path. Problems found by [`Config::validate`]
+/// are logged but don't block the save; the deployment saves once at startup,
+/// so this also covers the config loaded from disk.
 pub async fn save_config_to_file(
     config: &Config,
     config_path: &PathBuf,
 ) -> Result<(), ConfigError> {
+    if let Err(issues) = config.validate() {
+        for issue in issues {
+            tracing::warn!("Config issue: {}", issue);
+        }
+    }
     let raw_config = serde_json::to_string_pretty(config)?;
     std::fs::write(config_path, raw_config)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, ConfigError, load_config_checked};
+
+    #[test]
+    fn newer_config_version_is_backed_up_and_replaced_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let config_path = dir.path().join("config.json");
+        let raw = r#"{"config_version":"v11","theme":"DARK","future_setting":true}"#;
+        std::fs::write(&config_path, raw).unwrap();
+
+        let (config, warning) = load_config_checked(&config_path);
+
+        assert_eq!(
+            serde_json::to_value(&config).unwrap(),
+            serde_json::to_value(Config::default()).unwrap()
+        );
+        assert!(matches!(
+            warning,
+            Some(ConfigError::NewerVersion {
+                found: 11,
+                supported: 9
+            })
+        ));
+        let backups: Vec<_> = std::fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path != &config_path)
+            .collect();
+        assert_eq!(backups.len(), 1);
+        assert!(
+            backups[0]
+                .file_name()
+                .unwrap()
+                .to_string_lossy()
+                .starts_with("config.json.v11.")
+        );
+        assert_eq!(std::fs::read_to_string(&backups[0]).unwrap(), raw);
+    }
+}
@@ -7,3 +7,87 @@ pub(super) mod v6;
 pub(super) mod v7;
 pub(super) mod v8;
 pub(super) mod v9;
+
+/// Schema version written by this build.
+pub(super) const CURRENT_CONFIG_VERSION: u32 = 9;
+
+/// Numeric `config_version` of a raw config (`"v9"` -> 9), if it has one.
+pub(super) fn raw_config_version(raw_config: &str) -> Option<u32> {
+    let value: serde_json::Value = serde_json::from_str(raw_config).ok()?;
+    value
+        .get("config_version")?
+        .as_str()?
+        .strip_prefix('v')?
+        .parse()
+        .ok()
+}
+
+/// Upgrade `raw_config` one schema step, to version `target`.
+fn upgrade_step(target: u32, raw_config: &str) -> Result<String, anyhow::Error> {
+    let upgraded = match target {
+        2 => serde_json::to_string(&v2::Config::from_previous_version(raw_config)?)?,
+        3 => serde_json::to_string(&v3::Config::from_previous_version(raw_config)?)?,
+        4 => serde_json::to_string(&v4::Config::from_previous_version(raw_config)?)?,
+        5 => serde_json::to_string(&v5::Config::from_previous_version(raw_config)?)?,
+        6 => serde_json::to_string(&v6::Config::from_previous_version(raw_config)?)?,
+        7 => serde_json::to_string(&v7::Config::from_previous_version(raw_config)?)?,
+        8 => serde_json::to_string(&v8::Config::from_previous_version(raw_config)?)?,
+        9 => serde_json::to_string(&v9::Config::from_previous_version(raw_config)?)?,
+        _ => anyhow::bail!("no migration to config v{target}"),
+    };
+    Ok(upgraded)
+}
+
+/// Migrate a raw config of any known version to the current schema. The
+/// incoming `config_version` picks the starting point (v1 configs have none),
+/// then each migration step runs in sequence.
+pub(super) fn migrate_to_current(raw_config: &str) -> Result<v9::Config, anyhow::Error> {
+    let from = raw_config_version(raw_config).unwrap_or(1);
+    if from > CURRENT_CONFIG_VERSION {
+        anyhow::bail!("config v{from} is newer than supported v{CURRENT_CONFIG_VERSION}");
+    }
+
+    let mut raw = raw_config.to_string();
+    for target in (from + 1)..=CURRENT_CONFIG_VERSION {
+        raw = upgrade_step(target, &raw)?;
+    }
+    if from < CURRENT_CONFIG_VERSION {
+        tracing::info!(
+            "Config upgraded from v{} to v{}",
+            from,
+            CURRENT_CONFIG_VERSION
+        );
+    }
+    Ok(serde_json::from_str(&raw)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{migrate_to_current, v7};
+
+    #[test]
+    fn migrates_v7_config_through_each_step_to_current() {
+        let old = v7::Config {
+            git_branch_prefix: "team".to_string(),
+            workspace_dir: Some("/work".to_string()),
+            analytics_enabled: Some(false),
+            ..Default::default()
+        };
+        let raw = serde_json::to_string(&old).unwrap();
+
+        let config = migrate_to_current(&raw).expect("migrate v7 config");
+
+        assert_eq!(config.config_version, "v9");
+        assert_eq!(config.git_branch_prefix, "team");
+        assert_eq!(config.workspace_dir.as_deref(), Some("/work"));
+        // v8 turned the optional analytics flag into a plain bool.
+        assert!(!config.analytics_enabled);
+        // v9 added chat presets.
+        assert!(!config.chat_presets.members.is_empty());
+    }
+
+    #[test]
+    fn rejects_versions_newer_than_current() {
+        assert!(migrate_to_current(r#"{"config_version":"v12"}"#).is_err());
+    }
+}
@@ -1,4 +1,4 @@
-use std::collections::HashSet;
+use std::{collections::HashSet, path::PathBuf, str::FromStr};
 
 use anyhow::Error;
 use executors::{executors::BaseCodingAgent, profile::ExecutorProfileId};
@@ -45,6 +45,15 @@ pub struct ChatMemberPreset {
     /// Whether this preset is enabled (visible for import)
     #[serde(default = "default_true")]
     pub enabled: bool,
+    /// Tags for organizing large preset libraries
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Position in the import list; presets without one are listed last
+    #[serde(default)]
+    pub sort_order: Option<i32>,
+    /// ID of a member preset whose system prompt comes before this one's
+    #[serde(default)]
+    pub extends: Option<String>,
 }
 
 /// Chat Team Preset Template
@@ -63,6 +72,9 @@ pub struct ChatTeamPreset {
     /// Whether this preset is enabled (visible for import)
     #[serde(default = "default_true")]
     pub enabled: bool,
+    /// Position in the import list; presets without one are listed last
+    #[serde(default)]
+    pub sort_order: Option<i32>,
 }
 
 /// Chat Presets Configuration
@@ -72,10 +84,204 @@ pub struct ChatPresetsConfig {
     pub members: Vec<ChatMemberPreset>,
     /// List of team preset templates
     pub teams: Vec<ChatTeamPreset>,
+    /// Standing instruction prepended to every session's context as a system message
+    #[serde(default)]
+    pub context_preamble: Option<String>,
+}
+
+/// Sort key for presets: explicit `sort_order` first (ascending), then name.
+fn preset_sort_key(sort_order: Option<i32>, name: &str) -> (bool, i32, String) {
+    (
+        sort_order.is_none(),
+        sort_order.unwrap_or_default(),
+        name.to_lowercase(),
+    )
+}
+
+/// Directory a member preset works in. An absolute
+/// `default_workspace_path` is used as is; a relative one is joined onto
+/// `config.workspace_dir`, and without one the workspace dir itself is used.
+/// `None` when there is nothing absolute to resolve against. `~` expands to
+/// the home directory and blank paths count as unset.
+pub fn resolve_workspace_path(config: &Config, preset: &ChatMemberPreset) -> Option<PathBuf> {
+    let non_blank = |path: Option<&str>| {
+        path.map(str::trim)
+            .filter(|path| !path.is_empty())
+            .map(utils::path::expand_tilde)
+    };
+    let workspace_dir = non_blank(config.workspace_dir.as_deref());
+    match non_blank(preset.default_workspace_path.as_deref()) {
+        Some(path) if path.is_absolute() => Some(path),
+        Some(path) => workspace_dir.map(|dir| dir.join(path)),
+        None => workspace_dir,
+    }
+}
+
+/// Longest `extends` chain [`resolved_system_prompt`] follows, counting the
+/// preset itself.
+pub const MAX_PRESET_INHERITANCE_DEPTH: usize = 8;
+
+/// Error returned by [`resolved_system_prompt`].
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum PresetInheritanceError {
+    #[error("member preset '{0}' extends an unknown preset")]
+    UnknownParent(String),
+    #[error("member preset inheritance cycle: {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
+    #[error("member preset '{0}' extends more than {MAX_PRESET_INHERITANCE_DEPTH} levels deep")]
+    TooDeep(String),
+}
+
+/// System prompt of `preset` with every preset it `extends` applied: the
+/// root ancestor's prompt comes first and each child's follows it, separated
+/// by a blank line. Blank prompts are skipped.
+pub fn resolved_system_prompt(
+    config: &Config,
+    preset: &ChatMemberPreset,
+) -> Result<String, PresetInheritanceError> {
+    let members = &config.chat_presets.members;
+    let mut chain = vec![preset];
+    let mut current = preset;
+    while let Some(parent_id) = current.extends.as_deref() {
+        if let Some(start) = chain.iter().position(|seen| seen.id == parent_id) {
+            let mut ids: Vec<String> = chain[start..].iter().map(|p| p.id.clone()).collect();
+            ids.push(parent_id.to_string());
+            return Err(PresetInheritanceError::Cycle(ids));
+        }
+        if chain.len() >= MAX_PRESET_INHERITANCE_DEPTH {
+            return Err(PresetInheritanceError::TooDeep(preset.id.clone()));
+        }
+        current = members
+            .iter()
+            .find(|member| member.id == parent_id)
+            .ok_or_else(|| PresetInheritanceError::UnknownParent(current.id.clone()))?;
+        chain.push(current);
+    }
+
+    Ok(chain
+        .iter()
+        .rev()
+        .map(|p| p.system_prompt.trim())
+        .filter(|prompt| !prompt.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n"))
+}
+
+/// Error returned by [`ChatPresetsConfig::reset_builtin`].
+#[derive(Debug, thiserror::Error, PartialEq, Eq)]
+pub enum ResetError {
+    #[error("'{0}' is not a built-in chat preset")]
+    NotBuiltin(String),
+}
+
+impl ChatPresetsConfig {
+    /// Restore the built-in member or team preset `id` to its shipped defaults.
+    pub fn reset_builtin(&mut self, id: &str) -> Result<(), ResetError> {
+        let defaults = default_chat_presets();
+
+        if let Some(default) = defaults.members.into_iter().find(|preset| preset.id == id) {
+            match self.members.iter_mut().find(|preset| preset.id == id) {
+                Some(existing) => *existing = default,
+                None => self.members.push(default),
+            }
+            return Ok(());
+        }
+        if let Some(default) = defaults.teams.into_iter().find(|preset| preset.id == id) {
+            match self.teams.iter_mut().find(|preset| preset.id == id) {
+                Some(existing) => *existing = default,
+                None => self.teams.push(default),
+            }
+            return Ok(());
+        }
+
+        Err(ResetError::NotBuiltin(id.to_string()))
+    }
+
+    /// Check that every member and team ID is unique, including across the two
+    /// collections. Returns one message per problem ID.
+    pub fn validate_unique_ids(&self) -> Result<(), Vec<String>> {
+        let mut problems = Vec::new();
+        let mut reported = HashSet::new();
+
+        let mut member_ids = HashSet::new();
+        for preset in &self.members {
+            if !member_ids.insert(preset.id.as_str()) && reported.insert(preset.id.as_str()) {
+                problems.push(format!("duplicate member preset id '{}'", preset.id));
+            }
+        }
+        let mut team_ids = HashSet::new();
+        for preset in &self.teams {
+            let id = preset.id.as_str();
+            if !team_ids.insert(id) {
+                if reported.insert(id) {
+                    problems.push(format!("duplicate team preset id '{id}'"));
+                }
+            } else if member_ids.contains(id) && reported.insert(id) {
+                problems.push(format!(
+                    "preset id '{id}' is used by both a member and a team"
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Clear member `runner_type` values that don't name a known
+    /// [`BaseCodingAgent`], so a typo falls back to the default runner visibly
+    /// (with a warning) instead of failing at launch. Names are matched the
+    /// way the chat runner parses them: case-insensitive, `-` or space for `_`.
+    pub fn normalize_runner_types(&mut self) {
+        for preset in &mut self.members {
+            let Some(raw) = preset.runner_type.as_deref() else {
+                continue;
+            };
+            let normalized = raw.trim().replace(['-', ' '], "_").to_ascii_uppercase();
+            if BaseCodingAgent::from_str(&normalized).is_err() {
+                tracing::warn!(
+                    "Chat presets config: member preset '{}' has unknown runner_type '{}'; using the default runner",
+                    preset.id,
+                    raw
+                );
+                preset.runner_type = None;
+            }
+        }
+    }
+
+    /// Member presets in import-list order.
+    pub fn sorted_members(&self) -> Vec<&ChatMemberPreset> {
+        let mut members: Vec<&ChatMemberPreset> = self.members.iter().collect();
+        members.sort_by_cached_key(|preset| preset_sort_key(preset.sort_order, &preset.name));
+        members
+    }
+
+    /// Team presets in import-list order.
+    pub fn sorted_teams(&self) -> Vec<&ChatTeamPreset> {
+        let mut teams: Vec<&ChatTeamPreset> = self.teams.iter().collect();
+        teams.sort_by_cached_key(|preset| preset_sort_key(preset.sort_order, &preset.name));
+        teams
+    }
+
+    /// Member presets carrying `tag`, compared case-insensitively.
+    pub fn members_by_tag(&self, tag: &str) -> Vec<&ChatMemberPreset> {
+        let tag = tag.trim();
+        self.members
+            .iter()
+            .filter(|preset| {
+                preset
+                    .tags
+                    .iter()
+                    .any(|candidate| candidate.trim().eq_ignore_ascii_case(tag))
+            })
+            .collect()
+    }
 }
 
 /// Chat Compression Configuration
-#[derive(Clone, Debug, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[derive(Clone, Debug, Serialize, Deserialize, TS, PartialEq)]
 #[ts(export)]
 pub struct ChatCompressionConfig {
     /// Token threshold before compression kicks in (default: 5000000)
@@ -84,6 +290,10 @@ pub struct ChatCompressionConfig {
     /// Percentage of messages to compress (default: 25)
     #[serde(default = "default_compression_percentage")]
     pub compression_percentage: u8,
+    /// Multiplier on token estimates when deciding whether history must be
+    /// split, for models tiktoken undercounts (default: 1.0)
+    #[serde(default = "default_token_safety_margin")]
+    pub token_safety_margin: f32,
 }
 
 fn default_token_threshold() -> u32 {
@@ -94,15 +304,48 @@ fn default_compression_percentage() -> u8 {
     25
 }
 
+fn default_token_safety_margin() -> f32 {
+    1.0
+}
+
 impl Default for ChatCompressionConfig {
     fn default() -> Self {
         Self {
             token_threshold: default_token_threshold(),
             compression_percentage: default_compression_percentage(),
+            token_safety_margin: default_token_safety_margin(),
         }
     }
 }
 
+/// A daily do-not-disturb window in local time. `start` is inclusive and
+/// `end` exclusive; a window whose `end` is before its `start` wraps past
+/// midnight (e.g. 22:00-07:00). Equal times make an empty window.
+#[derive(Clone, Debug, Serialize, Deserialize, TS, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= time && time < self.end
+        } else {
+            time >= self.start || time < self.end
+        }
+    }
+}
+
+/// When mention notifications may be shown.
+#[derive(Clone, Debug, Default, Serialize, Deserialize, TS, PartialEq, Eq)]
+#[ts(export)]
+pub struct NotificationSchedule {
+    /// Windows during which notifications are held back
+    #[serde(default)]
+    pub quiet_hours: Vec<QuietHours>,
+}
+
 fn default_chat_compression() -> ChatCompressionConfig {
     ChatCompressionConfig::default()
 }
@@ -111,6 +354,10 @@ fn default_true() -> bool {
     true
 }
 
+fn default_chat_max_mentions_per_message() -> u32 {
+    20
+}
+
 const TEAM_COLLAB_PROTOCOL: &str = "[Team Collaboration Protocol]\n\
 - @Request: @Role | Task(one line) | Input | Output format | Acceptance | Constraints(optional) | Due(optional)\n\
 - Cite context: use \"CITE#source: content\" (priority: msg id > path > commit > link); if unsure: \"UNSURE: ...\"\n\
@@ -207,6 +454,9 @@ fn builtin_member(
         tools_enabled: serde_json::json!({}),
         is_builtin: true,
         enabled: true,
+        tags: Vec::new(),
+        sort_order: None,
+        extends: None,
     }
 }
 
@@ -218,6 +468,7 @@ fn builtin_team(id: &str, name: &str, description: &str, member_ids: &[&str]) ->
         member_ids: member_ids.iter().map(|member| member.to_string()).collect(),
         is_builtin: true,
         enabled: true,
+        sort_order: None,
     }
 }
 
@@ -728,6 +979,7 @@ fn default_chat_presets() -> ChatPresetsConfig {
                 ],
             ),
         ],
+        context_preamble: None,
     }
 }
 
@@ -771,11 +1023,247 @@ pub struct Config {
     /// Chat compression configuration
     #[serde(default = "default_chat_compression")]
     pub chat_compression: ChatCompressionConfig,
+    /// Replace secrets (API keys, tokens) in chat messages before they are stored
+    #[serde(default)]
+    pub chat_redact_secrets: bool,
+    /// Messages mentioning more distinct handles than this are rejected
+    #[serde(default = "default_chat_max_mentions_per_message")]
+    pub chat_max_mentions_per_message: u32,
+    /// Collapse runs of blank lines and trailing spaces in chat messages before they are stored
+    #[serde(default)]
+    pub chat_normalize_whitespace: bool,
+    /// Oldest messages beyond this many per session move to the split history file
+    #[serde(default)]
+    pub chat_max_messages_per_session: Option<u32>,
+    /// Quiet hours during which mention notifications are queued
+    #[serde(default)]
+    pub chat_notification_schedule: NotificationSchedule,
+}
+
+/// A problem found by [`Config::validate`].
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{field}: {message}")]
+pub struct ConfigIssue {
+    /// Dotted path of the offending field, as in [`FieldChange::path`].
+    pub field: String,
+    pub message: String,
+}
+
+impl ConfigIssue {
+    fn new(field: impl Into<String>, message: impl Into<String>) -> Self {
+        Self {
+            field: field.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Error returned by [`Config::apply_patch`].
+#[derive(Debug, thiserror::Error)]
+pub enum PatchError {
+    #[error("config patch must be a JSON object")]
+    NotAnObject,
+    #[error("unknown config field '{0}'")]
+    UnknownField(String),
+    #[error("config field '{0}' can't be patched")]
+    ReadOnlyField(String),
+    #[error("invalid config patch: {0}")]
+    InvalidValue(#[from] serde_json::Error),
+}
+
+/// Merge `patch` into `target`: objects are merged key by key, anything else
+/// (including lists) replaces the current value. Keys must already exist.
+fn merge_patch(
+    path: &str,
+    target: &mut serde_json::Map<String, serde_json::Value>,
+    patch: serde_json::Map<String, serde_json::Value>,
+) -> Result<(), PatchError> {
+    for (key, value) in patch {
+        let field = if path.is_empty() {
+            key.clone()
+        } else {
+            format!("{path}.{key}")
+        };
+        let Some(current) = target.get_mut(&key) else {
+            return Err(PatchError::UnknownField(field));
+        };
+        match (current, value) {
+            (serde_json::Value::Object(current), serde_json::Value::Object(value)) => {
+                merge_patch(&field, current, value)?;
+            }
+            (current, value) => *current = value,
+        }
+    }
+    Ok(())
+}
+
+/// One field that differs between two configs, see [`Config::diff`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldChange {
+    /// Dotted path to the field, with list indices in brackets, e.g.
+    /// `chat_presets.members[2].enabled`.
+    pub path: String,
+    /// Value in `self`, `Null` if the field or list entry was absent.
+    pub before: serde_json::Value,
+    /// Value in `other`, `Null` if the field or list entry was absent.
+    pub after: serde_json::Value,
+}
+
+fn diff_values(
+    path: &str,
+    before: &serde_json::Value,
+    after: &serde_json::Value,
+    changes: &mut Vec<FieldChange>,
+) {
+    use serde_json::Value;
+
+    if before == after {
+        return;
+    }
+    match (before, after) {
+        (Value::Object(a), Value::Object(b)) => {
+            let keys: std::collections::BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+            for key in keys {
+                let child = if path.is_empty() {
+                    key.clone()
+                } else {
+                    format!("{path}.{key}")
+                };
+                diff_values(
+                    &child,
+                    a.get(key).unwrap_or(&Value::Null),
+                    b.get(key).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            for index in 0..a.len().max(b.len()) {
+                diff_values(
+                    &format!("{path}[{index}]"),
+                    a.get(index).unwrap_or(&Value::Null),
+                    b.get(index).unwrap_or(&Value::Null),
+                    changes,
+                );
+            }
+        }
+        _ => changes.push(FieldChange {
+            path: path.to_string(),
+            before: before.clone(),
+            after: after.clone(),
+        }),
+    }
 }
 
 impl Config {
+    /// Leaf fields that differ from `other`, as they serialize. Meant for
+    /// asserting that a migration only touched the fields it should.
+    pub fn diff(&self, other: &Config) -> Vec<FieldChange> {
+        let before = serde_json::to_value(self).unwrap_or_default();
+        let after = serde_json::to_value(other).unwrap_or_default();
+        let mut changes = Vec::new();
+        diff_values("", &before, &after, &mut changes);
+        changes
+    }
+
+    /// Merge a JSON object holding only the changed fields into this config,
+    /// leaving every other field as it is. Nested settings such as
+    /// `chat_compression` can be patched field by field; lists are replaced
+    /// whole. On error the config is left unchanged.
+    pub fn apply_patch(&mut self, patch: serde_json::Value) -> Result<(), PatchError> {
+        let serde_json::Value::Object(patch) = patch else {
+            return Err(PatchError::NotAnObject);
+        };
+        if patch.contains_key("config_version") {
+            return Err(PatchError::ReadOnlyField("config_version".to_string()));
+        }
+
+        let mut merged = match serde_json::to_value(&*self)? {
+            serde_json::Value::Object(map) => map,
+            _ => return Err(PatchError::NotAnObject),
+        };
+        merge_patch("", &mut merged, patch)?;
+        *self = serde_json::from_value(serde_json::Value::Object(merged))?;
+        Ok(())
+    }
+
+    /// Check field values the types can't express. Issues are meant to be
+    /// reported, not fatal: the config still loads with them.
+    pub fn validate(&self) -> Result<(), Vec<ConfigIssue>> {
+        let mut issues = Vec::new();
+
+        if self.git_branch_prefix.trim().is_empty() {
+            issues.push(ConfigIssue::new("git_branch_prefix", "must not be empty"));
+        }
+        if let Some(dir) = &self.workspace_dir {
+            let path = utils::path::expand_tilde(dir);
+            if !path.is_dir() {
+                issues.push(ConfigIssue::new(
+                    "workspace_dir",
+                    format!("{} is not an existing directory", path.display()),
+                ));
+            }
+        }
+
+        let presets = &self.chat_presets;
+        if let Err(problems) = presets.validate_unique_ids() {
+            issues.extend(
+                problems
+                    .into_iter()
+                    .map(|problem| ConfigIssue::new("chat_presets", problem)),
+            );
+        }
+        for (index, member) in presets.members.iter().enumerate() {
+            if member.id.trim().is_empty() {
+                issues.push(ConfigIssue::new(
+                    format!("chat_presets.members[{index}].id"),
+                    "must not be empty",
+                ));
+            }
+            if member.name.trim().is_empty() {
+                issues.push(ConfigIssue::new(
+                    format!("chat_presets.members[{index}].name"),
+                    "must not be empty",
+                ));
+            }
+            if let Err(err) = resolved_system_prompt(self, member) {
+                issues.push(ConfigIssue::new(
+                    format!("chat_presets.members[{index}].extends"),
+                    err.to_string(),
+                ));
+            }
+        }
+        let member_ids: HashSet<&str> = presets.members.iter().map(|m| m.id.as_str()).collect();
+        for (index, team) in presets.teams.iter().enumerate() {
+            if team.id.trim().is_empty() {
+                issues.push(ConfigIssue::new(
+                    format!("chat_presets.teams[{index}].id"),
+                    "must not be empty",
+                ));
+            }
+            for member_id in &team.member_ids {
+                if !member_ids.contains(member_id.as_str()) {
+                    issues.push(ConfigIssue::new(
+                        format!("chat_presets.teams[{index}].member_ids"),
+                        format!(
+                            "team '{}' references unknown member preset '{member_id}'",
+                            team.id
+                        ),
+                    ));
+                }
+            }
+        }
+
+        if issues.is_empty() {
+            Ok(())
+        } else {
+            Err(issues)
+        }
+    }
+
     fn with_completed_chat_presets(mut self) -> Self {
         complete_chat_presets_with_builtins(&mut self.chat_presets);
+        self.chat_presets.normalize_runner_types();
         self
     }
 
@@ -805,6 +1293,11 @@ impl Config {
             send_message_shortcut: old_config.send_message_shortcut,
             chat_presets: default_chat_presets(),
             chat_compression: ChatCompressionConfig::default(),
+            chat_redact_secrets: false,
+            chat_max_mentions_per_message: default_chat_max_mentions_per_message(),
+            chat_normalize_whitespace: false,
+            chat_max_messages_per_session: None,
+            chat_notification_schedule: NotificationSchedule::default(),
         }
         .with_completed_chat_presets()
     }
@@ -823,11 +1316,8 @@ impl From<String> for Config {
             return config.with_completed_chat_presets();
         }
 
-        match Self::from_previous_version(&raw_config) {
-            Ok(config) => {
-                tracing::info!("Config upgraded to v9");
-                config.with_completed_chat_presets()
-            }
+        match super::migrate_to_current(&raw_config) {
+            Ok(config) => config.with_completed_chat_presets(),
             Err(e) => {
                 tracing::warn!("Config migration failed: {}, using default", e);
                 Self::default().with_completed_chat_presets()
@@ -863,6 +1353,389 @@ impl Default for Config {
             send_message_shortcut: SendMessageShortcut::default(),
             chat_presets: default_chat_presets(),
             chat_compression: ChatCompressionConfig::default(),
+            chat_redact_secrets: false,
+            chat_max_mentions_per_message: default_chat_max_mentions_per_message(),
+            chat_normalize_whitespace: false,
+            chat_max_messages_per_session: None,
+            chat_notification_schedule: NotificationSchedule::default(),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn preset_with_workspace(path: Option<&str>) -> ChatMemberPreset {
+        let mut preset = default_chat_presets().members.remove(0);
+        preset.default_workspace_path = path.map(str::to_string);
+        preset
+    }
+
+    fn config_with_members(members: &[(&str, Option<&str>, &str)]) -> Config {
+        let template = default_chat_presets().members.remove(0);
+        let mut config = Config::default();
+        config.chat_presets.members = members
+            .iter()
+            .map(|(id, extends, prompt)| ChatMemberPreset {
+                id: id.to_string(),
+                name: id.to_string(),
+                system_prompt: prompt.to_string(),
+                extends: extends.map(str::to_string),
+                ..template.clone()
+            })
+            .collect();
+        config
+    }
+
+    #[test]
+    fn resolved_system_prompt_applies_two_levels_of_inheritance() {
+        let config = config_with_members(&[
+            ("base", None, "Be concise."),
+            ("engineer", Some("base"), "Write Rust."),
+            ("reviewer", Some("engineer"), "Review diffs."),
+        ]);
+        let reviewer = &config.chat_presets.members[2];
+
+        assert_eq!(
+            resolved_system_prompt(&config, reviewer).unwrap(),
+            "Be concise.\n\nWrite Rust.\n\nReview diffs."
+        );
+        assert_eq!(
+            resolved_system_prompt(&config, &config.chat_presets.members[0]).unwrap(),
+            "Be concise."
+        );
+    }
+
+    #[test]
+    fn resolved_system_prompt_rejects_cycles() {
+        let config = config_with_members(&[
+            ("a", Some("b"), "A"),
+            ("b", Some("a"), "B"),
+            ("orphan", Some("missing"), "C"),
+        ]);
+
+        assert_eq!(
+            resolved_system_prompt(&config, &config.chat_presets.members[0]),
+            Err(PresetInheritanceError::Cycle(vec![
+                "a".to_string(),
+                "b".to_string(),
+                "a".to_string(),
+            ]))
+        );
+        assert_eq!(
+            resolved_system_prompt(&config, &config.chat_presets.members[2]),
+            Err(PresetInheritanceError::UnknownParent("orphan".to_string()))
+        );
+        let issues = config.validate().unwrap_err();
+        assert!(
+            issues
+                .iter()
+                .any(|issue| issue.field == "chat_presets.members[1].extends")
+        );
+    }
+
+    #[test]
+    fn relative_preset_workspace_joins_the_workspace_dir() {
+        let root = std::env::temp_dir().join("workspaces");
+        let config = Config {
+            workspace_dir: Some(root.to_string_lossy().to_string()),
+            ..Config::default()
+        };
+
+        assert_eq!(
+            resolve_workspace_path(&config, &preset_with_workspace(Some("backend"))),
+            Some(root.join("backend"))
+        );
+        assert_eq!(
+            resolve_workspace_path(&config, &preset_with_workspace(None)),
+            Some(root.clone())
+        );
+        assert_eq!(
+            resolve_workspace_path(&config, &preset_with_workspace(Some("  "))),
+            Some(root)
+        );
+    }
+
+    #[test]
+    fn absolute_preset_workspace_is_used_verbatim() {
+        let absolute = std::env::temp_dir().join("elsewhere");
+        let config = Config {
+            workspace_dir: Some(
+                std::env::temp_dir()
+                    .join("workspaces")
+                    .to_string_lossy()
+                    .to_string(),
+            ),
+            ..Config::default()
+        };
+        let preset = preset_with_workspace(Some(absolute.to_str().unwrap()));
+
+        assert_eq!(
+            resolve_workspace_path(&config, &preset),
+            Some(absolute.clone())
+        );
+        let without_dir = Config {
+            workspace_dir: None,
+            ..Config::default()
+        };
+        assert_eq!(
+            resolve_workspace_path(&without_dir, &preset),
+            Some(absolute)
+        );
+    }
+
+    #[test]
+    fn unresolvable_workspace_is_none() {
+        let config = Config {
+            workspace_dir: None,
+            ..Config::default()
+        };
+        assert_eq!(
+            resolve_workspace_path(&config, &preset_with_workspace(None)),
+            None
+        );
+        assert_eq!(
+            resolve_workspace_path(&config, &preset_with_workspace(Some("backend"))),
+            None
+        );
+    }
+
+    #[test]
+    fn apply_patch_changes_only_the_given_fields() {
+        let original = Config::default();
+        let mut config = original.clone();
+
+        config
+            .apply_patch(serde_json::json!({ "theme": "DARK" }))
+            .unwrap();
+        assert!(matches!(config.theme, ThemeMode::Dark));
+        let paths: Vec<String> = original
+            .diff(&config)
+            .into_iter()
+            .map(|change| change.path)
+            .collect();
+        assert_eq!(paths, vec!["theme"]);
+
+        config
+            .apply_patch(serde_json::json!({ "chat_compression": { "token_threshold": 1234 } }))
+            .unwrap();
+        assert_eq!(config.chat_compression.token_threshold, 1234);
+        assert_eq!(
+            config.chat_compression.compression_percentage,
+            original.chat_compression.compression_percentage
+        );
+    }
+
+    #[test]
+    fn apply_patch_rejects_bad_patches_without_changing_config() {
+        let mut config = Config::default();
+
+        assert!(matches!(
+            config.apply_patch(serde_json::json!({ "theme": 3 })),
+            Err(PatchError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            config.apply_patch(serde_json::json!({ "chat_redact_secrets": true, "no_such_field": 1 })),
+            Err(PatchError::UnknownField(field)) if field == "no_such_field"
+        ));
+        assert!(matches!(
+            config.apply_patch(serde_json::json!(["theme"])),
+            Err(PatchError::NotAnObject)
+        ));
+        assert!(config.diff(&Config::default()).is_empty());
+    }
+
+    #[test]
+    fn default_config_is_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn validate_reports_empty_branch_prefix() {
+        let config = Config {
+            git_branch_prefix: "  ".to_string(),
+            ..Config::default()
+        };
+
+        let issues = config.validate().unwrap_err();
+        assert_eq!(
+            issues,
+            vec![ConfigIssue::new("git_branch_prefix", "must not be empty")]
+        );
+    }
+
+    #[test]
+    fn validate_reports_dangling_team_reference() {
+        let mut config = Config::default();
+        config.chat_presets.teams[0]
+            .member_ids
+            .push("removed-member".to_string());
+
+        let issues = config.validate().unwrap_err();
+        assert_eq!(issues.len(), 1);
+        assert_eq!(issues[0].field, "chat_presets.teams[0].member_ids");
+        assert!(issues[0].message.contains("'removed-member'"));
+    }
+
+    #[test]
+    fn diff_reports_only_changed_fields() {
+        let original = Config::default();
+        assert!(original.diff(&original.clone()).is_empty());
+
+        let mut changed = original.clone();
+        changed.chat_redact_secrets = true;
+        changed.chat_compression.token_threshold += 1;
+        changed.chat_presets.members[1].enabled = false;
+        changed.chat_max_messages_per_session = Some(500);
+
+        let changes = original.diff(&changed);
+        let paths: Vec<&str> = changes.iter().map(|change| change.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                "chat_compression.token_threshold",
+                "chat_max_messages_per_session",
+                "chat_presets.members[1].enabled",
+                "chat_redact_secrets",
+            ]
+        );
+        let limit = &changes[1];
+        assert_eq!(limit.before, serde_json::Value::Null);
+        assert_eq!(limit.after, serde_json::json!(500));
+    }
+
+    #[test]
+    fn members_by_tag_filters_case_insensitively() {
+        let mut presets = default_chat_presets();
+        presets.members[0].tags = vec!["Planning".to_string()];
+        presets.members[1].tags = vec!["planning".to_string(), "product".to_string()];
+
+        let ids: Vec<&str> = presets
+            .members_by_tag("planning")
+            .into_iter()
+            .map(|preset| preset.id.as_str())
+            .collect();
+        assert_eq!(
+            ids,
+            vec![
+                presets.members[0].id.as_str(),
+                presets.members[1].id.as_str()
+            ]
+        );
+        assert!(presets.members_by_tag("unused").is_empty());
+    }
+
+    #[test]
+    fn member_presets_without_tags_deserialize_to_empty() {
+        let preset: ChatMemberPreset = serde_json::from_value(serde_json::json!({
+            "id": "custom",
+            "name": "custom",
+            "description": "",
+            "runner_type": null,
+            "system_prompt": "",
+            "default_workspace_path": null,
+            "tools_enabled": {},
+            "is_builtin": false
+        }))
+        .unwrap();
+        assert!(preset.tags.is_empty());
+    }
+
+    #[test]
+    fn reset_builtin_restores_shipped_defaults() {
+        let defaults = default_chat_presets();
+        let mut presets = defaults.clone();
+        let member_id = presets.members[0].id.clone();
+        let team_id = presets.teams[0].id.clone();
+        presets.members[0].system_prompt = "customized".to_string();
+        presets.members[0].enabled = false;
+        presets.teams[0].member_ids.clear();
+
+        presets.reset_builtin(&member_id).unwrap();
+        presets.reset_builtin(&team_id).unwrap();
+        assert_eq!(presets, defaults);
+    }
+
+    #[test]
+    fn reset_builtin_rejects_custom_presets() {
+        let mut presets = default_chat_presets();
+        let mut custom = presets.members[0].clone();
+        custom.id = "custom_member_preset".to_string();
+        custom.is_builtin = false;
+        presets.members.push(custom.clone());
+
+        assert_eq!(
+            presets.reset_builtin("custom_member_preset"),
+            Err(ResetError::NotBuiltin("custom_member_preset".to_string()))
+        );
+        assert_eq!(presets.members.last(), Some(&custom));
+    }
+
+    #[test]
+    fn validate_unique_ids_reports_duplicates_and_clashes() {
+        let mut presets = default_chat_presets();
+        assert_eq!(presets.validate_unique_ids(), Ok(()));
+
+        let duplicate_member = presets.members[0].clone();
+        let mut clashing_team = presets.teams[0].clone();
+        clashing_team.id = presets.members[1].id.clone();
+        presets.members.push(duplicate_member.clone());
+        presets.teams.push(clashing_team);
+
+        assert_eq!(
+            presets.validate_unique_ids(),
+            Err(vec![
+                format!("duplicate member preset id '{}'", duplicate_member.id),
+                format!(
+                    "preset id '{}' is used by both a member and a team",
+                    presets.members[1].id
+                ),
+            ])
+        );
+    }
+
+    #[test]
+    fn normalize_runner_types_keeps_known_and_clears_unknown() {
+        let mut presets = default_chat_presets();
+        presets.members[0].runner_type = Some("claude-code".to_string());
+        presets.members[1].runner_type = Some("CLAUDE_KODE".to_string());
+
+        presets.normalize_runner_types();
+
+        assert_eq!(
+            presets.members[0].runner_type.as_deref(),
+            Some("claude-code")
+        );
+        assert_eq!(presets.members[1].runner_type, None);
+    }
+
+    #[test]
+    fn sorted_members_orders_by_sort_order_then_name() {
+        let template = default_chat_presets().members[0].clone();
+        let member = |name: &str, sort_order: Option<i32>| ChatMemberPreset {
+            id: name.to_string(),
+            name: name.to_string(),
+            sort_order,
+            ..template.clone()
+        };
+        let presets = ChatPresetsConfig {
+            members: vec![
+                member("zed", None),
+                member("beta", Some(2)),
+                member("alpha", None),
+                member("gamma", Some(1)),
+                member("delta", Some(2)),
+            ],
+            teams: Vec::new(),
+            context_preamble: None,
+        };
+
+        let names: Vec<&str> = presets
+            .sorted_members()
+            .into_iter()
+            .map(|preset| preset.name.as_str())
+            .collect();
+        assert_eq!(names, vec!["gamma", "beta", "delta", "alpha", "zed"]);
+    }
+}
@@ -2,8 +2,14 @@ pub mod analytics;
 pub mod approvals;
 pub mod auth;
 pub mod chat;
+pub mod chat_archive_checksum;
+pub mod chat_dead_letter;
 pub mod chat_history_file;
+pub mod chat_html_export;
+pub mod chat_indexer;
+pub mod chat_redaction;
 pub mod chat_runner;
+pub mod chat_session_meta;
 pub mod config;
 pub mod container;
 pub mod diff_stream;
@@ -1,9 +1,56 @@
-use std::sync::{Arc, OnceLock};
+use std::{
+    collections::HashSet,
+    sync::{Arc, OnceLock},
+};
 
+use chrono::NaiveTime;
+use db::models::chat_message::ChatSenderType;
 use tokio::sync::RwLock;
 use utils;
+use uuid::Uuid;
 
-use crate::services::config::{Config, NotificationConfig, SoundFile};
+use crate::services::{
+    chat::{MentionEvent, MentionNotifier},
+    config::{Config, NotificationConfig, NotificationSchedule, SoundFile},
+};
+
+/// Whether a notification may be shown at local time `now`, i.e. `now` is
+/// outside every quiet-hours window of `schedule`.
+pub fn should_notify_now(schedule: &NotificationSchedule, now: NaiveTime) -> bool {
+    !schedule
+        .quiet_hours
+        .iter()
+        .any(|window| window.contains(now))
+}
+
+/// How long until local time `now` is outside every quiet-hours window of
+/// `schedule`, or `None` if it already is. Windows that touch or overlap are
+/// waited out together.
+pub fn quiet_hours_remaining(
+    schedule: &NotificationSchedule,
+    now: NaiveTime,
+) -> Option<std::time::Duration> {
+    let mut at = now;
+    let mut remaining = chrono::Duration::zero();
+    // Each window is passed at most once unless they cover the whole day, in
+    // which case the caller checks again after the day's worth of waiting.
+    for _ in 0..schedule.quiet_hours.len() {
+        let Some(window) = schedule
+            .quiet_hours
+            .iter()
+            .find(|window| window.contains(at))
+        else {
+            break;
+        };
+        let mut step = window.end.signed_duration_since(at);
+        if step <= chrono::Duration::zero() {
+            step += chrono::Duration::days(1);
+        }
+        remaining += step;
+        at = window.end;
+    }
+    (!remaining.is_zero()).then(|| remaining.to_std().unwrap_or_default())
+}
 
 /// Service for handling cross-platform notifications including sound alerts and push notifications
 #[derive(Debug, Clone)]
@@ -236,3 +283,171 @@ impl NotificationService {
         }
     }
 }
+
+/// Shows a notification (sound and/or push, per the notification config) when
+/// an agent or system message mentions the user. Mentions of other agents are
+/// routing between agents and stay quiet, as do the user's own messages.
+impl MentionNotifier for NotificationService {
+    fn notify(&self, event: &MentionEvent) {
+        if !should_announce_mention(event) {
+            return;
+        }
+        let service = self.clone();
+        tokio::spawn(async move {
+            NotificationService::notify(
+                &service,
+                "New mention",
+                "You were mentioned in a chat session",
+            )
+            .await;
+        });
+    }
+
+    /// One summary for everything held back during quiet hours.
+    fn notify_held(&self, events: &[MentionEvent]) {
+        let Some(message) = held_mentions_message(events) else {
+            return;
+        };
+        let service = self.clone();
+        tokio::spawn(async move {
+            NotificationService::notify(&service, "New mentions", &message).await;
+        });
+    }
+}
+
+fn should_announce_mention(event: &MentionEvent) -> bool {
+    event.mentions_user && event.sender_type != ChatSenderType::User
+}
+
+fn held_mentions_message(events: &[MentionEvent]) -> Option<String> {
+    let announced: Vec<&MentionEvent> = events
+        .iter()
+        .filter(|event| should_announce_mention(event))
+        .collect();
+    let sessions: HashSet<Uuid> = announced.iter().map(|event| event.session_id).collect();
+    match (announced.len(), sessions.len()) {
+        (0, _) => None,
+        (1, _) => Some("You were mentioned in a chat session".to_string()),
+        (count, 1) => Some(format!(
+            "You were mentioned {count} times in a chat session during quiet hours"
+        )),
+        (count, sessions) => Some(format!(
+            "You were mentioned {count} times in {sessions} chat sessions during quiet hours"
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::config::QuietHours;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn schedule(start: NaiveTime, end: NaiveTime) -> NotificationSchedule {
+        NotificationSchedule {
+            quiet_hours: vec![QuietHours { start, end }],
+        }
+    }
+
+    #[test]
+    fn quiet_hours_include_start_and_exclude_end() {
+        let schedule = schedule(at(12, 0), at(13, 30));
+        assert!(should_notify_now(&schedule, at(11, 59)));
+        assert!(!should_notify_now(&schedule, at(12, 0)));
+        assert!(!should_notify_now(&schedule, at(13, 29)));
+        assert!(should_notify_now(&schedule, at(13, 30)));
+    }
+
+    #[test]
+    fn quiet_hours_can_wrap_midnight() {
+        let schedule = schedule(at(22, 0), at(7, 0));
+        assert!(should_notify_now(&schedule, at(21, 59)));
+        assert!(!should_notify_now(&schedule, at(22, 0)));
+        assert!(!should_notify_now(&schedule, at(0, 0)));
+        assert!(!should_notify_now(&schedule, at(6, 59)));
+        assert!(should_notify_now(&schedule, at(7, 0)));
+        assert!(should_notify_now(&schedule, at(12, 0)));
+    }
+
+    #[test]
+    fn only_mentions_of_the_user_are_announced() {
+        let event = |sender_type, mentions_user| MentionEvent {
+            session_id: Uuid::new_v4(),
+            message_id: Uuid::new_v4(),
+            sender_type,
+            handles: vec!["coder".to_string()],
+            mentions_user,
+        };
+        assert!(should_announce_mention(&event(ChatSenderType::Agent, true)));
+        assert!(!should_announce_mention(&event(
+            ChatSenderType::Agent,
+            false
+        )));
+        assert!(!should_announce_mention(&event(ChatSenderType::User, true)));
+    }
+
+    #[test]
+    fn quiet_hours_remaining_waits_out_touching_windows() {
+        let mut schedule = schedule(at(22, 0), at(1, 0));
+        schedule.quiet_hours.push(QuietHours {
+            start: at(1, 0),
+            end: at(7, 30),
+        });
+        let hours = |hours: f64| std::time::Duration::from_secs_f64(hours * 3600.0);
+        assert_eq!(
+            quiet_hours_remaining(&schedule, at(23, 0)),
+            Some(hours(8.5))
+        );
+        assert_eq!(
+            quiet_hours_remaining(&schedule, at(6, 30)),
+            Some(hours(1.0))
+        );
+        assert_eq!(quiet_hours_remaining(&schedule, at(7, 30)), None);
+        assert_eq!(
+            quiet_hours_remaining(&NotificationSchedule::default(), at(3, 0)),
+            None
+        );
+    }
+
+    #[test]
+    fn held_mentions_are_summarized_once() {
+        let session = Uuid::new_v4();
+        let event = |session_id, mentions_user| MentionEvent {
+            session_id,
+            message_id: Uuid::new_v4(),
+            sender_type: ChatSenderType::Agent,
+            handles: vec!["you".to_string()],
+            mentions_user,
+        };
+        assert_eq!(held_mentions_message(&[event(session, false)]), None);
+        assert_eq!(
+            held_mentions_message(&[event(session, true), event(session, false)]).as_deref(),
+            Some("You were mentioned in a chat session")
+        );
+        assert_eq!(
+            held_mentions_message(&[event(session, true), event(session, true)]).as_deref(),
+            Some("You were mentioned 2 times in a chat session during quiet hours")
+        );
+        assert_eq!(
+            held_mentions_message(&[
+                event(session, true),
+                event(Uuid::new_v4(), true),
+                event(session, true),
+            ])
+            .as_deref(),
+            Some("You were mentioned 3 times in 2 chat sessions during quiet hours")
+        );
+    }
+
+    #[test]
+    fn empty_schedules_and_windows_never_suppress() {
+        assert!(should_notify_now(
+            &NotificationSchedule::default(),
+            at(3, 0)
+        ));
+        assert!(should_notify_now(&schedule(at(9, 0), at(9, 0)), at(9, 0)));
+    }
+}
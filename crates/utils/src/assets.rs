@@ -1,13 +1,19 @@
 use directories::ProjectDirs;
 use rust_embed::RustEmbed;
 
+use crate::profile;
+
 const PROJECT_ROOT: &str = env!("CARGO_MANIFEST_DIR");
 
 pub fn asset_dir() -> std::path::PathBuf {
     let path = if cfg!(debug_assertions) {
-        std::path::PathBuf::from(PROJECT_ROOT).join("../../dev_assets")
+        let dev_assets = std::path::PathBuf::from(PROJECT_ROOT).join("../../dev_assets");
+        match profile::active_profile() {
+            Some(profile) => dev_assets.join("profiles").join(profile),
+            None => dev_assets,
+        }
     } else {
-        ProjectDirs::from("ai", "starterra.ai", "agents-chatgroup")
+        ProjectDirs::from("ai", "starterra.ai", &profile::app_name())
             .expect("OS didn't give us a home directory")
             .data_dir()
             .to_path_buf()
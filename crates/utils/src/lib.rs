@@ -21,6 +21,8 @@ pub mod text;
 pub mod tokio;
 pub mod version;
 
+pub use app_profile as profile;
+
 /// Cache for WSL2 detection result
 static WSL2_CACHE: OnceLock<bool> = OnceLock::new();
 
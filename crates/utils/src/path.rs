@@ -106,22 +106,7 @@ pub fn normalize_macos_private_alias<P: AsRef<Path>>(p: P) -> PathBuf {
 }
 
 pub fn get_agent_chatgroup_temp_dir() -> std::path::PathBuf {
-    let dir_name = if cfg!(debug_assertions) {
-        "agents-chatgroup-dev"
-    } else {
-        "agents-chatgroup"
-    };
-
-    if cfg!(target_os = "macos") {
-        // macOS already uses /var/folders/... which is persistent storage
-        std::env::temp_dir().join(dir_name)
-    } else if cfg!(target_os = "linux") {
-        // Linux: use /var/tmp instead of /tmp to avoid RAM usage
-        std::path::PathBuf::from("/var/tmp").join(dir_name)
-    } else {
-        // Windows and other platforms: use temp dir with agents-chatgroup subdirectory
-        std::env::temp_dir().join(dir_name)
-    }
+    crate::profile::temp_dir_for(crate::profile::active_profile().as_deref())
 }
 
 /// Expand leading ~ to user's home directory.
@@ -3,8 +3,9 @@ use std::{env, path::PathBuf};
 use tokio::fs;
 
 pub async fn write_port_file(port: u16) -> std::io::Result<PathBuf> {
-    let dir = env::temp_dir().join("agents-chatgroup");
-    let path = dir.join("agents-chatgroup.port");
+    let app_name = crate::profile::app_name();
+    let dir = env::temp_dir().join(&app_name);
+    let path = dir.join(format!("{app_name}.port"));
     tracing::debug!("Writing port {} to {:?}", port, path);
     fs::create_dir_all(&dir).await?;
     fs::write(&path, port.to_string()).await?;
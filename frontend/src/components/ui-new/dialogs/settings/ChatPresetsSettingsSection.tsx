@@ -34,6 +34,7 @@ type PresetsTab = 'members' | 'teams';
 const emptyPresets = (): ChatPresetsConfig => ({
   members: [],
   teams: [],
+  context_preamble: null,
 });
 
 const slugify = (value: string): string => {
@@ -94,7 +95,7 @@ const normalizeDraft = (draft: ChatPresetsConfig): ChatPresetsConfig => {
     member_ids: team.member_ids.filter((id) => validMemberIds.has(id)),
   }));
 
-  return { members, teams };
+  return { ...draft, members, teams };
 };
 
 interface PresetListItemProps {
@@ -351,6 +352,9 @@ export function ChatPresetsSettingsSectionContent() {
         tools_enabled: {},
         is_builtin: false,
         enabled: true,
+        tags: [],
+        sort_order: null,
+        extends: null,
       };
       return {
         ...prev,
@@ -412,6 +416,7 @@ export function ChatPresetsSettingsSectionContent() {
         member_ids: [],
         is_builtin: false,
         enabled: true,
+        sort_order: null,
       };
       return {
         ...prev,
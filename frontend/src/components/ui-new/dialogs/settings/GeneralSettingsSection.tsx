@@ -472,6 +472,8 @@ export function GeneralSettingsSection() {
                   token_threshold: value,
                   compression_percentage:
                     draft?.chat_compression?.compression_percentage ?? 25,
+                  token_safety_margin:
+                    draft?.chat_compression?.token_safety_margin ?? 1,
                 },
               })
             }
@@ -497,6 +499,8 @@ export function GeneralSettingsSection() {
                   token_threshold:
                     draft?.chat_compression?.token_threshold ?? 50000,
                   compression_percentage: value,
+                  token_safety_margin:
+                    draft?.chat_compression?.token_safety_margin ?? 1,
                 },
               })
             }
@@ -947,6 +947,13 @@ export const configApi = {
     });
     return handleApiResponse<Config>(response);
   },
+  patchConfig: async (patch: Partial<Config>): Promise<Config> => {
+    const response = await makeRequest('/api/config', {
+      method: 'PATCH',
+      body: JSON.stringify(patch),
+    });
+    return handleApiResponse<Config>(response);
+  },
   checkEditorAvailability: async (
     editorType: EditorType
   ): Promise<CheckEditorAvailabilityResponse> => {
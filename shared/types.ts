@@ -470,7 +470,27 @@ chat_presets: ChatPresetsConfig,
 /**
  * Chat compression configuration
  */
-chat_compression: ChatCompressionConfig, };
+chat_compression: ChatCompressionConfig, 
+/**
+ * Replace secrets (API keys, tokens) in chat messages before they are stored
+ */
+chat_redact_secrets: boolean, 
+/**
+ * Messages mentioning more distinct handles than this are rejected
+ */
+chat_max_mentions_per_message: number, 
+/**
+ * Collapse runs of blank lines and trailing spaces in chat messages before they are stored
+ */
+chat_normalize_whitespace: boolean, 
+/**
+ * Oldest messages beyond this many per session move to the split history file
+ */
+chat_max_messages_per_session: number | null, 
+/**
+ * Quiet hours during which mention notifications are queued
+ */
+chat_notification_schedule: NotificationSchedule, };
 
 export type NotificationConfig = { sound_enabled: boolean, push_enabled: boolean, sound_file: SoundFile, };
 
@@ -500,7 +520,20 @@ token_threshold: number,
 /**
  * Percentage of messages to compress (default: 25)
  */
-compression_percentage: number, };
+compression_percentage: number, 
+/**
+ * Multiplier on token estimates when deciding whether history must be
+ * split, for models tiktoken undercounts (default: 1.0)
+ */
+token_safety_margin: number, };
+
+export type NotificationSchedule = { 
+/**
+ * Windows during which notifications are held back
+ */
+quiet_hours: Array<QuietHours>, };
+
+export type QuietHours = { start: string, end: string, };
 
 export type ChatPresetsConfig = { 
 /**
@@ -510,7 +543,11 @@ members: Array<ChatMemberPreset>,
 /**
  * List of team preset templates
  */
-teams: Array<ChatTeamPreset>, };
+teams: Array<ChatTeamPreset>, 
+/**
+ * Standing instruction prepended to every session's context as a system message
+ */
+context_preamble: string | null, };
 
 export type ChatMemberPreset = { 
 /**
@@ -548,7 +585,19 @@ is_builtin: boolean,
 /**
  * Whether this preset is enabled (visible for import)
  */
-enabled: boolean, };
+enabled: boolean, 
+/**
+ * Tags for organizing large preset libraries
+ */
+tags: Array<string>, 
+/**
+ * Position in the import list; presets without one are listed last
+ */
+sort_order: number | null, 
+/**
+ * ID of a member preset whose system prompt comes before this one's
+ */
+extends: string | null, };
 
 export type ChatTeamPreset = { 
 /**
@@ -574,7 +623,11 @@ is_builtin: boolean,
 /**
  * Whether this preset is enabled (visible for import)
  */
-enabled: boolean, };
+enabled: boolean, 
+/**
+ * Position in the import list; presets without one are listed last
+ */
+sort_order: number | null, };
 
 export type GitBranch = { name: string, is_current: boolean, is_remote: boolean, last_commit_date: Date, };
 
@@ -0,0 +1,209 @@
+//! Calls from the desktop shell into the backend sidecar's HTTP API.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::Deserialize;
+use uuid::Uuid;
+
+/// The `ApiResponse` envelope every backend route returns.
+#[derive(Debug, Deserialize)]
+struct ApiEnvelope<T> {
+    success: bool,
+    data: Option<T>,
+    message: Option<String>,
+}
+
+pub fn parse_session_id(raw: &str) -> Result<Uuid, String> {
+    Uuid::parse_str(raw.trim()).map_err(|_| format!("Invalid session id: {:?}", raw))
+}
+
+/// The shell places the archive itself, so the destination must not depend on
+/// its working directory.
+pub fn parse_export_dest(raw: &str) -> Result<PathBuf, String> {
+    let dest = PathBuf::from(raw.trim());
+    if dest.as_os_str().is_empty() {
+        return Err("Export destination is required".to_string());
+    }
+    if !dest.is_absolute() {
+        return Err(format!(
+            "Export destination must be an absolute path: {}",
+            dest.display()
+        ));
+    }
+    Ok(dest)
+}
+
+/// Ask the backend on `port` to export a session archive, then move it from
+/// the backend's staging directory into `dest`. Returns `dest`.
+pub async fn export_session(port: u16, session_id: Uuid, dest: PathBuf) -> Result<String, String> {
+    let url = format!(
+        "http://127.0.0.1:{}/api/chat/sessions/{}/export",
+        port, session_id
+    );
+    let response = reqwest::Client::new()
+        .post(&url)
+        .send()
+        .await
+        .map_err(|e| format!("Could not reach the backend: {}", e))?;
+    let staged: String = read_envelope(response, "Export failed").await?;
+    place_export(Path::new(&staged), &dest)?;
+    Ok(dest.display().to_string())
+}
+
+/// The parts of a backend `Session` the shell needs.
+#[derive(Debug, Deserialize)]
+struct SessionInfo {
+    workspace_id: String,
+}
+
+/// The parts of a backend `Workspace` the shell needs.
+#[derive(Debug, Deserialize)]
+struct WorkspaceInfo {
+    container_ref: Option<String>,
+}
+
+/// Ask the backend on `port` where the workspace of session `session_id`
+/// lives on disk (its workspace's `container_ref`).
+pub async fn session_workspace_path(port: u16, session_id: Uuid) -> Result<PathBuf, String> {
+    let client = reqwest::Client::new();
+    let session: SessionInfo = get_json(
+        &client,
+        &format!("http://127.0.0.1:{}/api/sessions/{}", port, session_id),
+        "Session lookup failed",
+    )
+    .await?;
+    let workspace: WorkspaceInfo = get_json(
+        &client,
+        &format!(
+            "http://127.0.0.1:{}/api/task-attempts/{}",
+            port, session.workspace_id
+        ),
+        "Workspace lookup failed",
+    )
+    .await?;
+    workspace
+        .container_ref
+        .map(PathBuf::from)
+        .ok_or_else(|| format!("Session {} has no workspace on disk", session_id))
+}
+
+async fn get_json<T: serde::de::DeserializeOwned>(
+    client: &reqwest::Client,
+    url: &str,
+    failure: &str,
+) -> Result<T, String> {
+    let response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| format!("Could not reach the backend: {}", e))?;
+    read_envelope(response, failure).await
+}
+
+/// The `data` of a successful [`ApiEnvelope`], or an error prefixed with
+/// `failure` that carries the backend's message.
+async fn read_envelope<T: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
+    failure: &str,
+) -> Result<T, String> {
+    let status = response.status();
+    let body: ApiEnvelope<T> = response
+        .json()
+        .await
+        .map_err(|e| format!("Unexpected backend response ({}): {}", status, e))?;
+    match body {
+        ApiEnvelope {
+            success: true,
+            data: Some(data),
+            ..
+        } => Ok(data),
+        ApiEnvelope { message, .. } => Err(format!(
+            "{}: {}",
+            failure,
+            message.unwrap_or_else(|| status.to_string())
+        )),
+    }
+}
+
+/// Copy the files of a staged export into `dest` (created if missing), then
+/// remove the staging directory.
+pub fn place_export(staged: &Path, dest: &Path) -> Result<(), String> {
+    copy_dir_contents(staged, dest)
+        .map_err(|e| format!("Failed to write export to {}: {}", dest.display(), e))?;
+    if let Err(err) = fs::remove_dir_all(staged) {
+        log::warn!(
+            "Failed to remove staged export {}: {}",
+            staged.display(),
+            err
+        );
+    }
+    Ok(())
+}
+
+fn copy_dir_contents(src: &Path, dest: &Path) -> io::Result<()> {
+    fs::create_dir_all(dest)?;
+    for entry in fs::read_dir(src)? {
+        let entry = entry?;
+        let target = dest.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_contents(&entry.path(), &target)?;
+        } else {
+            fs::copy(entry.path(), target)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn rejects_malformed_session_ids() {
+        assert!(parse_session_id("not-a-uuid").is_err());
+        assert!(parse_session_id("").is_err());
+        assert_eq!(
+            parse_session_id(" 3f2b8c1e-0000-4000-8000-000000000001 ").unwrap(),
+            Uuid::parse_str("3f2b8c1e-0000-4000-8000-000000000001").unwrap()
+        );
+    }
+
+    #[test]
+    fn requires_an_absolute_destination() {
+        assert!(parse_export_dest("").is_err());
+        assert!(parse_export_dest("exports/session").is_err());
+        let absolute = std::env::temp_dir().join("session-export");
+        assert_eq!(
+            parse_export_dest(absolute.to_str().unwrap()).unwrap(),
+            absolute
+        );
+    }
+
+    #[test]
+    fn place_export_moves_staged_files_into_dest() {
+        let root = scratch_dir("export");
+        let staged = root.join("staging").join("session_1");
+        fs::create_dir_all(staged.join("attachments")).unwrap();
+        fs::write(staged.join("messages_export.jsonl"), "{}").unwrap();
+        fs::write(staged.join("attachments").join("notes.txt"), "notes").unwrap();
+        let dest = root.join("picked");
+
+        place_export(&staged, &dest).unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest.join("messages_export.jsonl")).unwrap(),
+            "{}"
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join("attachments").join("notes.txt")).unwrap(),
+            "notes"
+        );
+        assert!(!staged.exists());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,265 @@
+//! Lifecycle of the backend sidecar process.
+//!
+//! Every spawned backend gets a monitor thread. When the child exits while it
+//! is still the current backend and the app isn't shutting down, it is
+//! restarted on a new port with backoff (see [`RestartPolicy`]), every window
+//! is pointed at the new port and [`BACKEND_RESTARTED_EVENT`] is emitted.
+//! A backend that stayed up long enough before crashing gets the full restart
+//! budget again (see [`RestartPolicy::recovered_after`]).
+
+use std::{
+    sync::atomic::Ordering,
+    time::{Duration, Instant},
+};
+
+use portpicker::pick_unused_port;
+use serde::Serialize;
+use tauri::{
+    api::process::{CommandEvent, TerminatedPayload},
+    async_runtime::Receiver,
+    AppHandle, Manager,
+};
+
+use crate::{launch_backend, BackendState};
+
+/// Emitted to all windows after the backend was restarted following a crash.
+pub const BACKEND_RESTARTED_EVENT: &str = "backend-restarted";
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BackendRestarted {
+    pub port: u16,
+    /// Crash restarts so far, including this one.
+    pub attempt: u32,
+}
+
+/// How often, and how patiently, a crashed backend is restarted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RestartPolicy {
+    pub max_restarts: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RestartPolicy {
+    /// Wait before the restart that follows `restarts_so_far` earlier ones:
+    /// `base_delay` doubled each time, capped at `max_delay`. `None` once
+    /// `max_restarts` is used up.
+    pub fn delay_before(&self, restarts_so_far: u32) -> Option<Duration> {
+        if restarts_so_far >= self.max_restarts {
+            return None;
+        }
+        let factor = 2u32.saturating_pow(restarts_so_far);
+        Some(self.base_delay.saturating_mul(factor).min(self.max_delay))
+    }
+
+    /// Whether a backend that ran for `uptime` before crashing had recovered,
+    /// so earlier crashes no longer count against `max_restarts`. Staying up
+    /// for the longest backoff, `max_delay`, counts as recovered.
+    pub fn recovered_after(&self, uptime: Duration) -> bool {
+        uptime >= self.max_delay
+    }
+}
+
+/// JavaScript that reloads the current page from the backend on `port`,
+/// keeping the path and query (e.g. a session window's `/chat/{id}`).
+pub fn redirect_script(port: u16) -> String {
+    format!(
+        "window.location.replace(window.location.href.replace(/^http:\\/\\/127\\.0\\.0\\.1:\\d+/, 'http://127.0.0.1:{}'))",
+        port
+    )
+}
+
+/// Watch the backend started as `generation` until it exits.
+pub fn watch(app: AppHandle, mut events: Receiver<CommandEvent>, generation: u64) {
+    let started_at = Instant::now();
+    std::thread::spawn(move || {
+        let mut terminated = None;
+        while let Some(event) = events.blocking_recv() {
+            if let CommandEvent::Terminated(payload) = event {
+                terminated = Some(payload);
+                break;
+            }
+        }
+        handle_exit(&app, generation, started_at.elapsed(), terminated);
+    });
+}
+
+fn handle_exit(
+    app: &AppHandle,
+    mut generation: u64,
+    uptime: Duration,
+    terminated: Option<TerminatedPayload>,
+) {
+    let Some(state) = app.try_state::<BackendState>() else {
+        return;
+    };
+    if !state.is_current(generation) {
+        // Replaced by a manual restart, or the app is exiting.
+        return;
+    }
+    log::warn!("Backend exited unexpectedly: {:?}", terminated);
+
+    let policy = RestartPolicy::default();
+    if policy.recovered_after(uptime) {
+        state.crash_restarts.store(0, Ordering::SeqCst);
+    }
+    loop {
+        let attempt = state.crash_restarts.fetch_add(1, Ordering::SeqCst);
+        let Some(delay) = policy.delay_before(attempt) else {
+            log::error!("Backend crashed {} times; not restarting again", attempt);
+            return;
+        };
+        std::thread::sleep(delay);
+
+        let Ok(mut child) = state.child.lock() else {
+            return;
+        };
+        if !state.is_current(generation) {
+            return;
+        }
+        match launch_backend(app, &mut child, pick_unused_port) {
+            Ok(port) => {
+                drop(child);
+                for window in app.windows().values() {
+                    let _ = window.eval(&redirect_script(port));
+                }
+                let _ = app.emit_all(
+                    BACKEND_RESTARTED_EVENT,
+                    BackendRestarted {
+                        port,
+                        attempt: attempt + 1,
+                    },
+                );
+                return;
+            }
+            Err(err) => {
+                log::error!("Failed to restart backend: {}", err);
+                generation = state.generation.load(Ordering::SeqCst);
+            }
+        }
+    }
+}
+
+/// Replace the backend in `slot`: kill the running child (if any), pick a port
+/// and spawn a new child on it. Returns the new port. If spawning fails the
+/// slot is left empty, so a later restart starts from a clean state.
+///
+/// Picking, spawning and killing are passed in so the sequencing can be
+/// tested without launching a sidecar.
+pub fn restart<C>(
+    slot: &mut Option<C>,
+    kill: impl FnOnce(C),
+    pick_port: impl FnOnce() -> Option<u16>,
+    spawn: impl FnOnce(u16) -> Result<C, String>,
+) -> Result<u16, String> {
+    if let Some(child) = slot.take() {
+        kill(child);
+    }
+    let port = pick_port().ok_or("No free port available for the backend")?;
+    *slot = Some(spawn(port)?);
+    Ok(port)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use super::*;
+
+    #[derive(Debug, PartialEq)]
+    struct FakeChild(u16);
+
+    #[test]
+    fn kills_old_child_and_spawns_on_new_port() {
+        let killed = RefCell::new(Vec::new());
+        let mut slot = Some(FakeChild(4000));
+
+        let port = restart(
+            &mut slot,
+            |child| killed.borrow_mut().push(child),
+            || Some(4100),
+            |port| Ok(FakeChild(port)),
+        )
+        .unwrap();
+
+        assert_eq!(port, 4100);
+        assert_eq!(slot, Some(FakeChild(4100)));
+        assert_eq!(*killed.borrow(), vec![FakeChild(4000)]);
+    }
+
+    #[test]
+    fn failed_spawn_leaves_slot_empty() {
+        let mut slot = Some(FakeChild(4000));
+
+        let result = restart(
+            &mut slot,
+            drop,
+            || Some(4100),
+            |_| Err::<FakeChild, _>("sidecar missing".to_string()),
+        );
+
+        assert_eq!(result, Err("sidecar missing".to_string()));
+        assert_eq!(slot, None);
+    }
+
+    #[test]
+    fn restart_delays_back_off_and_stop_at_the_limit() {
+        let policy = RestartPolicy {
+            max_restarts: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+        };
+
+        let delays: Vec<Option<Duration>> = (0..5).map(|n| policy.delay_before(n)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Some(Duration::from_secs(1)),
+                Some(Duration::from_secs(2)),
+                Some(Duration::from_secs(4)),
+                Some(Duration::from_secs(5)),
+                None,
+            ]
+        );
+        assert_eq!(policy.delay_before(40), None);
+    }
+
+    #[test]
+    fn backend_that_stayed_up_for_max_delay_has_recovered() {
+        let policy = RestartPolicy {
+            max_restarts: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(5),
+        };
+
+        assert!(!policy.recovered_after(Duration::from_secs(1)));
+        assert!(!policy.recovered_after(Duration::from_millis(4999)));
+        assert!(policy.recovered_after(Duration::from_secs(5)));
+        assert!(policy.recovered_after(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn redirect_keeps_path_and_query() {
+        let script = redirect_script(4200);
+        assert!(script.contains("'http://127.0.0.1:4200'"));
+        assert!(script.contains("window.location.href.replace"));
+    }
+
+    #[test]
+    fn no_free_port_is_an_error() {
+        let mut slot: Option<FakeChild> = None;
+        let result = restart(&mut slot, drop, || None, |port| Ok(FakeChild(port)));
+        assert!(result.is_err());
+        assert_eq!(slot, None);
+    }
+}
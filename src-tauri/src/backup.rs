@@ -0,0 +1,150 @@
+//! Restoring user data from a backup zip.
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+};
+
+use zip::ZipArchive;
+
+use crate::extract::safe_extract;
+
+/// Top-level files that mark a zip as a user data backup.
+const BACKUP_MARKERS: &[&str] = &["db.sqlite", "config.json"];
+
+/// Extract the backup at `src` into `data_dir`.
+///
+/// The whole archive is checked before anything is written: it must contain a
+/// backup marker, every entry must stay inside `data_dir`, and existing files
+/// are only replaced when `overwrite` is set. Returns the restored file paths.
+pub fn restore_from_zip(
+    src: &Path,
+    data_dir: &Path,
+    overwrite: bool,
+) -> Result<Vec<PathBuf>, String> {
+    let file = File::open(src).map_err(|e| format!("Failed to open {}: {}", src.display(), e))?;
+    let mut archive =
+        ZipArchive::new(file).map_err(|e| format!("Invalid backup archive: {}", e))?;
+
+    if !archive
+        .file_names()
+        .any(|name| BACKUP_MARKERS.contains(&name))
+    {
+        return Err(format!(
+            "{} does not look like a backup (expected one of {:?})",
+            src.display(),
+            BACKUP_MARKERS
+        ));
+    }
+
+    let mut targets = Vec::with_capacity(archive.len());
+    let mut conflicts = Vec::new();
+    for i in 0..archive.len() {
+        let entry = archive
+            .by_index(i)
+            .map_err(|e| format!("Failed to read archive entry: {}", e))?;
+        let target = safe_extract(entry.name(), data_dir)?;
+        if !entry.is_dir() && !overwrite && target.exists() {
+            conflicts.push(target.display().to_string());
+        }
+        targets.push((target, entry.is_dir()));
+    }
+
+    if !conflicts.is_empty() {
+        return Err(format!(
+            "Refusing to overwrite existing files: {}",
+            conflicts.join(", ")
+        ));
+    }
+
+    let mut restored = Vec::new();
+    for (i, (target, is_dir)) in targets.into_iter().enumerate() {
+        if is_dir {
+            fs::create_dir_all(&target)
+                .map_err(|e| format!("Failed to create {}: {}", target.display(), e))?;
+            continue;
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        let mut entry = archive
+            .by_index(i)
+            .map_err(|e| format!("Failed to read archive entry: {}", e))?;
+        let mut out = File::create(&target)
+            .map_err(|e| format!("Failed to create {}: {}", target.display(), e))?;
+        io::copy(&mut entry, &mut out)
+            .map_err(|e| format!("Failed to write {}: {}", target.display(), e))?;
+        restored.push(target);
+    }
+
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use zip::{write::FileOptions, ZipWriter};
+
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    fn write_zip(path: &Path, entries: &[(&str, &str)]) {
+        let mut zip = ZipWriter::new(File::create(path).unwrap());
+        for (name, content) in entries {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(content.as_bytes()).unwrap();
+        }
+        zip.finish().unwrap();
+    }
+
+    #[test]
+    fn restores_backup_into_data_dir() {
+        let root = scratch_dir("restore");
+        let src = root.join("backup.zip");
+        let data_dir = root.join("data");
+        write_zip(
+            &src,
+            &[("config.json", "{}"), ("nested/profiles.json", "[]")],
+        );
+
+        let restored = restore_from_zip(&src, &data_dir, false).unwrap();
+        assert_eq!(restored.len(), 2);
+        assert!(data_dir.join("nested/profiles.json").exists());
+
+        // Second restore collides with the files we just wrote.
+        assert!(restore_from_zip(&src, &data_dir, false).is_err());
+        assert!(restore_from_zip(&src, &data_dir, true).is_ok());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn rejects_zip_slip_entries() {
+        let root = scratch_dir("zip-slip");
+        let src = root.join("backup.zip");
+        let data_dir = root.join("data");
+        write_zip(&src, &[("config.json", "{}"), ("../escaped.txt", "pwned")]);
+
+        let err = restore_from_zip(&src, &data_dir, true).unwrap_err();
+        assert!(err.contains("unsafe path"), "{err}");
+        assert!(!root.join("escaped.txt").exists());
+        assert!(!data_dir.join("config.json").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn rejects_archives_without_backup_markers() {
+        let root = scratch_dir("markers");
+        let src = root.join("random.zip");
+        write_zip(&src, &[("notes.txt", "hello")]);
+
+        let err = restore_from_zip(&src, &root.join("data"), true).unwrap_err();
+        assert!(err.contains("does not look like a backup"), "{err}");
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,99 @@
+//! Per-profile data locations for the desktop shell.
+//!
+//! `AGENT_CHATGROUP_PROFILE=work` (or `--profile work` on the command line)
+//! maps every directory from `agents-chatgroup` to `agents-chatgroup-work`, so
+//! the deletion commands only touch the active profile. Name handling comes
+//! from the `app-profile` crate the backend uses too, so the two can't drift.
+
+use std::path::PathBuf;
+
+pub use app_profile::{
+    app_name_for as app_name, sanitize_profile, temp_dir_for as temp_workspace_dir, PROFILE_ENV,
+};
+use directories::{BaseDirs, ProjectDirs};
+
+/// Read `--profile <name>` or `--profile=<name>` from the command line.
+pub fn profile_from_args<I: IntoIterator<Item = String>>(args: I) -> Option<String> {
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        if arg == "--profile" {
+            return args.next().and_then(|value| sanitize_profile(&value));
+        }
+        if let Some(value) = arg.strip_prefix("--profile=") {
+            return sanitize_profile(value);
+        }
+    }
+    None
+}
+
+/// The active profile: `--profile` wins over `AGENT_CHATGROUP_PROFILE`.
+pub fn active_profile() -> Option<String> {
+    profile_from_args(std::env::args().skip(1)).or_else(app_profile::active_profile)
+}
+
+pub fn project_dirs(profile: Option<&str>) -> Option<ProjectDirs> {
+    ProjectDirs::from("ai", "starterra.ai", &app_name(profile))
+}
+
+/// Chat history directory the backend writes for `profile`. Must match
+/// `chat_history_file::chat_history_dir`.
+pub fn chat_history_dir(profile: Option<&str>) -> Option<PathBuf> {
+    BaseDirs::new().map(|dirs| {
+        dirs.data_dir()
+            .join(format!(".{}", app_name(profile)))
+            .join("chat_history")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn reads_profile_from_args() {
+        assert_eq!(
+            profile_from_args(args(&["--profile", "Work"])),
+            Some("work".to_string())
+        );
+        assert_eq!(
+            profile_from_args(args(&["--profile=personal"])),
+            Some("personal".to_string())
+        );
+        assert_eq!(profile_from_args(args(&["--profile"])), None);
+        assert_eq!(profile_from_args(args(&[])), None);
+    }
+
+    #[test]
+    fn profiles_resolve_to_distinct_directories() {
+        let work = project_dirs(Some("work")).expect("home directory");
+        let personal = project_dirs(Some("personal")).expect("home directory");
+        let default = project_dirs(None).expect("home directory");
+
+        assert_ne!(work.data_dir(), personal.data_dir());
+        assert_ne!(work.data_dir(), default.data_dir());
+        assert_ne!(work.cache_dir(), personal.cache_dir());
+        assert_ne!(
+            temp_workspace_dir(Some("work")),
+            temp_workspace_dir(Some("personal"))
+        );
+    }
+
+    #[test]
+    fn temp_workspace_dir_matches_backend_location() {
+        let dir_name = if cfg!(debug_assertions) {
+            "agents-chatgroup-dev"
+        } else {
+            "agents-chatgroup"
+        };
+        let expected = if cfg!(target_os = "linux") {
+            PathBuf::from("/var/tmp").join(dir_name)
+        } else {
+            std::env::temp_dir().join(dir_name)
+        };
+        assert_eq!(temp_workspace_dir(None), expected);
+    }
+}
@@ -0,0 +1,124 @@
+//! Path checks shared by everything that extracts archives into user data.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Resolve `entry_name` from an archive to a path inside `base_dir`.
+///
+/// Rejects absolute paths, drive prefixes and `..` segments that climb out of
+/// `base_dir`. The result is checked again after canonicalizing its existing
+/// ancestors, so a symlink already inside `base_dir` can't redirect the write.
+/// `base_dir` does not need to exist yet.
+pub fn safe_extract(entry_name: &str, base_dir: &Path) -> Result<PathBuf, String> {
+    let unsafe_path = || format!("Refusing to extract unsafe path: {}", entry_name);
+
+    // Archives created on Windows may use backslashes as separators.
+    let normalized = entry_name.replace('\\', "/");
+    let mut relative = PathBuf::new();
+    for component in Path::new(&normalized).components() {
+        match component {
+            Component::Normal(part) => relative.push(part),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !relative.pop() {
+                    return Err(unsafe_path());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(unsafe_path()),
+        }
+    }
+    if relative.as_os_str().is_empty() {
+        return Err(unsafe_path());
+    }
+
+    let base = canonicalize_existing(base_dir)
+        .map_err(|e| format!("Failed to resolve {}: {}", base_dir.display(), e))?;
+    let target = canonicalize_existing(&base.join(&relative))
+        .map_err(|e| format!("Failed to resolve {}: {}", entry_name, e))?;
+    if !target.starts_with(&base) {
+        return Err(unsafe_path());
+    }
+
+    Ok(target)
+}
+
+/// Canonicalize the deepest existing ancestor of `path` and re-append the rest.
+fn canonicalize_existing(path: &Path) -> std::io::Result<PathBuf> {
+    let mut existing = path;
+    let mut rest = Vec::new();
+    while !existing.exists() {
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                rest.push(name.to_os_string());
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+
+    let mut resolved = if existing.as_os_str().is_empty() {
+        std::env::current_dir()?
+    } else {
+        existing.canonicalize()?
+    };
+    for name in rest.into_iter().rev() {
+        resolved.push(name);
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn rejects_parent_traversal() {
+        let base = scratch_dir("traversal");
+        assert!(safe_extract("../../etc/passwd", &base).is_err());
+        assert!(safe_extract("nested/../../escape.txt", &base).is_err());
+        assert!(safe_extract("..\\..\\windows\\system.ini", &base).is_err());
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn rejects_absolute_paths() {
+        let base = scratch_dir("absolute");
+        assert!(safe_extract("/etc/passwd", &base).is_err());
+        assert!(safe_extract("", &base).is_err());
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn allows_nested_paths() {
+        let base = scratch_dir("nested");
+        let canonical_base = base.canonicalize().unwrap();
+
+        let target = safe_extract("chat_history/abc.json", &base).unwrap();
+        assert_eq!(target, canonical_base.join("chat_history/abc.json"));
+
+        let target = safe_extract("./a/../config.json", &base).unwrap();
+        assert_eq!(target, canonical_base.join("config.json"));
+
+        // Base directories that don't exist yet still resolve.
+        let missing = base.join("not-created");
+        let target = safe_extract("db.sqlite", &missing).unwrap();
+        assert_eq!(target, canonical_base.join("not-created/db.sqlite"));
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_symlink_escape() {
+        let base = scratch_dir("symlink");
+        let outside = scratch_dir("symlink-outside");
+        std::os::unix::fs::symlink(&outside, base.join("link")).unwrap();
+
+        assert!(safe_extract("link/file.txt", &base).is_err());
+
+        fs::remove_dir_all(base).unwrap();
+        fs::remove_dir_all(outside).unwrap();
+    }
+}
@@ -1,19 +1,67 @@
 #![cfg_attr(not(debug_assertions), windows_subsystem = "windows")]
 
-use std::sync::Mutex;
+mod backend_api;
+mod backend_process;
+mod backup;
+mod data_dirs;
+mod extract;
+mod reveal;
+mod session_windows;
+mod temp_workspaces;
+#[cfg(test)]
+mod test_support;
+mod usage;
+mod window_title;
+
+use std::sync::{
+    atomic::{AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering},
+    Mutex,
+};
 
-use directories::ProjectDirs;
 use portpicker::pick_unused_port;
-use tauri::{api::process::{Command, CommandChild}, Manager};
+use tauri::{
+    api::process::{Command, CommandChild, CommandEvent},
+    async_runtime::Receiver,
+    AppHandle, Manager,
+};
 
 struct BackendState {
     child: Mutex<Option<CommandChild>>,
+    /// Port of the running backend; changes when it is restarted.
+    port: AtomicU16,
+    /// Bumped on every (re)start so monitors of replaced children stand down.
+    generation: AtomicU64,
+    /// Crash restarts so far; reset by a manual restart, or when a backend
+    /// crashes after staying up long enough to count as recovered.
+    crash_restarts: AtomicU32,
+    /// Set when the app exits; the backend stopping after that is expected.
+    shutting_down: AtomicBool,
+}
+
+impl BackendState {
+    fn new(port: u16) -> Self {
+        Self {
+            child: Mutex::new(None),
+            port: AtomicU16::new(port),
+            generation: AtomicU64::new(0),
+            crash_restarts: AtomicU32::new(0),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    /// Whether the backend started as `generation` is the one that should be
+    /// running.
+    fn is_current(&self, generation: u64) -> bool {
+        !self.shutting_down.load(Ordering::SeqCst)
+            && self.generation.load(Ordering::SeqCst) == generation
+    }
 }
 
-/// Delete all user data (database, config, cache, workspaces)
+/// Delete all user data (database, config, cache, workspaces) for the active profile
 #[tauri::command]
 fn delete_all_user_data() -> Result<String, String> {
-    let proj = ProjectDirs::from("ai", "starterra.ai", "agents-chatgroup")
+    let profile = data_dirs::active_profile();
+    let proj = data_dirs::project_dirs(profile.as_deref())
         .ok_or("Could not determine data directories")?;
 
     let mut deleted_paths = Vec::new();
@@ -38,11 +86,7 @@ fn delete_all_user_data() -> Result<String, String> {
     }
 
     // Delete temp workspaces
-    let temp_dir = if cfg!(target_os = "macos") || cfg!(target_os = "linux") {
-        std::path::PathBuf::from("/var/tmp/agents-chatgroup")
-    } else {
-        std::env::temp_dir().join("agents-chatgroup")
-    };
+    let temp_dir = data_dirs::temp_workspace_dir(profile.as_deref());
     if temp_dir.exists() {
         match std::fs::remove_dir_all(&temp_dir) {
             Ok(_) => deleted_paths.push(temp_dir.display().to_string()),
@@ -58,11 +102,25 @@ fn delete_all_user_data() -> Result<String, String> {
 }
 
 /// Delete only cache and temp data (keep core data like db.sqlite, config.json)
+/// for the active profile. Temp workspaces of `keep_sessions` are preserved;
+/// their paths are looked up through the backend first, and nothing is deleted
+/// if any of them can't be resolved.
 #[tauri::command]
-fn delete_cache_data() -> Result<String, String> {
-    let proj = ProjectDirs::from("ai", "starterra.ai", "agents-chatgroup")
+async fn delete_cache_data(
+    keep_sessions: Vec<String>,
+    state: tauri::State<'_, BackendState>,
+) -> Result<String, String> {
+    let profile = data_dirs::active_profile();
+    let proj = data_dirs::project_dirs(profile.as_deref())
         .ok_or("Could not determine data directories")?;
 
+    let port = state.port.load(Ordering::SeqCst);
+    let mut keep_workspaces = Vec::with_capacity(keep_sessions.len());
+    for session_id in &keep_sessions {
+        let session_id = backend_api::parse_session_id(session_id)?;
+        keep_workspaces.push(backend_api::session_workspace_path(port, session_id).await?);
+    }
+
     let mut deleted_paths = Vec::new();
     let mut errors = Vec::new();
 
@@ -75,18 +133,14 @@ fn delete_cache_data() -> Result<String, String> {
         }
     }
 
-    // Delete temp workspaces
-    let temp_dir = if cfg!(target_os = "macos") || cfg!(target_os = "linux") {
-        std::path::PathBuf::from("/var/tmp/agents-chatgroup")
-    } else {
-        std::env::temp_dir().join("agents-chatgroup")
-    };
-    if temp_dir.exists() {
-        match std::fs::remove_dir_all(&temp_dir) {
-            Ok(_) => deleted_paths.push(temp_dir.display().to_string()),
-            Err(e) => errors.push(format!("Failed to delete {}: {}", temp_dir.display(), e)),
-        }
-    }
+    // Delete temp workspaces, except those of sessions the user wants to keep
+    let temp_dir = data_dirs::temp_workspace_dir(profile.as_deref());
+    temp_workspaces::clear_temp_workspaces(
+        &temp_dir,
+        &keep_workspaces,
+        &mut deleted_paths,
+        &mut errors,
+    );
 
     if errors.is_empty() {
         Ok(format!("Deleted: {:?}", deleted_paths))
@@ -95,30 +149,178 @@ fn delete_cache_data() -> Result<String, String> {
     }
 }
 
-fn spawn_backend(port: u16) -> Result<CommandChild, Box<dyn std::error::Error>> {
+/// Restore user data for the active profile from a backup zip. The backend is
+/// stopped while files are replaced, so it never sees a half-restored
+/// database, and is started again afterwards, also when the restore fails.
+/// Returns the new backend port.
+#[tauri::command]
+fn restore_user_data(
+    app: AppHandle,
+    src: String,
+    overwrite: bool,
+    state: tauri::State<'_, BackendState>,
+) -> Result<u16, String> {
+    let profile = data_dirs::active_profile();
+    let proj = data_dirs::project_dirs(profile.as_deref())
+        .ok_or("Could not determine data directories")?;
+
+    let mut child = state
+        .child
+        .lock()
+        .map_err(|_| "Backend state is unavailable")?;
+    // Bump first so the monitor of the child killed below stands down.
+    state.generation.fetch_add(1, Ordering::SeqCst);
+    if let Some(old) = child.take() {
+        let _ = old.kill();
+    }
+
+    let restored = backup::restore_from_zip(std::path::Path::new(&src), proj.data_dir(), overwrite);
+    state.crash_restarts.store(0, Ordering::SeqCst);
+    let port = launch_backend(&app, &mut child, pick_unused_port)?;
+    restored.map(|_| port)
+}
+
+/// Report how much disk space each data category uses for the active profile
+#[tauri::command]
+fn user_data_usage() -> Result<usage::DataUsage, String> {
+    usage::collect_usage(data_dirs::active_profile().as_deref())
+}
+
+/// Open the active profile's data directory in the OS file manager, creating
+/// it first if needed. Returns the directory path.
+#[tauri::command]
+fn reveal_data_dir() -> Result<String, String> {
+    let profile = data_dirs::active_profile();
+    let proj = data_dirs::project_dirs(profile.as_deref())
+        .ok_or("Could not determine data directories")?;
+
+    let data_dir = reveal::ensure_dir(proj.data_dir())?;
+    reveal::open_in_file_manager(&data_dir)?;
+    Ok(data_dir.display().to_string())
+}
+
+/// Export a chat session archive into `dest` through the backend. Returns the
+/// directory the archive was written to.
+#[tauri::command]
+async fn export_session(
+    session_id: String,
+    dest: String,
+    state: tauri::State<'_, BackendState>,
+) -> Result<String, String> {
+    let session_id = backend_api::parse_session_id(&session_id)?;
+    let dest = backend_api::parse_export_dest(&dest)?;
+    let port = state.port.load(Ordering::SeqCst);
+    backend_api::export_session(port, session_id, dest).await
+}
+
+/// Show the active session's title on the calling window; an empty title
+/// restores the app name. The frontend calls this whenever the selected session
+/// changes (and after a rename):
+///
+/// ```ts
+/// import { invoke } from '@tauri-apps/api/tauri';
+/// await invoke('set_window_title', { title: session.title ?? '' });
+/// ```
+#[tauri::command]
+fn set_window_title(window: tauri::Window, title: String) -> Result<(), String> {
+    let app_name = data_dirs::app_name(data_dirs::active_profile().as_deref());
+    window
+        .set_title(&window_title::resolve(&title, &app_name))
+        .map_err(|e| format!("Failed to set window title: {}", e))
+}
+
+/// Open a chat session in its own window, sharing the running backend.
+#[tauri::command]
+fn open_session_window(app: tauri::AppHandle, session_id: String) -> Result<(), String> {
+    let session_id = backend_api::parse_session_id(&session_id)?;
+    session_windows::open_session_window(&app, session_id)
+}
+
+/// Kill the backend and start a fresh one on a new port, e.g. after it crashed.
+/// Returns the new port so the frontend can navigate to it. Concurrent calls
+/// are serialized by the child lock.
+#[tauri::command]
+fn restart_backend(app: AppHandle, state: tauri::State<'_, BackendState>) -> Result<u16, String> {
+    let mut child = state
+        .child
+        .lock()
+        .map_err(|_| "Backend state is unavailable")?;
+    state.crash_restarts.store(0, Ordering::SeqCst);
+    launch_backend(&app, &mut child, pick_unused_port)
+}
+
+/// Replace the backend in `slot` with a new, monitored one on a port from
+/// `pick_port`. Callers hold the child lock. Returns the new port.
+fn launch_backend(
+    app: &AppHandle,
+    slot: &mut Option<CommandChild>,
+    pick_port: impl FnOnce() -> Option<u16>,
+) -> Result<u16, String> {
+    let state = app.state::<BackendState>();
+    // Bump first so the monitor of the child killed below stands down.
+    let generation = state.generation.fetch_add(1, Ordering::SeqCst) + 1;
+    let port = backend_process::restart(
+        slot,
+        |old| {
+            let _ = old.kill();
+        },
+        pick_port,
+        |port| {
+            let (events, child) =
+                spawn_backend(port).map_err(|e| format!("Failed to start backend: {}", e))?;
+            backend_process::watch(app.clone(), events, generation);
+            Ok(child)
+        },
+    )?;
+    state.port.store(port, Ordering::SeqCst);
+    Ok(port)
+}
+
+fn spawn_backend(
+    port: u16,
+) -> Result<(Receiver<CommandEvent>, CommandChild), Box<dyn std::error::Error>> {
     let mut cmd = Command::new_sidecar("server")?;
     let mut envs = std::collections::HashMap::new();
     envs.insert("BACKEND_PORT".to_string(), port.to_string());
     envs.insert("HOST".to_string(), "127.0.0.1".to_string());
     envs.insert("RUST_LOG".to_string(), "info".to_string());
     envs.insert("AGENT_CHATGROUP_DESKTOP".to_string(), "1".to_string());
+    if let Some(profile) = data_dirs::active_profile() {
+        envs.insert(data_dirs::PROFILE_ENV.to_string(), profile);
+    }
     cmd = cmd.envs(envs);
 
-    let (_rx, child) = cmd.spawn()?;
-
-    Ok(child)
+    Ok(cmd.spawn()?)
 }
 
 fn main() {
     tauri::Builder::default()
-        .invoke_handler(tauri::generate_handler![delete_all_user_data, delete_cache_data])
+        // Logs go to stdout and the app's log directory.
+        .plugin(tauri_plugin_log::Builder::default().build())
+        .invoke_handler(tauri::generate_handler![
+            delete_all_user_data,
+            delete_cache_data,
+            restore_user_data,
+            user_data_usage,
+            reveal_data_dir,
+            export_session,
+            set_window_title,
+            open_session_window,
+            restart_backend
+        ])
         .setup(|app| {
-            let port = pick_unused_port().unwrap_or(3999);
-            let child = spawn_backend(port)?;
-
-            app.manage(BackendState {
-                child: Mutex::new(Some(child)),
-            });
+            // One backend serves every window, including session windows.
+            let fallback_port = 3999;
+            app.manage(BackendState::new(fallback_port));
+            let state = app.state::<BackendState>();
+            let mut child = state
+                .child
+                .lock()
+                .map_err(|_| "Backend state is unavailable")?;
+            let port = launch_backend(&app.handle(), &mut child, || {
+                Some(pick_unused_port().unwrap_or(fallback_port))
+            })?;
+            drop(child);
 
             if let Some(window) = app.get_window("main") {
                 let url = format!("http://127.0.0.1:{}", port);
@@ -133,8 +335,11 @@ fn main() {
         .build(tauri::generate_context!())
         .expect("error while running tauri application")
         .run(|app, event| match event {
+            // Only raised once the last window closes, so closing a session
+            // window never stops the backend the others still use.
             tauri::RunEvent::ExitRequested { .. } => {
                 if let Some(state) = app.try_state::<BackendState>() {
+                    state.shutting_down.store(true, Ordering::SeqCst);
                     if let Ok(mut guard) = state.child.lock() {
                         if let Some(child) = guard.take() {
                             let _ = child.kill();
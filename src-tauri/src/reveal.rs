@@ -0,0 +1,58 @@
+//! Showing data directories in the platform file manager.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Program that opens a directory in the file manager on this platform.
+pub fn file_manager_program() -> &'static str {
+    if cfg!(target_os = "macos") {
+        "open"
+    } else if cfg!(target_os = "windows") {
+        "explorer"
+    } else {
+        "xdg-open"
+    }
+}
+
+/// Create `dir` if it doesn't exist yet, so there is something to reveal on a
+/// fresh install.
+pub fn ensure_dir(dir: &Path) -> Result<PathBuf, String> {
+    fs::create_dir_all(dir).map_err(|e| format!("Failed to create {}: {}", dir.display(), e))?;
+    Ok(dir.to_path_buf())
+}
+
+/// Open `dir` in Finder, Explorer or the desktop's default file manager.
+pub fn open_in_file_manager(dir: &Path) -> Result<(), String> {
+    let program = file_manager_program();
+    let mut child = Command::new(program)
+        .arg(dir)
+        .spawn()
+        .map_err(|e| format!("Failed to run {}: {}", program, e))?;
+    // Reap the launcher in the background; Explorer exits non-zero even on success.
+    std::thread::spawn(move || {
+        let _ = child.wait();
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn ensure_dir_creates_missing_directories() {
+        let root = scratch_dir("reveal");
+        let dir = root.join("data").join("nested");
+
+        assert_eq!(ensure_dir(&dir).unwrap(), dir);
+        assert!(dir.is_dir());
+        // Already existing is fine.
+        assert_eq!(ensure_dir(&dir).unwrap(), dir);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,61 @@
+//! Extra windows that each show one chat session.
+//!
+//! Every window loads the same backend sidecar; each opens on the frontend's
+//! `/chat/{id}` route so windows keep their own selection. Closing one of
+//! these windows leaves the backend running for the others.
+
+use std::sync::atomic::Ordering;
+
+use tauri::{AppHandle, Manager, WindowBuilder, WindowUrl};
+use uuid::Uuid;
+
+use crate::{data_dirs, BackendState};
+
+/// Window label for `session_id`, so a session opens at most one window.
+pub fn session_window_label(session_id: Uuid) -> String {
+    format!("session-{}", session_id)
+}
+
+/// Backend URL that opens the app on `session_id`.
+pub fn session_window_url(port: u16, session_id: Uuid) -> String {
+    format!("http://127.0.0.1:{}/chat/{}", port, session_id)
+}
+
+/// Open `session_id` in its own window, or focus the window already showing it.
+pub fn open_session_window(app: &AppHandle, session_id: Uuid) -> Result<(), String> {
+    let label = session_window_label(session_id);
+    if let Some(window) = app.get_window(&label) {
+        return window
+            .set_focus()
+            .map_err(|e| format!("Failed to focus session window: {}", e));
+    }
+
+    let port = app.state::<BackendState>().port.load(Ordering::SeqCst);
+    let url = session_window_url(port, session_id)
+        .parse()
+        .map_err(|e| format!("Invalid session window URL: {}", e))?;
+    WindowBuilder::new(app, label, WindowUrl::External(url))
+        .title(data_dirs::app_name(data_dirs::active_profile().as_deref()))
+        .inner_size(1200.0, 800.0)
+        .build()
+        .map_err(|e| format!("Failed to open session window: {}", e))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_session_urls_and_labels() {
+        let session_id = Uuid::parse_str("3f2b8c1e-0000-4000-8000-000000000001").unwrap();
+        assert_eq!(
+            session_window_url(4123, session_id),
+            "http://127.0.0.1:4123/chat/3f2b8c1e-0000-4000-8000-000000000001"
+        );
+        assert_eq!(
+            session_window_label(session_id),
+            "session-3f2b8c1e-0000-4000-8000-000000000001"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+//! Clearing temp workspaces while keeping the ones that belong to given sessions.
+//!
+//! Workspace folders are named after their workspace, not the session, so the
+//! caller resolves which folders to keep (see
+//! [`crate::backend_api::session_workspace_path`]).
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Remove everything under `temp_dir` except the `keep` workspace folders (and
+/// the folders that contain them). Kept paths outside `temp_dir` are ignored.
+/// Deleted paths and errors are appended to the given lists. With nothing to
+/// keep under it, `temp_dir` itself is removed.
+pub fn clear_temp_workspaces(
+    temp_dir: &Path,
+    keep: &[PathBuf],
+    deleted_paths: &mut Vec<String>,
+    errors: &mut Vec<String>,
+) {
+    if !temp_dir.exists() {
+        return;
+    }
+
+    // Compare resolved paths, since the temp dir may sit behind a symlink
+    // (e.g. /var -> /private/var on macOS).
+    let temp_dir = fs::canonicalize(temp_dir).unwrap_or_else(|_| temp_dir.to_path_buf());
+    let keep: Vec<PathBuf> = keep
+        .iter()
+        .filter_map(|path| fs::canonicalize(path).ok())
+        .filter(|path| path.starts_with(&temp_dir) && *path != temp_dir)
+        .collect();
+    if keep.is_empty() {
+        match fs::remove_dir_all(&temp_dir) {
+            Ok(_) => deleted_paths.push(temp_dir.display().to_string()),
+            Err(e) => errors.push(format!("Failed to delete {}: {}", temp_dir.display(), e)),
+        }
+        return;
+    }
+
+    remove_all_except(&temp_dir, &keep, deleted_paths, errors);
+}
+
+fn remove_all_except(
+    dir: &Path,
+    keep: &[PathBuf],
+    deleted_paths: &mut Vec<String>,
+    errors: &mut Vec<String>,
+) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            errors.push(format!("Failed to read {}: {}", dir.display(), e));
+            return;
+        }
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if keep.contains(&path) {
+            continue;
+        }
+        if keep.iter().any(|kept| kept.starts_with(&path)) {
+            remove_all_except(&path, keep, deleted_paths, errors);
+            continue;
+        }
+
+        let result = if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        match result {
+            Ok(_) => deleted_paths.push(path.display().to_string()),
+            Err(e) => errors.push(format!("Failed to delete {}: {}", path.display(), e)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn keeps_only_listed_workspaces() {
+        let temp_dir = scratch_dir("keep");
+        // `{short workspace id}-{task title}`, as the backend names them.
+        let kept = temp_dir.join("worktrees/3f2b-fix-login-bug");
+        let removed = temp_dir.join("worktrees/9a1c-add-dark-mode");
+        fs::create_dir_all(kept.join("repo")).unwrap();
+        fs::create_dir_all(removed.join("repo")).unwrap();
+        fs::create_dir_all(temp_dir.join("qa-repos/sample")).unwrap();
+        fs::write(temp_dir.join("stray.log"), "log").unwrap();
+        let elsewhere = scratch_dir("keep-elsewhere");
+
+        let mut deleted = Vec::new();
+        let mut errors = Vec::new();
+        clear_temp_workspaces(
+            &temp_dir,
+            &[kept.clone(), elsewhere.clone()],
+            &mut deleted,
+            &mut errors,
+        );
+
+        assert!(errors.is_empty(), "{errors:?}");
+        assert!(kept.join("repo").exists());
+        assert!(!removed.exists());
+        assert!(!temp_dir.join("qa-repos").exists());
+        assert!(!temp_dir.join("stray.log").exists());
+        assert!(elsewhere.exists());
+
+        // Nothing to keep: the whole temp dir goes.
+        clear_temp_workspaces(&temp_dir, &[], &mut deleted, &mut errors);
+        assert!(!temp_dir.exists());
+        fs::remove_dir_all(elsewhere).unwrap();
+    }
+}
@@ -0,0 +1,20 @@
+//! Helpers shared by the unit tests.
+
+use std::{fs, path::PathBuf, time::SystemTime};
+
+/// Create an empty directory under the system temp dir that no other test (or
+/// concurrent test run) uses. `name` only makes it easier to spot.
+pub fn scratch_dir(name: &str) -> PathBuf {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    let dir = std::env::temp_dir().join(format!(
+        "agents-chatgroup-test-{}-{}-{}",
+        name,
+        std::process::id(),
+        nanos
+    ));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
@@ -0,0 +1,78 @@
+//! Disk usage of the user data directories, shown before clearing data.
+
+use std::{fs, io, path::Path};
+
+use serde::Serialize;
+
+use crate::data_dirs;
+
+/// Bytes used by each data category. Missing directories count as zero.
+#[derive(Debug, Default, Serialize)]
+pub struct DataUsage {
+    pub data_dir: u64,
+    pub cache_dir: u64,
+    pub temp_workspaces: u64,
+    pub chat_history: u64,
+}
+
+/// Total size of the files under `path`. Symlinks are not followed, so a link
+/// out of the directory doesn't inflate the total.
+pub fn dir_size(path: &Path) -> io::Result<u64> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        Err(e) => return Err(e),
+    };
+    if !metadata.is_dir() {
+        return Ok(if metadata.is_file() {
+            metadata.len()
+        } else {
+            0
+        });
+    }
+
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += dir_size(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// Usage for `profile`'s directories.
+pub fn collect_usage(profile: Option<&str>) -> Result<DataUsage, String> {
+    let proj = data_dirs::project_dirs(profile).ok_or("Could not determine data directories")?;
+    let size = |path: &Path| {
+        dir_size(path).map_err(|e| format!("Failed to measure {}: {}", path.display(), e))
+    };
+
+    Ok(DataUsage {
+        data_dir: size(proj.data_dir())?,
+        cache_dir: size(proj.cache_dir())?,
+        temp_workspaces: size(&data_dirs::temp_workspace_dir(profile))?,
+        chat_history: match data_dirs::chat_history_dir(profile) {
+            Some(dir) => size(&dir)?,
+            None => 0,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::scratch_dir;
+
+    #[test]
+    fn sums_nested_files_and_ignores_missing_dirs() {
+        let root = scratch_dir("usage");
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        fs::write(root.join("top.txt"), vec![0u8; 10]).unwrap();
+        fs::write(root.join("a/mid.txt"), vec![0u8; 20]).unwrap();
+        fs::write(root.join("a/b/deep.txt"), vec![0u8; 30]).unwrap();
+
+        assert_eq!(dir_size(&root).unwrap(), 60);
+        assert_eq!(dir_size(&root.join("a")).unwrap(), 50);
+        assert_eq!(dir_size(&root.join("missing")).unwrap(), 0);
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
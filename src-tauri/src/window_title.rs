@@ -0,0 +1,27 @@
+//! Main window title that follows the active chat session.
+
+/// Title to show for a session titled `title`. Whitespace (including
+/// newlines) is collapsed; an empty title falls back to `app_name`.
+pub fn resolve(title: &str, app_name: &str) -> String {
+    let title = title.split_whitespace().collect::<Vec<_>>().join(" ");
+    if title.is_empty() {
+        app_name.to_string()
+    } else {
+        title
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_titles_fall_back_to_app_name() {
+        assert_eq!(resolve("", "agents-chatgroup"), "agents-chatgroup");
+        assert_eq!(resolve(" \n\t ", "agents-chatgroup"), "agents-chatgroup");
+        assert_eq!(
+            resolve("  Release\n planning ", "agents-chatgroup"),
+            "Release planning"
+        );
+    }
+}